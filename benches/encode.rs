@@ -0,0 +1,74 @@
+//! Encode time per frame on synthetic screen content, for comparing the
+//! software backends (openh264, and rav1e with `--features av1`) and
+//! openh264's threaded slices at 4K.
+//!
+//! cargo bench --bench encode --features av1
+
+use std::{hint::black_box, sync::Arc, time::Instant};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use foundry::{
+    test_frames,
+    video_pipeline::{PipelineFrame, PipelineOptions, PixelFormat, RateControl, VideoCodec, VideoPipeline},
+};
+
+/// Distinct pictures cycled through, so every frame has motion to code
+const PICTURES: u32 = 16;
+
+/// `codec` encoding a moving `width` x `height` gradient at `bitrate_bps`,
+/// on `threads` threads or the pipeline's default for the size
+fn bench_codec(
+    c: &mut Criterion,
+    group: &str,
+    codec: VideoCodec,
+    (width, height): (u32, u32),
+    bitrate_bps: u32,
+    threads: Option<u16>,
+) {
+    let options = PipelineOptions {
+        rate_control: RateControl::Bitrate(bitrate_bps),
+        threads,
+        ..PipelineOptions::default()
+    };
+    let mut pipeline = VideoPipeline::new(codec, options).unwrap();
+    let name = match threads {
+        Some(threads) => format!("{}/{threads}_threads", pipeline.backend()),
+        None => pipeline.backend().to_string(),
+    };
+    let pictures: Vec<_> = (0..PICTURES)
+        .map(|step| Arc::new(test_frames::moving_gradient(width, height, step)))
+        .collect();
+    let mut next = 0;
+
+    let mut group = c.benchmark_group(group);
+    group.throughput(Throughput::Elements(1));
+    group.sample_size(20);
+    group.bench_function(name, |b| {
+        b.iter(|| {
+            let frame = PipelineFrame::Rgba {
+                frame: pictures[next % pictures.len()].clone(),
+                stride: width as usize * 4,
+                format: PixelFormat::Bgra8888,
+            };
+            next += 1;
+            black_box(pipeline.encode(frame, Instant::now(), false).unwrap())
+        })
+    });
+    group.finish();
+}
+
+fn encode_1080p(c: &mut Criterion) {
+    bench_codec(c, "encode_1080p", VideoCodec::Avc, (1920, 1080), 6_000_000, None);
+    #[cfg(feature = "av1")]
+    bench_codec(c, "encode_1080p", VideoCodec::Av1, (1920, 1080), 6_000_000, None);
+}
+
+/// Sliced openh264 encoding against a single thread, at the size threads are for
+fn encode_4k(c: &mut Criterion) {
+    for threads in [1, 4] {
+        bench_codec(c, "encode_4k", VideoCodec::Avc, (3840, 2160), 16_000_000, Some(threads));
+    }
+}
+
+criterion_group!(benches, encode_1080p, encode_4k);
+criterion_main!(benches);
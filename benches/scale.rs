@@ -0,0 +1,42 @@
+//! Downscaling a capture to a 1080p rung: the any-factor `Scaler` against
+//! the whole-factor box filter (`average_area`) that rungs used before,
+//! which can only halve. Bilinear, or Lanczos-3 with `--features lanczos`.
+//!
+//! cargo bench --bench scale
+
+use std::{hint::black_box, sync::Arc};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use foundry::{
+    scaler::{average_area, Scaler},
+    test_frames,
+};
+
+fn downscale_to_1080p(c: &mut Criterion) {
+    let mut group = c.benchmark_group("downscale_to_1080p");
+    group.sample_size(20);
+    // A 4K display halves exactly; a 16" Retina one doesn't
+    for (name, width, height) in [("4k", 3840, 2160), ("retina", 3456, 2234)] {
+        let frame = Arc::new(test_frames::text_like(width, height, 1));
+        let (width, height) = (width as usize, height as usize);
+        group.throughput(Throughput::Elements((width * height) as u64));
+
+        let mut scaler = Scaler::new(Some(1920 * 1080));
+        group.bench_function(BenchmarkId::new("scaler", name), |b| {
+            b.iter(|| black_box(scaler.scale(frame.clone(), width * 4)))
+        });
+
+        // What the old Downsampler did: the next whole factor, rounded to even
+        let (dst_w, dst_h) = ((width / 2) & !1, (height / 2) & !1);
+        let mut dst = vec![0u8; dst_w * dst_h * 4];
+        group.bench_function(BenchmarkId::new("box_2x", name), |b| {
+            b.iter(|| {
+                average_area(black_box(&frame.raw), width, height, width * 4, &mut dst, dst_w, dst_h);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, downscale_to_1080p);
+criterion_main!(benches);
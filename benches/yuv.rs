@@ -0,0 +1,39 @@
+//! RGB to I420: the row-parallel `I420::convert` against the per-pixel
+//! reference conversion, on text-like screen content
+//!
+//! cargo bench --bench yuv
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use foundry::{
+    test_frames,
+    video_pipeline::{ColorMatrix, PixelFormat},
+    yuv::{self, I420},
+};
+
+fn rgb_to_i420(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rgb_to_i420");
+    for (name, width, height) in [("1080p", 1920, 1080), ("4k", 3840, 2160)] {
+        let frame = test_frames::text_like(width, height, 1);
+        let (width, height) = (width as usize, height as usize);
+        group.throughput(Throughput::Elements((width * height) as u64));
+        let mut planes = I420::default();
+        group.bench_function(BenchmarkId::new("parallel", name), |b| {
+            b.iter(|| {
+                let src = black_box(&frame.raw);
+                planes.convert(src, PixelFormat::Bgra8888, width * 4, width, height, ColorMatrix::Bt709);
+            })
+        });
+        group.bench_function(BenchmarkId::new("reference", name), |b| {
+            b.iter(|| {
+                let src = black_box(&frame.raw);
+                yuv::reference_convert(src, PixelFormat::Bgra8888, width * 4, width, height, ColorMatrix::Bt709)
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, rgb_to_i420);
+criterion_main!(benches);
@@ -0,0 +1,69 @@
+//! Embeds the web client into the binary: writes `$OUT_DIR/assets.rs`, a
+//! table of `include_bytes!` entries with precomputed ETags, so a copied
+//! binary serves the full client with no files on disk.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    env, fs,
+    hash::{Hash, Hasher},
+    path::PathBuf,
+};
+
+/// Client files under src/, served at `/<name>`
+const CLIENT_FILES: &[&str] = &[
+    "root.html",
+    "root.js",
+    "video_worker.js",
+    "audio_worklet.js",
+    "audio.js",
+    "stats.js",
+    "video.js",
+    "mse.js",
+    "gui.js",
+    "screen.js",
+    "screen.html",
+];
+
+fn main() {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    let mut entries: Vec<(String, PathBuf)> = CLIENT_FILES
+        .iter()
+        .map(|name| (name.to_string(), manifest_dir.join("src").join(name)))
+        .collect();
+
+    // The spark bundle is built outside this repo; embed it when it's there
+    let spark = manifest_dir.join("../../dist/spark.module.js");
+    println!("cargo:rerun-if-changed={}", spark.display());
+    if spark.is_file() {
+        entries.push(("dist/spark.module.js".to_string(), spark));
+    } else {
+        println!(
+            "cargo:warning={} not found; /dist/spark.module.js is only served with --assets-dir",
+            spark.display()
+        );
+    }
+
+    let mut table = String::from("pub static EMBEDDED: &[(&str, &[u8], &str)] = &[\n");
+    for (name, path) in entries {
+        println!("cargo:rerun-if-changed={}", path.display());
+        let bytes = fs::read(&path).unwrap_or_else(|err| panic!("reading {}: {}", path.display(), err));
+        let path = path.canonicalize().unwrap();
+        table.push_str(&format!(
+            "    ({:?}, include_bytes!({:?}), {:?}),\n",
+            name,
+            path,
+            etag(&bytes)
+        ));
+    }
+    table.push_str("];\n");
+    fs::write(out_dir.join("assets.rs"), table).unwrap();
+}
+
+/// Same scheme as `assets::etag` so disk overrides and embedded files agree
+fn etag(bytes: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
@@ -0,0 +1,154 @@
+//! On-disk PCM cache for `--audio-cache`
+//!
+//! Audio is decoded once into a raw s16le file in the temp directory and then
+//! memory-mapped, so long recordings don't need their full PCM in RAM.
+//! File layout: 16-byte header ("FPCM", version, sample rate, channels)
+//! followed by interleaved little-endian i16 samples.
+
+use anyhow::{anyhow, Result};
+use memmap2::Mmap;
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+use crate::audio_decoder::{self, DecodedAudio, SampleSink};
+use crate::demuxer::Input;
+
+const CACHE_MAGIC: &[u8; 4] = b"FPCM";
+const CACHE_VERSION: u32 = 1;
+/// Header size; keeps the sample data 2-byte aligned within the mapping
+const CACHE_HEADER_BYTES: usize = 16;
+
+/// A memory-mapped PCM cache file
+pub struct MappedPcm {
+    map: Mmap,
+}
+
+impl MappedPcm {
+    pub fn samples(&self) -> &[i16] {
+        let data = &self.map[CACHE_HEADER_BYTES..];
+        // SAFETY: the mapping is page aligned and the header is 16 bytes, so the
+        // sample region is suitably aligned for i16; samples are stored
+        // little-endian, matching every platform we target.
+        unsafe { std::slice::from_raw_parts(data.as_ptr() as *const i16, data.len() / 2) }
+    }
+}
+
+/// Load audio from the cache for `input`, decoding into it first if needed.
+///
+/// With `keep == false` the file is unlinked right after mapping; the mapping
+/// stays valid until the process exits.
+pub fn load_or_decode(input: &Input, keep: bool) -> Result<Option<DecodedAudio>> {
+    let path = cache_path(input)?;
+
+    if !path.exists() {
+        println!("Decoding audio into cache {:?}...", path);
+        let partial = path.with_extension("partial");
+        let mut writer = CacheWriter::create(&partial)?;
+        match audio_decoder::decode_audio_into(input, &mut writer)? {
+            Some((sample_rate, channels)) => {
+                writer.finish(sample_rate, channels)?;
+                fs::rename(&partial, &path)?;
+            }
+            None => {
+                drop(writer);
+                let _ = fs::remove_file(&partial);
+                return Ok(None);
+            }
+        }
+    } else {
+        println!("Reusing audio cache {:?}", path);
+    }
+
+    let file = File::open(&path)?;
+    // SAFETY: cache files are only written via a rename from a completed
+    // `.partial` file, so the mapped contents don't change underneath us.
+    let map = unsafe { Mmap::map(&file)? };
+    if map.len() < CACHE_HEADER_BYTES || &map[..4] != CACHE_MAGIC {
+        let _ = fs::remove_file(&path);
+        return Err(anyhow!("Corrupt audio cache {:?}, removed", path));
+    }
+    let version = u32::from_le_bytes(map[4..8].try_into()?);
+    if version != CACHE_VERSION {
+        let _ = fs::remove_file(&path);
+        return Err(anyhow!("Stale audio cache version {} in {:?}, removed", version, path));
+    }
+    let sample_rate = u32::from_le_bytes(map[8..12].try_into()?);
+    let channels = u32::from_le_bytes(map[12..16].try_into()?);
+
+    if !keep {
+        fs::remove_file(&path)?;
+    }
+
+    Ok(Some(DecodedAudio::from_mapped(
+        MappedPcm { map },
+        sample_rate,
+        channels,
+    )))
+}
+
+/// Cache file name keyed by source location and modification time
+fn cache_path(input: &Input) -> Result<PathBuf> {
+    let mut hasher = DefaultHasher::new();
+    match input {
+        Input::File(path) => {
+            let canonical = fs::canonicalize(path)?;
+            canonical.hash(&mut hasher);
+            fs::metadata(&canonical)?.modified()?.hash(&mut hasher);
+        }
+        Input::Http(cache) => {
+            cache.url().hash(&mut hasher);
+            cache.len().hash(&mut hasher);
+        }
+    }
+    Ok(std::env::temp_dir().join(format!("foundry-audio-{:016x}.pcm", hasher.finish())))
+}
+
+/// Streams decoded samples to disk behind a placeholder header
+struct CacheWriter {
+    writer: BufWriter<File>,
+    written: usize,
+}
+
+impl CacheWriter {
+    fn create(path: &PathBuf) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&[0u8; CACHE_HEADER_BYTES])?;
+        Ok(Self { writer, written: 0 })
+    }
+
+    fn finish(mut self, sample_rate: u32, channels: u32) -> Result<()> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(CACHE_MAGIC)?;
+        self.writer.write_all(&CACHE_VERSION.to_le_bytes())?;
+        self.writer.write_all(&sample_rate.to_le_bytes())?;
+        self.writer.write_all(&channels.to_le_bytes())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl SampleSink for CacheWriter {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        for s in samples {
+            self.writer.write_all(&s.to_le_bytes())?;
+        }
+        self.written += samples.len();
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.writer.seek(SeekFrom::Start(CACHE_HEADER_BYTES as u64))?;
+        self.writer.get_ref().set_len(CACHE_HEADER_BYTES as u64)?;
+        self.written = 0;
+        Ok(())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.written == 0
+    }
+}
@@ -1,9 +1,7 @@
 //! Audio decoder with symphonia + ffmpeg fallback
 
 use anyhow::{anyhow, Result};
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+use std::io::{Read, Seek, SeekFrom};
 use std::process::{Command, Stdio};
 use symphonia::core::audio::{AudioBufferRef, Signal};
 use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
@@ -12,19 +10,87 @@ use symphonia::core::io::MediaSourceStream;
 use symphonia::core::meta::MetadataOptions;
 use symphonia::core::probe::Hint;
 
+use crate::audio_cache::MappedPcm;
+use crate::demuxer::{Input, MediaSource};
+
 /// Decoded audio data
 pub struct DecodedAudio {
-    pub samples: Vec<i16>,
+    samples: AudioSamples,
     pub sample_rate: u32,
     pub channels: u32,
 }
 
+/// Backing storage for decoded PCM
+enum AudioSamples {
+    Memory(Vec<i16>),
+    /// Memory-mapped `--audio-cache` file
+    Mapped(MappedPcm),
+}
+
+impl DecodedAudio {
+    pub fn new(samples: Vec<i16>, sample_rate: u32, channels: u32) -> Self {
+        Self {
+            samples: AudioSamples::Memory(samples),
+            sample_rate,
+            channels,
+        }
+    }
+
+    pub fn from_mapped(pcm: MappedPcm, sample_rate: u32, channels: u32) -> Self {
+        Self {
+            samples: AudioSamples::Mapped(pcm),
+            sample_rate,
+            channels,
+        }
+    }
+
+    /// Interleaved s16 samples
+    pub fn samples(&self) -> &[i16] {
+        match &self.samples {
+            AudioSamples::Memory(samples) => samples,
+            AudioSamples::Mapped(pcm) => pcm.samples(),
+        }
+    }
+}
+
+/// Receives decoded interleaved samples as the decoder produces them
+pub trait SampleSink {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()>;
+    /// Discard everything written so far (used before a decoder fallback)
+    fn clear(&mut self) -> Result<()>;
+    fn is_empty(&self) -> bool;
+}
+
+impl SampleSink for Vec<i16> {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        self.extend_from_slice(samples);
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        Vec::clear(self);
+        Ok(())
+    }
+
+    fn is_empty(&self) -> bool {
+        Vec::is_empty(self)
+    }
+}
+
 /// Decode all audio from an MP4 file
 /// Tries symphonia first, falls back to ffmpeg if that fails
-pub fn decode_audio(path: &Path) -> Result<Option<DecodedAudio>> {
+pub fn decode_audio(input: &Input) -> Result<Option<DecodedAudio>> {
+    let mut samples: Vec<i16> = Vec::new();
+    Ok(decode_audio_into(input, &mut samples)?
+        .map(|(sample_rate, channels)| DecodedAudio::new(samples, sample_rate, channels)))
+}
+
+/// Decode all audio into `sink`, returning (sample_rate, channels)
+/// Tries symphonia first, falls back to ffmpeg if that fails
+pub fn decode_audio_into(input: &Input, sink: &mut dyn SampleSink) -> Result<Option<(u32, u32)>> {
     // Try symphonia first (fast, no external dependencies)
-    match decode_audio_symphonia(path) {
-        Ok(Some(audio)) => return Ok(Some(audio)),
+    match decode_audio_symphonia(input, sink) {
+        Ok(Some(format)) => return Ok(Some(format)),
         Ok(None) => return Ok(None),
         Err(e) => {
             eprintln!("Symphonia decode failed: {}", e);
@@ -33,10 +99,11 @@ pub fn decode_audio(path: &Path) -> Result<Option<DecodedAudio>> {
     }
 
     // Fall back to ffmpeg
-    match decode_audio_ffmpeg(path) {
-        Ok(Some(audio)) => {
+    sink.clear()?;
+    match decode_audio_ffmpeg(input, sink) {
+        Ok(Some(format)) => {
             println!("Audio decoded via ffmpeg");
-            Ok(Some(audio))
+            Ok(Some(format))
         }
         Ok(None) => Ok(None),
         Err(e) => Err(anyhow!("Both decoders failed. ffmpeg error: {}", e)),
@@ -44,13 +111,14 @@ pub fn decode_audio(path: &Path) -> Result<Option<DecodedAudio>> {
 }
 
 /// Decode audio using symphonia (built-in, supports AAC-LC)
-fn decode_audio_symphonia(path: &Path) -> Result<Option<DecodedAudio>> {
-    let file = File::open(path)?;
-    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+fn decode_audio_symphonia(input: &Input, sink: &mut dyn SampleSink) -> Result<Option<(u32, u32)>> {
+    let (reader, size) = input.open()?;
+    let source = SymphoniaSource { reader, size };
+    let mss = MediaSourceStream::new(Box::new(source), Default::default());
 
     let mut hint = Hint::new();
-    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-        hint.with_extension(ext);
+    if let Some(ext) = input.extension() {
+        hint.with_extension(&ext);
     }
 
     let format_opts = FormatOptions::default();
@@ -78,8 +146,6 @@ fn decode_audio_symphonia(path: &Path) -> Result<Option<DecodedAudio>> {
         .make(&track.codec_params, &decoder_opts)
         .map_err(|e| anyhow!("Failed to create decoder: {}", e))?;
 
-    let mut all_samples: Vec<i16> = Vec::new();
-
     // Decode all packets
     loop {
         let packet = match format.next_packet() {
@@ -103,7 +169,7 @@ fn decode_audio_symphonia(path: &Path) -> Result<Option<DecodedAudio>> {
         match decoder.decode(&packet) {
             Ok(decoded) => {
                 let samples = convert_to_i16(&decoded, channels);
-                all_samples.extend(samples);
+                sink.write_samples(&samples)?;
             }
             Err(e) => {
                 eprintln!("Audio decode warning: {}", e);
@@ -111,26 +177,22 @@ fn decode_audio_symphonia(path: &Path) -> Result<Option<DecodedAudio>> {
         }
     }
 
-    if all_samples.is_empty() {
+    if sink.is_empty() {
         return Ok(None);
     }
 
-    Ok(Some(DecodedAudio {
-        samples: all_samples,
-        sample_rate,
-        channels,
-    }))
+    Ok(Some((sample_rate, channels)))
 }
 
 /// Decode audio using ffmpeg (external, supports all formats)
 /// Always outputs 48kHz stereo for consistency
-fn decode_audio_ffmpeg(path: &Path) -> Result<Option<DecodedAudio>> {
+fn decode_audio_ffmpeg(input: &Input, sink: &mut dyn SampleSink) -> Result<Option<(u32, u32)>> {
     // Check if ffmpeg is available
     if Command::new("ffmpeg").arg("-version").output().is_err() {
         return Err(anyhow!("ffmpeg not found. Install with: brew install ffmpeg"));
     }
 
-    let path_str = path.to_string_lossy();
+    let path_str = input.ffmpeg_arg();
     
     // Always output 48kHz stereo - simpler and more reliable than probing
     let sample_rate: u32 = 48000;
@@ -153,30 +215,66 @@ fn decode_audio_ffmpeg(path: &Path) -> Result<Option<DecodedAudio>> {
 
     let mut stdout = child.stdout.take().ok_or_else(|| anyhow!("No stdout"))?;
     
-    // Read all PCM data
-    let mut pcm_data = Vec::new();
-    stdout.read_to_end(&mut pcm_data)?;
+    // Stream PCM data to the sink, carrying a split sample across reads
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut carry: Option<u8> = None;
+    let mut samples: Vec<i16> = Vec::with_capacity(buf.len() / 2 + 1);
+    loop {
+        let n = stdout.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        samples.clear();
+        let mut bytes = &buf[..n];
+        if let Some(low) = carry.take() {
+            samples.push(i16::from_le_bytes([low, bytes[0]]));
+            bytes = &bytes[1..];
+        }
+        // Convert bytes to i16 samples (little-endian)
+        let mut chunks = bytes.chunks_exact(2);
+        samples.extend(chunks.by_ref().map(|chunk| i16::from_le_bytes([chunk[0], chunk[1]])));
+        carry = chunks.remainder().first().copied();
+        sink.write_samples(&samples)?;
+    }
 
     let status = child.wait()?;
     if !status.success() {
         return Err(anyhow!("ffmpeg decoding failed"));
     }
 
-    if pcm_data.is_empty() {
+    if sink.is_empty() {
         return Ok(None);
     }
 
-    // Convert bytes to i16 samples (little-endian)
-    let samples: Vec<i16> = pcm_data
-        .chunks_exact(2)
-        .map(|chunk| i16::from_le_bytes([chunk[0], chunk[1]]))
-        .collect();
+    Ok(Some((sample_rate, channels)))
+}
 
-    Ok(Some(DecodedAudio {
-        samples,
-        sample_rate,
-        channels,
-    }))
+/// Adapts an input reader to symphonia's `MediaSource`
+struct SymphoniaSource {
+    reader: Box<dyn MediaSource>,
+    size: u64,
+}
+
+impl Read for SymphoniaSource {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Seek for SymphoniaSource {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.reader.seek(pos)
+    }
+}
+
+impl symphonia::core::io::MediaSource for SymphoniaSource {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        Some(self.size)
+    }
 }
 
 /// Convert audio buffer to interleaved i16 samples
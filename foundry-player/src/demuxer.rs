@@ -5,11 +5,62 @@ use base64::Engine;
 use mp4::{Mp4Reader, TrackType};
 use std::{
     fs::File,
-    io::BufReader,
-    path::Path,
+    io::{BufReader, Read, Seek},
+    path::PathBuf,
+    sync::Arc,
 };
 
+use crate::remote::{HttpReader, RangeCache};
+
+/// Anything the MP4 reader and audio decoders can pull bytes from
+pub trait MediaSource: Read + Seek + Send + Sync {}
+
+impl<T: Read + Seek + Send + Sync> MediaSource for T {}
+
+/// Where the MP4 bytes live
+#[derive(Clone)]
+pub enum Input {
+    /// A local file (including downloaded temp files)
+    File(PathBuf),
+    /// A remote file read on demand with HTTP range requests
+    Http(Arc<RangeCache>),
+}
+
+impl Input {
+    /// Open a fresh reader positioned at the start, plus the total size
+    pub fn open(&self) -> Result<(Box<dyn MediaSource>, u64)> {
+        match self {
+            Input::File(path) => {
+                let file = File::open(path)?;
+                let size = file.metadata()?.len();
+                Ok((Box::new(BufReader::new(file)), size))
+            }
+            Input::Http(cache) => Ok((Box::new(HttpReader::new(cache.clone())), cache.len())),
+        }
+    }
+
+    /// Argument to hand to ffmpeg (it reads URLs natively)
+    pub fn ffmpeg_arg(&self) -> String {
+        match self {
+            Input::File(path) => path.to_string_lossy().into_owned(),
+            Input::Http(cache) => cache.url().to_string(),
+        }
+    }
+
+    /// File extension hint for format probing
+    pub fn extension(&self) -> Option<String> {
+        match self {
+            Input::File(path) => path
+                .extension()
+                .and_then(|e| e.to_str())
+                .map(|e| e.to_string()),
+            Input::Http(_) => Some("mp4".to_string()),
+        }
+    }
+}
+
 /// Video configuration for WebCodecs
+#[derive(Clone)]
 pub struct VideoConfig {
     pub codec_string: String,
     pub description_b64: String,
@@ -29,7 +80,7 @@ pub enum MediaFrame {
 
 /// MP4 demuxer with H.264 passthrough
 pub struct Mp4Demuxer {
-    path: std::path::PathBuf,
+    input: Input,
     video_track_id: u32,
     has_audio: bool,
     video_width: u32,
@@ -42,10 +93,8 @@ pub struct Mp4Demuxer {
 }
 
 impl Mp4Demuxer {
-    pub fn open(path: &Path) -> Result<Self> {
-        let file = File::open(path)?;
-        let size = file.metadata()?.len();
-        let reader = BufReader::new(file);
+    pub fn open(input: &Input) -> Result<Self> {
+        let (reader, size) = input.open()?;
         let mp4 = Mp4Reader::read_header(reader, size)?;
 
         // Find video track
@@ -78,7 +127,7 @@ impl Mp4Demuxer {
             .any(|t| matches!(t.track_type(), Ok(TrackType::Audio)));
 
         Ok(Self {
-            path: path.to_path_buf(),
+            input: input.clone(),
             video_track_id,
             has_audio,
             video_width,
@@ -111,33 +160,16 @@ impl Mp4Demuxer {
     }
 
     pub fn video_config(&self) -> Result<VideoConfig> {
-        // Build codec string from AVCC
-        let codec_string = if self.avcc_data.len() >= 4 {
-            format!(
-                "avc1.{:02X}{:02X}{:02X}",
-                self.avcc_data[1], // profile
-                self.avcc_data[2], // constraints
-                self.avcc_data[3], // level
-            )
-        } else {
-            "avc1.42E01E".to_string() // fallback baseline
-        };
-
-        let description_b64 = base64::engine::general_purpose::STANDARD.encode(&self.avcc_data);
-
-        Ok(VideoConfig {
-            codec_string,
-            description_b64,
-            width: self.video_width,
-            height: self.video_height,
-        })
+        Ok(build_video_config(
+            &self.avcc_data,
+            self.video_width,
+            self.video_height,
+        ))
     }
 
     /// Returns an iterator over video frames in the file
     pub fn frames(&self) -> Result<FrameIterator> {
-        let file = File::open(&self.path)?;
-        let size = file.metadata()?.len();
-        let reader = BufReader::new(file);
+        let (reader, size) = self.input.open()?;
         let mp4 = Mp4Reader::read_header(reader, size)?;
 
         Ok(FrameIterator {
@@ -151,7 +183,7 @@ impl Mp4Demuxer {
 }
 
 pub struct FrameIterator {
-    mp4: Mp4Reader<BufReader<File>>,
+    mp4: Mp4Reader<Box<dyn MediaSource>>,
     video_track_id: u32,
     video_sample_idx: u32,
     frame_rate: f64,
@@ -203,51 +235,78 @@ impl Iterator for FrameIterator {
     }
 }
 
+/// Build the WebCodecs config (codec string + base64 avcC) for a video track
+pub fn build_video_config(avcc_data: &[u8], width: u32, height: u32) -> VideoConfig {
+    // Build codec string from AVCC
+    let codec_string = if avcc_data.len() >= 4 {
+        format!(
+            "avc1.{:02X}{:02X}{:02X}",
+            avcc_data[1], // profile
+            avcc_data[2], // constraints
+            avcc_data[3], // level
+        )
+    } else {
+        "avc1.42E01E".to_string() // fallback baseline
+    };
+
+    let description_b64 = base64::engine::general_purpose::STANDARD.encode(avcc_data);
+
+    VideoConfig {
+        codec_string,
+        description_b64,
+        width,
+        height,
+    }
+}
+
 /// Extract AVCC configuration from video track
 /// Returns (avcc_config, sps_pps_avcc) where sps_pps_avcc has 4-byte length prefixes
 fn extract_avcc(track: &mp4::Mp4Track) -> Result<(Vec<u8>, Vec<u8>)> {
     // Get the AVCC box data
     if let Some(avc1) = &track.trak.mdia.minf.stbl.stsd.avc1 {
-        let avcc = &avc1.avcc;
-        
-        // Build AVCC configuration record (for WebCodecs config)
-        let mut config = Vec::new();
-        config.push(avcc.configuration_version);
-        config.push(avcc.avc_profile_indication);
-        config.push(avcc.profile_compatibility);
-        config.push(avcc.avc_level_indication);
-        config.push(0xFF); // 4-byte NALU length (0xFF = 0b11111111, lower 2 bits = length - 1)
-        
-        // SPS for config
-        config.push(0xE0 | (avcc.sequence_parameter_sets.len() as u8));
-        for sps in &avcc.sequence_parameter_sets {
-            config.extend_from_slice(&(sps.bytes.len() as u16).to_be_bytes());
-            config.extend_from_slice(&sps.bytes);
-        }
-        
-        // PPS for config
-        config.push(avcc.picture_parameter_sets.len() as u8);
-        for pps in &avcc.picture_parameter_sets {
-            config.extend_from_slice(&(pps.bytes.len() as u16).to_be_bytes());
-            config.extend_from_slice(&pps.bytes);
-        }
-        
-        // Build SPS/PPS with 4-byte length prefix (for prepending to keyframes)
-        let mut sps_pps = Vec::new();
-        for sps in &avcc.sequence_parameter_sets {
-            let len = sps.bytes.len() as u32;
-            sps_pps.extend_from_slice(&len.to_be_bytes());
-            sps_pps.extend_from_slice(&sps.bytes);
-        }
-        for pps in &avcc.picture_parameter_sets {
-            let len = pps.bytes.len() as u32;
-            sps_pps.extend_from_slice(&len.to_be_bytes());
-            sps_pps.extend_from_slice(&pps.bytes);
-        }
-        
-        Ok((config, sps_pps))
+        Ok(avcc_records(&avc1.avcc))
     } else {
         Err(anyhow!("No AVC configuration found in video track"))
     }
 }
 
+/// Build (avcc_config, sps_pps_avcc) from a parsed avcC box
+pub fn avcc_records(avcc: &mp4::AvcCBox) -> (Vec<u8>, Vec<u8>) {
+    // Build AVCC configuration record (for WebCodecs config)
+    let mut config = vec![
+        avcc.configuration_version,
+        avcc.avc_profile_indication,
+        avcc.profile_compatibility,
+        avcc.avc_level_indication,
+        0xFF, // 4-byte NALU length (0xFF = 0b11111111, lower 2 bits = length - 1)
+    ];
+    
+    // SPS for config
+    config.push(0xE0 | (avcc.sequence_parameter_sets.len() as u8));
+    for sps in &avcc.sequence_parameter_sets {
+        config.extend_from_slice(&(sps.bytes.len() as u16).to_be_bytes());
+        config.extend_from_slice(&sps.bytes);
+    }
+    
+    // PPS for config
+    config.push(avcc.picture_parameter_sets.len() as u8);
+    for pps in &avcc.picture_parameter_sets {
+        config.extend_from_slice(&(pps.bytes.len() as u16).to_be_bytes());
+        config.extend_from_slice(&pps.bytes);
+    }
+    
+    // Build SPS/PPS with 4-byte length prefix (for prepending to keyframes)
+    let mut sps_pps = Vec::new();
+    for sps in &avcc.sequence_parameter_sets {
+        let len = sps.bytes.len() as u32;
+        sps_pps.extend_from_slice(&len.to_be_bytes());
+        sps_pps.extend_from_slice(&sps.bytes);
+    }
+    for pps in &avcc.picture_parameter_sets {
+        let len = pps.bytes.len() as u32;
+        sps_pps.extend_from_slice(&len.to_be_bytes());
+        sps_pps.extend_from_slice(&pps.bytes);
+    }
+    
+    (config, sps_pps)
+}
@@ -0,0 +1,213 @@
+//! Lossless clip export: copies samples between two times into a new MP4
+//!
+//! Usage: foundry-player movie.mp4 --export 63.5..93.5 --out clip.mp4
+
+use anyhow::{anyhow, Result};
+use mp4::{
+    AacConfig, AvcConfig, MediaConfig, Mp4Config, Mp4Reader, Mp4Sample, Mp4Writer, TrackConfig,
+    TrackType,
+};
+use std::{fs::File, io::BufWriter, path::Path};
+
+use crate::demuxer::{Input, MediaSource};
+
+/// Parse a `start..end` range in seconds (clap value parser)
+pub fn parse_range(value: &str) -> Result<(f64, f64), String> {
+    let (start, end) = value
+        .split_once("..")
+        .ok_or_else(|| "expected START..END in seconds, e.g. 30..60".to_string())?;
+    let start: f64 = start
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid start time: {}", start))?;
+    let end: f64 = end
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid end time: {}", end))?;
+    if start < 0.0 || end <= start {
+        return Err("range must satisfy 0 <= START < END".to_string());
+    }
+    Ok((start, end))
+}
+
+/// Export `start..end` (seconds) of `input` to `out` without re-encoding.
+///
+/// Video starts at the keyframe at or before `start` so the clip decodes on
+/// its own; audio covers the same span. Both tracks are rebased to zero.
+pub fn export_clip(input: &Input, start: f64, end: f64, out: &Path) -> Result<()> {
+    let (reader, size) = input.open()?;
+    let mut mp4 = Mp4Reader::read_header(reader, size)?;
+
+    let video = mp4
+        .tracks()
+        .values()
+        .find(|t| matches!(t.track_type(), Ok(TrackType::Video)))
+        .ok_or_else(|| anyhow!("No video track found"))?;
+    let video_id = video.track_id();
+    let video_timescale = video.timescale();
+    let video_config = avc_track_config(video)?;
+
+    let audio = mp4
+        .tracks()
+        .values()
+        .find(|t| matches!(t.track_type(), Ok(TrackType::Audio)));
+    let audio_track = match audio {
+        Some(track) => Some((track.track_id(), track.timescale(), aac_track_config(track)?)),
+        None => None,
+    };
+
+    // Collect video samples, starting from the GOP that contains `start`.
+    let start_ts = (start * video_timescale as f64) as u64;
+    let end_ts = (end * video_timescale as f64) as u64;
+    let video_samples = clip_video_samples(&mut mp4, video_id, start_ts, end_ts)?;
+    let first = video_samples
+        .first()
+        .ok_or_else(|| anyhow!("No video samples in {:.2}..{:.2}", start, end))?;
+    let clip_start_ts = first.start_time;
+    let clip_start_secs = clip_start_ts as f64 / video_timescale as f64;
+
+    let file = File::create(out)?;
+    let config = Mp4Config {
+        major_brand: "isom".parse()?,
+        minor_version: 512,
+        compatible_brands: vec![
+            "isom".parse()?,
+            "iso2".parse()?,
+            "avc1".parse()?,
+            "mp41".parse()?,
+        ],
+        timescale: 1000,
+    };
+    let mut writer = Mp4Writer::write_start(BufWriter::new(file), &config)?;
+
+    writer.add_track(&video_config)?;
+    let video_out_id = 1;
+    let mut video_written = 0;
+    for mut sample in video_samples {
+        sample.start_time -= clip_start_ts;
+        writer.write_sample(video_out_id, &sample)?;
+        video_written += 1;
+    }
+
+    let mut audio_written = 0;
+    if let Some((audio_id, audio_timescale, audio_config)) = audio_track {
+        writer.add_track(&audio_config)?;
+        let audio_out_id = 2;
+        let audio_start = (clip_start_secs * audio_timescale as f64) as u64;
+        let audio_end = (end * audio_timescale as f64) as u64;
+        let count = mp4.sample_count(audio_id)?;
+        for idx in 1..=count {
+            let Some(mut sample) = mp4.read_sample(audio_id, idx)? else {
+                continue;
+            };
+            if sample.start_time < audio_start {
+                continue;
+            }
+            if sample.start_time >= audio_end {
+                break;
+            }
+            sample.start_time -= audio_start;
+            writer.write_sample(audio_out_id, &sample)?;
+            audio_written += 1;
+        }
+    }
+
+    writer.write_end()?;
+
+    println!(
+        "Exported {:.2}s..{:.2}s to {:?} ({} video, {} audio samples)",
+        clip_start_secs, end, out, video_written, audio_written
+    );
+    if clip_start_secs < start {
+        println!(
+            "Clip starts {:.2}s early at the preceding keyframe",
+            start - clip_start_secs
+        );
+    }
+    Ok(())
+}
+
+/// Video samples from the last keyframe at or before `start_ts` up to `end_ts`
+fn clip_video_samples(
+    mp4: &mut Mp4Reader<Box<dyn MediaSource>>,
+    track_id: u32,
+    start_ts: u64,
+    end_ts: u64,
+) -> Result<Vec<Mp4Sample>> {
+    let count = mp4.sample_count(track_id)?;
+    let mut samples: Vec<Mp4Sample> = Vec::new();
+
+    for idx in 1..=count {
+        let Some(sample) = mp4.read_sample(track_id, idx)? else {
+            continue;
+        };
+        if sample.rendering_offset != 0 {
+            return Err(anyhow!(
+                "Source uses composition time offsets (B-frames / ctts), which the MP4 writer can't represent"
+            ));
+        }
+        if sample.start_time >= end_ts {
+            break;
+        }
+        if sample.start_time <= start_ts && sample.is_sync {
+            // A later keyframe still precedes `start`: drop the earlier GOP.
+            samples.clear();
+        }
+        if samples.is_empty() && !sample.is_sync {
+            continue;
+        }
+        samples.push(sample);
+    }
+
+    Ok(samples)
+}
+
+fn avc_track_config(track: &mp4::Mp4Track) -> Result<TrackConfig> {
+    let avc1 = track
+        .trak
+        .mdia
+        .minf
+        .stbl
+        .stsd
+        .avc1
+        .as_ref()
+        .ok_or_else(|| anyhow!("Only H.264 (avc1) video can be exported"))?;
+    let avcc = &avc1.avcc;
+    if avcc.sequence_parameter_sets.len() != 1 || avcc.picture_parameter_sets.len() != 1 {
+        return Err(anyhow!(
+            "Source has {} SPS / {} PPS; the MP4 writer only supports one of each",
+            avcc.sequence_parameter_sets.len(),
+            avcc.picture_parameter_sets.len()
+        ));
+    }
+
+    Ok(TrackConfig {
+        track_type: TrackType::Video,
+        timescale: track.timescale(),
+        language: track.language().to_string(),
+        media_conf: MediaConfig::AvcConfig(AvcConfig {
+            width: avc1.width,
+            height: avc1.height,
+            seq_param_set: avcc.sequence_parameter_sets[0].bytes.clone(),
+            pic_param_set: avcc.picture_parameter_sets[0].bytes.clone(),
+        }),
+    })
+}
+
+fn aac_track_config(track: &mp4::Mp4Track) -> Result<TrackConfig> {
+    if track.trak.mdia.minf.stbl.stsd.mp4a.is_none() {
+        return Err(anyhow!("Only AAC (mp4a) audio can be exported"));
+    }
+
+    Ok(TrackConfig {
+        track_type: TrackType::Audio,
+        timescale: track.timescale(),
+        language: track.language().to_string(),
+        media_conf: MediaConfig::AacConfig(AacConfig {
+            bitrate: track.bitrate(),
+            profile: track.audio_profile()?,
+            freq_index: track.sample_freq_index()?,
+            chan_conf: track.channel_config()?,
+        }),
+    })
+}
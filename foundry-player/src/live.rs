@@ -0,0 +1,251 @@
+//! Live fragmented MP4 input: reads `ftyp/moov` + `moof/mdat` boxes from stdin
+//!
+//! Usage: ffmpeg ... -movflags frag_keyframe+empty_moov -f mp4 - | foundry-player -
+
+use anyhow::{anyhow, Result};
+use mp4::{BoxHeader, BoxType, MoofBox, MoovBox, ReadBox};
+use std::{
+    io::{Cursor, Read},
+    sync::Arc,
+    thread,
+};
+use tokio::sync::{broadcast, watch};
+
+use crate::demuxer::{self, MediaFrame, TimestampedFrame, VideoConfig};
+
+/// Number of frames buffered per client before it is considered lagging
+const LIVE_BROADCAST_DEPTH: usize = 120;
+
+/// `sample_is_non_sync_sample` bit in trun/tfhd/trex sample flags
+const SAMPLE_FLAG_NON_SYNC: u32 = 0x0001_0000;
+
+/// Video track parameters parsed from the init segment
+struct LiveTrack {
+    track_id: u32,
+    timescale: u32,
+    default_sample_duration: u32,
+    default_sample_size: u32,
+    default_sample_flags: u32,
+    /// SPS/PPS NALs to prepend to keyframes
+    sps_pps_avcc: Vec<u8>,
+}
+
+/// A live stream fed by the stdin reader thread
+pub struct LiveStream {
+    config: watch::Receiver<Option<Arc<VideoConfig>>>,
+    frames: broadcast::Sender<Arc<TimestampedFrame>>,
+}
+
+impl LiveStream {
+    /// Start reading fragmented MP4 from stdin on a dedicated thread
+    pub fn from_stdin() -> Arc<Self> {
+        let (config_tx, config_rx) = watch::channel(None);
+        let (frames_tx, _) = broadcast::channel(LIVE_BROADCAST_DEPTH);
+
+        let frames_clone = frames_tx.clone();
+        thread::spawn(move || {
+            let stdin = std::io::stdin().lock();
+            match read_fragments(stdin, &config_tx, &frames_clone) {
+                Ok(()) => println!("Live input ended"),
+                Err(e) => eprintln!("Live input error: {}", e),
+            }
+        });
+
+        Arc::new(Self {
+            config: config_rx,
+            frames: frames_tx,
+        })
+    }
+
+    /// A stream whose input has already ended, for session tests
+    #[cfg(test)]
+    pub fn ended() -> Arc<Self> {
+        let (_, config) = watch::channel(None);
+        let (frames, _) = broadcast::channel(LIVE_BROADCAST_DEPTH);
+        Arc::new(Self { config, frames })
+    }
+
+    /// Wait until the init segment has been parsed and return the video config
+    pub async fn video_config(&self) -> Option<Arc<VideoConfig>> {
+        let mut config = self.config.clone();
+        let ready = config.wait_for(|c| c.is_some()).await.ok()?;
+        ready.clone()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<TimestampedFrame>> {
+        self.frames.subscribe()
+    }
+}
+
+fn read_fragments<R: Read>(
+    mut reader: R,
+    config_tx: &watch::Sender<Option<Arc<VideoConfig>>>,
+    frames_tx: &broadcast::Sender<Arc<TimestampedFrame>>,
+) -> Result<()> {
+    let mut track: Option<LiveTrack> = None;
+    // Most recent moof and its total size, waiting for the following mdat
+    let mut pending_moof: Option<(MoofBox, u64)> = None;
+
+    loop {
+        let header = match BoxHeader::read(&mut reader) {
+            Ok(header) => header,
+            Err(mp4::Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Ok(());
+            }
+            Err(e) => return Err(anyhow!("Failed to read box header: {}", e)),
+        };
+
+        let header_len = if header.size > u32::MAX as u64 { 16 } else { 8 };
+        if header.size < header_len {
+            return Err(anyhow!("Unsupported box size {} in live input", header.size));
+        }
+        let mut body = vec![0u8; (header.size - header_len) as usize];
+        reader.read_exact(&mut body)?;
+
+        match header.name {
+            BoxType::MoovBox => {
+                let moov: MoovBox = read_box_from_body(&body)?;
+                let live_track = parse_init_segment(&moov, config_tx)?;
+                track = Some(live_track);
+            }
+            BoxType::MoofBox => {
+                let moof: MoofBox = read_box_from_body(&body)?;
+                pending_moof = Some((moof, header.size));
+            }
+            BoxType::MdatBox => {
+                let (Some(track), Some((moof, moof_size))) = (&track, pending_moof.take()) else {
+                    continue;
+                };
+                for frame in fragment_frames(track, &moof, moof_size, header_len, &body) {
+                    // Non-blocking: lagging receivers are handled on their side.
+                    let _ = frames_tx.send(Arc::new(frame));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Parse a box whose header has already been consumed.
+///
+/// The mp4 crate expects the reader to sit just past an 8-byte header, so the
+/// body is placed behind a placeholder header in the cursor.
+fn read_box_from_body<B>(body: &[u8]) -> Result<B>
+where
+    for<'a> B: ReadBox<&'a mut Cursor<Vec<u8>>>,
+{
+    let mut buf = vec![0u8; 8];
+    buf.extend_from_slice(body);
+    let size = buf.len() as u64;
+    let mut cursor = Cursor::new(buf);
+    cursor.set_position(8);
+    B::read_box(&mut cursor, size).map_err(|e| anyhow!("Failed to parse box: {}", e))
+}
+
+fn parse_init_segment(
+    moov: &MoovBox,
+    config_tx: &watch::Sender<Option<Arc<VideoConfig>>>,
+) -> Result<LiveTrack> {
+    let trak = moov
+        .traks
+        .iter()
+        .find(|t| t.mdia.minf.stbl.stsd.avc1.is_some())
+        .ok_or_else(|| anyhow!("No H.264 video track in live input"))?;
+    let avc1 = trak.mdia.minf.stbl.stsd.avc1.as_ref().unwrap();
+    let track_id = trak.tkhd.track_id;
+
+    let (avcc_data, sps_pps_avcc) = demuxer::avcc_records(&avc1.avcc);
+    let config = demuxer::build_video_config(&avcc_data, avc1.width as u32, avc1.height as u32);
+    println!(
+        "Live video: {}x{} ({})",
+        config.width, config.height, config.codec_string
+    );
+    config_tx.send_replace(Some(Arc::new(config)));
+
+    let trex = moov
+        .mvex
+        .as_ref()
+        .map(|mvex| &mvex.trex)
+        .filter(|trex| trex.track_id == track_id);
+
+    Ok(LiveTrack {
+        track_id,
+        timescale: trak.mdia.mdhd.timescale.max(1),
+        default_sample_duration: trex.map(|t| t.default_sample_duration).unwrap_or(0),
+        default_sample_size: trex.map(|t| t.default_sample_size).unwrap_or(0),
+        default_sample_flags: trex.map(|t| t.default_sample_flags).unwrap_or(0),
+        sps_pps_avcc,
+    })
+}
+
+/// Split an mdat payload into frames using the preceding moof's trun tables
+fn fragment_frames(
+    track: &LiveTrack,
+    moof: &MoofBox,
+    moof_size: u64,
+    mdat_header_len: u64,
+    mdat: &[u8],
+) -> Vec<TimestampedFrame> {
+    let mut frames = Vec::new();
+
+    for traf in &moof.trafs {
+        if traf.tfhd.track_id != track.track_id {
+            continue;
+        }
+        let Some(trun) = &traf.trun else {
+            continue;
+        };
+
+        let default_duration = traf
+            .tfhd
+            .default_sample_duration
+            .unwrap_or(track.default_sample_duration);
+        let default_size = traf
+            .tfhd
+            .default_sample_size
+            .unwrap_or(track.default_sample_size);
+        let default_flags = traf
+            .tfhd
+            .default_sample_flags
+            .unwrap_or(track.default_sample_flags);
+
+        // data_offset is relative to the start of the moof (default-base-is-moof)
+        let data_start = trun.data_offset.unwrap_or(0) as i64 - (moof_size + mdat_header_len) as i64;
+        let mut offset = data_start.max(0) as usize;
+        let mut decode_time = traf.tfdt.as_ref().map(|t| t.base_media_decode_time).unwrap_or(0);
+
+        for i in 0..trun.sample_count as usize {
+            let size = trun.sample_sizes.get(i).copied().unwrap_or(default_size) as usize;
+            let duration = trun.sample_durations.get(i).copied().unwrap_or(default_duration);
+            let flags = match (trun.sample_flags.get(i), trun.first_sample_flags) {
+                (Some(flags), _) => *flags,
+                (None, Some(first)) if i == 0 => first,
+                _ => default_flags,
+            };
+
+            if offset + size > mdat.len() {
+                eprintln!("Live input: sample {} overruns mdat, skipping rest of fragment", i);
+                break;
+            }
+            let sample = &mdat[offset..offset + size];
+            offset += size;
+
+            let is_keyframe = flags & SAMPLE_FLAG_NON_SYNC == 0;
+            let data = if is_keyframe && !track.sps_pps_avcc.is_empty() {
+                let mut full_data = track.sps_pps_avcc.clone();
+                full_data.extend_from_slice(sample);
+                full_data
+            } else {
+                sample.to_vec()
+            };
+
+            frames.push(TimestampedFrame {
+                timestamp_secs: decode_time as f64 / track.timescale as f64,
+                media: MediaFrame::Video { data, is_keyframe },
+            });
+            decode_time += duration as u64;
+        }
+    }
+
+    frames
+}
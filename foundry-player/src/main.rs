@@ -1,12 +1,15 @@
 //! foundry-player: Stream MP4 files over WebSocket
 //!
 //! Usage: foundry-player movie.mp4
+//!        foundry-player https://example.com/movie.mp4
+//!        foundry-player movie.mp4 --export 30..60 --out clip.mp4
+//!        ffmpeg ... -movflags frag_keyframe+empty_moov -f mp4 - | foundry-player -
 
 use anyhow::{anyhow, Result};
 use axum::{
-    body::Body,
+    body::{Body, Bytes},
     extract::{
-        ws::{Message, Utf8Bytes, WebSocket, WebSocketUpgrade},
+        ws::{close_code, CloseFrame, Message, Utf8Bytes, WebSocket, WebSocketUpgrade},
         State,
     },
     response::{IntoResponse, Response},
@@ -16,35 +19,51 @@ use axum::{
 use clap::Parser;
 use futures_util::{SinkExt, StreamExt};
 use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     path::PathBuf,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 use tokio::{
     fs,
-    sync::mpsc,
-    time::{interval, MissedTickBehavior},
+    sync::{mpsc, watch},
+    time::{interval, timeout, MissedTickBehavior},
 };
 
+mod audio_cache;
 mod audio_decoder;
 mod demuxer;
+mod export;
+mod live;
+mod remote;
 
 use audio_decoder::DecodedAudio;
-use demuxer::{MediaFrame, Mp4Demuxer};
+use demuxer::{Input, MediaFrame, Mp4Demuxer};
+use live::LiveStream;
 
 const OUTBOUND_BUFFER: usize = 256;
 
+/// Audio chunk size: 40ms worth of samples (balance between latency and overhead)
+const AUDIO_CHUNK_MS: u32 = 40;
+
 #[derive(Parser)]
 #[command(name = "foundry-player")]
 #[command(about = "Stream MP4 files over WebSocket")]
 struct Cli {
-    /// Path to the MP4 file to stream
+    /// Path or http(s) URL of the MP4 to stream, or `-` to read fragmented MP4 from stdin
     file: PathBuf,
 
     /// Port to listen on
     #[arg(long, default_value = "23646")]
     port: u16,
 
+    /// Address to listen on, e.g. 127.0.0.1 or [::]
+    #[arg(long, default_value = "0.0.0.0", value_parser = parse_bind_addr)]
+    bind: IpAddr,
+
     /// Loop playback
     #[arg(long)]
     loop_playback: bool,
@@ -52,26 +71,187 @@ struct Cli {
     /// Start time in seconds (seek into the video)
     #[arg(long, default_value = "0")]
     start: f64,
+
+    /// Decode audio once into a memory-mapped temp file instead of RAM
+    #[arg(long)]
+    audio_cache: bool,
+
+    /// Delete the audio cache file instead of keeping it for later runs
+    #[arg(long, requires = "audio_cache")]
+    no_keep_cache: bool,
+
+    /// Export START..END seconds to a new MP4 without re-encoding (no server)
+    #[arg(long, value_parser = export::parse_range, requires = "out")]
+    export: Option<(f64, f64)>,
+
+    /// Output path for --export
+    #[arg(long)]
+    out: Option<PathBuf>,
+
+    /// Seconds between WebSocket pings; a client missing two pongs is dropped
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    heartbeat_secs: u64,
+}
+
+/// Where the media comes from
+#[derive(Clone)]
+enum Source {
+    /// A seekable MP4 file, paced by the server
+    File(Arc<Mp4Demuxer>),
+    /// Fragmented MP4 arriving on stdin, forwarded as it arrives
+    Live(Arc<LiveStream>),
 }
 
 #[derive(Clone)]
 struct AppState {
-    demuxer: Arc<Mp4Demuxer>,
+    source: Source,
     audio: Option<Arc<DecodedAudio>>,
     loop_playback: bool,
     start_time: f64,
+    heartbeat: Duration,
+    /// Flips to true on Ctrl-C
+    shutdown: watch::Receiver<bool>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    if !cli.file.exists() {
-        return Err(anyhow!("File not found: {:?}", cli.file));
+    if let (Some((start, end)), Some(out)) = (cli.export, &cli.out) {
+        let input = open_input(&cli)?;
+        return export::export_clip(&input, start, end, out);
+    }
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let state = if cli.file.as_os_str() == "-" {
+        if cli.loop_playback || cli.start != 0.0 {
+            eprintln!("--loop-playback and --start are ignored for live stdin input");
+        }
+        println!("Reading fragmented MP4 from stdin...");
+        println!("Audio: not supported for live input");
+        AppState {
+            source: Source::Live(LiveStream::from_stdin()),
+            audio: None,
+            loop_playback: false,
+            start_time: 0.0,
+            heartbeat: Duration::from_secs(cli.heartbeat_secs),
+            shutdown: shutdown_rx.clone(),
+        }
+    } else {
+        load_file(&cli, shutdown_rx.clone())?
+    };
+
+    let app = Router::new()
+        .route("/", get(serve_html))
+        .route("/ws", get(get_ws))
+        .route("/video.js", get(|| serve_static("video.js")))
+        .route("/video_worker.js", get(|| serve_static("video_worker.js")))
+        .route("/audio.js", get(|| serve_static("audio.js")))
+        .route("/audio_worklet.js", get(|| serve_static("audio_worklet.js")))
+        .route("/gui.js", get(|| serve_static("gui.js")))
+        .route("/stats.js", get(|| serve_static("stats.js")))
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind(SocketAddr::new(cli.bind, cli.port)).await?;
+    let port = listener.local_addr()?.port();
+    let mut urls: Vec<String> = reachable_addrs(cli.bind)
+        .into_iter()
+        .map(|ip| format!("http://{}/", SocketAddr::new(ip, port)))
+        .collect();
+    if cli.bind.is_unspecified() || cli.bind.is_loopback() {
+        urls.push(format!("http://localhost:{}/", port));
     }
+    println!("Open {}", urls.join(" or "));
+
+    // First Ctrl-C closes sessions and drains the server; a second one exits now
+    tokio::spawn(async move {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            eprintln!("Failed to listen for Ctrl-C: {}", e);
+            return std::future::pending::<()>().await;
+        }
+        println!("Shutting down (Ctrl-C again to force)...");
+        shutdown_tx.send_replace(true);
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("Forced exit");
+            std::process::exit(130);
+        }
+        std::future::pending::<()>().await
+    });
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_requested(shutdown_rx))
+        .await?;
+    println!("Server stopped");
+
+    Ok(())
+}
+
+/// Parse `--bind`, accepting bracketed IPv6 like `[::]`
+fn parse_bind_addr(value: &str) -> Result<IpAddr, String> {
+    let trimmed = value
+        .strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .unwrap_or(value);
+    trimmed
+        .parse()
+        .map_err(|_| format!("invalid IP address: {}", value))
+}
+
+/// Non-loopback addresses clients on the LAN can use to reach `bind`
+fn reachable_addrs(bind: IpAddr) -> Vec<IpAddr> {
+    if bind.is_loopback() {
+        return Vec::new();
+    }
+    if !bind.is_unspecified() {
+        return vec![bind];
+    }
+
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            eprintln!("Failed to list network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+    let mut addrs: Vec<IpAddr> = interfaces
+        .iter()
+        .filter(|iface| !iface.is_loopback())
+        .map(|iface| iface.ip())
+        .filter(|ip| match ip {
+            // 0.0.0.0 only accepts IPv4; [::] is dual-stack on most systems
+            IpAddr::V6(v6) => {
+                bind != IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+                    // Link-local addresses need a scope id to be usable in a URL
+                    && (v6.segments()[0] & 0xffc0) != 0xfe80
+            }
+            IpAddr::V4(_) => true,
+        })
+        .collect();
+    addrs.sort();
+    addrs.dedup();
+    addrs
+}
+
+fn open_input(cli: &Cli) -> Result<Input> {
+    let file_arg = cli.file.to_string_lossy();
+    if remote::is_url(&file_arg) {
+        Ok(match remote::open(&file_arg)? {
+            remote::RemoteInput::Ranged(cache) => Input::Http(cache),
+            remote::RemoteInput::Downloaded(path) => Input::File(path),
+        })
+    } else {
+        if !cli.file.exists() {
+            return Err(anyhow!("File not found: {:?}", cli.file));
+        }
+        Ok(Input::File(cli.file.clone()))
+    }
+}
+
+fn load_file(cli: &Cli, shutdown: watch::Receiver<bool>) -> Result<AppState> {
+    let input = open_input(cli)?;
 
     println!("Loading {:?}...", cli.file);
-    let demuxer = Mp4Demuxer::open(&cli.file)?;
+    let demuxer = Mp4Demuxer::open(&input)?;
 
     println!(
         "Video: {}x{} @ {:.2} fps, {} frames",
@@ -84,9 +264,14 @@ async fn main() -> Result<()> {
     // Decode audio
     let audio = if demuxer.has_audio() {
         println!("Decoding audio...");
-        match audio_decoder::decode_audio(&cli.file) {
+        let decoded = if cli.audio_cache {
+            audio_cache::load_or_decode(&input, !cli.no_keep_cache)
+        } else {
+            audio_decoder::decode_audio(&input)
+        };
+        match decoded {
             Ok(Some(decoded)) => {
-                let duration_secs = decoded.samples.len() as f64 
+                let duration_secs = decoded.samples().len() as f64 
                     / decoded.sample_rate as f64 
                     / decoded.channels as f64;
                 println!(
@@ -111,30 +296,14 @@ async fn main() -> Result<()> {
         None
     };
 
-    let state = AppState {
-        demuxer: Arc::new(demuxer),
+    Ok(AppState {
+        source: Source::File(Arc::new(demuxer)),
         audio,
         loop_playback: cli.loop_playback,
         start_time: cli.start,
-    };
-
-    let app = Router::new()
-        .route("/", get(serve_html))
-        .route("/ws", get(get_ws))
-        .route("/video.js", get(|| serve_static("video.js")))
-        .route("/video_worker.js", get(|| serve_static("video_worker.js")))
-        .route("/audio.js", get(|| serve_static("audio.js")))
-        .route("/audio_worklet.js", get(|| serve_static("audio_worklet.js")))
-        .route("/gui.js", get(|| serve_static("gui.js")))
-        .route("/stats.js", get(|| serve_static("stats.js")))
-        .with_state(state);
-
-    let addr = format!("0.0.0.0:{}", cli.port);
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
-    println!("Open http://localhost:{}/", cli.port);
-    axum::serve(listener, app).await?;
-
-    Ok(())
+        heartbeat: Duration::from_secs(cli.heartbeat_secs),
+        shutdown,
+    })
 }
 
 async fn serve_html() -> Response {
@@ -177,20 +346,51 @@ async fn handle_ws(stream: WebSocket, state: AppState) {
     let (mut sender, mut receiver) = stream.split();
     let (tx, mut rx) = mpsc::channel::<Message>(OUTBOUND_BUFFER);
 
+    // Liveness: pings carry microseconds since session start, echoed back in pongs
+    let session_start = Instant::now();
+    let last_pong_us = Arc::new(AtomicU64::new(0));
+    let last_rtt_us = Arc::new(AtomicU64::new(0)); // 0 = no pong yet
+    let heartbeat = state.heartbeat;
+    let shutdown = state.shutdown.clone();
+    let close_tx = tx.clone();
+
     // Outbound task: send messages to client
-    let outbound = tokio::spawn(async move {
-        let mut ticker = interval(Duration::from_secs(10));
+    let pong_seen = last_pong_us.clone();
+    let rtt_seen = last_rtt_us.clone();
+    let mut outbound = tokio::spawn(async move {
+        let mut ticker = interval(heartbeat);
         ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
         loop {
             tokio::select! {
                 Some(msg) = rx.recv() => {
-                    if sender.send(msg).await.is_err() {
+                    let closing = matches!(msg, Message::Close(_));
+                    if sender.send(msg).await.is_err() || closing {
                         break;
                     }
                 }
                 _ = ticker.tick() => {
-                    if sender.send(Message::Text(Utf8Bytes::from("heartbeat"))).await.is_err() {
+                    let now_us = session_start.elapsed().as_micros() as u64;
+                    let silent_us = now_us.saturating_sub(pong_seen.load(Ordering::Relaxed));
+                    if silent_us > 2 * heartbeat.as_micros() as u64 {
+                        println!(
+                            "No pong for {:.1}s, closing connection",
+                            silent_us as f64 / 1e6
+                        );
+                        let _ = sender.send(Message::Close(None)).await;
+                        break;
+                    }
+
+                    let rtt_us = rtt_seen.load(Ordering::Relaxed);
+                    let stats_json = serde_json::json!({
+                        "type": "stats",
+                        "rtt_ms": (rtt_us > 0).then(|| rtt_us as f64 / 1000.0),
+                    });
+                    if sender.send(Message::Text(Utf8Bytes::from(stats_json.to_string()))).await.is_err() {
+                        break;
+                    }
+                    let ping = Bytes::copy_from_slice(&now_us.to_le_bytes());
+                    if sender.send(Message::Ping(ping)).await.is_err() {
                         break;
                     }
                 }
@@ -200,17 +400,46 @@ async fn handle_ws(stream: WebSocket, state: AppState) {
 
     // Playback task
     let tx_clone = tx.clone();
+    let error_tx = tx.clone();
+    let is_live = matches!(state.source, Source::Live(_));
     let playback = tokio::spawn(async move {
-        if let Err(e) = run_playback(tx_clone, state).await {
+        let result = match &state.source {
+            Source::File(demuxer) => run_playback(tx_clone, demuxer.clone(), &state).await,
+            Source::Live(live) => run_live(tx_clone, live.clone()).await,
+        };
+        if let Err(e) = result {
             eprintln!("Playback error: {}", e);
+            // Surface I/O failures (e.g. network errors on remote input) to the client
+            let error_json = serde_json::json!({
+                "type": "error",
+                "message": format!("playback error: {}", e),
+            });
+            let _ = error_tx
+                .send(Message::Text(Utf8Bytes::from(error_json.to_string())))
+                .await;
         }
     });
 
     // Inbound task: handle client messages
-    let inbound = tokio::spawn(async move {
+    let mut inbound = tokio::spawn(async move {
         while let Some(Ok(msg)) = receiver.next().await {
             match msg {
+                Message::Pong(payload) => {
+                    let Ok(sent) = <[u8; 8]>::try_from(payload.as_ref()) else {
+                        continue;
+                    };
+                    let now_us = session_start.elapsed().as_micros() as u64;
+                    let rtt_us = now_us.saturating_sub(u64::from_le_bytes(sent)).max(1);
+                    last_rtt_us.store(rtt_us, Ordering::Relaxed);
+                    last_pong_us.store(now_us, Ordering::Relaxed);
+                }
                 Message::Text(text) => {
+                    if is_live {
+                        if let Some(reply) = live_command_error(&text) {
+                            let _ = tx.send(Message::Text(Utf8Bytes::from(reply))).await;
+                            continue;
+                        }
+                    }
                     // Handle commands like seek, pause, etc. (future)
                     println!("Received: {}", text);
                 }
@@ -220,16 +449,60 @@ async fn handle_ws(stream: WebSocket, state: AppState) {
         }
     });
 
-    let _ = tokio::try_join!(outbound, playback, inbound);
+    // Either side going away ends the session; stop playback so we don't
+    // keep decoding for a client that's gone
+    tokio::select! {
+        _ = &mut outbound => {}
+        _ = &mut inbound => {}
+        _ = shutdown_requested(shutdown) => {
+            playback.abort();
+            let close = Message::Close(Some(CloseFrame {
+                code: close_code::AWAY,
+                reason: Utf8Bytes::from("server shutting down"),
+            }));
+            if close_tx.send(close).await.is_ok() {
+                let _ = timeout(Duration::from_secs(1), &mut outbound).await;
+            }
+        }
+    }
+    playback.abort();
+    outbound.abort();
+    inbound.abort();
     println!("Session ended");
 }
 
-async fn run_playback(tx: mpsc::Sender<Message>, state: AppState) -> Result<()> {
+/// Resolves once Ctrl-C has been pressed
+async fn shutdown_requested(mut shutdown: watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stop| *stop).await;
+}
+
+/// Reply for commands that make no sense on a live stream (seek, loop)
+fn live_command_error(text: &str) -> Option<String> {
+    let msg: serde_json::Value = serde_json::from_str(text).ok()?;
+    let command = msg.get("type")?.as_str()?;
+    match command {
+        "seek" | "loop" => Some(
+            serde_json::json!({
+                "type": "error",
+                "command": command,
+                "message": format!("{} is not supported for live stdin input", command),
+            })
+            .to_string(),
+        ),
+        _ => None,
+    }
+}
+
+async fn run_playback(
+    tx: mpsc::Sender<Message>,
+    demuxer: Arc<Mp4Demuxer>,
+    state: &AppState,
+) -> Result<()> {
     let start_time = state.start_time;
     println!("Starting playback at {:.1}s...", start_time);
 
     // Send video config first
-    let config = state.demuxer.video_config()?;
+    let config = demuxer.video_config()?;
     let config_json = serde_json::json!({
         "type": "video-config",
         "config": {
@@ -242,46 +515,80 @@ async fn run_playback(tx: mpsc::Sender<Message>, state: AppState) -> Result<()>
     tx.send(Message::Text(Utf8Bytes::from(config_json.to_string())))
         .await?;
 
+    // Announce audio parameters before the first AUD0 chunk
+    let audio_config = audio_config_json(state.audio.as_deref());
+    tx.send(Message::Text(Utf8Bytes::from(audio_config.to_string())))
+        .await?;
+
     // Send mode ack
     tx.send(Message::Text(Utf8Bytes::from(
-        r#"{"type":"mode-ack","mode":"video","codec":"avc"}"#,
+        r#"{"type":"mode-ack","mode":"video","codec":"avc","framing":"vid0"}"#,
     )))
     .await?;
 
     // Audio state
     let audio_sample_rate = state.audio.as_ref().map(|a| a.sample_rate).unwrap_or(48000);
     let audio_channels = state.audio.as_ref().map(|a| a.channels).unwrap_or(2);
-    let audio_samples = state.audio.as_ref().map(|a| &a.samples[..]);
+    let audio_samples = state.audio.as_ref().map(|a| a.samples());
     
-    // Audio chunk size: ~40ms worth of samples (balance between latency and overhead)
-    let audio_chunk_duration = 0.04; // 40ms
+    let audio_chunk_duration = AUDIO_CHUNK_MS as f64 / 1000.0;
     let audio_chunk_samples = (audio_sample_rate as f64 * audio_channels as f64 * audio_chunk_duration) as usize;
 
+    let mut sequence: u64 = 0;
+
     loop {
-        let playback_start = Instant::now();
         let mut last_audio_time: f64 = start_time;
-        let mut found_keyframe = false;
+        // Frames from the keyframe at or before start_time, held until we
+        // reach the requested position
+        let mut preroll: Vec<demuxer::TimestampedFrame> = Vec::new();
+        let mut playback_start: Option<Instant> = None;
         
         // Create a fresh iterator for each playback loop
-        let frames = state.demuxer.frames()?;
+        let frames = demuxer.frames()?;
 
         for frame in frames {
             let frame = frame?;
-            
-            // Skip frames before start time
-            if frame.timestamp_secs < start_time {
-                continue;
-            }
-            
-            // For first frame after start_time, we need a keyframe
             let MediaFrame::Video { is_keyframe, .. } = &frame.media;
-            if !found_keyframe {
-                if !is_keyframe {
-                    continue; // Skip until we get a keyframe
+
+            // Collect the GOP leading up to start time
+            if playback_start.is_none() && frame.timestamp_secs < start_time {
+                if *is_keyframe {
+                    preroll.clear();
+                }
+                if *is_keyframe || !preroll.is_empty() {
+                    preroll.push(frame);
                 }
-                found_keyframe = true;
+                continue;
             }
 
+            // First frame at/after start time: send the preroll immediately,
+            // flagged decode-only so the client shows nothing before start_time
+            let playback_start = match playback_start {
+                Some(started) => started,
+                None => {
+                    if preroll.is_empty() && !is_keyframe {
+                        continue; // No keyframe yet, can't decode from here
+                    }
+                    for early in preroll.drain(..) {
+                        let MediaFrame::Video { data, is_keyframe } = early.media;
+                        let packet = build_video_packet(
+                            sequence,
+                            early.timestamp_secs,
+                            is_keyframe,
+                            true,
+                            &data,
+                        );
+                        sequence += 1;
+                        if tx.send(Message::Binary(packet.into())).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                    let started = Instant::now();
+                    playback_start = Some(started);
+                    started
+                }
+            };
+
             // Calculate when this frame should be presented (relative to start_time)
             let relative_time = frame.timestamp_secs - start_time;
             let target_time = Duration::from_secs_f64(relative_time);
@@ -289,7 +596,10 @@ async fn run_playback(tx: mpsc::Sender<Message>, state: AppState) -> Result<()>
 
             // Wait until it's time to send this frame
             if target_time > elapsed {
-                tokio::time::sleep(target_time - elapsed).await;
+                tokio::select! {
+                    _ = tokio::time::sleep(target_time - elapsed) => {}
+                    _ = shutdown_requested(state.shutdown.clone()) => return Ok(()),
+                }
             }
 
             // Send audio for this time window (send audio just before video for sync)
@@ -304,7 +614,7 @@ async fn run_playback(tx: mpsc::Sender<Message>, state: AppState) -> Result<()>
                     let chunk = &samples[pos..chunk_end];
                     
                     if !chunk.is_empty() {
-                        let audio_msg = build_audio_chunk(chunk, audio_sample_rate);
+                        let audio_msg = build_audio_chunk(chunk, audio_sample_rate, audio_channels);
                         if tx.send(Message::Binary(audio_msg.into())).await.is_err() {
                             return Ok(());
                         }
@@ -315,8 +625,10 @@ async fn run_playback(tx: mpsc::Sender<Message>, state: AppState) -> Result<()>
             }
 
             // Send video frame
-            let MediaFrame::Video { data, .. } = frame.media;
-            if tx.send(Message::Binary(data.into())).await.is_err() {
+            let MediaFrame::Video { data, is_keyframe } = frame.media;
+            let packet = build_video_packet(sequence, frame.timestamp_secs, is_keyframe, false, &data);
+            sequence += 1;
+            if tx.send(Message::Binary(packet.into())).await.is_err() {
                 return Ok(());
             }
         }
@@ -332,9 +644,125 @@ async fn run_playback(tx: mpsc::Sender<Message>, state: AppState) -> Result<()>
     Ok(())
 }
 
+/// Forward live frames as they arrive, without pacing.
+///
+/// Slow clients never block the stdin reader: when this client falls behind
+/// (broadcast lag or a full outbound queue) it drops frames until the next
+/// keyframe so the decoder can resume cleanly.
+async fn run_live(tx: mpsc::Sender<Message>, live: Arc<LiveStream>) -> Result<()> {
+    let mut frames = live.subscribe();
+
+    println!("Waiting for live init segment...");
+    let Some(config) = live.video_config().await else {
+        return Err(anyhow!("Live input ended before the init segment"));
+    };
+    let config_json = serde_json::json!({
+        "type": "video-config",
+        "config": {
+            "codec": config.codec_string,
+            "description": config.description_b64,
+            "width": config.width,
+            "height": config.height,
+        }
+    });
+    tx.send(Message::Text(Utf8Bytes::from(config_json.to_string())))
+        .await?;
+    let audio_config = audio_config_json(None);
+    tx.send(Message::Text(Utf8Bytes::from(audio_config.to_string())))
+        .await?;
+    tx.send(Message::Text(Utf8Bytes::from(
+        r#"{"type":"mode-ack","mode":"video","codec":"avc","framing":"vid0"}"#,
+    )))
+    .await?;
+
+    let mut sequence: u64 = 0;
+    let mut waiting_for_keyframe = true;
+    loop {
+        let frame = match frames.recv().await {
+            Ok(frame) => frame,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                eprintln!("Live client lagging, skipped {} frames", skipped);
+                waiting_for_keyframe = true;
+                continue;
+            }
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                println!("Live playback complete");
+                return Ok(());
+            }
+        };
+
+        let MediaFrame::Video { data, is_keyframe } = &frame.media;
+        if waiting_for_keyframe {
+            if !is_keyframe {
+                continue;
+            }
+            waiting_for_keyframe = false;
+        }
+
+        let packet = build_video_packet(sequence, frame.timestamp_secs, *is_keyframe, false, data);
+        sequence += 1;
+        match tx.try_send(Message::Binary(packet.into())) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => waiting_for_keyframe = true,
+            Err(mpsc::error::TrySendError::Closed(_)) => return Ok(()),
+        }
+    }
+}
+
+/// Video packet flag: chunk is a keyframe
+const VIDEO_FLAG_KEYFRAME: u8 = 0x01;
+/// Video packet flag: decode but don't display (preroll before --start)
+const VIDEO_FLAG_DISCARD: u8 = 0x02;
+
+/// Build a video packet: "VID0" header followed by the AVCC payload.
+///
+/// Layout (little-endian): magic[4], sequence u64, timestamp_ms f64,
+/// flags u8, payload_len u32, payload.
+fn build_video_packet(
+    sequence: u64,
+    timestamp_secs: f64,
+    is_keyframe: bool,
+    discard: bool,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut flags = 0u8;
+    if is_keyframe {
+        flags |= VIDEO_FLAG_KEYFRAME;
+    }
+    if discard {
+        flags |= VIDEO_FLAG_DISCARD;
+    }
+
+    let mut out = Vec::with_capacity(25 + payload.len());
+    out.extend_from_slice(b"VID0");
+    out.extend_from_slice(&sequence.to_le_bytes());
+    out.extend_from_slice(&(timestamp_secs * 1000.0).to_le_bytes());
+    out.push(flags);
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(payload);
+    out
+}
+
+/// The `audio-config` message describing the AUD0 stream (or its absence)
+fn audio_config_json(audio: Option<&DecodedAudio>) -> serde_json::Value {
+    match audio {
+        Some(audio) => serde_json::json!({
+            "type": "audio-config",
+            "present": true,
+            "format": "pcm_s16",
+            "sample_rate": audio.sample_rate,
+            "channels": audio.channels,
+            "chunk_ms": AUDIO_CHUNK_MS,
+        }),
+        None => serde_json::json!({
+            "type": "audio-config",
+            "present": false,
+        }),
+    }
+}
+
 /// Build audio chunk in Foundry's format
-fn build_audio_chunk(samples: &[i16], sample_rate: u32) -> Vec<u8> {
-    let channels = 2u32; // Stereo
+fn build_audio_chunk(samples: &[i16], sample_rate: u32, channels: u32) -> Vec<u8> {
     let sample_count = samples.len() as u32;
 
     let mut out = Vec::with_capacity(24 + samples.len() * 2);
@@ -348,3 +776,55 @@ fn build_audio_chunk(samples: &[i16], sample_rate: u32) -> Vec<u8> {
     }
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::tungstenite;
+
+    #[tokio::test]
+    async fn shutdown_closes_connected_sessions() {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let state = AppState {
+            source: Source::Live(LiveStream::ended()),
+            audio: None,
+            loop_playback: false,
+            start_time: 0.0,
+            heartbeat: Duration::from_secs(60),
+            shutdown: shutdown_rx.clone(),
+        };
+        let app = Router::new().route("/ws", get(get_ws)).with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown_requested(shutdown_rx))
+                .await
+        });
+
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        shutdown_tx.send_replace(true);
+        let close = timeout(Duration::from_secs(1), async {
+            while let Some(msg) = client.next().await {
+                if let tungstenite::Message::Close(frame) = msg.unwrap() {
+                    return frame;
+                }
+            }
+            None
+        })
+        .await
+        .expect("no Close within a second of shutdown")
+        .expect("Close without a frame");
+        assert_eq!(u16::from(close.code), close_code::AWAY);
+        assert_eq!(close.reason.as_str(), "server shutting down");
+
+        drop(client);
+        timeout(Duration::from_secs(1), server)
+            .await
+            .expect("server still running after shutdown")
+            .unwrap()
+            .unwrap();
+    }
+}
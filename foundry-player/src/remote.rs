@@ -0,0 +1,213 @@
+//! HTTP(S) input: serves MP4 bytes to the demuxer via range requests
+//!
+//! Uses blocking `ureq` since the demuxer and decoders read synchronously
+//! (and a blocking reqwest client would panic inside the tokio runtime).
+
+use anyhow::{anyhow, Result};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Size of each cached byte range
+const RANGE_CHUNK_BYTES: u64 = 1024 * 1024;
+
+/// Number of ranges kept in the LRU (~32 MB)
+const RANGE_CACHE_CHUNKS: usize = 32;
+
+/// Shared state for one remote file: URL, length, and recently fetched ranges
+pub struct RangeCache {
+    url: String,
+    len: u64,
+    /// Most recently used at the back
+    chunks: Mutex<VecDeque<(u64, Arc<Vec<u8>>)>>,
+}
+
+/// Result of probing a URL for range support
+pub enum RemoteInput {
+    /// Server honours `Range`; read on demand
+    Ranged(Arc<RangeCache>),
+    /// Server ignored `Range`; the whole file was downloaded here
+    Downloaded(PathBuf),
+}
+
+pub fn is_url(input: &str) -> bool {
+    input.starts_with("http://") || input.starts_with("https://")
+}
+
+/// Probe the server with a one-byte range request, falling back to a full
+/// download when ranges aren't supported.
+pub fn open(url: &str) -> Result<RemoteInput> {
+    let response = ureq::get(url)
+        .set("Range", "bytes=0-0")
+        .call()
+        .map_err(|e| anyhow!("Failed to fetch {}: {}", url, e))?;
+
+    if response.status() == 206 {
+        let total = response
+            .header("Content-Range")
+            .and_then(|range| range.rsplit('/').next())
+            .and_then(|total| total.trim().parse::<u64>().ok());
+        if let Some(len) = total {
+            println!("Streaming {} ({:.1} MB, range requests)", url, len as f64 / 1e6);
+            return Ok(RemoteInput::Ranged(Arc::new(RangeCache {
+                url: url.to_string(),
+                len,
+                chunks: Mutex::new(VecDeque::new()),
+            })));
+        }
+    }
+
+    println!("Server does not support range requests, downloading {}...", url);
+    let path = download_to_temp(url, response)?;
+    Ok(RemoteInput::Downloaded(path))
+}
+
+fn download_to_temp(url: &str, probe: ureq::Response) -> Result<PathBuf> {
+    // The probe response already carries the full body when ranges are ignored.
+    let response = if probe.status() == 200 {
+        probe
+    } else {
+        ureq::get(url)
+            .call()
+            .map_err(|e| anyhow!("Failed to download {}: {}", url, e))?
+    };
+    let total: Option<u64> = response
+        .header("Content-Length")
+        .and_then(|len| len.parse().ok());
+
+    let path = std::env::temp_dir().join(format!("foundry-player-{}.mp4", std::process::id()));
+    let mut file = File::create(&path)?;
+    let mut reader = response.into_reader();
+    let mut buf = vec![0u8; 256 * 1024];
+    let mut done: u64 = 0;
+    let mut last_report = Instant::now();
+
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        file.write_all(&buf[..n])?;
+        done += n as u64;
+        if last_report.elapsed().as_millis() > 250 {
+            print_progress(done, total);
+            last_report = Instant::now();
+        }
+    }
+    print_progress(done, total);
+    eprintln!();
+
+    Ok(path)
+}
+
+fn print_progress(done: u64, total: Option<u64>) {
+    match total {
+        Some(total) if total > 0 => eprint!(
+            "\rDownloaded {:.1} / {:.1} MB ({:.0}%)",
+            done as f64 / 1e6,
+            total as f64 / 1e6,
+            done as f64 * 100.0 / total as f64
+        ),
+        _ => eprint!("\rDownloaded {:.1} MB", done as f64 / 1e6),
+    }
+}
+
+impl RangeCache {
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Fetch a chunk by index, from the LRU if possible
+    fn chunk(&self, index: u64) -> io::Result<Arc<Vec<u8>>> {
+        {
+            let mut chunks = self.chunks.lock().unwrap();
+            if let Some(pos) = chunks.iter().position(|(i, _)| *i == index) {
+                let entry = chunks.remove(pos).unwrap();
+                let data = entry.1.clone();
+                chunks.push_back(entry);
+                return Ok(data);
+            }
+        }
+
+        let start = index * RANGE_CHUNK_BYTES;
+        let end = (start + RANGE_CHUNK_BYTES).min(self.len) - 1;
+        let response = ureq::get(&self.url)
+            .set("Range", &format!("bytes={}-{}", start, end))
+            .call()
+            .map_err(|e| io::Error::other(format!("range request failed: {}", e)))?;
+        if response.status() != 206 {
+            return Err(io::Error::other(format!(
+                "range request returned HTTP {}",
+                response.status()
+            )));
+        }
+
+        let mut data = Vec::with_capacity((end - start + 1) as usize);
+        response.into_reader().read_to_end(&mut data)?;
+        let data = Arc::new(data);
+
+        let mut chunks = self.chunks.lock().unwrap();
+        chunks.push_back((index, data.clone()));
+        while chunks.len() > RANGE_CACHE_CHUNKS {
+            chunks.pop_front();
+        }
+        Ok(data)
+    }
+}
+
+/// A `Read + Seek` cursor over a remote file; cheap to create per iterator
+pub struct HttpReader {
+    cache: Arc<RangeCache>,
+    pos: u64,
+}
+
+impl HttpReader {
+    pub fn new(cache: Arc<RangeCache>) -> Self {
+        Self { cache, pos: 0 }
+    }
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.cache.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self.pos / RANGE_CHUNK_BYTES;
+        let chunk = self.cache.chunk(index)?;
+        let offset = (self.pos - index * RANGE_CHUNK_BYTES) as usize;
+        if offset >= chunk.len() {
+            return Ok(0);
+        }
+        let n = buf.len().min(chunk.len() - offset);
+        buf[..n].copy_from_slice(&chunk[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for HttpReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::End(p) => self.cache.len as i64 + p,
+            SeekFrom::Current(p) => self.pos as i64 + p,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before start of remote file",
+            ));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,390 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
+use xcap::Frame;
+
+use crate::frame_pool::FramePool;
+
+/// Shapes on screen at once across all viewers; the oldest go first
+const MAX_SHAPES: usize = 64;
+
+/// Points accepted per shape (freehand strokes)
+const MAX_POINTS: usize = 512;
+
+/// How long a shape stays up when the message doesn't say
+const DEFAULT_TTL: Duration = Duration::from_millis(4000);
+/// Longest ttl_ms honoured
+const MAX_TTL: Duration = Duration::from_secs(60);
+
+/// Stroke thickness as a fraction of the captured width, so shapes look the
+/// same on every rung after downsampling
+const STROKE_FRACTION: f32 = 1.0 / 480.0;
+
+/// Arrowhead side length relative to the shaft, and its cap in stroke widths
+const ARROWHEAD_FRACTION: f32 = 0.25;
+const ARROWHEAD_MAX_STROKES: f32 = 8.0;
+
+const DEFAULT_COLOR: [u8; 4] = [0xff, 0x00, 0x00, 0xff];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ShapeKind {
+    Arrow,
+    Rect,
+    Freehand,
+}
+
+/// One viewer-drawn shape, in captured-source pixels
+#[derive(Debug, Clone)]
+pub struct Shape {
+    kind: ShapeKind,
+    points: Vec<(f32, f32)>,
+    /// RGBA
+    color: [u8; 4],
+    expires: Instant,
+}
+
+/// Shapes viewers have drawn over the current source (--allow-annotations)
+#[derive(Default)]
+pub struct Annotations {
+    shapes: Mutex<Vec<Shape>>,
+}
+
+impl Annotations {
+    pub fn add(&self, new: Vec<Shape>) {
+        let mut shapes = self.shapes.lock().unwrap();
+        shapes.extend(new);
+        let excess = shapes.len().saturating_sub(MAX_SHAPES);
+        shapes.drain(..excess);
+    }
+
+    /// Drop every shape, e.g. when the source changes and their coordinates no longer apply
+    pub fn clear(&self) {
+        self.shapes.lock().unwrap().clear();
+    }
+}
+
+/// Parse the `shapes` of an `annotate` message. `to_source` maps a point from
+/// the viewer's stream pixels to captured-source pixels.
+pub fn parse_shapes(
+    msg: &Value,
+    to_source: impl Fn((f32, f32)) -> Option<(f32, f32)>,
+) -> Result<Vec<Shape>, String> {
+    let list = msg
+        .get("shapes")
+        .and_then(|s| s.as_array())
+        .ok_or_else(|| "annotate needs a shapes array".to_string())?;
+    let now = Instant::now();
+    list.iter()
+        .map(|shape| {
+            let kind = match shape.get("kind").and_then(|k| k.as_str()) {
+                Some("arrow") => ShapeKind::Arrow,
+                Some("rect") => ShapeKind::Rect,
+                Some("freehand") => ShapeKind::Freehand,
+                Some(other) => return Err(format!("unknown shape kind: {}", other)),
+                None => return Err("shape.kind is missing".to_string()),
+            };
+            let points = shape
+                .get("points")
+                .and_then(|p| p.as_array())
+                .ok_or_else(|| "shape.points must be an array of [x, y] pairs".to_string())?;
+            if points.len() > MAX_POINTS {
+                return Err(format!("a shape may have at most {} points", MAX_POINTS));
+            }
+            let points = points
+                .iter()
+                .map(|point| {
+                    let pair = point.as_array().filter(|pair| pair.len() == 2);
+                    let coord = |i: usize| pair.and_then(|pair| pair[i].as_f64()).map(|v| v as f32);
+                    match (coord(0), coord(1)) {
+                        (Some(x), Some(y)) => to_source((x, y)).ok_or_else(|| "no video yet".to_string()),
+                        _ => Err("shape.points must be an array of [x, y] pairs".to_string()),
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            match kind {
+                ShapeKind::Freehand if points.is_empty() => {
+                    return Err("freehand shapes need at least one point".to_string());
+                }
+                ShapeKind::Arrow | ShapeKind::Rect if points.len() != 2 => {
+                    return Err("arrow and rect shapes need exactly 2 points".to_string());
+                }
+                _ => {}
+            }
+            let color = match shape.get("color") {
+                None => DEFAULT_COLOR,
+                Some(color) => color
+                    .as_str()
+                    .and_then(parse_color)
+                    .ok_or_else(|| "color must be #rgb or #rrggbb".to_string())?,
+            };
+            let ttl = shape
+                .get("ttl_ms")
+                .and_then(|t| t.as_u64())
+                .map_or(DEFAULT_TTL, Duration::from_millis)
+                .min(MAX_TTL);
+            Ok(Shape {
+                kind,
+                points,
+                color,
+                expires: now + ttl,
+            })
+        })
+        .collect()
+}
+
+fn parse_color(text: &str) -> Option<[u8; 4]> {
+    let hex = text.strip_prefix('#')?;
+    let digit = |i: usize, len: usize| u8::from_str_radix(hex.get(i..i + len)?, 16).ok();
+    match hex.len() {
+        3 => Some([digit(0, 1)? * 17, digit(1, 1)? * 17, digit(2, 1)? * 17, 0xff]),
+        6 => Some([digit(0, 2)?, digit(2, 2)?, digit(4, 2)?, 0xff]),
+        _ => None,
+    }
+}
+
+/// Draws the live shapes into captured frames for one encoder
+pub struct Annotator {
+    annotations: Arc<Annotations>,
+    pool: FramePool,
+}
+
+impl Annotator {
+    pub fn new(annotations: Arc<Annotations>) -> Self {
+        Self {
+            annotations,
+            pool: FramePool::default(),
+        }
+    }
+
+    /// `frame` (rows `stride` bytes apart) with every unexpired shape drawn
+    /// on it, laid out the same; passed through when there are none
+    pub fn apply(&mut self, frame: Arc<Frame>, stride: usize) -> Arc<Frame> {
+        let mut shapes = self.annotations.shapes.lock().unwrap();
+        let now = Instant::now();
+        shapes.retain(|shape| shape.expires > now);
+        if shapes.is_empty() {
+            return frame;
+        }
+        let stroke = (frame.width as f32 * STROKE_FRACTION).max(2.0);
+        self.pool.edit(&frame, |canvas| {
+            for shape in shapes.iter() {
+                draw_shape(canvas, stride, shape, stroke);
+            }
+        })
+    }
+}
+
+fn draw_shape(canvas: &mut Frame, stride: usize, shape: &Shape, stroke: f32) {
+    let color = shape.color;
+    match shape.kind {
+        ShapeKind::Freehand => {
+            if let [point] = shape.points.as_slice() {
+                draw_line(canvas, stride, *point, *point, stroke, color);
+            }
+            for pair in shape.points.windows(2) {
+                draw_line(canvas, stride, pair[0], pair[1], stroke, color);
+            }
+        }
+        ShapeKind::Rect => {
+            let ((x0, y0), (x1, y1)) = (shape.points[0], shape.points[1]);
+            draw_line(canvas, stride, (x0, y0), (x1, y0), stroke, color);
+            draw_line(canvas, stride, (x1, y0), (x1, y1), stroke, color);
+            draw_line(canvas, stride, (x1, y1), (x0, y1), stroke, color);
+            draw_line(canvas, stride, (x0, y1), (x0, y0), stroke, color);
+        }
+        ShapeKind::Arrow => {
+            let (tail, head) = (shape.points[0], shape.points[1]);
+            draw_line(canvas, stride, tail, head, stroke, color);
+            let (dx, dy) = (head.0 - tail.0, head.1 - tail.1);
+            let length = (dx * dx + dy * dy).sqrt();
+            if length < 1.0 {
+                return;
+            }
+            let barb = (length * ARROWHEAD_FRACTION).min(stroke * ARROWHEAD_MAX_STROKES);
+            // Unit vector pointing back along the shaft, rotated +-30 degrees
+            let (ux, uy) = (-dx / length, -dy / length);
+            let (sin, cos) = 30f32.to_radians().sin_cos();
+            for sin in [sin, -sin] {
+                let end = (
+                    head.0 + barb * (ux * cos - uy * sin),
+                    head.1 + barb * (ux * sin + uy * cos),
+                );
+                draw_line(canvas, stride, head, end, stroke, color);
+            }
+        }
+    }
+}
+
+/// Stroke a line `width` pixels thick by stamping a square brush along it,
+/// on a canvas with rows `stride` bytes apart
+fn draw_line(canvas: &mut Frame, stride: usize, from: (f32, f32), to: (f32, f32), width: f32, color: [u8; 4]) {
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let steps = dx.abs().max(dy.abs()).ceil().max(1.0) as usize;
+    let half = (width / 2.0).max(0.5);
+    for step in 0..=steps {
+        let t = step as f32 / steps as f32;
+        fill_square(canvas, stride, from.0 + dx * t, from.1 + dy * t, half, color);
+    }
+}
+
+fn fill_square(canvas: &mut Frame, stride: usize, cx: f32, cy: f32, half: f32, color: [u8; 4]) {
+    let (width, height) = (canvas.width as f32, canvas.height as f32);
+    let x0 = (cx - half).round().clamp(0.0, width) as usize;
+    let x1 = (cx + half).round().clamp(0.0, width) as usize;
+    let y0 = (cy - half).round().clamp(0.0, height) as usize;
+    let y1 = (cy + half).round().clamp(0.0, height) as usize;
+    for y in y0..y1 {
+        let row = &mut canvas.raw[y * stride + x0 * 4..y * stride + x1 * 4];
+        for pixel in row.chunks_exact_mut(4) {
+            pixel.copy_from_slice(&color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::packed_stride;
+    use serde_json::json;
+
+    const RED: [u8; 4] = [0xff, 0x00, 0x00, 0xff];
+
+    fn blank(width: u32, height: u32) -> Frame {
+        Frame {
+            width,
+            height,
+            raw: vec![0; (width * height * 4) as usize],
+        }
+    }
+
+    /// Whether the pixel at (x, y) of a packed frame was painted
+    fn painted(frame: &Frame, x: usize, y: usize) -> bool {
+        let offset = y * packed_stride(frame) + x * 4;
+        frame.raw[offset..offset + 4] == RED
+    }
+
+    fn painted_count(frame: &Frame) -> usize {
+        frame.raw.chunks_exact(4).filter(|pixel| *pixel == RED).count()
+    }
+
+    fn shape(kind: ShapeKind, points: Vec<(f32, f32)>) -> Shape {
+        Shape {
+            kind,
+            points,
+            color: RED,
+            expires: Instant::now() + DEFAULT_TTL,
+        }
+    }
+
+    #[test]
+    fn lines_cover_their_stroke_and_nothing_else() {
+        let mut canvas = blank(10, 10);
+        let stride = packed_stride(&canvas);
+        draw_line(&mut canvas, stride, (2.0, 5.0), (7.0, 5.0), 2.0, RED);
+        for y in 0..10 {
+            for x in 0..10 {
+                let inside = (1..8).contains(&x) && (4..6).contains(&y);
+                assert_eq!(painted(&canvas, x, y), inside, "({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn rects_are_outlines() {
+        let mut canvas = blank(16, 16);
+        let stride = packed_stride(&canvas);
+        draw_shape(&mut canvas, stride, &shape(ShapeKind::Rect, vec![(2.0, 2.0), (13.0, 13.0)]), 2.0);
+        for corner in [(2, 2), (12, 2), (2, 12), (12, 12)] {
+            assert!(painted(&canvas, corner.0, corner.1), "{:?}", corner);
+        }
+        assert!(painted(&canvas, 7, 2) && painted(&canvas, 2, 7));
+        assert!((4..11).all(|y| (4..11).all(|x| !painted(&canvas, x, y))));
+        assert!(!painted(&canvas, 0, 0) && !painted(&canvas, 15, 15));
+    }
+
+    #[test]
+    fn arrows_have_barbs_at_the_head_only() {
+        let mut canvas = blank(20, 20);
+        let stride = packed_stride(&canvas);
+        draw_shape(&mut canvas, stride, &shape(ShapeKind::Arrow, vec![(1.0, 10.0), (18.0, 10.0)]), 2.0);
+        // The shaft alone covers rows 9 and 10
+        assert!(painted(&canvas, 10, 9) && painted(&canvas, 10, 10));
+        assert!(painted(&canvas, 14, 12) && painted(&canvas, 14, 7));
+        assert!(!painted(&canvas, 3, 12) && !painted(&canvas, 3, 7));
+    }
+
+    #[test]
+    fn shapes_are_clipped_to_the_frame() {
+        let mut canvas = blank(8, 8);
+        let stride = packed_stride(&canvas);
+        draw_line(&mut canvas, stride, (-20.0, 3.0), (30.0, 3.0), 2.0, RED);
+        assert_eq!(painted_count(&canvas), 16);
+        assert!((0..8).all(|x| painted(&canvas, x, 2) && painted(&canvas, x, 3)));
+
+        let mut canvas = blank(8, 8);
+
+        let stride = packed_stride(&canvas);
+        draw_shape(&mut canvas, stride, &shape(ShapeKind::Rect, vec![(-50.0, -50.0), (-10.0, -10.0)]), 2.0);
+        draw_line(&mut canvas, stride, (100.0, 100.0), (200.0, 100.0), 2.0, RED);
+        assert_eq!(painted_count(&canvas), 0);
+    }
+
+    #[test]
+    fn row_padding_is_left_alone() {
+        let (width, height, stride) = (4, 4, 6 * 4);
+        let mut canvas = Frame {
+            width,
+            height,
+            raw: vec![0; stride * height as usize],
+        };
+        draw_line(&mut canvas, stride, (-5.0, 0.0), (5.0, 4.0), 8.0, RED);
+        for row in canvas.raw.chunks_exact(stride) {
+            assert!(row[..16].chunks_exact(4).all(|pixel| pixel == RED));
+            assert!(row[16..].iter().all(|&byte| byte == 0));
+        }
+    }
+
+    #[test]
+    fn annotator_skips_frames_without_live_shapes() {
+        let annotations = Arc::new(Annotations::default());
+        let mut annotator = Annotator::new(annotations.clone());
+        let frame = Arc::new(blank(32, 32));
+        assert!(Arc::ptr_eq(&annotator.apply(frame.clone(), 32 * 4), &frame));
+
+        let mut expired = shape(ShapeKind::Freehand, vec![(4.0, 4.0)]);
+        expired.expires = Instant::now() - Duration::from_millis(1);
+        annotations.add(vec![expired]);
+        assert!(Arc::ptr_eq(&annotator.apply(frame.clone(), 32 * 4), &frame));
+
+        annotations.add(vec![shape(ShapeKind::Freehand, vec![(4.0, 4.0)])]);
+        let drawn = annotator.apply(frame.clone(), 32 * 4);
+        assert!(painted(&drawn, 4, 4));
+        assert_eq!(painted_count(&frame), 0);
+    }
+
+    #[test]
+    fn parses_shapes_and_maps_them_to_the_source() {
+        let msg = json!({"shapes": [
+            {"kind": "arrow", "points": [[1, 2], [3, 4]], "color": "#0f0", "ttl_ms": 1000},
+            {"kind": "freehand", "points": [[5, 6]]},
+        ]});
+        let shapes = parse_shapes(&msg, |(x, y)| Some((x * 2.0, y * 2.0))).unwrap();
+        assert_eq!(shapes[0].kind, ShapeKind::Arrow);
+        assert_eq!(shapes[0].points, vec![(2.0, 4.0), (6.0, 8.0)]);
+        assert_eq!(shapes[0].color, [0x00, 0xff, 0x00, 0xff]);
+        assert_eq!(shapes[1].color, DEFAULT_COLOR);
+
+        for bad in [
+            json!({}),
+            json!({"shapes": [{"kind": "circle", "points": [[1, 2]]}]}),
+            json!({"shapes": [{"kind": "rect", "points": [[1, 2]]}]}),
+            json!({"shapes": [{"kind": "freehand", "points": [[1]]}]}),
+            json!({"shapes": [{"kind": "freehand", "points": [[1, 2]], "color": "red"}]}),
+        ] {
+            assert!(parse_shapes(&bad, Some).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    path::{Component, Path},
+};
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, StatusCode},
+    response::Response,
+};
+
+// `EMBEDDED`: (path, contents, etag) for every client file, generated by build.rs
+include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+/// Serve a client file: from `dir` when given (live editing with --assets-dir,
+/// falling back to the embedded copy), otherwise from the binary.
+pub async fn serve(path: &str, dir: Option<&Path>, headers: &HeaderMap) -> Response {
+    let path = match path.trim_start_matches('/') {
+        "" => "root.html",
+        path => path,
+    };
+
+    if let Some(dir) = dir {
+        // Only plain relative paths; nothing may escape the assets dir
+        let relative = Path::new(path);
+        if relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            if let Ok(bytes) = tokio::fs::read(dir.join(relative)).await {
+                let tag = etag(&bytes);
+                return respond(path, bytes, &tag, headers);
+            }
+        }
+    }
+
+    match EMBEDDED.iter().find(|(name, _, _)| *name == path) {
+        Some((_, bytes, tag)) => respond(path, *bytes, tag, headers),
+        None => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("not found"))
+            .unwrap(),
+    }
+}
+
+fn respond(path: &str, body: impl Into<Body>, tag: &str, headers: &HeaderMap) -> Response {
+    let fresh = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|candidate| candidate.trim() == tag));
+    let builder = Response::builder()
+        .header(header::ETAG, tag)
+        .header(header::CACHE_CONTROL, "no-cache");
+    if fresh {
+        return builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap();
+    }
+    builder
+        .header(header::CONTENT_TYPE, content_type(path))
+        .body(body.into())
+        .unwrap()
+}
+
+fn content_type(path: &str) -> &'static str {
+    match path.rsplit('.').next() {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "text/javascript",
+        Some("css") => "text/css",
+        Some("json") | Some("map") => "application/json",
+        Some("wasm") => "application/wasm",
+        Some("png") => "image/png",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Same scheme as build.rs uses for the embedded files
+fn etag(bytes: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
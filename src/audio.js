@@ -1,6 +1,7 @@
 const AUDIO_SAMPLE_RATE = 24000;
 const AUDIO_CHUNK_MS = 100;
 const AUDIO_MAGIC_BYTES = [0x41, 0x55, 0x44, 0x30]; // "AUD0"
+const OPUS_MAGIC_BYTES = [0x4f, 0x50, 0x53, 0x30]; // "OPS0"
 const MIC_LABELS = {
   start: "Start mic",
   stop: "Stop mic",
@@ -23,6 +24,11 @@ export function createAudioController({
   let audioSilenceSink = null;
   let audioStream = null;
   let nextPlaybackTime = null;
+  // Remote stream parameters from the server's "audio-config" message
+  let remoteAudioConfig = null;
+  // WebCodecs decoder for OPS0 packets, and the "rate/channels" it was configured for
+  let opusDecoder = null;
+  let opusDecoderKey = null;
 
   syncMicUi();
   setMicLevel(0);
@@ -86,12 +92,12 @@ export function createAudioController({
     }
   }
 
-  function computeLevel(samples) {
+  function computeLevel(samples, scale = 32768) {
     if (!samples || !samples.length) return 0;
     let sumSq = 0;
     const len = samples.length;
     for (let i = 0; i < len; i++) {
-      const s = samples[i] / 32768;
+      const s = samples[i] / scale;
       sumSq += s * s;
     }
     const rms = Math.sqrt(sumSq / len);
@@ -104,8 +110,8 @@ export function createAudioController({
     setMicLevel(computeLevel(samples));
   }
 
-  function updateRemoteMeter(samples) {
-    setRemoteLevel(computeLevel(samples));
+  function updateRemoteMeter(samples, scale) {
+    setRemoteLevel(computeLevel(samples, scale));
   }
 
   function packAudioChunk({ startMs, sampleRate, channels, samples }) {
@@ -133,21 +139,29 @@ export function createAudioController({
 
   function schedulePlayback(chunk) {
     ensureAudioContext();
-    if (chunk.channels !== 1) return;
     const { samples, sampleRate } = chunk;
-    const floatBuf = new Float32Array(samples.length);
-    for (let i = 0; i < samples.length; i++) {
-      floatBuf[i] = samples[i] / 32768;
+    const channels = Math.max(1, chunk.channels);
+    const frameCount = Math.floor(samples.length / channels);
+    if (!frameCount) return;
+    const audioBuffer = audioCtx.createBuffer(channels, frameCount, sampleRate);
+    for (let ch = 0; ch < channels; ch++) {
+      const channelData = audioBuffer.getChannelData(ch);
+      for (let i = 0; i < frameCount; i++) {
+        channelData[i] = samples[i * channels + ch] / 32768;
+      }
     }
-    const audioBuffer = audioCtx.createBuffer(1, floatBuf.length, sampleRate);
-    audioBuffer.copyToChannel(floatBuf, 0);
+    playBuffer(audioBuffer);
+  }
 
+  function playBuffer(audioBuffer) {
+    const frameCount = audioBuffer.length;
+    const sampleRate = audioBuffer.sampleRate;
     const src = audioCtx.createBufferSource();
     src.buffer = audioBuffer;
     src.connect(audioCtx.destination);
 
     const now = audioCtx.currentTime;
-    const duration = floatBuf.length / sampleRate;
+    const duration = frameCount / sampleRate;
     if (nextPlaybackTime === null) {
       nextPlaybackTime = now + 0.1;
     }
@@ -156,15 +170,121 @@ export function createAudioController({
     nextPlaybackTime = startAt + duration;
   }
 
-  function isAudioBuffer(data) {
+  function hasMagic(data, magic) {
     if (!(data instanceof ArrayBuffer)) return false;
     const view = new Uint8Array(data);
-    if (view.length < AUDIO_MAGIC_BYTES.length) return false;
-    return AUDIO_MAGIC_BYTES.every((code, idx) => view[idx] === code);
+    if (view.length < magic.length) return false;
+    return magic.every((code, idx) => view[idx] === code);
+  }
+
+  function isAudioBuffer(data) {
+    return hasMagic(data, AUDIO_MAGIC_BYTES) || hasMagic(data, OPUS_MAGIC_BYTES);
+  }
+
+  // Format to ask for in the mode message; the server answers with what it
+  // will actually send in mode-ack's "audio" field.
+  function preferredFormat() {
+    return typeof AudioDecoder === "function" ? "opus" : "pcm";
+  }
+
+  function parseOpusPacket(buffer) {
+    const view = new DataView(buffer);
+    const timestampMs = view.getFloat64(4, true);
+    const sampleRate = view.getUint32(12, true);
+    const channels = view.getUint32(16, true);
+    const frameSamples = view.getUint32(20, true);
+    const length = view.getUint32(24, true);
+    const payload = new Uint8Array(buffer, 28, length);
+    return { timestampMs, sampleRate, channels, frameSamples, payload };
+  }
+
+  function playDecodedAudio(audioData) {
+    try {
+      ensureAudioContext();
+      const channels = audioData.numberOfChannels;
+      const audioBuffer = audioCtx.createBuffer(
+        channels,
+        audioData.numberOfFrames,
+        audioData.sampleRate,
+      );
+      for (let ch = 0; ch < channels; ch++) {
+        audioData.copyTo(audioBuffer.getChannelData(ch), {
+          planeIndex: ch,
+          format: "f32-planar",
+        });
+      }
+      updateRemoteMeter(audioBuffer.getChannelData(0), 1);
+      playBuffer(audioBuffer);
+    } finally {
+      audioData.close();
+    }
+  }
+
+  function ensureOpusDecoder(sampleRate, channels) {
+    const key = `${sampleRate}/${channels}`;
+    if (opusDecoder && opusDecoderKey === key) return opusDecoder;
+    closeOpusDecoder();
+    opusDecoder = new AudioDecoder({
+      output: playDecodedAudio,
+      error: (err) => log(`opus decode error: ${err?.message ?? err}`),
+    });
+    opusDecoder.configure({
+      codec: "opus",
+      sampleRate,
+      numberOfChannels: channels,
+    });
+    opusDecoderKey = key;
+    log(`remote audio: opus ${sampleRate}Hz ${channels}ch`);
+    return opusDecoder;
+  }
+
+  function closeOpusDecoder() {
+    if (opusDecoder && opusDecoder.state !== "closed") {
+      try {
+        opusDecoder.close();
+      } catch (_) {}
+    }
+    opusDecoder = null;
+    opusDecoderKey = null;
+  }
+
+  function handleOpusPacket(buffer) {
+    const packet = parseOpusPacket(buffer);
+    const decoder = ensureOpusDecoder(packet.sampleRate, packet.channels);
+    decoder.decode(
+      new EncodedAudioChunk({
+        type: "key",
+        timestamp: Math.round(packet.timestampMs * 1000),
+        duration: Math.round((packet.frameSamples * 1e6) / packet.sampleRate),
+        data: packet.payload,
+      }),
+    );
+  }
+
+  // Called with the server's audio-config message, which arrives before the
+  // first AUD0 chunk; sets up the audio graph so no early chunks are lost.
+  function configureRemoteAudio(config) {
+    remoteAudioConfig = config;
+    if (!config?.present) {
+      log("remote audio: none");
+      return;
+    }
+    log(
+      `remote audio: ${config.format} ${config.sample_rate}Hz ` +
+        `${config.channels}ch, chunk=${config.chunk_ms}ms`,
+    );
+    ensureAudioWorklet().catch((err) => {
+      log(`audio setup failed: ${err?.message ?? err}`);
+    });
   }
 
   function handleIncomingAudio(buffer) {
+    if (remoteAudioConfig && !remoteAudioConfig.present) return;
     try {
+      if (hasMagic(buffer, OPUS_MAGIC_BYTES)) {
+        handleOpusPacket(buffer);
+        return;
+      }
       const chunk = parseIncomingAudio(buffer);
       updateRemoteMeter(chunk.samples);
       schedulePlayback(chunk);
@@ -290,12 +410,16 @@ export function createAudioController({
   function onSocketClosed() {
     stopAudio("socket-closed");
     setRemoteLevel(0);
+    remoteAudioConfig = null;
+    closeOpusDecoder();
   }
 
   return {
     handleMicToggle,
     handleIncomingAudio,
+    configureRemoteAudio,
     isAudioBuffer,
+    preferredFormat,
     stop: stopAudio,
     onSocketOpen,
     onSocketClosed,
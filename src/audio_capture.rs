@@ -1,5 +1,9 @@
+use std::time::Instant;
+
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::audio_mixer::{MixerInput, CHUNK_MS};
 
 /// Raw audio chunk for direct streaming (bypasses mixer for low latency)
 #[derive(Debug, Clone)]
@@ -13,120 +17,186 @@ pub struct AudioChunk {
 #[derive(Clone)]
 pub struct AudioBroadcast {
     sender: broadcast::Sender<AudioChunk>,
+    sources: usize,
 }
 
 impl AudioBroadcast {
     pub fn subscribe(&self) -> broadcast::Receiver<AudioChunk> {
         self.sender.subscribe()
     }
+
+    /// More than one device is captured, so audio goes out through the mixer instead of `subscribe`
+    pub fn is_mixed(&self) -> bool {
+        self.sources > 1
+    }
+}
+
+/// Which devices to capture and how loud
+pub struct CaptureOptions {
+    /// Capture a microphone alongside system audio
+    pub mic: bool,
+    /// Pick the first input whose name contains this (case-insensitive) instead of the default input
+    pub mic_name: Option<String>,
+    pub mic_gain: f32,
+    pub system_gain: f32,
 }
 
 /// Audio capture (not Send/Sync - keep on main thread)
 pub struct AudioCapture {
-    _stream: cpal::Stream,
+    _streams: Vec<cpal::Stream>,
 }
 
 /// Start audio capture and return a broadcast handle that can be shared across threads.
 /// The AudioCapture must be kept alive (not dropped) for capture to continue.
-pub fn start_audio_capture() -> anyhow::Result<(AudioCapture, AudioBroadcast)> {
+///
+/// With a single device the chunks go straight to the broadcast (low latency). With system
+/// audio and a microphone both open, each device is resampled to the system device's rate,
+/// gained and fed into `mixer` as a separate source in `CHUNK_MS` slices.
+pub fn start_audio_capture(
+    options: &CaptureOptions,
+    mixer: mpsc::Sender<MixerInput>,
+) -> anyhow::Result<(AudioCapture, AudioBroadcast)> {
     let host = cpal::default_host();
     
     // Try to find BlackHole device first for system audio capture
-    let device = host
-        .input_devices()?
-        .find(|d| {
-            d.name()
-                .map(|n| n.to_lowercase().contains("blackhole"))
-                .unwrap_or(false)
-        })
-        .or_else(|| {
-            println!("[Audio] BlackHole not found, using default input device");
-            println!("[Audio] For system audio capture, install: brew install blackhole-2ch");
-            host.default_input_device()
-        })
-        .ok_or_else(|| anyhow::anyhow!("No audio input device found"))?;
+    let system = find_input(&host, "blackhole")?;
+    if system.is_none() {
+        println!("[Audio] BlackHole not found");
+        println!("[Audio] For system audio capture, install: brew install blackhole-2ch");
+    }
+
+    let mic = if options.mic {
+        let mic = match &options.mic_name {
+            Some(name) => Some(
+                find_input(&host, name)?
+                    .ok_or_else(|| anyhow::anyhow!("No audio input device matching {:?}", name))?,
+            ),
+            None => host.default_input_device(),
+        };
+        // With no loopback driver the default input may be the same device
+        let system_name = system.as_ref().and_then(|d| d.name().ok());
+        mic.filter(|d| system_name.is_none() || d.name().ok() != system_name)
+    } else {
+        None
+    };
+
+    // Broadcast channel for sending to all connected clients
+    let (sender, _) = broadcast::channel::<AudioChunk>(64);
+
+    let streams = match (system, mic) {
+        (Some(system), Some(mic)) => {
+            let config = device_config(&system, "system")?;
+            let rate = config.sample_rate().0;
+            let clock = Instant::now();
+            let mut system_feed = MixFeed::new(&config, rate, options.system_gain, clock, mixer.clone());
+            let mic_config = device_config(&mic, "mic")?;
+            if mic_config.sample_rate().0 != rate {
+                println!("[Audio] Resampling mic from {} Hz to {} Hz", mic_config.sample_rate().0, rate);
+            }
+            let mut mic_feed = MixFeed::new(&mic_config, rate, options.mic_gain, clock, mixer);
+            let streams = vec![
+                build_stream(&system, config, move |data| system_feed.push(data))?,
+                build_stream(&mic, mic_config, move |data| mic_feed.push(data))?,
+            ];
+            println!("[Audio] Capture started (system + mic, mixed)");
+            streams
+        }
+        (Some(device), None) | (None, Some(device)) => {
+            let config = device_config(&device, "input")?;
+            let gain = if options.mic && !is_named(&device, "blackhole") {
+                options.mic_gain
+            } else {
+                options.system_gain
+            };
+            let sample_rate = config.sample_rate().0;
+            let channels = config.channels() as u32;
+            let sender = sender.clone();
+            let stream = build_stream(&device, config, move |data| {
+                let samples: Vec<i16> = data.iter().map(|s| to_i16(s * gain)).collect();
+                if samples.is_empty() {
+                    return;
+                }
+                // Non-blocking send - if no receivers or buffer full, drop
+                let _ = sender.send(AudioChunk {
+                    sample_rate,
+                    channels,
+                    samples,
+                });
+            })?;
+            println!("[Audio] Capture started (low-latency direct mode)");
+            vec![stream]
+        }
+        (None, None) => return Err(anyhow::anyhow!("No audio input device found")),
+    };
 
+    let broadcast = AudioBroadcast {
+        sender,
+        sources: streams.len(),
+    };
+    let capture = AudioCapture { _streams: streams };
+
+    Ok((capture, broadcast))
+}
+
+fn is_named(device: &cpal::Device, needle: &str) -> bool {
+    device
+        .name()
+        .map(|n| n.to_lowercase().contains(&needle.to_lowercase()))
+        .unwrap_or(false)
+}
+
+fn find_input(host: &cpal::Host, needle: &str) -> anyhow::Result<Option<cpal::Device>> {
+    Ok(host.input_devices()?.find(|d| is_named(d, needle)))
+}
+
+fn device_config(device: &cpal::Device, role: &str) -> anyhow::Result<cpal::SupportedStreamConfig> {
     let device_name = device.name().unwrap_or_else(|_| "Unknown".to_string());
-    println!("[Audio] Using input device: {}", device_name);
+    println!("[Audio] Using {} device: {}", role, device_name);
 
     let config = device.default_input_config()?;
     println!("[Audio] Sample rate: {}, Channels: {}", 
         config.sample_rate().0, config.channels());
+    Ok(config)
+}
 
-    let sample_rate = config.sample_rate().0;
-    let channels = config.channels() as u32;
-    
-    // Broadcast channel for sending to all connected clients
-    let (sender, _) = broadcast::channel::<AudioChunk>(64);
-    let sender_clone = sender.clone();
-
+/// Open an input stream that hands each callback's samples to `sink` as f32
+fn build_stream<F>(
+    device: &cpal::Device,
+    config: cpal::SupportedStreamConfig,
+    sink: F,
+) -> anyhow::Result<cpal::Stream>
+where
+    F: FnMut(&[f32]) + Send + 'static,
+{
     // Build the appropriate stream based on sample format
     let stream = match config.sample_format() {
-        cpal::SampleFormat::F32 => build_stream::<f32>(
-            &device, 
-            &config.into(), 
-            sender_clone, 
-            sample_rate, 
-            channels,
-        )?,
-        cpal::SampleFormat::I16 => build_stream::<i16>(
-            &device, 
-            &config.into(), 
-            sender_clone, 
-            sample_rate, 
-            channels,
-        )?,
-        cpal::SampleFormat::U16 => build_stream::<u16>(
-            &device, 
-            &config.into(), 
-            sender_clone, 
-            sample_rate, 
-            channels,
-        )?,
+        cpal::SampleFormat::F32 => build_typed_stream::<f32, F>(device, &config.into(), sink)?,
+        cpal::SampleFormat::I16 => build_typed_stream::<i16, F>(device, &config.into(), sink)?,
+        cpal::SampleFormat::U16 => build_typed_stream::<u16, F>(device, &config.into(), sink)?,
         _ => return Err(anyhow::anyhow!("Unsupported sample format")),
     };
-
     stream.play()?;
-    println!("[Audio] Capture started (low-latency direct mode)");
-
-    let capture = AudioCapture { _stream: stream };
-    let broadcast = AudioBroadcast { sender };
-    
-    Ok((capture, broadcast))
+    Ok(stream)
 }
 
-fn build_stream<T>(
+fn build_typed_stream<T, F>(
     device: &cpal::Device,
     config: &cpal::StreamConfig,
-    sender: broadcast::Sender<AudioChunk>,
-    sample_rate: u32,
-    channels: u32,
+    mut sink: F,
 ) -> anyhow::Result<cpal::Stream>
 where
     T: cpal::Sample<Float = f32> + cpal::SizedSample + Send + 'static,
+    F: FnMut(&[f32]) + Send + 'static,
 {
     let err_fn = |err| eprintln!("[Audio] Stream error: {}", err);
 
+    let mut buf: Vec<f32> = Vec::new();
     let stream = device.build_input_stream(
         config,
         move |data: &[T], _: &cpal::InputCallbackInfo| {
-            // Keep stereo, convert to i16
-            let samples: Vec<i16> = data.iter()
-                .map(|s| sample_to_i16(*s))
-                .collect();
-
-            if samples.is_empty() {
-                return;
-            }
-
-            let chunk = AudioChunk {
-                sample_rate,
-                channels,
-                samples,
-            };
-
-            // Non-blocking send - if no receivers or buffer full, drop
-            let _ = sender.send(chunk);
+            buf.clear();
+            buf.extend(data.iter().map(|s| s.to_float_sample()));
+            sink(&buf);
         },
         err_fn,
         None,
@@ -135,9 +205,125 @@ where
     Ok(stream)
 }
 
-fn sample_to_i16<T: cpal::Sample<Float = f32>>(sample: T) -> i16 {
-    let float_sample: f32 = sample.to_float_sample();
+fn to_i16(sample: f32) -> i16 {
     // Convert f32 [-1.0, 1.0] to i16 [-32768, 32767]
-    (float_sample * 32767.0).clamp(-32768.0, 32767.0) as i16
+    (sample * 32767.0).clamp(-32768.0, 32767.0) as i16
+}
+
+/// One capture device feeding the mixer: stereo at the common rate, in bucket-aligned slices
+struct MixFeed {
+    device_channels: usize,
+    gain: f32,
+    rate: u32,
+    resampler: Resampler,
+    /// Shared by all feeds so their slices land in the same mixer buckets
+    clock: Instant,
+    next_start_ms: Option<f64>,
+    pending: Vec<i16>,
+    frames: Vec<[f32; 2]>,
+    resampled: Vec<[f32; 2]>,
+    mixer: mpsc::Sender<MixerInput>,
+}
+
+impl MixFeed {
+    fn new(
+        config: &cpal::SupportedStreamConfig,
+        rate: u32,
+        gain: f32,
+        clock: Instant,
+        mixer: mpsc::Sender<MixerInput>,
+    ) -> Self {
+        Self {
+            device_channels: (config.channels() as usize).max(1),
+            gain,
+            rate,
+            resampler: Resampler::new(config.sample_rate().0, rate),
+            clock,
+            next_start_ms: None,
+            pending: Vec::new(),
+            frames: Vec::new(),
+            resampled: Vec::new(),
+            mixer,
+        }
+    }
+
+    fn push(&mut self, data: &[f32]) {
+        let gain = self.gain;
+        self.frames.clear();
+        self.frames.extend(data.chunks_exact(self.device_channels).map(|f| {
+            let left = f[0];
+            let right = f.get(1).copied().unwrap_or(left);
+            [left * gain, right * gain]
+        }));
+        self.resampled.clear();
+        self.resampler.process(&self.frames, &mut self.resampled);
+
+        if self.next_start_ms.is_none() {
+            // Pad the first slice with silence so it starts on a bucket boundary
+            let elapsed_ms = self.clock.elapsed().as_secs_f64() * 1000.0;
+            let start_ms = (elapsed_ms / CHUNK_MS as f64).floor() * CHUNK_MS as f64;
+            let pad = ((elapsed_ms - start_ms) * self.rate as f64 / 1000.0) as usize;
+            self.pending.resize(pad * 2, 0);
+            self.next_start_ms = Some(start_ms);
+        }
+        for frame in &self.resampled {
+            self.pending.push(to_i16(frame[0]));
+            self.pending.push(to_i16(frame[1]));
+        }
+
+        let slice_len = (self.rate as u64 * CHUNK_MS / 1000) as usize * 2;
+        while self.pending.len() >= slice_len {
+            let samples: Vec<i16> = self.pending.drain(..slice_len).collect();
+            let start_ms = self.next_start_ms.unwrap_or_default();
+            self.next_start_ms = Some(start_ms + CHUNK_MS as f64);
+            // Audio callbacks can't wait; a full mixer queue drops the slice
+            let _ = self.mixer.try_send(MixerInput {
+                start_ms,
+                sample_rate: self.rate,
+                channels: 2,
+                samples,
+            });
+        }
+    }
+}
+
+/// Linear-interpolating stereo resampler that carries its position across callbacks
+pub(crate) struct Resampler {
+    /// Input frames advanced per output frame
+    step: f64,
+    /// Read position in the current input, in frames; -1.0 is the previous call's last frame
+    pos: f64,
+    last: [f32; 2],
+}
+
+impl Resampler {
+    pub(crate) fn new(from: u32, to: u32) -> Self {
+        Self {
+            step: from as f64 / to.max(1) as f64,
+            pos: 0.0,
+            last: [0.0; 2],
+        }
+    }
+
+    pub(crate) fn process(&mut self, input: &[[f32; 2]], out: &mut Vec<[f32; 2]>) {
+        if self.step == 1.0 {
+            out.extend_from_slice(input);
+            return;
+        }
+        let Some(&tail) = input.last() else {
+            return;
+        };
+        let len = input.len() as f64;
+        while self.pos < len - 1.0 {
+            let index = self.pos.floor();
+            let t = (self.pos - index) as f32;
+            let a = if index < 0.0 { self.last } else { input[index as usize] };
+            let b = input[(index + 1.0) as usize];
+            out.push([a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t]);
+            self.pos += self.step;
+        }
+        self.pos -= len;
+        self.last = tail;
+    }
 }
 
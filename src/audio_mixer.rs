@@ -1,10 +1,16 @@
 use std::collections::HashMap;
-use std::time::Instant;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use tokio::sync::{broadcast, mpsc};
+use tokio::time::{interval, MissedTickBehavior};
 
-const CHUNK_MS: u64 = 100;
+/// Length of one mix bucket; live capture sources emit chunks of exactly this length
+pub const CHUNK_MS: u64 = 100;
 const MAX_BUCKET_AGE_MS: u64 = 2_000;
+/// A bucket still missing sources is sent with what it has after this long
+const FLUSH_AFTER: Duration = Duration::from_millis(CHUNK_MS * 3);
 
 #[derive(Debug)]
 pub struct MixerInput {
@@ -28,26 +34,76 @@ struct MixBucket {
     channels: u32,
     sum: Vec<i32>,
     max_len: usize,
+    contributions: usize,
+    /// Sent short of sources by the flush timer; later contributions are too late to play
+    flushed: bool,
+    created: Instant,
     last_update: Instant,
 }
 
+impl MixBucket {
+    fn to_chunk(&self) -> MixedChunk {
+        let mut mixed = Vec::with_capacity(self.max_len);
+        for v in self.sum.iter().take(self.max_len) {
+            let val = *v;
+            let clamped = val
+                .max(i16::MIN as i32)
+                .min(i16::MAX as i32) as i16;
+            mixed.push(clamped);
+        }
+        MixedChunk {
+            start_ms: self.start_ms,
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            samples: mixed,
+        }
+    }
+}
+
 pub struct AudioMixer {
     tx: mpsc::Sender<MixerInput>,
     bcast: broadcast::Sender<MixedChunk>,
+    expected_sources: Arc<AtomicUsize>,
 }
 
 impl AudioMixer {
     pub fn new() -> Self {
         let (tx, mut rx) = mpsc::channel::<MixerInput>(256);
         let (bcast, _rx) = broadcast::channel::<MixedChunk>(128);
+        let expected_sources = Arc::new(AtomicUsize::new(1));
 
         let bcast_tx = bcast.clone();
+        let expected = expected_sources.clone();
         tokio::spawn(async move {
             let mut buckets: HashMap<u64, MixBucket> = HashMap::new();
             let mut last_prune = Instant::now();
-            while let Some(input) = rx.recv().await {
-                if input.channels != 1 {
-                    // Only mix mono inputs.
+            let mut flush_ticker = interval(Duration::from_millis(CHUNK_MS));
+            flush_ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                let input = tokio::select! {
+                    input = rx.recv() => match input {
+                        Some(input) => input,
+                        None => break,
+                    },
+                    _ = flush_ticker.tick() => {
+                        // Send buckets a source never showed up for, oldest first
+                        let mut late: Vec<_> = buckets
+                            .iter_mut()
+                            .filter(|(_, b)| {
+                                !b.flushed
+                                    && b.contributions < expected.load(Ordering::Relaxed)
+                                    && b.created.elapsed() >= FLUSH_AFTER
+                            })
+                            .collect();
+                        late.sort_by_key(|(key, _)| **key);
+                        for (_, bucket) in late {
+                            bucket.flushed = true;
+                            let _ = bcast_tx.send(bucket.to_chunk());
+                        }
+                        continue;
+                    }
+                };
+                if input.channels == 0 {
                     continue;
                 }
                 let key = (input.start_ms / CHUNK_MS as f64).floor() as u64;
@@ -58,6 +114,9 @@ impl AudioMixer {
                     channels: input.channels,
                     sum: Vec::new(),
                     max_len: 0,
+                    contributions: 0,
+                    flushed: false,
+                    created: Instant::now(),
                     last_update: Instant::now(),
                 });
 
@@ -65,6 +124,9 @@ impl AudioMixer {
                     // Skip mismatched sample rate/channel contributions.
                     continue;
                 }
+                if bucket.flushed {
+                    continue;
+                }
 
                 if bucket.sum.len() < input.samples.len() {
                     bucket.sum.resize(input.samples.len(), 0);
@@ -76,23 +138,13 @@ impl AudioMixer {
                 for (idx, sample) in input.samples.iter().enumerate() {
                     bucket.sum[idx] = bucket.sum[idx].saturating_add(*sample as i32);
                 }
+                bucket.contributions += 1;
                 bucket.last_update = Instant::now();
 
-                // Emit the current mixed chunk.
-                let mut mixed = Vec::with_capacity(bucket.max_len);
-                for v in bucket.sum.iter().take(bucket.max_len) {
-                    let val = *v;
-                    let clamped = val
-                        .max(i16::MIN as i32)
-                        .min(i16::MAX as i32) as i16;
-                    mixed.push(clamped);
+                // Emit the current mixed chunk once every expected source is in.
+                if bucket.contributions >= expected.load(Ordering::Relaxed) {
+                    let _ = bcast_tx.send(bucket.to_chunk());
                 }
-                let _ = bcast_tx.send(MixedChunk {
-                    start_ms: bucket.start_ms,
-                    sample_rate: bucket.sample_rate,
-                    channels: bucket.channels,
-                    samples: mixed,
-                });
 
                 // Prune old buckets occasionally.
                 if last_prune.elapsed().as_millis() as u64 > CHUNK_MS {
@@ -103,7 +155,12 @@ impl AudioMixer {
             }
         });
 
-        Self { tx, bcast }
+        Self { tx, bcast, expected_sources }
+    }
+
+    /// How many live sources contribute to each bucket; a bucket is held back until they all have
+    pub fn set_expected_sources(&self, sources: usize) {
+        self.expected_sources.store(sources.max(1), Ordering::Relaxed);
     }
 
     pub fn input_sender(&self) -> mpsc::Sender<MixerInput> {
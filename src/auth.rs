@@ -0,0 +1,98 @@
+/// Random token for `--require-auth` without `--token`: 16 bytes, hex encoded
+pub fn generate_token() -> anyhow::Result<String> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Compare a client-supplied token against the secret without an early exit,
+/// so response timing doesn't reveal how much of the prefix matched
+pub fn token_matches(expected: &str, supplied: &str) -> bool {
+    let (expected, supplied) = (expected.as_bytes(), supplied.as_bytes());
+    if expected.len() != supplied.len() {
+        return false;
+    }
+    expected
+        .iter()
+        .zip(supplied)
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// What a session may do, decided by the token it presented
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Receives media and may change its own stream (resolution, fps, pause)
+    View,
+    /// May also change what everyone sees: source, crop, quality, overlays, clipboard
+    Control,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::View => "view",
+            Role::Control => "control",
+        }
+    }
+}
+
+/// The secrets a server accepts (--token, --token-control, --token-view)
+pub struct Tokens {
+    tokens: Vec<(String, Role)>,
+}
+
+impl Tokens {
+    /// None when no token was configured, i.e. the server is open
+    pub fn new(control: Vec<String>, view: Vec<String>) -> Option<Self> {
+        let tokens: Vec<_> = control
+            .into_iter()
+            .map(|token| (token, Role::Control))
+            .chain(view.into_iter().map(|token| (token, Role::View)))
+            .collect();
+        (!tokens.is_empty()).then_some(Self { tokens })
+    }
+
+    /// The role `supplied` grants, if any. Every token is compared, so timing
+    /// doesn't reveal which one matched.
+    pub fn role_for(&self, supplied: &str) -> Option<Role> {
+        self.tokens.iter().fold(None, |granted, (token, role)| {
+            let matched = token_matches(token, supplied).then_some(*role);
+            granted.max(matched)
+        })
+    }
+
+    /// Token for the link printed at startup: a control one when there is one
+    pub fn link_token(&self) -> &str {
+        self.tokens
+            .iter()
+            .max_by_key(|(_, role)| *role)
+            .map(|(token, _)| token.as_str())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_matches_only_the_exact_token() {
+        assert!(token_matches("s3cret", "s3cret"));
+        assert!(!token_matches("s3cret", "s3creT"));
+        assert!(!token_matches("s3cret", "x3cret"));
+        // Wrong length, including a prefix and an extension of the secret
+        assert!(!token_matches("s3cret", "s3cre"));
+        assert!(!token_matches("s3cret", "s3crets"));
+        assert!(!token_matches("s3cret", ""));
+        assert!(!token_matches("", "s3cret"));
+    }
+
+    #[test]
+    fn generated_tokens_are_32_hex_digits_and_differ() {
+        let (a, b) = (generate_token().unwrap(), generate_token().unwrap());
+        assert_eq!(a.len(), 32);
+        assert!(a.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase()));
+        assert_ne!(a, b);
+    }
+}
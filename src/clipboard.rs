@@ -0,0 +1,79 @@
+use std::{
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+use tokio::sync::broadcast;
+
+/// Largest clipboard text accepted or sent, in bytes
+pub const MAX_CLIPBOARD_BYTES: usize = 1024 * 1024;
+
+/// How often the host clipboard is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Text clipboard shared between the host and connected clients (`--allow-clipboard`)
+pub struct ClipboardSync {
+    /// Text for the watcher thread to put on the host clipboard
+    set_text: mpsc::Sender<String>,
+    /// Host clipboard changes, pushed to every session
+    changes: broadcast::Sender<String>,
+}
+
+impl ClipboardSync {
+    /// Start the watcher thread that owns the host clipboard
+    pub fn start() -> anyhow::Result<Self> {
+        let mut clipboard = arboard::Clipboard::new()?;
+        let (set_text, set_rx) = mpsc::channel::<String>();
+        let (changes, _) = broadcast::channel(8);
+
+        let changes_tx = changes.clone();
+        thread::spawn(move || {
+            // Don't push whatever was on the clipboard before we started
+            let mut last = clipboard.get_text().ok();
+            loop {
+                match set_rx.recv_timeout(POLL_INTERVAL) {
+                    Ok(text) => {
+                        if let Err(err) = clipboard.set_text(text.clone()) {
+                            eprintln!("failed to set clipboard: {}", err);
+                        }
+                        // Remember it so it isn't echoed back to clients
+                        last = Some(text);
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        let Ok(text) = clipboard.get_text() else {
+                            continue; // empty or non-text content
+                        };
+                        if last.as_deref() == Some(text.as_str()) {
+                            continue;
+                        }
+                        last = Some(text.clone());
+                        if text.len() > MAX_CLIPBOARD_BYTES {
+                            eprintln!("host clipboard is {} bytes, not syncing", text.len());
+                            continue;
+                        }
+                        _ = changes_tx.send(text);
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            println!("clipboard watcher stopped");
+        });
+
+        Ok(Self { set_text, changes })
+    }
+
+    /// Put client text on the host clipboard
+    pub fn set(&self, text: String) -> anyhow::Result<()> {
+        if text.len() > MAX_CLIPBOARD_BYTES {
+            anyhow::bail!("clipboard text exceeds {} bytes", MAX_CLIPBOARD_BYTES);
+        }
+        self.set_text
+            .send(text)
+            .map_err(|_| anyhow::anyhow!("clipboard watcher is not running"))
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.changes.subscribe()
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use clap::{parser::ValueSource, ArgMatches};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    pip::Corner,
+    recording::{CaptureBackend, Region},
+    shared_encoder::{Resolution, SimulcastRung},
+    video_pipeline::{ColorMatrix, PixelFormat, RateControl, UsageType},
+    Cli,
+};
+
+/// Settings from a foundry.toml. Keys are the long CLI flag names; a flag
+/// given on the command line wins over the file, which wins over the built-in default.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    pub port: Option<u16>,
+    pub bind: Option<IpAddr>,
+    pub window: Option<u32>,
+    pub follow_focus: Option<bool>,
+    pub app: Option<String>,
+    pub region: Option<Region>,
+    pub reattach_by_title: Option<bool>,
+    pub capture_fps: Option<u32>,
+    pub capture_backend: Option<CaptureBackend>,
+    pub skip_unchanged: Option<bool>,
+    pub monitor_id: Option<u32>,
+    pub pip_window: Option<u32>,
+    pub pip_monitor: Option<u32>,
+    pub pip_corner: Option<Corner>,
+    pub pip_size: Option<f32>,
+    pub mdns: Option<bool>,
+    /// `cursor = false` is the file's spelling of --no-cursor
+    pub cursor: Option<bool>,
+    pub allow_clipboard: Option<bool>,
+    pub allow_annotations: Option<bool>,
+    pub token: Option<String>,
+    pub token_control: Option<Vec<String>>,
+    pub token_view: Option<Vec<String>>,
+    pub require_auth: Option<bool>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_self_signed: Option<bool>,
+    pub min_bitrate_kbps: Option<u32>,
+    pub max_bitrate_kbps: Option<u32>,
+    pub keyframe_interval: Option<u64>,
+    pub bitrate_kbps: Option<u32>,
+    pub encoder_fps: Option<f32>,
+    pub pixel_format: Option<PixelFormat>,
+    pub rate_control: Option<RateControl>,
+    pub encoder_usage: Option<UsageType>,
+    pub encoder_threads: Option<u16>,
+    pub color_matrix: Option<ColorMatrix>,
+    pub drop_streak: Option<u32>,
+    pub max_pixels: Option<u64>,
+    pub keyframe_request_interval: Option<f64>,
+    pub idle_timeout: Option<u64>,
+    pub max_sessions: Option<u64>,
+    pub max_resolution: Option<Resolution>,
+    pub simulcast: Option<Vec<SimulcastRung>>,
+    pub mic: Option<String>,
+    pub no_mic: Option<bool>,
+    pub mic_gain: Option<f32>,
+    pub system_gain: Option<f32>,
+    pub start_muted: Option<bool>,
+    pub audio_offset_ms: Option<i64>,
+    pub hls: Option<bool>,
+    pub control_socket: Option<PathBuf>,
+    pub no_control_socket: Option<bool>,
+    /// Keys this version doesn't know; warned about rather than rejected so
+    /// a config survives version skew
+    #[serde(flatten, skip_serializing)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+impl Config {
+    /// Read `explicit`, or the first of ./foundry.toml and ~/.config/foundry/config.toml
+    /// that exists. None when there's no config to apply.
+    pub fn load(explicit: Option<&Path>) -> Result<Option<(PathBuf, Config)>> {
+        let path = match explicit {
+            Some(path) => path.to_path_buf(),
+            None => {
+                let home = std::env::var_os("HOME").map(PathBuf::from);
+                let candidates = [
+                    Some(PathBuf::from("foundry.toml")),
+                    home.map(|home| home.join(".config/foundry/config.toml")),
+                ];
+                match candidates.into_iter().flatten().find(|path| path.is_file()) {
+                    Some(path) => path,
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        let config: Config =
+            toml::from_str(&text).with_context(|| format!("parsing {}", path.display()))?;
+        for key in config.unknown.keys() {
+            eprintln!("Warning: unknown key `{}` in {}", key, path.display());
+        }
+        Ok(Some((path, config)))
+    }
+
+    /// Fill in every setting that wasn't given on the command line
+    pub fn apply(self, cli: &mut Cli, matches: &ArgMatches) {
+        merge(matches, "port", &mut cli.port, self.port);
+        merge(matches, "bind", &mut cli.bind, self.bind);
+        merge(matches, "window", &mut cli.window, self.window.map(Some));
+        merge(matches, "follow_focus", &mut cli.follow_focus, self.follow_focus);
+        merge(matches, "app", &mut cli.app, self.app.map(Some));
+        merge(matches, "region", &mut cli.region, self.region.map(Some));
+        merge(matches, "reattach_by_title", &mut cli.reattach_by_title, self.reattach_by_title);
+        merge(matches, "capture_fps", &mut cli.capture_fps, self.capture_fps);
+        merge(matches, "capture_backend", &mut cli.capture_backend, self.capture_backend);
+        merge(matches, "skip_unchanged", &mut cli.skip_unchanged, self.skip_unchanged);
+        merge(matches, "monitor_id", &mut cli.monitor_id, self.monitor_id.map(Some));
+        merge(matches, "pip_window", &mut cli.pip_window, self.pip_window.map(Some));
+        merge(matches, "pip_monitor", &mut cli.pip_monitor, self.pip_monitor.map(Some));
+        merge(matches, "pip_corner", &mut cli.pip_corner, self.pip_corner);
+        merge(matches, "pip_size", &mut cli.pip_size, self.pip_size);
+        merge(matches, "mdns", &mut cli.mdns, self.mdns);
+        if !on_command_line(matches, "cursor") && !on_command_line(matches, "no_cursor") {
+            if let Some(cursor) = self.cursor {
+                cli.cursor = cursor;
+                cli.no_cursor = !cursor;
+            }
+        }
+        merge(matches, "allow_clipboard", &mut cli.allow_clipboard, self.allow_clipboard);
+        merge(matches, "allow_annotations", &mut cli.allow_annotations, self.allow_annotations);
+        merge(matches, "token", &mut cli.token, self.token.map(Some));
+        merge(matches, "token_control", &mut cli.token_control, self.token_control);
+        merge(matches, "token_view", &mut cli.token_view, self.token_view);
+        merge(matches, "require_auth", &mut cli.require_auth, self.require_auth);
+        merge(matches, "tls_cert", &mut cli.tls_cert, self.tls_cert.map(Some));
+        merge(matches, "tls_key", &mut cli.tls_key, self.tls_key.map(Some));
+        merge(matches, "tls_self_signed", &mut cli.tls_self_signed, self.tls_self_signed);
+        merge(matches, "min_bitrate_kbps", &mut cli.min_bitrate_kbps, self.min_bitrate_kbps);
+        merge(matches, "max_bitrate_kbps", &mut cli.max_bitrate_kbps, self.max_bitrate_kbps);
+        merge(matches, "keyframe_interval", &mut cli.keyframe_interval, self.keyframe_interval);
+        merge(matches, "bitrate_kbps", &mut cli.bitrate_kbps, self.bitrate_kbps.map(Some));
+        merge(matches, "encoder_fps", &mut cli.encoder_fps, self.encoder_fps);
+        merge(matches, "pixel_format", &mut cli.pixel_format, self.pixel_format.map(Some));
+        merge(matches, "rate_control", &mut cli.rate_control, self.rate_control);
+        merge(matches, "encoder_usage", &mut cli.encoder_usage, self.encoder_usage);
+        merge(matches, "encoder_threads", &mut cli.encoder_threads, self.encoder_threads.map(Some));
+        merge(matches, "color_matrix", &mut cli.color_matrix, self.color_matrix);
+        merge(matches, "drop_streak", &mut cli.drop_streak, self.drop_streak);
+        merge(matches, "keyframe_request_interval", &mut cli.keyframe_request_interval, self.keyframe_request_interval);
+        merge(matches, "idle_timeout", &mut cli.idle_timeout, self.idle_timeout);
+        merge(matches, "max_sessions", &mut cli.max_sessions, self.max_sessions.map(Some));
+        // The two spellings conflict on the command line; either one there wins over both here
+        if !on_command_line(matches, "max_pixels") && !on_command_line(matches, "max_resolution") {
+            merge(matches, "max_pixels", &mut cli.max_pixels, self.max_pixels.map(Some));
+            merge(matches, "max_resolution", &mut cli.max_resolution, self.max_resolution.map(Some));
+        }
+        merge(matches, "simulcast", &mut cli.simulcast, self.simulcast);
+        merge(matches, "mic", &mut cli.mic, self.mic.map(Some));
+        merge(matches, "no_mic", &mut cli.no_mic, self.no_mic);
+        merge(matches, "mic_gain", &mut cli.mic_gain, self.mic_gain);
+        merge(matches, "system_gain", &mut cli.system_gain, self.system_gain);
+        merge(matches, "start_muted", &mut cli.start_muted, self.start_muted);
+        merge(matches, "audio_offset_ms", &mut cli.audio_offset_ms, self.audio_offset_ms);
+        merge(matches, "hls", &mut cli.hls, self.hls);
+        merge(matches, "control_socket", &mut cli.control_socket, self.control_socket.map(Some));
+        merge(matches, "no_control_socket", &mut cli.no_control_socket, self.no_control_socket);
+    }
+
+    /// The effective settings after merging, for --print-config
+    pub fn effective(cli: &Cli) -> Config {
+        Config {
+            port: Some(cli.port),
+            bind: Some(cli.bind),
+            window: cli.window,
+            follow_focus: Some(cli.follow_focus),
+            app: cli.app.clone(),
+            region: cli.region,
+            reattach_by_title: Some(cli.reattach_by_title),
+            capture_fps: Some(cli.capture_fps),
+            capture_backend: Some(cli.capture_backend),
+            skip_unchanged: Some(cli.skip_unchanged),
+            monitor_id: cli.monitor_id,
+            pip_window: cli.pip_window,
+            pip_monitor: cli.pip_monitor,
+            pip_corner: Some(cli.pip_corner),
+            pip_size: Some(cli.pip_size),
+            mdns: Some(cli.mdns),
+            cursor: Some(cli.show_cursor()),
+            allow_clipboard: Some(cli.allow_clipboard),
+            allow_annotations: Some(cli.allow_annotations),
+            token: cli.token.clone(),
+            token_control: Some(cli.token_control.clone()),
+            token_view: Some(cli.token_view.clone()),
+            require_auth: Some(cli.require_auth),
+            tls_cert: cli.tls_cert.clone(),
+            tls_key: cli.tls_key.clone(),
+            tls_self_signed: Some(cli.tls_self_signed),
+            min_bitrate_kbps: Some(cli.min_bitrate_kbps),
+            max_bitrate_kbps: Some(cli.max_bitrate_kbps),
+            keyframe_interval: Some(cli.keyframe_interval),
+            bitrate_kbps: cli.bitrate_kbps,
+            encoder_fps: Some(cli.encoder_fps),
+            pixel_format: cli.pixel_format,
+            rate_control: Some(cli.rate_control),
+            encoder_usage: Some(cli.encoder_usage),
+            encoder_threads: cli.encoder_threads,
+            color_matrix: Some(cli.color_matrix),
+            drop_streak: Some(cli.drop_streak),
+            max_pixels: cli.max_pixels,
+            keyframe_request_interval: Some(cli.keyframe_request_interval),
+            idle_timeout: Some(cli.idle_timeout),
+            max_sessions: cli.max_sessions,
+            max_resolution: cli.max_resolution,
+            simulcast: Some(cli.simulcast.clone()),
+            mic: cli.mic.clone(),
+            no_mic: Some(cli.no_mic),
+            mic_gain: Some(cli.mic_gain),
+            system_gain: Some(cli.system_gain),
+            start_muted: Some(cli.start_muted),
+            audio_offset_ms: Some(cli.audio_offset_ms),
+            hls: Some(cli.hls),
+            control_socket: cli.control_socket.clone(),
+            no_control_socket: Some(cli.no_control_socket),
+            unknown: BTreeMap::new(),
+        }
+    }
+}
+
+fn on_command_line(matches: &ArgMatches, id: &str) -> bool {
+    matches.value_source(id) == Some(ValueSource::CommandLine)
+}
+
+fn merge<T>(matches: &ArgMatches, id: &str, target: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        if !on_command_line(matches, id) {
+            *target = value;
+        }
+    }
+}
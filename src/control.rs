@@ -0,0 +1,188 @@
+use std::{
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::watch,
+};
+
+use crate::{session, shutdown_requested, AppState};
+
+/// Default --control-socket: one per port, so several servers can run side by side
+pub fn default_path(port: u16) -> PathBuf {
+    std::env::temp_dir().join(format!("foundry-{}.sock", port))
+}
+
+/// Listen on `path` for local scripting until shutdown, then remove it.
+/// Commands are JSON objects, one per line, answered with one line each.
+/// Whoever can open the socket (0600: the user running the server) needs no token.
+pub fn start(path: PathBuf, state: AppState, shutdown: watch::Receiver<bool>) -> anyhow::Result<()> {
+    // A socket left behind by a crashed server is replaced; a live one is not
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+            anyhow::bail!("{} is in use by another server", path.display());
+        }
+        std::fs::remove_file(&path).with_context(|| format!("removing stale {}", path.display()))?;
+    }
+
+    // Bind inside a private directory and move the socket into place once it
+    // is 0600, so nobody can connect in between
+    let staging = path.with_extension("sock.tmp");
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .with_context(|| format!("creating {}", staging.display()))?;
+    let staged = staging.join("control.sock");
+    let bound = UnixListener::bind(&staged)
+        .and_then(|listener| {
+            std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+            std::fs::rename(&staged, &path)?;
+            Ok(listener)
+        })
+        .with_context(|| format!("binding {}", path.display()));
+    _ = std::fs::remove_dir_all(&staging);
+    let listener = bound?;
+
+    println!("Control socket at {}", path.display());
+    tokio::spawn(serve(listener, path, state, shutdown));
+    Ok(())
+}
+
+async fn serve(listener: UnixListener, path: PathBuf, state: AppState, shutdown: watch::Receiver<bool>) {
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_connection(stream, state.clone()));
+                }
+                Err(err) => {
+                    eprintln!("control socket accept failed: {err}");
+                    break;
+                }
+            },
+            _ = shutdown_requested(shutdown.clone()) => break,
+        }
+    }
+    _ = std::fs::remove_file(&path);
+}
+
+async fn handle_connection(stream: UnixStream, state: AppState) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut reply = handle_command(&state, &line).await.to_string();
+        reply.push('\n');
+        if writer.write_all(reply.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Run one command; the replies match the WebSocket's for the same message
+async fn handle_command(state: &AppState, line: &str) -> Value {
+    let msg: Value = match serde_json::from_str(line) {
+        Ok(msg) => msg,
+        Err(err) => return error("", format!("invalid JSON: {err}")),
+    };
+    let command = msg.get("type").and_then(|t| t.as_str()).unwrap_or_default();
+    match command {
+        "status" => {
+            let mut status = crate::status_json(state);
+            status["type"] = serde_json::json!("status");
+            status
+        }
+        "set-source" => match session::set_source(state, &msg).await {
+            Ok(_) => {
+                // Shapes were placed on the old source
+                if let Some(annotations) = &state.annotations {
+                    annotations.clear();
+                }
+                println!("control socket switched source to {}", state.recorder.source());
+                serde_json::json!({
+                    "type": "source-changed",
+                    "source": state.recorder.source_json(),
+                })
+            }
+            Err(err) => error(command, err.to_string()),
+        },
+        "set-crop" => {
+            let Some(encoders) = &state.encoders else {
+                return error(command, "no video encoder".to_string());
+            };
+            match session::parse_crop(&msg).and_then(|rect| encoders.set_crop(rect).map(|()| rect)) {
+                Ok(rect) => serde_json::json!({
+                    "type": "crop-ack",
+                    "crop": rect.map(|rect| rect.to_json()),
+                }),
+                Err(message) => error(command, message),
+            }
+        }
+        // Viewers stay on their rungs; the preset's rung gets its bitrate and frame rate
+        "quality" => {
+            let Some(encoders) = &state.encoders else {
+                return error(command, "no video encoder".to_string());
+            };
+            let requested = msg.get("preset").and_then(|p| p.as_str()).unwrap_or_default();
+            match session::apply_quality(encoders, requested) {
+                Ok(rung) => serde_json::json!({
+                    "type": "quality-ack",
+                    "preset": requested,
+                    "resolution": rung,
+                }),
+                Err(message) => error(command, message),
+            }
+        }
+        // e.g. a presentation mode dropping a mostly static window to 15 fps
+        "capture-fps" => match msg.get("fps").and_then(|fps| fps.as_u64()) {
+            Some(fps) => {
+                let fps = state.recorder.set_capture_fps(fps.min(u32::MAX as u64) as u32);
+                serde_json::json!({ "type": "capture-fps-ack", "fps": fps })
+            }
+            None => error(command, "capture-fps needs a numeric fps".to_string()),
+        },
+        "record" => error(command, "this server can't record to a file".to_string()),
+        "" => error("", "commands need a type field".to_string()),
+        other => error(
+            other,
+            format!("unknown command {other:?} (status, set-source, set-crop, quality, capture-fps)"),
+        ),
+    }
+}
+
+fn error(command: &str, message: String) -> Value {
+    serde_json::json!({
+        "type": "error",
+        "command": command,
+        "message": message,
+    })
+}
+
+/// `--ctl`: send one command to a running server and return its reply.
+/// A bare word like `status` is shorthand for `{"type":"status"}`.
+pub async fn send(path: &Path, command: &str) -> anyhow::Result<String> {
+    let command = if command.trim_start().starts_with('{') {
+        command.to_string()
+    } else {
+        serde_json::json!({ "type": command.trim() }).to_string()
+    };
+    let stream = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("connecting to {} (is foundry running?)", path.display()))?;
+    let (reader, mut writer) = stream.into_split();
+    writer.write_all(command.as_bytes()).await?;
+    writer.write_all(b"\n").await?;
+    let reply = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .context("the server closed the connection without replying")?;
+    Ok(reply)
+}
@@ -0,0 +1,127 @@
+use xcap::{Frame, Monitor, Window};
+
+/// Arrow pointer at one sprite pixel per screen point:
+/// '#' outline, '.' fill, anything else transparent. The hotspot is the top-left.
+const ARROW: [&[u8; 12]; 19] = [
+    b"#           ",
+    b"##          ",
+    b"#.#         ",
+    b"#..#        ",
+    b"#...#       ",
+    b"#....#      ",
+    b"#.....#     ",
+    b"#......#    ",
+    b"#.......#   ",
+    b"#........#  ",
+    b"#.........# ",
+    b"#......#####",
+    b"#...#..#    ",
+    b"#..##..#    ",
+    b"#.#  #..#   ",
+    b"##   #..#   ",
+    b"#     #..#  ",
+    b"      #..#  ",
+    b"       ##   ",
+];
+
+const OUTLINE: [u8; 4] = [0, 0, 0, 255];
+const FILL: [u8; 4] = [255, 255, 255, 255];
+
+/// Area being captured, in global screen points (the space cursor positions use)
+#[derive(Debug, Clone, Copy)]
+pub struct CaptureRegion {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl CaptureRegion {
+    pub fn of_monitor(monitor: &Monitor) -> Option<Self> {
+        Some(Self {
+            x: monitor.x().ok()? as f64,
+            y: monitor.y().ok()? as f64,
+            width: monitor.width().ok()? as f64,
+            height: monitor.height().ok()? as f64,
+        })
+    }
+
+    pub fn of_window(window: &Window) -> Option<Self> {
+        Some(Self {
+            x: window.x().ok()? as f64,
+            y: window.y().ok()? as f64,
+            width: window.width().ok()? as f64,
+            height: window.height().ok()? as f64,
+        })
+    }
+}
+
+/// Draws the mouse pointer into captured frames (`--no-cursor` disables it).
+///
+/// Stamping happens at capture resolution, before frames reach the encoders,
+/// so each resolution rung's Scaler scales the pointer with the rest of
+/// the picture.
+pub struct CursorOverlay {
+    region: Option<CaptureRegion>,
+}
+
+impl CursorOverlay {
+    pub fn new(region: Option<CaptureRegion>) -> Self {
+        Self { region }
+    }
+
+    /// Update the captured area, e.g. after the window moved
+    pub fn set_region(&mut self, region: Option<CaptureRegion>) {
+        if region.is_some() {
+            self.region = region;
+        }
+    }
+
+    /// Stamp the pointer into `frame`, whose rows are `stride` bytes apart,
+    /// if it is over the captured area
+    pub fn stamp(&self, frame: &mut Frame, stride: usize) {
+        let Some(region) = self.region else {
+            return;
+        };
+        let Some((cursor_x, cursor_y)) = window_pick::cursor_position() else {
+            return;
+        };
+        let local_x = cursor_x - region.x;
+        let local_y = cursor_y - region.y;
+        if region.width <= 0.0
+            || local_x < 0.0
+            || local_y < 0.0
+            || local_x >= region.width
+            || local_y >= region.height
+        {
+            return;
+        }
+
+        let frame_w = frame.width as usize;
+        let frame_h = frame.height as usize;
+        if frame.raw.len() < stride * frame_h {
+            return;
+        }
+
+        // Frame pixels per screen point (2.0 on Retina)
+        let scale = frame.width as f64 / region.width;
+        let origin_x = (local_x * scale) as usize;
+        let origin_y = (local_y * scale) as usize;
+        let sprite_w = (ARROW[0].len() as f64 * scale).ceil() as usize;
+        let sprite_h = (ARROW.len() as f64 * scale).ceil() as usize;
+
+        for dy in 0..sprite_h.min(frame_h.saturating_sub(origin_y)) {
+            let row = ARROW[((dy as f64 / scale) as usize).min(ARROW.len() - 1)];
+            let line = (origin_y + dy) * stride;
+            for dx in 0..sprite_w.min(frame_w.saturating_sub(origin_x)) {
+                let color = match row[((dx as f64 / scale) as usize).min(row.len() - 1)] {
+                    b'#' => &OUTLINE,
+                    b'.' => &FILL,
+                    _ => continue,
+                };
+                let idx = line + (origin_x + dx) * 4;
+                frame.raw[idx..idx + 4].copy_from_slice(color);
+            }
+        }
+    }
+}
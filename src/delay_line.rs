@@ -0,0 +1,88 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Largest --audio-offset-ms / av-offset accepted either way
+pub const MAX_OFFSET_MS: i64 = 2000;
+
+/// Items held before the oldest are dropped; covers MAX_OFFSET_MS of 60 fps
+/// video or 10 ms audio chunks with room to spare
+const MAX_HELD: usize = 512;
+
+/// Split an audio/video offset into (audio delay, video delay): positive
+/// values hold audio back, negative ones video
+pub fn offset_delays(offset_ms: i64) -> (Duration, Duration) {
+    let offset_ms = offset_ms.clamp(-MAX_OFFSET_MS, MAX_OFFSET_MS);
+    let delay = Duration::from_millis(offset_ms.unsigned_abs());
+    if offset_ms >= 0 {
+        (delay, Duration::ZERO)
+    } else {
+        (Duration::ZERO, delay)
+    }
+}
+
+/// Holds items from a broadcast channel until `delay` after they arrived
+/// (--audio-offset-ms). With no delay and nothing held it passes items
+/// straight through.
+pub struct DelayLine<T> {
+    delay: Duration,
+    held: VecDeque<(Instant, T)>,
+    /// Dropped off the front since the last `recv`; reported as a lag
+    dropped: u64,
+}
+
+impl<T: Clone> DelayLine<T> {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            held: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Held items keep their arrival times, so a shorter delay releases them sooner
+    pub fn set_delay(&mut self, delay: Duration) {
+        self.delay = delay;
+    }
+
+    /// Forget held items, e.g. when they belong to an encoder the viewer left
+    pub fn clear(&mut self) {
+        self.held.clear();
+        self.dropped = 0;
+    }
+
+    /// Like `rx.recv()`, but each item comes out `delay` after it arrived.
+    /// Items dropped because too many were held show up as `Lagged`.
+    /// Cancel-safe: a received item is held before the next await.
+    pub async fn recv(&mut self, rx: &mut broadcast::Receiver<T>) -> Result<T, RecvError> {
+        loop {
+            if self.dropped > 0 {
+                return Err(RecvError::Lagged(std::mem::take(&mut self.dropped)));
+            }
+            let now = Instant::now();
+            let due = self.held.front().map(|(arrived, _)| *arrived + self.delay);
+            if due.is_some_and(|due| due <= now) {
+                if let Some((_, item)) = self.held.pop_front() {
+                    return Ok(item);
+                }
+            }
+            tokio::select! {
+                received = rx.recv() => {
+                    let item = received?;
+                    if self.delay.is_zero() && self.held.is_empty() {
+                        return Ok(item);
+                    }
+                    self.held.push_back((Instant::now(), item));
+                    if self.held.len() > MAX_HELD {
+                        self.held.pop_front();
+                        self.dropped += 1;
+                    }
+                }
+                _ = tokio::time::sleep_until(due.unwrap_or(now).into()), if due.is_some() => {}
+            }
+        }
+    }
+}
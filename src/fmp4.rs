@@ -0,0 +1,275 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::body::Bytes;
+use base64::engine::general_purpose::STANDARD as B64;
+use base64::Engine;
+
+use crate::{
+    shared_encoder::SharedChunk,
+    video_pipeline::{VideoCodec, VideoConfig},
+};
+
+/// Media timescale: 90 kHz, the usual for video
+pub const TIMESCALE: u32 = 90_000;
+const TRACK_ID: u32 = 1;
+/// Duration given to the first frame, before there's a delta to measure
+const FIRST_FRAME_TICKS: u32 = TIMESCALE / 30;
+/// Longer gaps (pauses, lag drops) are squashed so the timeline stays contiguous
+const MAX_FRAME_TICKS: u32 = TIMESCALE;
+
+const SAMPLE_FLAGS_SYNC: u32 = 0x0200_0000;
+const SAMPLE_FLAGS_NON_SYNC: u32 = 0x0101_0000;
+
+/// Wraps encoded AVC chunks as fragmented MP4 for Media Source Extensions
+/// clients (`"transport":"fmp4"`): an init segment per video config, then one
+/// `moof`+`mdat` per frame on a contiguous decode timeline.
+#[derive(Default)]
+pub struct Fmp4Muxer {
+    /// Config the last init segment was built from
+    init_config: Option<Arc<VideoConfig>>,
+    sequence: u32,
+    /// Decode time of the next fragment, in TIMESCALE ticks
+    decode_time: u64,
+    last_timestamp_ms: Option<f64>,
+}
+
+impl Fmp4Muxer {
+    /// The segments for one encoded frame: an init segment first whenever the config changed
+    pub fn push(&mut self, chunk: &SharedChunk) -> Result<Vec<Bytes>> {
+        let mut segments = Vec::with_capacity(2);
+        if let Some(init) = self.init_segment(chunk)? {
+            segments.push(init);
+        }
+        segments.push(self.fragment(chunk)?);
+        Ok(segments)
+    }
+
+    /// The init segment to send ahead of `chunk`, when its config differs from the last one
+    pub fn init_segment(&mut self, chunk: &SharedChunk) -> Result<Option<Bytes>> {
+        if self
+            .init_config
+            .as_ref()
+            .is_some_and(|config| Arc::ptr_eq(config, &chunk.config))
+        {
+            return Ok(None);
+        }
+        let segment = build_init_segment(&chunk.config)?;
+        self.init_config = Some(chunk.config.clone());
+        Ok(Some(segment))
+    }
+
+    /// One `moof`+`mdat` holding the frame in `chunk`
+    pub fn fragment(&mut self, chunk: &SharedChunk) -> Result<Bytes> {
+        let (timestamp_ms, payload) = (chunk.captured_ms, &chunk.data);
+
+        // Each frame lasts until the next one; the previous frame's delta is the best guess
+        let duration = match self.last_timestamp_ms {
+            Some(last) => {
+                let ticks = ((timestamp_ms - last) * TIMESCALE as f64 / 1000.0).round();
+                (ticks as u32).clamp(1, MAX_FRAME_TICKS)
+            }
+            None => FIRST_FRAME_TICKS,
+        };
+        self.last_timestamp_ms = Some(timestamp_ms);
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let flags = if chunk.is_keyframe { SAMPLE_FLAGS_SYNC } else { SAMPLE_FLAGS_NON_SYNC };
+        let mut out = Vec::with_capacity(payload.len() + 128);
+        let mut data_offset_at = 0;
+        write_box(&mut out, b"moof", |out| {
+            write_full_box(out, b"mfhd", 0, 0, |out| put_u32(out, self.sequence));
+            write_box(out, b"traf", |out| {
+                // default-base-is-moof: data offsets count from the start of moof
+                write_full_box(out, b"tfhd", 0, 0x02_0000, |out| put_u32(out, TRACK_ID));
+                write_full_box(out, b"tfdt", 1, 0, |out| {
+                    out.extend_from_slice(&self.decode_time.to_be_bytes())
+                });
+                // data-offset, sample-duration, sample-size and sample-flags present
+                write_full_box(out, b"trun", 0, 0x00_0701, |out| {
+                    put_u32(out, 1);
+                    data_offset_at = out.len();
+                    put_u32(out, 0);
+                    put_u32(out, duration);
+                    put_u32(out, payload.len() as u32);
+                    put_u32(out, flags);
+                });
+            });
+        });
+        // The sample starts right after the mdat header
+        let data_offset = (out.len() + 8) as u32;
+        out[data_offset_at..data_offset_at + 4].copy_from_slice(&data_offset.to_be_bytes());
+        write_box(&mut out, b"mdat", |out| out.extend_from_slice(payload));
+
+        self.decode_time += duration as u64;
+        Ok(Bytes::from(out))
+    }
+
+    /// Where the next fragment starts on the decode timeline, in TIMESCALE ticks
+    pub fn decode_time(&self) -> u64 {
+        self.decode_time
+    }
+}
+
+/// The RFC 6381 codec string MSE needs for `addSourceBuffer`, from the avcC profile bytes
+pub fn codec_string(config: &VideoConfig) -> Option<String> {
+    if config.codec != VideoCodec::Avc {
+        return None;
+    }
+    let avcc = B64.decode(&config.description_b64).ok()?;
+    let profile = avcc.get(1..4)?;
+    Some(format!("avc1.{:02X}{:02X}{:02X}", profile[0], profile[1], profile[2]))
+}
+
+/// `ftyp` + `moov` describing one AVC track with no samples
+fn build_init_segment(config: &VideoConfig) -> Result<Bytes> {
+    let avcc = B64.decode(&config.description_b64)?;
+    let (width, height) = (config.width, config.height);
+
+    let mut out = Vec::with_capacity(avcc.len() + 640);
+    write_box(&mut out, b"ftyp", |out| {
+        out.extend_from_slice(b"isom");
+        put_u32(out, 0x200);
+        for brand in [b"isom", b"iso6", b"avc1", b"mp41"] {
+            out.extend_from_slice(brand);
+        }
+    });
+    write_box(&mut out, b"moov", |out| {
+        write_full_box(out, b"mvhd", 0, 0, |out| {
+            put_u32(out, 0); // creation_time
+            put_u32(out, 0); // modification_time
+            put_u32(out, 1000);
+            put_u32(out, 0); // duration: unknown, fragmented
+            put_u32(out, 0x0001_0000); // rate 1.0
+            put_u16(out, 0x0100); // volume 1.0
+            out.extend_from_slice(&[0; 10]);
+            put_matrix(out);
+            out.extend_from_slice(&[0; 24]); // pre_defined
+            put_u32(out, TRACK_ID + 1); // next_track_ID
+        });
+        write_box(out, b"trak", |out| {
+            // track enabled + in movie
+            write_full_box(out, b"tkhd", 0, 0x3, |out| {
+                put_u32(out, 0);
+                put_u32(out, 0);
+                put_u32(out, TRACK_ID);
+                put_u32(out, 0);
+                put_u32(out, 0); // duration
+                out.extend_from_slice(&[0; 8]);
+                put_u16(out, 0); // layer
+                put_u16(out, 0); // alternate_group
+                put_u16(out, 0); // volume: video
+                put_u16(out, 0);
+                put_matrix(out);
+                put_u32(out, width << 16);
+                put_u32(out, height << 16);
+            });
+            write_box(out, b"mdia", |out| {
+                write_full_box(out, b"mdhd", 0, 0, |out| {
+                    put_u32(out, 0);
+                    put_u32(out, 0);
+                    put_u32(out, TIMESCALE);
+                    put_u32(out, 0);
+                    put_u16(out, 0x55c4); // language "und"
+                    put_u16(out, 0);
+                });
+                write_full_box(out, b"hdlr", 0, 0, |out| {
+                    put_u32(out, 0);
+                    out.extend_from_slice(b"vide");
+                    out.extend_from_slice(&[0; 12]);
+                    out.extend_from_slice(b"foundry\0");
+                });
+                write_box(out, b"minf", |out| {
+                    write_full_box(out, b"vmhd", 0, 1, |out| out.extend_from_slice(&[0; 8]));
+                    write_box(out, b"dinf", |out| {
+                        write_full_box(out, b"dref", 0, 0, |out| {
+                            put_u32(out, 1);
+                            // self-contained: the media is in this file
+                            write_full_box(out, b"url ", 0, 1, |_| {});
+                        });
+                    });
+                    write_box(out, b"stbl", |out| {
+                        write_full_box(out, b"stsd", 0, 0, |out| {
+                            put_u32(out, 1);
+                            write_avc1(out, width, height, &avcc);
+                        });
+                        // Samples live in the fragments; these tables stay empty
+                        write_full_box(out, b"stts", 0, 0, |out| put_u32(out, 0));
+                        write_full_box(out, b"stsc", 0, 0, |out| put_u32(out, 0));
+                        write_full_box(out, b"stsz", 0, 0, |out| {
+                            put_u32(out, 0);
+                            put_u32(out, 0);
+                        });
+                        write_full_box(out, b"stco", 0, 0, |out| put_u32(out, 0));
+                    });
+                });
+            });
+        });
+        write_box(out, b"mvex", |out| {
+            write_full_box(out, b"trex", 0, 0, |out| {
+                put_u32(out, TRACK_ID);
+                put_u32(out, 1); // default_sample_description_index
+                put_u32(out, 0);
+                put_u32(out, 0);
+                put_u32(out, 0);
+            });
+        });
+    });
+    Ok(Bytes::from(out))
+}
+
+fn write_avc1(out: &mut Vec<u8>, width: u32, height: u32, avcc: &[u8]) {
+    write_box(out, b"avc1", |out| {
+        out.extend_from_slice(&[0; 6]);
+        put_u16(out, 1); // data_reference_index
+        out.extend_from_slice(&[0; 16]); // pre_defined + reserved
+        put_u16(out, width as u16);
+        put_u16(out, height as u16);
+        put_u32(out, 0x0048_0000); // 72 dpi
+        put_u32(out, 0x0048_0000);
+        put_u32(out, 0);
+        put_u16(out, 1); // frame_count
+        out.extend_from_slice(&[0; 32]); // compressorname
+        put_u16(out, 0x0018); // depth
+        put_u16(out, 0xffff); // pre_defined = -1
+        write_box(out, b"avcC", |out| out.extend_from_slice(avcc));
+    });
+}
+
+fn write_box(out: &mut Vec<u8>, kind: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    put_u32(out, 0);
+    out.extend_from_slice(kind);
+    body(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn write_full_box(
+    out: &mut Vec<u8>,
+    kind: &[u8; 4],
+    version: u8,
+    flags: u32,
+    body: impl FnOnce(&mut Vec<u8>),
+) {
+    write_box(out, kind, |out| {
+        out.push(version);
+        out.extend_from_slice(&flags.to_be_bytes()[1..]);
+        body(out);
+    });
+}
+
+/// Identity transformation matrix
+fn put_matrix(out: &mut Vec<u8>) {
+    for value in [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000] {
+        put_u32(out, value);
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
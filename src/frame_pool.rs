@@ -0,0 +1,61 @@
+use std::sync::Arc;
+
+use xcap::Frame;
+
+/// Frames kept for reuse; a buffer is only rewritten once the change
+/// detector and encoder have let go of it
+const FRAME_POOL_SIZE: usize = 4;
+
+/// Reusable frame buffers for stages that draw over captured frames (pip
+/// inset, annotations) or resize them, which can't write into the
+/// Recorder's shared Arc
+#[derive(Default)]
+pub struct FramePool {
+    frames: Vec<Arc<Frame>>,
+}
+
+impl FramePool {
+    /// A copy of `source` with `draw` applied, in a pooled buffer
+    pub fn edit(&mut self, source: &Frame, draw: impl FnOnce(&mut Frame)) -> Arc<Frame> {
+        let mut out = self.take_buffer(source.width, source.height, source.raw.len());
+        let frame = Arc::get_mut(&mut out).expect("pooled frame is unshared");
+        frame.raw.copy_from_slice(&source.raw);
+        draw(frame);
+        self.frames.push(out.clone());
+        out
+    }
+
+    /// A packed `width` x `height` frame filled in by `draw`, in a pooled
+    /// buffer (its previous contents are left for `draw` to overwrite)
+    pub fn render(&mut self, width: u32, height: u32, draw: impl FnOnce(&mut Frame)) -> Arc<Frame> {
+        let mut out = self.take_buffer(width, height, width as usize * height as usize * 4);
+        draw(Arc::get_mut(&mut out).expect("pooled frame is unshared"));
+        self.frames.push(out.clone());
+        out
+    }
+
+    /// A pooled frame of this size that nobody else holds, or a new one
+    fn take_buffer(&mut self, width: u32, height: u32, len: usize) -> Arc<Frame> {
+        let reusable = self.frames.iter().position(|frame| {
+            Arc::strong_count(frame) == 1 && frame.width == width && frame.height == height && frame.raw.len() == len
+        });
+        if let Some(index) = reusable {
+            return self.frames.swap_remove(index);
+        }
+        // Make room by dropping an idle or mismatched buffer; if every pooled
+        // frame is still in use the oldest is simply forgotten
+        if self.frames.len() >= FRAME_POOL_SIZE {
+            let index = self
+                .frames
+                .iter()
+                .position(|frame| Arc::strong_count(frame) == 1)
+                .unwrap_or(0);
+            self.frames.remove(index);
+        }
+        Arc::new(Frame {
+            width,
+            height,
+            raw: vec![0; len],
+        })
+    }
+}
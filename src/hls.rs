@@ -0,0 +1,220 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+use axum::body::Bytes;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    fmp4::{Fmp4Muxer, TIMESCALE},
+    shared_encoder::SharedEncoder,
+};
+
+/// Segments are cut at the first keyframe after this many seconds, so with
+/// the default 4 s --keyframe-interval they come out 4 s long
+const TARGET_SEGMENT_SECS: f64 = 2.0;
+/// Segments kept for the playlist (the DVR window); older ones are dropped
+const WINDOW_SEGMENTS: usize = 8;
+
+struct Segment {
+    sequence: u64,
+    /// Init segment this one decodes against
+    init_id: u64,
+    duration_secs: f64,
+    /// First segment after a config change or a gap in the stream
+    discontinuity: bool,
+    data: Bytes,
+}
+
+/// The segment being filled from the encoder
+struct Building {
+    init_id: u64,
+    start_ticks: u64,
+    discontinuity: bool,
+    data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Window {
+    inits: VecDeque<(u64, Bytes)>,
+    segments: VecDeque<Segment>,
+    next_sequence: u64,
+    /// Discontinuities that have scrolled out of the window (EXT-X-DISCONTINUITY-SEQUENCE)
+    discontinuity_sequence: u64,
+}
+
+impl Window {
+    fn push(&mut self, segment: Segment) {
+        self.segments.push_back(segment);
+        while self.segments.len() > WINDOW_SEGMENTS {
+            if let Some(dropped) = self.segments.pop_front() {
+                if dropped.discontinuity {
+                    self.discontinuity_sequence += 1;
+                }
+            }
+        }
+        // Inits older than the oldest remaining segment can't be asked for any more
+        if let Some(oldest) = self.segments.front().map(|s| s.init_id) {
+            self.inits.retain(|(id, _)| *id >= oldest);
+        }
+    }
+}
+
+/// `--hls`: cuts the shared encoded stream into rolling fMP4 segments kept in
+/// memory for `/hls/stream.m3u8`. Video only for now.
+pub struct HlsSegmenter {
+    window: Mutex<Window>,
+}
+
+impl HlsSegmenter {
+    /// Subscribe to `encoder` and segment its output until the encoder goes away
+    pub fn start(encoder: Arc<SharedEncoder>) -> Arc<Self> {
+        let segmenter = Arc::new(Self {
+            window: Mutex::new(Window::default()),
+        });
+        let task_segmenter = segmenter.clone();
+        tokio::spawn(async move { task_segmenter.run(encoder).await });
+        segmenter
+    }
+
+    async fn run(&self, encoder: Arc<SharedEncoder>) {
+        let mut chunks = encoder.subscribe();
+        encoder.request_keyframe();
+        let mut muxer = Fmp4Muxer::default();
+        let mut next_init_id = 0;
+        let mut current_init: Option<u64> = None;
+        let mut building: Option<Building> = None;
+        // Set after a lag so the next segment is flagged
+        let mut discontinuity = false;
+
+        loop {
+            let chunk = match chunks.recv().await {
+                Ok(chunk) => chunk,
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("hls segmenter lagging, skipped {skipped} chunks");
+                    // The partial segment has a hole in it; start over at the next keyframe
+                    building = None;
+                    discontinuity = true;
+                    encoder.request_keyframe();
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            if building.is_none() && !chunk.is_keyframe {
+                continue;
+            }
+
+            let init = match muxer.init_segment(&chunk) {
+                Ok(init) => init,
+                Err(err) => {
+                    eprintln!("hls init segment failed: {err}");
+                    continue;
+                }
+            };
+            if let Some(init) = init {
+                // New config: close the segment on the old one, which needs a keyframe anyway
+                self.finish(building.take(), &muxer);
+                let id = next_init_id;
+                next_init_id += 1;
+                self.window.lock().unwrap().inits.push_back((id, init));
+                discontinuity |= current_init.is_some();
+                current_init = Some(id);
+            }
+            let Some(init_id) = current_init else {
+                continue;
+            };
+
+            if chunk.is_keyframe {
+                let elapsed = building.as_ref().map(|b| {
+                    (muxer.decode_time() - b.start_ticks) as f64 / TIMESCALE as f64
+                });
+                if elapsed.is_none_or(|secs| secs >= TARGET_SEGMENT_SECS) {
+                    self.finish(building.take(), &muxer);
+                    building = Some(Building {
+                        init_id,
+                        start_ticks: muxer.decode_time(),
+                        discontinuity: std::mem::take(&mut discontinuity),
+                        data: Vec::new(),
+                    });
+                }
+            }
+
+            let Some(segment) = building.as_mut() else {
+                continue;
+            };
+            match muxer.fragment(&chunk) {
+                Ok(fragment) => segment.data.extend_from_slice(&fragment),
+                Err(err) => eprintln!("hls fragment failed: {err}"),
+            }
+        }
+    }
+
+    fn finish(&self, building: Option<Building>, muxer: &Fmp4Muxer) {
+        let Some(building) = building else {
+            return;
+        };
+        if building.data.is_empty() {
+            return;
+        }
+        let duration_secs = (muxer.decode_time() - building.start_ticks) as f64 / TIMESCALE as f64;
+        let mut window = self.window.lock().unwrap();
+        let sequence = window.next_sequence;
+        window.next_sequence += 1;
+        window.push(Segment {
+            sequence,
+            init_id: building.init_id,
+            duration_secs,
+            discontinuity: building.discontinuity,
+            data: Bytes::from(building.data),
+        });
+    }
+
+    /// The live playlist; `query` (e.g. `?token=...`) is appended to every URI in it
+    pub fn playlist(&self, query: &str) -> String {
+        let window = self.window.lock().unwrap();
+        let target = window
+            .segments
+            .iter()
+            .map(|s| s.duration_secs.ceil() as u64)
+            .max()
+            .unwrap_or(TARGET_SEGMENT_SECS as u64);
+        let first_sequence = window.segments.front().map_or(window.next_sequence, |s| s.sequence);
+
+        let mut out = String::new();
+        let _ = writeln!(out, "#EXTM3U");
+        let _ = writeln!(out, "#EXT-X-VERSION:7");
+        let _ = writeln!(out, "#EXT-X-TARGETDURATION:{}", target);
+        let _ = writeln!(out, "#EXT-X-MEDIA-SEQUENCE:{}", first_sequence);
+        let _ = writeln!(out, "#EXT-X-DISCONTINUITY-SEQUENCE:{}", window.discontinuity_sequence);
+        let mut map: Option<u64> = None;
+        for segment in &window.segments {
+            if map.is_some() && segment.discontinuity {
+                let _ = writeln!(out, "#EXT-X-DISCONTINUITY");
+            }
+            if map != Some(segment.init_id) {
+                let _ = writeln!(out, "#EXT-X-MAP:URI=\"init-{}.mp4{}\"", segment.init_id, query);
+                map = Some(segment.init_id);
+            }
+            let _ = writeln!(out, "#EXTINF:{:.3},", segment.duration_secs);
+            let _ = writeln!(out, "seg-{}.m4s{}", segment.sequence, query);
+        }
+        out
+    }
+
+    /// `init-N.mp4` or `seg-N.m4s` from the window; None once it has scrolled out
+    pub fn file(&self, name: &str) -> Option<Bytes> {
+        let window = self.window.lock().unwrap();
+        if let Some(id) = name.strip_prefix("init-").and_then(|n| n.strip_suffix(".mp4")) {
+            let id: u64 = id.parse().ok()?;
+            return window.inits.iter().find(|(i, _)| *i == id).map(|(_, b)| b.clone());
+        }
+        let sequence: u64 = name.strip_prefix("seg-")?.strip_suffix(".m4s")?.parse().ok()?;
+        window
+            .segments
+            .iter()
+            .find(|s| s.sequence == sequence)
+            .map(|s| s.data.clone())
+    }
+}
@@ -0,0 +1,1186 @@
+use axum::{
+    body::Body,
+    extract::{
+        ws::{close_code, CloseFrame, Message, Utf8Bytes, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    http::{HeaderMap, Uri},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use clap::{CommandFactory, FromArgMatches, Parser};
+use futures_util::{stream::SplitStream, SinkExt, StreamExt};
+use serde::Deserialize;
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{mpsc, watch},
+    time::{interval, timeout, MissedTickBehavior},
+};
+
+/// Control messages (JSON, pings, Close) queued per viewer; media goes through a MediaQueue
+const OUTBOUND_BUFFER: usize = 1024;
+
+/// How long Ctrl-C waits for sessions and capture to wind down before exiting anyway
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(3);
+
+mod session;
+mod recording;
+mod resume;
+pub mod scaler;
+#[cfg(all(feature = "screencapturekit", target_os = "macos"))]
+mod sck;
+mod shared_encoder;
+pub mod video_pipeline;
+#[cfg(all(feature = "videotoolbox", target_os = "macos"))]
+mod videotoolbox;
+#[cfg(feature = "av1")]
+pub mod av1;
+mod audio_mixer;
+mod audio_capture;
+mod opus_audio;
+mod permissions;
+mod pip;
+mod annotate;
+mod assets;
+mod auth;
+mod config;
+mod delay_line;
+mod clipboard;
+#[cfg(unix)]
+mod control;
+mod fmp4;
+mod frame_pool;
+mod hls;
+mod mdns;
+mod media_queue;
+mod mjpeg;
+#[cfg(test)]
+mod mock_encoder;
+mod cursor;
+mod screenshot;
+mod status;
+mod throttle;
+pub mod test_frames;
+mod tls;
+#[cfg(any(feature = "openh264-encoder", feature = "av1"))]
+pub mod yuv;
+
+#[derive(Parser)]
+#[command(name = "foundry")]
+#[command(about = "A fast screen streaming server using H.264 over WebSocket")]
+struct Cli {
+    /// Read defaults from this TOML file (default: ./foundry.toml, then ~/.config/foundry/config.toml)
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Print the effective configuration (file merged with flags) as TOML and exit
+    #[arg(long)]
+    print_config: bool,
+
+    /// Stream a specific window by ID (use window-pick to get the ID)
+    #[arg(long)]
+    window: Option<u32>,
+
+    /// Stream the primary monitor (the default)
+    #[arg(long, conflicts_with = "window")]
+    monitor: bool,
+
+    /// Stream a specific monitor by ID (see --list-monitors)
+    #[arg(long, conflicts_with_all = ["window", "monitor"])]
+    monitor_id: Option<u32>,
+
+    /// Draw this window as a picture-in-picture inset over the stream
+    #[arg(long)]
+    pip_window: Option<u32>,
+
+    /// Draw this monitor as a picture-in-picture inset over the stream (see --list-monitors)
+    #[arg(long, conflicts_with = "pip_window")]
+    pip_monitor: Option<u32>,
+
+    /// Corner for the inset: top-left, top-right, bottom-left or bottom-right
+    #[arg(long, default_value = "bottom-right")]
+    pip_corner: pip::Corner,
+
+    /// Inset width as a fraction of the stream's width
+    #[arg(long, default_value = "0.25")]
+    pip_size: f32,
+
+    /// Print the available monitors and exit
+    #[arg(long)]
+    list_monitors: bool,
+
+    /// Ask macOS for Screen Recording permission (shows the system prompt) if it isn't granted
+    #[arg(long)]
+    request_permissions: bool,
+
+    /// Advertise this server on the local network as _foundry._tcp (Bonjour/mDNS)
+    #[arg(long)]
+    mdns: bool,
+
+    /// Look for foundry servers on the local network, print them as JSON and exit
+    #[arg(long)]
+    discover: bool,
+
+    /// Unix socket for local scripting with newline-delimited JSON commands (default: $TMPDIR/foundry-<port>.sock)
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
+
+    /// Don't open the control socket
+    #[arg(long, conflicts_with = "control_socket")]
+    no_control_socket: bool,
+
+    /// Send one command (JSON, or a bare type like `status`) to a running server's control socket, print the reply and exit
+    #[arg(long, value_name = "COMMAND")]
+    ctl: Option<String>,
+
+    /// Click on a window to stream it before the server starts (macOS only)
+    #[arg(long, conflicts_with_all = ["window", "monitor", "monitor_id"])]
+    pick: bool,
+
+    /// Stream whichever window is in front, switching as focus changes (macOS only)
+    #[arg(long, conflicts_with_all = ["window", "monitor", "monitor_id", "pick"])]
+    follow_focus: bool,
+
+    /// Stream a window of this app (case-insensitive), following it across the app's windows; waits
+    /// up to 15s for the app to open one
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["window", "monitor", "monitor_id", "pick", "follow_focus"])]
+    app: Option<String>,
+
+    /// Stream only this part of the monitor (--monitor-id, else the primary one): X,Y,WIDTHxHEIGHT in
+    /// points from its top-left, e.g. 0,0,1280x720
+    #[arg(long, value_name = "X,Y,WxH", conflicts_with_all = ["window", "pick", "follow_focus", "app"])]
+    region: Option<recording::Region>,
+
+    /// When the streamed window closes, wait for a window with the same app and title and carry on with it
+    #[arg(long)]
+    reattach_by_title: bool,
+
+    /// How many times a second a window is captured (monitors deliver at the display's rate)
+    #[arg(long, default_value = "60", value_parser = clap::value_parser!(u32).range(1..=240))]
+    capture_fps: u32,
+
+    /// What captures the screen: auto (ScreenCaptureKit when built in and
+    /// available, else xcap), xcap or screencapturekit
+    #[arg(long, default_value = "auto")]
+    capture_backend: recording::CaptureBackend,
+
+    /// Don't pass on polled window frames identical to the last one, except one a second; saves CPU on
+    /// static windows, but a new viewer may wait up to a second for its first picture
+    #[arg(long)]
+    skip_unchanged: bool,
+
+    /// Give up on --pick after this many seconds
+    #[arg(long, requires = "pick")]
+    timeout: Option<u64>,
+
+    /// Draw the mouse pointer into the stream (the default)
+    #[arg(long, overrides_with = "no_cursor")]
+    cursor: bool,
+
+    /// Don't draw the mouse pointer
+    #[arg(long, overrides_with = "cursor")]
+    no_cursor: bool,
+
+    /// Sync text clipboard between this machine and connected viewers
+    #[arg(long)]
+    allow_clipboard: bool,
+
+    /// Let viewers draw arrows, boxes and strokes into the stream for everyone to see
+    #[arg(long)]
+    allow_annotations: bool,
+
+    /// Require viewers to present this secret (`?token=` or in the mode message); it grants control
+    #[arg(long)]
+    token: Option<String>,
+
+    /// Another secret granting control: source, crop, quality, overlays and clipboard (repeatable)
+    #[arg(long)]
+    token_control: Vec<String>,
+
+    /// A secret that can only watch; control messages are refused (repeatable)
+    #[arg(long)]
+    token_view: Vec<String>,
+
+    /// Require a token; a control one is generated and printed if none is given
+    #[arg(long)]
+    require_auth: bool,
+
+    /// PEM certificate chain; serves https/wss together with --tls-key
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Serve https/wss with a generated self-signed certificate (LAN testing)
+    #[arg(long, conflicts_with_all = ["tls_cert", "tls_key"])]
+    tls_self_signed: bool,
+
+    /// Lowest bitrate adaptive rate control may drop to, in kbit/s
+    #[arg(long, default_value = "300")]
+    min_bitrate_kbps: u32,
+
+    /// Highest bitrate adaptive rate control may use, in kbit/s
+    #[arg(long, default_value = "15000")]
+    max_bitrate_kbps: u32,
+
+    /// Seconds between forced keyframes so late joiners and lossy clients recover (0 = off)
+    #[arg(long, default_value = "4")]
+    keyframe_interval: u64,
+
+    /// Starting encoder bitrate in kbit/s instead of one derived from the frame size;
+    /// adaptive rate control moves from there
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    bitrate_kbps: Option<u32>,
+
+    /// Frame rate the encoder budgets bits for
+    #[arg(long, default_value = "60")]
+    encoder_fps: f32,
+
+    /// Byte order of captured frames, rgba or bgra, when the capture
+    /// backend's default is wrong (red and blue swapped in the stream)
+    #[arg(long)]
+    pixel_format: Option<video_pipeline::PixelFormat>,
+
+    /// Encoder rate control: bitrate, quality[:QP] (QP 0-51, lower is better;
+    /// default 24) or off[:QP] for a fixed QP (default 26)
+    #[arg(long, default_value = "bitrate")]
+    rate_control: video_pipeline::RateControl,
+
+    /// What openh264 tunes for: camera (or motion) or screen (text and flat
+    /// areas: sharper text, no denoising)
+    #[arg(long, visible_alias = "tune", default_value = "camera")]
+    encoder_usage: video_pipeline::UsageType,
+
+    /// openh264 encoding threads, each on its own slice (default: 1 below
+    /// 1080p, up to 4 from there)
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..=16))]
+    encoder_threads: Option<u16>,
+
+    /// RGB to YUV conversion for the software encoders: bt709, bt601,
+    /// bt709-full or bt601-full
+    #[arg(long, default_value = "bt709")]
+    color_matrix: video_pipeline::ColorMatrix,
+
+    /// Captures lost in a row (encoding too slow) before the encoder only
+    /// takes every other one until it catches up (0 = never)
+    #[arg(long, default_value_t = video_pipeline::DEFAULT_DROP_STREAK)]
+    drop_streak: u32,
+
+    /// Downsample the default stream to at most this many pixels and cap every
+    /// resolution viewers can ask for (default: 1080p default, native available)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(4..))]
+    max_pixels: Option<u64>,
+
+    /// Like --max-pixels, as WIDTHxHEIGHT (e.g. 1280x720 or 5120x2880)
+    #[arg(long, conflicts_with = "max_pixels")]
+    max_resolution: Option<shared_encoder::Resolution>,
+
+    /// Replace the resolution ladder with these rungs, each WIDTHxHEIGHT@KBPS
+    /// (comma-separated, e.g. 1280x720@1500,3840x2160@12000); a rung only
+    /// encodes while someone watches it
+    #[arg(long, value_delimiter = ',', conflicts_with_all = ["max_pixels", "max_resolution"])]
+    simulcast: Vec<shared_encoder::SimulcastRung>,
+
+    /// Seconds each viewer must wait between the keyframes it asks for; extra requests are coalesced (0 = no limit)
+    #[arg(long, default_value = "2")]
+    keyframe_request_interval: f64,
+
+    /// Close sessions that haven't sent anything (not even a pong) for this many seconds (0 = never)
+    #[arg(long, default_value = "600")]
+    idle_timeout: u64,
+
+    /// Refuse sessions beyond this many at once (default: unlimited)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_sessions: Option<u64>,
+
+    /// Capture the input device whose name contains this as the microphone (default: system default input)
+    #[arg(long, conflicts_with = "no_mic")]
+    mic: Option<String>,
+
+    /// Don't capture a microphone, only system audio
+    #[arg(long)]
+    no_mic: bool,
+
+    /// Microphone volume multiplier applied before mixing
+    #[arg(long, default_value = "1.0")]
+    mic_gain: f32,
+
+    /// System audio volume multiplier applied before mixing
+    #[arg(long, default_value = "1.0")]
+    system_gain: f32,
+
+    /// Serve the default-resolution stream as HLS at /hls/stream.m3u8 (video only, a few seconds behind)
+    #[arg(long)]
+    hls: bool,
+
+    /// Start every session with audio muted (viewers unmute with an audio-unmute message)
+    #[arg(long)]
+    start_muted: bool,
+
+    /// Delay audio by this many milliseconds to line it up with video; negative values delay video instead
+    #[arg(long, default_value = "0", allow_hyphen_values = true,
+          value_parser = clap::value_parser!(i64).range(-delay_line::MAX_OFFSET_MS..=delay_line::MAX_OFFSET_MS))]
+    audio_offset_ms: i64,
+
+    /// Serve the web client from this directory instead of the copy built into the binary (development)
+    #[arg(long)]
+    assets_dir: Option<PathBuf>,
+
+    /// Port to listen on
+    #[arg(long, default_value = "23646")]
+    port: u16,
+
+    /// Address to listen on, e.g. 127.0.0.1 or [::]
+    #[arg(long, default_value = "0.0.0.0", value_parser = parse_bind_addr)]
+    bind: IpAddr,
+}
+
+impl Cli {
+    /// --cursor/--no-cursor: the pointer is shown unless turned off
+    fn show_cursor(&self) -> bool {
+        self.cursor || !self.no_cursor
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    recorder: Arc<recording::Recorder>,
+    /// None when no encoder is available (e.g. built without openh264)
+    encoders: Option<Arc<shared_encoder::EncoderLadder>>,
+    mixer: Arc<audio_mixer::AudioMixer>,
+    audio_broadcast: Option<audio_capture::AudioBroadcast>,
+    /// Per-viewer limit on client-triggered IDRs; None when unlimited
+    keyframe_request_interval: Option<Duration>,
+    /// New sessions begin muted (--start-muted)
+    start_muted: bool,
+    /// --audio-offset-ms for new sessions; each can change its own with av-offset
+    audio_offset_ms: i64,
+    /// Present only with --allow-clipboard
+    clipboard: Option<Arc<clipboard::ClipboardSync>>,
+    /// Present only with --allow-annotations
+    annotations: Option<Arc<annotate::Annotations>>,
+    /// Secrets viewers must present and the roles they grant; None leaves /ws open
+    auth: Option<Arc<auth::Tokens>>,
+    /// macOS Screen Recording permission as of startup
+    screen_permission: permissions::ScreenPermission,
+    /// Flips to true on Ctrl-C
+    shutdown: watch::Receiver<bool>,
+    /// Connected viewers, for /status
+    sessions: Arc<status::SessionRegistry>,
+    /// Settings of recently dropped sessions, for resume
+    resumable: Arc<resume::ResumeRegistry>,
+    started: Instant,
+    /// --pip-window/--pip-monitor: inset layout viewers can change
+    pip: Option<Arc<pip::Pip>>,
+    /// --hls: rolling in-memory segments of the default encoder rung
+    hls: Option<Arc<hls::HlsSegmenter>>,
+    /// --assets-dir: client files are read from here first
+    assets_dir: Option<Arc<std::path::Path>>,
+}
+
+/// Parse the command line, then serve until Ctrl-C
+#[tokio::main]
+pub async fn run() {
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    match config::Config::load(cli.config.as_deref()) {
+        Ok(Some((path, config))) => {
+            eprintln!("Using config {}", path.display());
+            config.apply(&mut cli, &matches);
+        }
+        Ok(None) => {}
+        Err(err) => {
+            eprintln!("Failed to load config: {:#}", err);
+            std::process::exit(1);
+        }
+    }
+
+    if cli.print_config {
+        match toml::to_string_pretty(&config::Config::effective(&cli)) {
+            Ok(text) => print!("{}", text),
+            Err(err) => {
+                eprintln!("Failed to print config: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if cli.discover {
+        if let Err(err) = mdns::discover() {
+            eprintln!("Discovery failed: {:#}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(command) = &cli.ctl {
+        run_ctl(&cli, command).await;
+        return;
+    }
+
+    if cli.list_monitors {
+        match recording::describe_monitors() {
+            Ok(monitors) => {
+                println!("ID\tNAME\tRESOLUTION");
+                for monitor in monitors {
+                    println!("{}", monitor);
+                }
+                return;
+            }
+            Err(err) => {
+                eprintln!("Failed to list monitors: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Check TLS material before starting capture so a bad path fails fast
+    let tls = match (&cli.tls_cert, &cli.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::from_pem_files(cert, key).await),
+        _ if cli.tls_self_signed => Some(tls::self_signed(cli.bind).await),
+        _ => None,
+    };
+    let tls = match tls.transpose() {
+        Ok(tls) => tls,
+        Err(err) => {
+            eprintln!("TLS setup failed: {:#}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // Without this permission macOS hands out black frames and untitled windows
+    let mut screen_permission = permissions::screen_recording();
+    if screen_permission.is_denied() && cli.request_permissions {
+        screen_permission = permissions::request_screen_recording();
+    }
+    if screen_permission.is_denied() {
+        permissions::print_instructions();
+    }
+
+    let capture_source = match cli.window {
+        Some(id) => recording::CaptureSource::Window { id, fps: cli.capture_fps },
+        None if cli.pick => recording::CaptureSource::Window {
+            id: pick_window(cli.timeout),
+            fps: cli.capture_fps,
+        },
+        None if cli.follow_focus => recording::CaptureSource::FrontmostWindow { fps: cli.capture_fps },
+        None if cli.app.is_some() => recording::CaptureSource::App {
+            name: cli.app.clone().unwrap_or_default(),
+            fps: cli.capture_fps,
+        },
+        None => match (cli.region, cli.monitor_id) {
+            (Some(region), display_id) => recording::CaptureSource::Region { display_id, region },
+            (None, Some(monitor_id)) => recording::CaptureSource::Monitor(monitor_id),
+            (None, None) => recording::CaptureSource::PrimaryMonitor,
+        },
+    };
+
+    let capture_options = recording::CaptureOptions {
+        show_cursor: cli.show_cursor(),
+        reattach_by_title: cli.reattach_by_title,
+        backend: cli.capture_backend,
+        dedupe: cli.skip_unchanged,
+    };
+    let recorder = recording::Recorder::new(capture_source.clone(), cli.pixel_format, capture_options);
+    let recorder = match recorder {
+        Ok(recorder) => recorder,
+        Err(err) => {
+            eprintln!("Failed to start capture of {}: {}", capture_source, err);
+            std::process::exit(1);
+        }
+    };
+    let size = recorder
+        .native_size()
+        .map(|(width, height)| format!("{}x{}", width, height));
+    match (recorder.source_name(), size) {
+        (Some(name), Some(size)) => println!("Streaming {} ({}, {})", capture_source, name, size),
+        (Some(name), None) => println!("Streaming {} ({})", capture_source, name),
+        (None, Some(size)) => println!("Streaming {} ({})", capture_source, size),
+        (None, None) => println!("Streaming {}", capture_source),
+    }
+    let mixer = audio_mixer::AudioMixer::new();
+    
+    // Start system audio capture (requires BlackHole for system audio) plus the microphone
+    // We must keep _audio_capture alive - dropping it stops the capture
+    let audio_options = audio_capture::CaptureOptions {
+        mic: !cli.no_mic,
+        mic_name: cli.mic,
+        mic_gain: cli.mic_gain,
+        system_gain: cli.system_gain,
+    };
+    let (_audio_capture, audio_broadcast) = match audio_capture::start_audio_capture(&audio_options, mixer.input_sender()) {
+        Ok((capture, broadcast)) => {
+            println!("Audio capture enabled");
+            if broadcast.is_mixed() {
+                mixer.set_expected_sources(2);
+            }
+            (Some(capture), Some(broadcast))
+        }
+        Err(err) => {
+            eprintln!("Audio capture not available: {}", err);
+            eprintln!("For system audio, install BlackHole: brew install blackhole-2ch");
+            (None, None)
+        }
+    };
+    
+    let recorder = Arc::new(recorder);
+
+    let pip_source = match (cli.pip_window, cli.pip_monitor) {
+        (Some(id), _) => Some(recording::CaptureSource::Window { id, fps: cli.capture_fps }),
+        (None, Some(monitor_id)) => Some(recording::CaptureSource::Monitor(monitor_id)),
+        (None, None) => None,
+    };
+    // The pointer belongs to the primary source, so the inset is captured without it
+    let pip_options = recording::CaptureOptions {
+        show_cursor: false,
+        ..capture_options
+    };
+    let pip_recorder = pip_source.map(|source| match recording::Recorder::new(
+        source.clone(),
+        cli.pixel_format,
+        pip_options,
+    ) {
+        Ok(recorder) => {
+            println!("Picture-in-picture: {} in the {} corner", source, cli.pip_corner.as_str());
+            Arc::new(recorder)
+        }
+        Err(err) => {
+            eprintln!("Failed to start capture of {}: {}", source, err);
+            std::process::exit(1);
+        }
+    });
+    let pip = pip_recorder
+        .clone()
+        .map(|recorder| pip::Pip::start(recorder, cli.pip_corner, cli.pip_size));
+
+    let bitrate_bounds = shared_encoder::BitrateBounds {
+        min_bps: cli.min_bitrate_kbps.saturating_mul(1000),
+        max_bps: cli.max_bitrate_kbps.max(cli.min_bitrate_kbps).saturating_mul(1000),
+    };
+    let pipeline_options = video_pipeline::PipelineOptions {
+        rate_control: match (cli.rate_control, cli.bitrate_kbps) {
+            (video_pipeline::RateControl::Bitrate(0), Some(kbps)) => {
+                video_pipeline::RateControl::Bitrate(kbps.saturating_mul(1000))
+            }
+            (rate_control, _) => rate_control,
+        },
+        max_fps: if cli.encoder_fps > 0.0 { cli.encoder_fps } else { 60.0 },
+        keyframe_interval: (cli.keyframe_interval > 0).then(|| Duration::from_secs(cli.keyframe_interval)),
+        usage: cli.encoder_usage,
+        threads: cli.encoder_threads,
+        color_matrix: cli.color_matrix,
+        drop_streak: cli.drop_streak,
+        // Viewers' decoders are configured from the avcC description
+        output_format: video_pipeline::OutputFormat::Avcc,
+    };
+    let keyframe_request_interval = Duration::try_from_secs_f64(cli.keyframe_request_interval)
+        .ok()
+        .filter(|interval| !interval.is_zero());
+    let idle_timeout = (cli.idle_timeout > 0).then(|| Duration::from_secs(cli.idle_timeout));
+    let annotations = cli.allow_annotations.then(|| {
+        println!("Viewer annotations enabled");
+        Arc::new(annotate::Annotations::default())
+    });
+    let max_pixels = match (cli.max_resolution, cli.max_pixels) {
+        (Some(resolution), _) => Some(resolution.pixels()),
+        (None, Some(pixels)) => Some(pixels as usize),
+        (None, None) => None,
+    };
+    let encoders = match shared_encoder::EncoderLadder::start(
+        recorder.clone(),
+        bitrate_bounds,
+        pipeline_options,
+        max_pixels,
+        &cli.simulcast,
+        shared_encoder::Overlays {
+            pip: pip.clone(),
+            annotations: annotations.clone(),
+        },
+    ) {
+        Ok(encoders) => Some(encoders),
+        Err(err) => {
+            eprintln!("Video encoder not available: {}", err);
+            None
+        }
+    };
+
+    let hls = match (&encoders, cli.hls) {
+        (Some(encoders), true) => {
+            println!("HLS enabled at /hls/stream.m3u8");
+            Some(hls::HlsSegmenter::start(encoders.pick(None).1))
+        }
+        (None, true) => {
+            eprintln!("HLS needs a video encoder; not serving /hls");
+            None
+        }
+        (_, false) => None,
+    };
+
+    let clipboard = if cli.allow_clipboard {
+        match clipboard::ClipboardSync::start() {
+            Ok(clipboard) => {
+                println!("Clipboard sync enabled");
+                Some(Arc::new(clipboard))
+            }
+            Err(err) => {
+                eprintln!("Clipboard not available: {}", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut control_tokens: Vec<String> = cli.token.into_iter().chain(cli.token_control).collect();
+    if cli.require_auth && control_tokens.is_empty() && cli.token_view.is_empty() {
+        match auth::generate_token() {
+            Ok(token) => control_tokens.push(token),
+            Err(err) => {
+                eprintln!("Failed to generate an auth token: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let auth_tokens = auth::Tokens::new(control_tokens, cli.token_view).map(Arc::new);
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let state = AppState {
+        recorder: recorder.clone(),
+        encoders,
+        mixer: Arc::new(mixer),
+        audio_broadcast,
+        keyframe_request_interval,
+        start_muted: cli.start_muted,
+        audio_offset_ms: cli.audio_offset_ms,
+        clipboard,
+        auth: auth_tokens.clone(),
+        screen_permission,
+        shutdown: shutdown_rx.clone(),
+        sessions: Arc::new(status::SessionRegistry::new(idle_timeout, cli.max_sessions.map(|max| max as usize))),
+        resumable: Arc::new(resume::ResumeRegistry::default()),
+        started: Instant::now(),
+        pip,
+        annotations,
+        hls,
+        assets_dir: cli.assets_dir.as_deref().map(Arc::from),
+    };
+
+    #[cfg(unix)]
+    if !cli.no_control_socket {
+        let path = cli.control_socket.clone().unwrap_or_else(|| control::default_path(cli.port));
+        // Scripting is a convenience: serve normally without it
+        if let Err(err) = control::start(path, state.clone(), shutdown_rx.clone()) {
+            eprintln!("Control socket not available: {:#}", err);
+        }
+    }
+
+    let app = Router::new()
+        .route("/ws", get(get_ws))
+        .route("/screenshot.png", get(get_screenshot))
+        .route("/status", get(get_status))
+        .route("/hls/{file}", get(get_hls))
+        .fallback(get_asset)
+        .with_state(state);
+
+    let addr = SocketAddr::new(cli.bind, cli.port);
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) if err.kind() == std::io::ErrorKind::AddrInUse => {
+            eprintln!(
+                "Port {} is already in use on {}; is another foundry running? Try --port",
+                cli.port, cli.bind
+            );
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("Failed to listen on {}: {}", addr, err);
+            std::process::exit(1);
+        }
+    };
+    let port = listener.local_addr().map(|a| a.port()).unwrap_or(cli.port);
+    // The page reads the token from the fragment, which never reaches server logs
+    let fragment = auth_tokens
+        .as_ref()
+        .map(|tokens| format!("#token={}", tokens.link_token()))
+        .unwrap_or_default();
+    let scheme = if tls.is_some() { "https" } else { "http" };
+
+    // Discovery is a convenience: serve normally if the network won't have it
+    let advertisement = if cli.mdns {
+        let source = match recorder.source_name() {
+            Some(name) => format!("{} ({})", recorder.source(), name),
+            None => recorder.source().to_string(),
+        };
+        match mdns::Advertisement::register(port, &source, auth_tokens.is_some(), tls.is_some()) {
+            Ok(advertisement) => Some(advertisement),
+            Err(err) => {
+                eprintln!("mDNS advertisement failed, continuing without it: {:#}", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if cli.bind.is_unspecified() || cli.bind.is_loopback() {
+        println!("Open {}://localhost:{}/{}", scheme, port, fragment);
+    }
+    if !cli.bind.is_loopback() {
+        println!("Listening on {}://{}/{}", scheme, SocketAddr::new(cli.bind, port), fragment);
+    }
+
+    // First Ctrl-C closes sessions and drains the server; a second one, or
+    // missing the deadline, exits immediately
+    tokio::spawn(async move {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            eprintln!("Failed to listen for Ctrl-C: {}", err);
+            return;
+        }
+        println!("Shutting down (Ctrl-C again to force)...");
+        shutdown_tx.send_replace(true);
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => eprintln!("Forced exit"),
+            _ = tokio::time::sleep(SHUTDOWN_DEADLINE) => {
+                eprintln!("Shutdown took longer than {}s, exiting", SHUTDOWN_DEADLINE.as_secs());
+            }
+        }
+        std::process::exit(130);
+    });
+
+    match tls {
+        None => axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown_requested(shutdown_rx))
+            .await
+            .unwrap(),
+        Some(config) => {
+            let handle = axum_server::Handle::new();
+            let drain = handle.clone();
+            tokio::spawn(async move {
+                shutdown_requested(shutdown_rx).await;
+                drain.graceful_shutdown(None);
+            });
+            // Reuse the already-bound socket so the AddrInUse handling above applies
+            let listener = listener.into_std().unwrap();
+            axum_server::from_tcp_rustls(listener, config)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        }
+    }
+
+    if let Some(advertisement) = advertisement {
+        advertisement.shutdown();
+    }
+
+    // Sessions are gone; stop the capture thread rather than leaving it to the OS
+    _ = tokio::task::spawn_blocking(move || {
+        recorder.shutdown();
+        if let Some(pip_recorder) = pip_recorder {
+            pip_recorder.shutdown();
+        }
+    })
+    .await;
+    println!("Server stopped");
+}
+
+/// Resolves once Ctrl-C has been pressed
+async fn shutdown_requested(mut shutdown: watch::Receiver<bool>) {
+    _ = shutdown.wait_for(|stop| *stop).await;
+}
+
+/// `--ctl`: one round trip over the control socket of the server on --port
+#[cfg(unix)]
+async fn run_ctl(cli: &Cli, command: &str) {
+    let path = cli.control_socket.clone().unwrap_or_else(|| control::default_path(cli.port));
+    match control::send(&path, command).await {
+        Ok(reply) => println!("{}", reply),
+        Err(err) => {
+            eprintln!("Control command failed: {:#}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn run_ctl(_cli: &Cli, _command: &str) {
+    eprintln!("--ctl needs a Unix domain socket, which this platform doesn't have");
+    std::process::exit(1);
+}
+
+/// Parse `--bind`, accepting bracketed IPv6 like `[::]`
+fn parse_bind_addr(value: &str) -> Result<IpAddr, String> {
+    let trimmed = value
+        .strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .unwrap_or(value);
+    trimmed
+        .parse()
+        .map_err(|_| format!("invalid IP address: {}", value))
+}
+
+/// Run the click-to-select flow, exiting if nothing usable is picked
+fn pick_window(timeout: Option<u64>) -> u32 {
+    println!("Click on the window to stream...");
+    match window_pick::click_to_select(timeout.map(Duration::from_secs)) {
+        Ok(window) => {
+            println!(
+                "Picked window {}: {} (app: {})",
+                window.id,
+                window.title.as_deref().unwrap_or("<untitled>"),
+                window.app.as_deref().unwrap_or("<unknown>")
+            );
+            window.id
+        }
+        Err(err) => {
+            eprintln!("--pick failed: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Everything that isn't an API route is a web client file
+async fn get_asset(State(state): State<AppState>, uri: Uri, headers: HeaderMap) -> Response {
+    assets::serve(uri.path(), state.assets_dir.as_deref(), &headers).await
+}
+
+/// Whether an HTTP request may see the capture: always, unless the server has a token
+fn authorized(state: &AppState, supplied: Option<&str>) -> bool {
+    role(state, supplied).is_some()
+}
+
+/// What a token lets a session do; an open server gives everyone control
+fn role(state: &AppState, supplied: Option<&str>) -> Option<auth::Role> {
+    match &state.auth {
+        None => Some(auth::Role::Control),
+        Some(tokens) => tokens.role_for(supplied.unwrap_or_default()),
+    }
+}
+
+fn unauthorized() -> Response {
+    Response::builder()
+        .status(401)
+        .body(Body::from("unauthorized"))
+        .unwrap()
+}
+
+#[derive(Deserialize)]
+struct StatusQuery {
+    token: Option<String>,
+}
+
+/// What the server is doing, for supervision scripts
+async fn get_status(State(state): State<AppState>, Query(query): Query<StatusQuery>) -> Response {
+    if !authorized(&state, query.token.as_deref()) {
+        return unauthorized();
+    }
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .body(Body::from(status_json(&state).to_string()))
+        .unwrap()
+}
+
+/// Body of /status, also the control socket's `status` reply
+fn status_json(state: &AppState) -> serde_json::Value {
+    serde_json::json!({
+        "uptime_secs": state.started.elapsed().as_secs(),
+        "source": state.recorder.source_json(),
+        "capture": state.recorder.capture_json(),
+        "audio_capture": state.audio_broadcast.is_some(),
+        "video_encoder": state.encoders.is_some(),
+        "encoders": state.encoders.as_ref().map(|encoders| encoders.stats_json()),
+        "screen_recording_permission": state.screen_permission.as_str(),
+        "sessions": state.sessions.to_json(),
+    })
+}
+
+#[derive(Deserialize)]
+struct ScreenshotQuery {
+    /// Maximum width in pixels; the capture is box-filtered down to fit
+    width: Option<u32>,
+    /// Required when the server has an auth token, as for /ws
+    token: Option<String>,
+}
+
+async fn get_screenshot(
+    State(state): State<AppState>,
+    Query(query): Query<ScreenshotQuery>,
+) -> Response {
+    if !authorized(&state, query.token.as_deref()) {
+        return unauthorized();
+    }
+
+    let captured = match state.recorder.snapshot().await {
+        Ok(captured) => captured,
+        Err(err) => {
+            eprintln!("screenshot failed: {}", err);
+            return Response::builder()
+                .status(503)
+                .body(Body::from(err.to_string()))
+                .unwrap();
+        }
+    };
+
+    // PNG encoding of a full-resolution capture takes a while; keep it off the runtime
+    let pixel_format = state.recorder.pixel_format();
+    let (frame, stride) = (captured.frame, captured.stride_bytes);
+    match tokio::task::spawn_blocking(move || screenshot::encode_png(&frame, stride, query.width, pixel_format)).await {
+        Ok(Ok(png)) => Response::builder()
+            .header("Content-Type", "image/png")
+            .header("Cache-Control", "no-store")
+            .body(Body::from(png))
+            .unwrap(),
+        Ok(Err(err)) => Response::builder()
+            .status(400)
+            .body(Body::from(err.to_string()))
+            .unwrap(),
+        Err(err) => Response::builder()
+            .status(500)
+            .body(Body::from(err.to_string()))
+            .unwrap(),
+    }
+}
+
+/// `/hls/stream.m3u8` and the init/media segments it lists
+async fn get_hls(
+    State(state): State<AppState>,
+    Path(file): Path<String>,
+    Query(query): Query<StatusQuery>,
+    uri: Uri,
+) -> Response {
+    if !authorized(&state, query.token.as_deref()) {
+        return unauthorized();
+    }
+    let Some(hls) = &state.hls else {
+        return Response::builder()
+            .status(404)
+            .body(Body::from("HLS is off; start the server with --hls"))
+            .unwrap();
+    };
+
+    if file == "stream.m3u8" {
+        // Segment URIs carry the same query so ?token= keeps working for them
+        let query = uri.query().map(|q| format!("?{}", q)).unwrap_or_default();
+        return Response::builder()
+            .header("Content-Type", "application/vnd.apple.mpegurl")
+            .header("Cache-Control", "no-store")
+            .body(Body::from(hls.playlist(&query)))
+            .unwrap();
+    }
+    match hls.file(&file) {
+        Some(data) => Response::builder()
+            .header("Content-Type", if file.ends_with(".m4s") { "video/iso.segment" } else { "video/mp4" })
+            .header("Cache-Control", "max-age=60")
+            .body(Body::from(data))
+            .unwrap(),
+        None => Response::builder()
+            .status(404)
+            .body(Body::from("segment not in the HLS window"))
+            .unwrap(),
+    }
+}
+
+#[derive(Deserialize)]
+struct WsQuery {
+    token: Option<String>,
+}
+
+async fn get_ws(
+    State(state): State<AppState>,
+    Query(query): Query<WsQuery>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    // Without a query token the session may still authenticate via the mode message
+    let role = role(&state, query.token.as_deref());
+    ws.on_upgrade(move |socket| handle_ws(socket, state, role))
+}
+
+async fn handle_ws(stream: WebSocket, state: AppState, role: Option<auth::Role>) {
+    let shutdown = state.shutdown.clone();
+    serve_socket(stream, shutdown, move |receiver, tx, media| {
+        session::start(receiver, tx, media, state, role)
+    })
+    .await;
+}
+
+/// Run `session` on `stream`: it reads the client's messages and queues
+/// replies on `tx` and `media`, which this sends along with heartbeats. On
+/// shutdown the session is stopped and the client gets a Close frame.
+async fn serve_socket<S, F>(stream: WebSocket, shutdown: watch::Receiver<bool>, session: S)
+where
+    S: FnOnce(SplitStream<WebSocket>, mpsc::Sender<Message>, Arc<media_queue::MediaQueue>) -> F,
+    F: Future<Output = ()> + Send + 'static,
+{
+    let (mut sender, receiver) = stream.split();
+    let (tx, mut rx) = mpsc::channel::<Message>(OUTBOUND_BUFFER);
+    let media = Arc::new(media_queue::MediaQueue::default());
+    let close_tx = tx.clone();
+
+    // Task: push outbound messages (control, media, heartbeats) to the client.
+    // Control messages go first so configs and acks aren't stuck behind video.
+    let outbound_media = media.clone();
+    let mut outbound = tokio::spawn(async move {
+        let mut ticker = interval(Duration::from_secs(10));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        // Set while max_kbps holds media back; control messages still go out
+        let mut paced_until: Option<Instant> = None;
+
+        'send: loop {
+            tokio::select! {
+                biased;
+                msg = rx.recv() => {
+                    // None: the session and handle_ws are done with the socket
+                    let Some(msg) = msg else { break };
+                    let closing = matches!(msg, Message::Close(_));
+                    if sender.send(msg).await.is_err() || closing {
+                        break;
+                    }
+                }
+                _ = ticker.tick() => {
+                    if sender.send(Message::Text(Utf8Bytes::from("heartbeat"))).await.is_err() {
+                        break;
+                    }
+                }
+                _ = tokio::time::sleep_until(paced_until.unwrap_or_else(Instant::now).into()), if paced_until.is_some() => {
+                    paced_until = None;
+                }
+                messages = outbound_media.next(), if paced_until.is_none() => {
+                    paced_until = outbound_media.pace(&messages);
+                    for msg in messages {
+                        if sender.send(msg).await.is_err() {
+                            break 'send;
+                        }
+                    }
+                }
+            }
+        }
+    });
+
+    // Task: read inbound messages and decide what to do with them.
+    let mut inbound = tokio::spawn(session(receiver, tx, media));
+
+    // Either side finishing ends the session. On shutdown, stop the session's
+    // pipeline first so nothing is queued behind the Close frame.
+    tokio::select! {
+        _ = &mut outbound => {}
+        _ = &mut inbound => {
+            // Let anything the session queued last (errors, a Close) go out
+            drop(close_tx);
+            _ = timeout(Duration::from_secs(1), &mut outbound).await;
+        }
+        _ = shutdown_requested(shutdown) => {
+            inbound.abort();
+            let close = Message::Close(Some(CloseFrame {
+                code: close_code::AWAY,
+                reason: Utf8Bytes::from("server shutting down"),
+            }));
+            if close_tx.send(close).await.is_ok() {
+                _ = timeout(Duration::from_secs(1), &mut outbound).await;
+            }
+        }
+    }
+    outbound.abort();
+    inbound.abort();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::tungstenite;
+
+    #[tokio::test]
+    async fn shutdown_closes_connected_sockets() {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let sessions = shutdown_rx.clone();
+        // A session that never ends by itself
+        let app = Router::new().route(
+            "/ws",
+            get(move |ws: WebSocketUpgrade| async move {
+                ws.on_upgrade(move |socket| serve_socket(socket, sessions, |_, _, _| std::future::pending()))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown_requested(shutdown_rx))
+                .await
+        });
+
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        shutdown_tx.send_replace(true);
+        let close = timeout(Duration::from_secs(1), async {
+            while let Some(msg) = client.next().await {
+                if let tungstenite::Message::Close(frame) = msg.unwrap() {
+                    return frame;
+                }
+            }
+            None
+        })
+        .await
+        .expect("no Close within a second of shutdown")
+        .expect("Close without a frame");
+        assert_eq!(u16::from(close.code), close_code::AWAY);
+        assert_eq!(close.reason.as_str(), "server shutting down");
+
+        drop(client);
+        timeout(SHUTDOWN_DEADLINE, server)
+            .await
+            .expect("server still running after shutdown")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn connecting_and_disconnecting_is_reflected_in_status() {
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let registry = Arc::new(status::SessionRegistry::new(None, None));
+        let sessions = registry.clone();
+        // Registered until the client goes away, like session::start
+        let app = Router::new().route(
+            "/ws",
+            get(move |ws: WebSocketUpgrade| async move {
+                ws.on_upgrade(move |socket| {
+                    serve_socket(socket, shutdown_rx, move |mut receiver, _, _| async move {
+                        let _registration = sessions.register("h264", "1080p", auth::Role::Control);
+                        while let Some(Ok(_)) = receiver.next().await {}
+                    })
+                })
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut viewers = registry.subscribe_viewers();
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(1), viewers.wait_for(|count| *count == 1))
+            .await
+            .expect("session never registered")
+            .unwrap();
+        let status = registry.to_json();
+        assert_eq!(status["count"], 1);
+        assert_eq!(status["list"][0]["codec"], "h264");
+
+        client.close(None).await.unwrap();
+        timeout(Duration::from_secs(1), viewers.wait_for(|count| *count == 0))
+            .await
+            .expect("session still registered after disconnect")
+            .unwrap();
+        assert_eq!(registry.to_json()["count"], 0);
+    }
+}
@@ -0,0 +1,88 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+
+const SERVICE_TYPE: &str = "_foundry._tcp.local.";
+
+/// Bumped when the WebSocket protocol changes incompatibly; sent as TXT `version`
+const PROTOCOL_VERSION: &str = "1";
+
+/// How long `--discover` listens for announcements
+const DISCOVER_TIME: Duration = Duration::from_secs(3);
+
+/// A registered `_foundry._tcp` service (--mdns); unregister with `shutdown`
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Advertisement {
+    pub fn register(port: u16, source: &str, auth_required: bool, tls: bool) -> anyhow::Result<Self> {
+        let daemon = ServiceDaemon::new().context("failed to start mDNS daemon")?;
+        let host = hostname::get()
+            .ok()
+            .and_then(|h| h.into_string().ok())
+            .unwrap_or_else(|| "foundry".to_string());
+        let host = host.trim_end_matches(".local").to_string();
+        let instance = format!("foundry on {}", host);
+        let properties = [
+            ("version", PROTOCOL_VERSION),
+            ("source", source),
+            ("auth", if auth_required { "1" } else { "0" }),
+            ("tls", if tls { "1" } else { "0" }),
+        ];
+        let service = ServiceInfo::new(
+            SERVICE_TYPE,
+            &instance,
+            &format!("{}.local.", host),
+            "",
+            port,
+            &properties[..],
+        )?
+        .enable_addr_auto();
+        let fullname = service.get_fullname().to_string();
+        daemon.register(service).context("failed to register mDNS service")?;
+        println!("Advertising {} via mDNS", instance);
+        Ok(Self { daemon, fullname })
+    }
+
+    /// Send the goodbye packet so browsers drop us right away
+    pub fn shutdown(self) {
+        if let Ok(done) = self.daemon.unregister(&self.fullname) {
+            _ = done.recv_timeout(Duration::from_secs(1));
+        }
+        _ = self.daemon.shutdown();
+    }
+}
+
+/// Browse for foundry servers on the local network and print them as JSON (--discover)
+pub fn discover() -> anyhow::Result<()> {
+    let daemon = ServiceDaemon::new().context("failed to start mDNS daemon")?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+    let deadline = Instant::now() + DISCOVER_TIME;
+    let mut servers = Vec::new();
+
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        let Ok(event) = events.recv_timeout(remaining) else {
+            break;
+        };
+        if let ServiceEvent::ServiceResolved(info) = event {
+            let property = |key: &str| info.get_property_val_str(key).map(str::to_string);
+            servers.push(serde_json::json!({
+                "name": info.get_fullname().trim_end_matches(SERVICE_TYPE).trim_end_matches('.'),
+                "host": info.get_hostname(),
+                "port": info.get_port(),
+                "addresses": info.get_addresses().iter().map(|a| a.to_string()).collect::<Vec<_>>(),
+                "version": property("version"),
+                "source": property("source"),
+                "auth_required": property("auth").as_deref() == Some("1"),
+                "tls": property("tls").as_deref() == Some("1"),
+            }));
+        }
+    }
+    _ = daemon.shutdown();
+
+    println!("{}", serde_json::to_string_pretty(&servers)?);
+    Ok(())
+}
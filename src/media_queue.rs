@@ -0,0 +1,238 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{body::Bytes, extract::ws::Message};
+use tokio::sync::Notify;
+
+use crate::throttle::Pacer;
+
+/// Audio messages held per viewer; beyond this the oldest are dropped
+/// (~1 s of 20 ms Opus packets)
+const AUDIO_DEPTH: usize = 50;
+
+/// What happened to a frame handed to `push_video`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoPush {
+    /// Queued; `was_idle` when nothing else was waiting, i.e. the socket is keeping up
+    Queued { was_idle: bool },
+    /// A delta arrived while another was still unsent. Both were dropped (a
+    /// later delta can't be decoded without the earlier one), so the session
+    /// must skip to the next keyframe.
+    Overrun,
+}
+
+/// One encoded frame
+struct VideoEntry {
+    /// What must reach the client before the frame (a video-config, an fMP4
+    /// init segment); kept even if the frame itself is dropped
+    prefix: Vec<Message>,
+    payload: Vec<Message>,
+    keyframe: bool,
+}
+
+#[derive(Default)]
+struct Pending {
+    /// At most a keyframe followed by one delta
+    video: VecDeque<VideoEntry>,
+    /// Prefixes of dropped frames, sent ahead of the next frame
+    carry: Vec<Message>,
+    audio: VecDeque<Message>,
+}
+
+impl Pending {
+    fn drop_video(&mut self, keep: impl Fn(&VideoEntry) -> bool) {
+        let mut kept = VecDeque::with_capacity(self.video.len());
+        for entry in self.video.drain(..) {
+            if keep(&entry) {
+                kept.push_back(entry);
+            } else {
+                self.carry.extend(entry.prefix);
+            }
+        }
+        self.video = kept;
+    }
+}
+
+/// Media waiting for one viewer's socket. Unlike the control channel this
+/// never builds a backlog: video keeps only the newest decodable frames and
+/// audio drops its oldest chunks, so a hiccup costs frames, not latency.
+#[derive(Default)]
+pub struct MediaQueue {
+    pending: Mutex<Pending>,
+    ready: Notify,
+    /// Bandwidth cap on media (max_kbps); control messages are never paced
+    pacer: Mutex<Option<Pacer>>,
+}
+
+/// What the pacer saw over one server-stats interval
+pub struct PacingWindow {
+    pub max_kbps: u32,
+    pub bytes_sent: u64,
+    /// Time media was held back to stay under the cap
+    pub delayed: Duration,
+}
+
+impl MediaQueue {
+    /// Queue a frame. A keyframe replaces everything still pending (nothing
+    /// before it is needed to decode it); a delta never displaces a keyframe.
+    pub fn push_video(&self, prefix: Vec<Message>, payload: Vec<Message>, keyframe: bool) -> VideoPush {
+        let mut pending = self.pending.lock().unwrap();
+        let was_idle = pending.video.is_empty();
+        if keyframe {
+            pending.drop_video(|_| false);
+        } else if pending.video.iter().any(|entry| !entry.keyframe) {
+            pending.drop_video(|entry| entry.keyframe);
+            pending.carry.extend(prefix);
+            return VideoPush::Overrun;
+        }
+        let mut prefix_with_carry = std::mem::take(&mut pending.carry);
+        prefix_with_carry.extend(prefix);
+        pending.video.push_back(VideoEntry {
+            prefix: prefix_with_carry,
+            payload,
+            keyframe,
+        });
+        drop(pending);
+        self.ready.notify_one();
+        VideoPush::Queued { was_idle }
+    }
+
+    /// Drop unsent video, e.g. when the viewer moves to another encoder
+    pub fn clear_video(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.video.clear();
+        pending.carry.clear();
+    }
+
+    /// Queue audio packets in order, dropping the oldest beyond AUDIO_DEPTH
+    pub fn push_audio(&self, packets: Vec<Bytes>) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.audio.extend(packets.into_iter().map(Message::Binary));
+        let excess = pending.audio.len().saturating_sub(AUDIO_DEPTH);
+        pending.audio.drain(..excess);
+        drop(pending);
+        self.ready.notify_one();
+    }
+
+    /// Cap media to `max_kbps`; None sends as fast as the socket allows
+    pub fn set_max_kbps(&self, max_kbps: Option<u32>) {
+        *self.pacer.lock().unwrap() = max_kbps.map(Pacer::new);
+    }
+
+    /// Account for media just written; returns when the next may go out,
+    /// or None when uncapped or already due
+    pub fn pace(&self, messages: &[Message]) -> Option<Instant> {
+        let mut pacer = self.pacer.lock().unwrap();
+        let pacer = pacer.as_mut()?;
+        let bytes = messages
+            .iter()
+            .map(|msg| match msg {
+                Message::Binary(data) => data.len(),
+                _ => 0,
+            })
+            .sum();
+        let now = Instant::now();
+        Some(pacer.sent(bytes, now)).filter(|ready| *ready > now)
+    }
+
+    /// The pacer's interval that just ended; None when uncapped
+    pub fn take_pacing(&self) -> Option<PacingWindow> {
+        let mut pacer = self.pacer.lock().unwrap();
+        let pacer = pacer.as_mut()?;
+        let (bytes_sent, delayed) = pacer.take_window();
+        Some(PacingWindow {
+            max_kbps: pacer.max_kbps(),
+            bytes_sent,
+            delayed,
+        })
+    }
+
+    /// Wait for the next messages to write: one audio chunk, or one frame with
+    /// its prefix. Audio goes first since it is small and glitches are audible.
+    /// Cancel-safe: nothing is taken until it is returned.
+    pub async fn next(&self) -> Vec<Message> {
+        loop {
+            let notified = self.ready.notified();
+            {
+                let mut pending = self.pending.lock().unwrap();
+                if let Some(audio) = pending.audio.pop_front() {
+                    return vec![audio];
+                }
+                if let Some(mut entry) = pending.video.pop_front() {
+                    entry.prefix.extend(entry.payload);
+                    return entry.prefix;
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+
+    fn packet(tag: u8) -> Message {
+        Message::Binary(Bytes::from(vec![tag]))
+    }
+
+    /// Everything `next` hands out without waiting
+    fn drain(queue: &MediaQueue) -> Vec<Vec<Message>> {
+        std::iter::from_fn(|| queue.next().now_or_never()).collect()
+    }
+
+    #[test]
+    fn keyframe_replaces_stale_deltas() {
+        let queue = MediaQueue::default();
+        assert_eq!(queue.push_video(vec![], vec![packet(1)], false), VideoPush::Queued { was_idle: true });
+        assert_eq!(queue.push_video(vec![], vec![packet(2)], true), VideoPush::Queued { was_idle: false });
+        assert_eq!(drain(&queue), vec![vec![packet(2)]]);
+    }
+
+    #[test]
+    fn second_unsent_delta_overruns_but_keeps_the_keyframe() {
+        let queue = MediaQueue::default();
+        let config = Message::Text("video-config".into());
+        queue.push_video(vec![config.clone()], vec![packet(1)], true);
+        assert_eq!(queue.push_video(vec![], vec![packet(2)], false), VideoPush::Queued { was_idle: false });
+        assert_eq!(queue.push_video(vec![], vec![packet(3)], false), VideoPush::Overrun);
+        assert_eq!(drain(&queue), vec![vec![config, packet(1)]]);
+    }
+
+    #[test]
+    fn prefixes_of_dropped_frames_go_out_with_the_next_one() {
+        let queue = MediaQueue::default();
+        let init = Message::Text("init".into());
+        queue.push_video(vec![init.clone()], vec![packet(1)], false);
+        assert_eq!(queue.push_video(vec![], vec![packet(2)], false), VideoPush::Overrun);
+        assert!(drain(&queue).is_empty());
+        queue.push_video(vec![], vec![packet(3)], true);
+        assert_eq!(drain(&queue), vec![vec![init, packet(3)]]);
+    }
+
+    #[test]
+    fn audio_goes_first_in_order_and_drops_the_oldest() {
+        let queue = MediaQueue::default();
+        queue.push_video(vec![], vec![packet(0)], true);
+        queue.push_audio((0..AUDIO_DEPTH as u8 + 10).map(|tag| Bytes::from(vec![tag])).collect());
+        let mut sent = drain(&queue);
+        assert_eq!(sent.pop(), Some(vec![packet(0)]));
+        let audio: Vec<_> = (10..AUDIO_DEPTH as u8 + 10).map(|tag| vec![packet(tag)]).collect();
+        assert_eq!(sent, audio);
+    }
+
+    #[test]
+    fn cleared_video_is_not_sent() {
+        let queue = MediaQueue::default();
+        queue.push_video(vec![Message::Text("init".into())], vec![packet(1)], false);
+        queue.push_video(vec![], vec![packet(2)], false);
+        queue.clear_video();
+        assert!(drain(&queue).is_empty());
+        assert_eq!(queue.push_video(vec![], vec![packet(3)], true), VideoPush::Queued { was_idle: true });
+        assert_eq!(drain(&queue), vec![vec![packet(3)]]);
+    }
+}
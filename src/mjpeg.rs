@@ -0,0 +1,148 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use anyhow::Result;
+#[cfg(not(feature = "mjpeg"))]
+use anyhow::anyhow;
+use axum::{body::Bytes, extract::ws::Message};
+use tokio::task::JoinHandle;
+use xcap::Frame;
+
+#[cfg(feature = "mjpeg")]
+use crate::{
+    recording::packed_stride,
+    scaler::average_area,
+    video_pipeline::VideoPipeline,
+};
+use crate::{
+    media_queue::MediaQueue,
+    recording::{Listener, ListenerOptions, Recorder},
+    screenshot,
+    video_pipeline::PixelFormat,
+};
+
+/// Whether this build can send JPEG stills when there is no H.264 encoder
+pub const AVAILABLE: bool = cfg!(feature = "mjpeg");
+
+/// Every frame is a whole JPEG, so the rate stays well below H.264's
+const MAX_FPS: u32 = 10;
+#[cfg(feature = "mjpeg")]
+const QUALITY: u8 = 70;
+/// Frames are shrunk to fit about 1280x720
+#[cfg(feature = "mjpeg")]
+const MAX_PIXELS: usize = 1280 * 720;
+
+/// Why a video session got JPEG stills instead of H.264
+pub fn fallback_reason() -> &'static str {
+    if cfg!(any(feature = "openh264-encoder", feature = "videotoolbox")) {
+        "encoder-init-failed"
+    } else {
+        "no-h264-encoder"
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    frames: AtomicU64,
+    bytes: AtomicU64,
+}
+
+/// One viewer's JPEG stream. Frames go out as IMG0 messages with an empty id
+/// (screenshot replies always carry one); capture stops when this is dropped.
+pub struct MjpegStream {
+    task: JoinHandle<()>,
+    counters: Arc<Counters>,
+}
+
+impl MjpegStream {
+    pub fn start(recorder: &Recorder, media: Arc<MediaQueue>) -> Self {
+        let counters = Arc::new(Counters::default());
+        let frames = recorder.new_listener_with_options(ListenerOptions {
+            max_fps: Some(MAX_FPS as f32),
+            ..Default::default()
+        });
+        let task = tokio::spawn(run(frames, recorder.pixel_format(), media, counters.clone()));
+        Self { task, counters }
+    }
+
+    /// Frames and bytes queued since the previous call
+    pub fn take_counts(&self) -> (u64, u64) {
+        (
+            self.counters.frames.swap(0, Ordering::Relaxed),
+            self.counters.bytes.swap(0, Ordering::Relaxed),
+        )
+    }
+}
+
+impl Drop for MjpegStream {
+    fn drop(&mut self) {
+        // Dropping the listener detaches it from capture on the next frame
+        self.task.abort();
+    }
+}
+
+async fn run(mut frames: Listener, pixel_format: PixelFormat, media: Arc<MediaQueue>, counters: Arc<Counters>) {
+    while let Some(captured) = frames.recv().await {
+        let (frame, stride) = (captured.frame, captured.stride_bytes);
+        let jpeg = match tokio::task::spawn_blocking(move || encode_jpeg(&frame, stride, pixel_format)).await {
+            Ok(Ok(jpeg)) => jpeg,
+            Ok(Err(err)) => {
+                eprintln!("jpeg encode failed: {err}");
+                continue;
+            }
+            Err(err) => {
+                eprintln!("jpeg encoder task failed: {err}");
+                break;
+            }
+        };
+        let packet = screenshot::build_image_packet("", &jpeg);
+        counters.frames.fetch_add(1, Ordering::Relaxed);
+        counters.bytes.fetch_add(packet.len() as u64, Ordering::Relaxed);
+        // Each still stands alone, so a slow socket just skips to the newest
+        media.push_video(Vec::new(), vec![Message::Binary(Bytes::from(packet))], true);
+    }
+}
+
+/// Box-filter the frame (rows `stride` bytes apart) down to MAX_PIXELS with
+/// an integer block size; None when it already fits
+#[cfg(feature = "mjpeg")]
+fn shrink(frame: &Frame, stride: usize) -> Option<Frame> {
+    let pixels = frame.width as usize * frame.height as usize;
+    let block = ((pixels as f64 / MAX_PIXELS as f64).sqrt().ceil() as usize).max(1);
+    let (dst_w, dst_h) = (frame.width as usize / block, frame.height as usize / block);
+    if block == 1 || dst_w == 0 || dst_h == 0 {
+        return None;
+    }
+    let mut dst = vec![0u8; dst_w * dst_h * 4];
+    average_area(
+        &frame.raw,
+        frame.width as usize,
+        frame.height as usize,
+        stride,
+        &mut dst,
+        dst_w,
+        dst_h,
+    );
+    Some(Frame {
+        width: dst_w as u32,
+        height: dst_h as u32,
+        raw: dst,
+    })
+}
+
+#[cfg(feature = "mjpeg")]
+fn encode_jpeg(frame: &Frame, stride: usize, pixel_format: PixelFormat) -> Result<Vec<u8>> {
+    let shrunk = shrink(frame, stride);
+    let (frame, stride) = match &shrunk {
+        Some(shrunk) => (shrunk, packed_stride(shrunk)),
+        None => (frame, stride),
+    };
+    VideoPipeline::snapshot(frame, stride, pixel_format, xcap::image::ImageFormat::Jpeg, QUALITY)
+}
+
+#[cfg(not(feature = "mjpeg"))]
+fn encode_jpeg(_frame: &Frame, _stride: usize, _pixel_format: PixelFormat) -> Result<Vec<u8>> {
+    Err(anyhow!("built without the mjpeg feature"))
+}
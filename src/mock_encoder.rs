@@ -0,0 +1,63 @@
+//! Stand-in backend for tests that drive the encode loop without openh264:
+//! it counts the frames it is given and hands back a tiny chunk for each
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use crate::video_pipeline::{EncodedChunk, PipelineFrame, VideoCodec, VideoConfig};
+
+/// Counterpart of the openh264 `EncoderImpl`, for `VideoPipeline::with_mock`
+pub struct MockEncoder {
+    width: u32,
+    height: u32,
+    /// Frames encoded so far, readable by the test while the encoder runs
+    pub encodes: Arc<AtomicU64>,
+    /// How long each encode blocks its thread, like a slow software encoder
+    pub delay: Duration,
+    pub bitrate_bps: u32,
+    pub idr_interval_frames: u32,
+}
+
+impl MockEncoder {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            width: 0,
+            height: 0,
+            encodes: Arc::default(),
+            delay,
+            bitrate_bps: 1_000_000,
+            idr_interval_frames: 0,
+        }
+    }
+
+    pub fn set_bitrate(&mut self, bitrate_bps: u32) -> Result<()> {
+        self.bitrate_bps = bitrate_bps;
+        Ok(())
+    }
+
+    /// A placeholder description, so the pipeline hands chunks out from the first frame
+    pub fn config(&self) -> VideoConfig {
+        VideoConfig {
+            codec: VideoCodec::Avc,
+            width: self.width,
+            height: self.height,
+            description_b64: "bW9jaw==".to_string(),
+            color_matrix: Default::default(),
+        }
+    }
+
+    /// A chunk holding the running frame number
+    pub fn encode(&mut self, frame: &PipelineFrame, force_idr: bool) -> Result<Option<EncodedChunk>> {
+        std::thread::sleep(self.delay);
+        (self.width, self.height) = (frame.width(), frame.height());
+        let number = self.encodes.fetch_add(1, Ordering::Relaxed);
+        Ok(Some(EncodedChunk::new(number.to_le_bytes().to_vec(), force_idr)))
+    }
+}
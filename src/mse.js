@@ -0,0 +1,212 @@
+// Media Source Extensions playback for the fMP4 transport, for browsers
+// without WebCodecs. Same interface as createVideoController in video.js:
+// the server's init and media segments go through enqueueChunk, and frames
+// are drawn from a hidden <video> element.
+
+// Seconds of media kept behind the playhead
+const MSE_KEEP_SECONDS = 10;
+// Jump back to the live edge once playback falls this far behind it
+const MSE_MAX_LATENCY_SECONDS = 0.5;
+
+export function isMseSupported() {
+  return typeof MediaSource === "function";
+}
+
+export function createMseVideoController({
+  canvas,
+  renderTarget,
+  log = () => {},
+  onFrame = () => {},
+  onFrameBitmap,
+  onFrameSizeChanged,
+  autoCloseBitmap = true,
+} = {}) {
+  const target = renderTarget ?? (canvas ? "canvas" : "bitmap");
+
+  if (target === "canvas" && !canvas) {
+    throw new Error("Canvas element is required for video rendering");
+  }
+  if (target !== "canvas" && typeof onFrameBitmap !== "function") {
+    throw new Error(
+      "onFrameBitmap callback is required when renderTarget is not canvas",
+    );
+  }
+
+  const ctx = target === "canvas" ? canvas.getContext("2d") : null;
+
+  const resizeCanvas = () => {
+    if (target !== "canvas") return;
+    const dpr = window.devicePixelRatio || 1;
+    const displayWidth = Math.floor(window.innerWidth);
+    const displayHeight = Math.floor(window.innerHeight);
+    canvas.width = Math.floor(displayWidth * dpr);
+    canvas.height = Math.floor(displayHeight * dpr);
+    canvas.style.width = `${displayWidth}px`;
+    canvas.style.height = `${displayHeight}px`;
+  };
+
+  if (target === "canvas") {
+    window.addEventListener("resize", resizeCanvas);
+    resizeCanvas();
+  }
+
+  const video = document.createElement("video");
+  video.muted = true;
+  video.playsInline = true;
+  video.autoplay = true;
+
+  const mediaSource = new MediaSource();
+  video.src = URL.createObjectURL(mediaSource);
+
+  let sourceBuffer = null;
+  let mimeType = null;
+  let pending = [];
+  let disposed = false;
+  let lastFrameWidth = 0;
+  let lastFrameHeight = 0;
+
+  const sourceOpen = new Promise((resolve) => {
+    mediaSource.addEventListener("sourceopen", resolve, { once: true });
+  });
+
+  async function configureDecoder(config) {
+    const codec = config?.mse_codec;
+    if (!codec) {
+      log("fmp4: video-config has no mse_codec");
+      return;
+    }
+    const type = `video/mp4; codecs="${codec}"`;
+    if (type === mimeType) return;
+    if (!MediaSource.isTypeSupported(type)) {
+      log(`fmp4: ${type} not supported`);
+      return;
+    }
+    await sourceOpen;
+    if (disposed) return;
+    if (sourceBuffer) {
+      // New profile/level mid-stream; the next init segment follows
+      sourceBuffer.changeType?.(type);
+    } else {
+      sourceBuffer = mediaSource.addSourceBuffer(type);
+      sourceBuffer.mode = "segments";
+      sourceBuffer.addEventListener("updateend", onUpdateEnd);
+      sourceBuffer.addEventListener("error", () => log("fmp4: SourceBuffer error"));
+    }
+    mimeType = type;
+    log(`fmp4: ${type}`);
+    appendNext();
+  }
+
+  function enqueueChunk(chunk) {
+    pending.push(chunk);
+    appendNext();
+  }
+
+  function appendNext() {
+    if (!sourceBuffer || sourceBuffer.updating || !pending.length) return;
+    const chunk = pending.shift();
+    try {
+      sourceBuffer.appendBuffer(chunk);
+    } catch (err) {
+      // QuotaExceededError: drop what's queued and let eviction catch up
+      log(`fmp4 append failed: ${err?.message ?? err}`);
+      pending = [];
+    }
+  }
+
+  function onUpdateEnd() {
+    const buffered = sourceBuffer.buffered;
+    if (buffered.length) {
+      const end = buffered.end(buffered.length - 1);
+      if (end - video.currentTime > MSE_MAX_LATENCY_SECONDS) {
+        video.currentTime = end - 0.05;
+      }
+      const start = buffered.start(0);
+      if (video.currentTime - start > MSE_KEEP_SECONDS * 2) {
+        sourceBuffer.remove(start, video.currentTime - MSE_KEEP_SECONDS);
+        return;
+      }
+    }
+    if (video.paused) {
+      video.play().catch(() => {});
+    }
+    appendNext();
+  }
+
+  function drawFrame() {
+    const fw = video.videoWidth;
+    const fh = video.videoHeight;
+    if (!fw || !fh) return;
+    onFrame();
+
+    const sizeChanged = fw !== lastFrameWidth || fh !== lastFrameHeight;
+    if (sizeChanged) {
+      lastFrameWidth = fw;
+      lastFrameHeight = fh;
+      onFrameSizeChanged?.(fw, fh);
+    }
+
+    if (target === "canvas" && ctx) {
+      ctx.save();
+      ctx.clearRect(0, 0, canvas.width, canvas.height);
+      ctx.imageSmoothingEnabled = true;
+      const scale = Math.min(canvas.width / fw, canvas.height / fh);
+      const drawWidth = Math.floor(fw * scale);
+      const drawHeight = Math.floor(fh * scale);
+      const dx = Math.floor((canvas.width - drawWidth) / 2);
+      const dy = Math.floor((canvas.height - drawHeight) / 2);
+      ctx.drawImage(video, dx, dy, drawWidth, drawHeight);
+      ctx.restore();
+      return;
+    }
+
+    createImageBitmap(video).then((bitmap) => {
+      try {
+        onFrameBitmap?.(bitmap, fw, fh, sizeChanged);
+      } finally {
+        if (autoCloseBitmap) {
+          bitmap.close?.();
+        }
+      }
+    });
+  }
+
+  // Draw each presented frame; browsers without requestVideoFrameCallback
+  // poll on animation frames instead
+  function scheduleDraw() {
+    if (disposed) return;
+    if (video.requestVideoFrameCallback) {
+      video.requestVideoFrameCallback(() => {
+        drawFrame();
+        scheduleDraw();
+      });
+    } else {
+      let lastTime = -1;
+      const poll = () => {
+        if (disposed) return;
+        if (video.currentTime !== lastTime) {
+          lastTime = video.currentTime;
+          drawFrame();
+        }
+        requestAnimationFrame(poll);
+      };
+      requestAnimationFrame(poll);
+    }
+  }
+  scheduleDraw();
+
+  function dispose() {
+    disposed = true;
+    if (target === "canvas") {
+      window.removeEventListener("resize", resizeCanvas);
+    }
+    video.pause();
+    URL.revokeObjectURL(video.src);
+  }
+
+  return {
+    enqueueChunk,
+    configureDecoder,
+    dispose,
+  };
+}
@@ -0,0 +1,155 @@
+use anyhow::Result;
+#[cfg(not(feature = "opus"))]
+use anyhow::anyhow;
+use axum::body::Bytes;
+
+#[cfg(feature = "opus")]
+use crate::audio_capture::Resampler;
+
+/// Whether this build can answer `"audio":"opus"` in the mode message
+pub const AVAILABLE: bool = cfg!(feature = "opus");
+
+/// Opus runs at 48 kHz stereo; other capture rates are resampled
+#[cfg(feature = "opus")]
+const OPUS_RATE: u32 = 48_000;
+/// 20 ms frames
+#[cfg(feature = "opus")]
+const FRAME_SAMPLES: usize = OPUS_RATE as usize / 50;
+#[cfg(feature = "opus")]
+const OPUS_BITRATE_BPS: i32 = 96_000;
+
+/// Per-session Opus encoder turning AUD0-style PCM chunks into OPS0 packets
+pub struct OpusStream {
+    inner: EncoderImpl,
+}
+
+impl OpusStream {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            inner: EncoderImpl::new()?,
+        })
+    }
+
+    /// Buffer a PCM chunk and return an OPS0 packet for every complete 20 ms frame
+    pub fn push(&mut self, sample_rate: u32, channels: u32, samples: &[i16]) -> Vec<Bytes> {
+        match self.inner.push(sample_rate, channels, samples) {
+            Ok(packets) => packets,
+            Err(err) => {
+                eprintln!("opus encode failed: {err}");
+                Vec::new()
+            }
+        }
+    }
+}
+
+#[cfg(feature = "opus")]
+struct EncoderImpl {
+    encoder: audiopus::coder::Encoder,
+    input_rate: u32,
+    resampler: Resampler,
+    /// Interleaved stereo at OPUS_RATE waiting for a full frame
+    pending: Vec<i16>,
+    frames: Vec<[f32; 2]>,
+    resampled: Vec<[f32; 2]>,
+    /// Samples per channel encoded so far; the packet timestamp base
+    samples_encoded: u64,
+    out: Vec<u8>,
+}
+
+#[cfg(feature = "opus")]
+impl EncoderImpl {
+    fn new() -> Result<Self> {
+        use audiopus::{coder::Encoder, Application, Bitrate, Channels, SampleRate};
+
+        let mut encoder = Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio)?;
+        encoder.set_bitrate(Bitrate::BitsPerSecond(OPUS_BITRATE_BPS))?;
+        Ok(Self {
+            encoder,
+            input_rate: OPUS_RATE,
+            resampler: Resampler::new(OPUS_RATE, OPUS_RATE),
+            pending: Vec::new(),
+            frames: Vec::new(),
+            resampled: Vec::new(),
+            samples_encoded: 0,
+            // Opus' recommended maximum packet size
+            out: vec![0; 4000],
+        })
+    }
+
+    fn push(&mut self, sample_rate: u32, channels: u32, samples: &[i16]) -> Result<Vec<Bytes>> {
+        if channels == 0 || sample_rate == 0 {
+            return Ok(Vec::new());
+        }
+        if sample_rate != self.input_rate {
+            self.input_rate = sample_rate;
+            self.resampler = Resampler::new(sample_rate, OPUS_RATE);
+        }
+
+        self.frames.clear();
+        self.frames.extend(samples.chunks_exact(channels as usize).map(|f| {
+            let left = f[0] as f32 / 32768.0;
+            let right = f.get(1).map_or(left, |s| *s as f32 / 32768.0);
+            [left, right]
+        }));
+        self.resampled.clear();
+        self.resampler.process(&self.frames, &mut self.resampled);
+        for frame in &self.resampled {
+            for s in frame {
+                self.pending.push((s * 32767.0).clamp(-32768.0, 32767.0) as i16);
+            }
+        }
+
+        let mut packets = Vec::new();
+        while self.pending.len() >= FRAME_SAMPLES * 2 {
+            let frame: Vec<i16> = self.pending.drain(..FRAME_SAMPLES * 2).collect();
+            let len = self.encoder.encode(&frame, &mut self.out)?;
+            let timestamp_ms = self.samples_encoded as f64 * 1000.0 / OPUS_RATE as f64;
+            self.samples_encoded += FRAME_SAMPLES as u64;
+            packets.push(build_opus_packet(
+                timestamp_ms,
+                OPUS_RATE,
+                2,
+                FRAME_SAMPLES as u32,
+                &self.out[..len],
+            ));
+        }
+        Ok(packets)
+    }
+}
+
+#[cfg(not(feature = "opus"))]
+struct EncoderImpl;
+
+#[cfg(not(feature = "opus"))]
+impl EncoderImpl {
+    fn new() -> Result<Self> {
+        Err(anyhow!("opus feature not enabled"))
+    }
+
+    fn push(&mut self, _sample_rate: u32, _channels: u32, _samples: &[i16]) -> Result<Vec<Bytes>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Build an Opus packet: "OPS0" header followed by one encoded frame.
+///
+/// Layout (little-endian): magic[4], timestamp_ms f64, sample_rate u32,
+/// channels u32, frame_samples u32 (per channel), payload_len u32, payload.
+#[cfg_attr(not(feature = "opus"), allow(dead_code))]
+fn build_opus_packet(
+    timestamp_ms: f64,
+    sample_rate: u32,
+    channels: u32,
+    frame_samples: u32,
+    payload: &[u8],
+) -> Bytes {
+    let mut out = Vec::with_capacity(28 + payload.len());
+    out.extend_from_slice(b"OPS0");
+    out.extend_from_slice(&timestamp_ms.to_le_bytes());
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&channels.to_le_bytes());
+    out.extend_from_slice(&frame_samples.to_le_bytes());
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(payload);
+    Bytes::from(out)
+}
@@ -0,0 +1,86 @@
+/// Whether macOS lets this process capture the screen. Without the Screen
+/// Recording permission captures come back black and windows have no titles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScreenPermission {
+    #[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+    Granted,
+    Denied,
+    /// Platforms without a screen-capture permission
+    NotRequired,
+}
+
+impl ScreenPermission {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ScreenPermission::Granted => "granted",
+            ScreenPermission::Denied => "denied",
+            ScreenPermission::NotRequired => "not-required",
+        }
+    }
+
+    pub fn is_denied(self) -> bool {
+        self == ScreenPermission::Denied
+    }
+}
+
+/// Current Screen Recording permission, without prompting
+pub fn screen_recording() -> ScreenPermission {
+    platform::preflight()
+}
+
+/// Show the system prompt (macOS only shows it once per app) and return the
+/// current state. A grant only takes effect after the process restarts.
+pub fn request_screen_recording() -> ScreenPermission {
+    platform::request()
+}
+
+/// How to fix a denied permission, for the terminal
+pub fn print_instructions() {
+    eprintln!("Screen Recording permission is not granted; the capture would be black.");
+    eprintln!("To fix it:");
+    eprintln!("  1. Open System Settings > Privacy & Security > Screen & System Audio Recording");
+    eprintln!("     (Screen Recording on macOS 14 and earlier)");
+    eprintln!("  2. Enable the app that runs foundry (Terminal, iTerm, your IDE, ...)");
+    eprintln!("  3. Quit and reopen that app, then start foundry again");
+    eprintln!("Run with --request-permissions to have macOS add the app to the list for you.");
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::ScreenPermission;
+
+    #[link(name = "CoreGraphics", kind = "framework")]
+    extern "C" {
+        fn CGPreflightScreenCaptureAccess() -> bool;
+        fn CGRequestScreenCaptureAccess() -> bool;
+    }
+
+    pub fn preflight() -> ScreenPermission {
+        if unsafe { CGPreflightScreenCaptureAccess() } {
+            ScreenPermission::Granted
+        } else {
+            ScreenPermission::Denied
+        }
+    }
+
+    pub fn request() -> ScreenPermission {
+        if unsafe { CGRequestScreenCaptureAccess() } {
+            ScreenPermission::Granted
+        } else {
+            ScreenPermission::Denied
+        }
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+mod platform {
+    use super::ScreenPermission;
+
+    pub fn preflight() -> ScreenPermission {
+        ScreenPermission::NotRequired
+    }
+
+    pub fn request() -> ScreenPermission {
+        ScreenPermission::NotRequired
+    }
+}
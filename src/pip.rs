@@ -0,0 +1,252 @@
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use xcap::Frame;
+
+use crate::{
+    frame_pool::FramePool,
+    recording::{CapturedFrame, Recorder},
+};
+
+/// Gap between the inset and the edges of the picture, as a fraction of its width
+const MARGIN_FRACTION: f32 = 0.02;
+
+/// Smallest and largest inset width, as a fraction of the primary's width
+const MIN_SIZE: f32 = 0.05;
+const MAX_SIZE: f32 = 1.0;
+
+/// Corner of the primary picture the inset is drawn in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Corner {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Corner::TopLeft => "top-left",
+            Corner::TopRight => "top-right",
+            Corner::BottomLeft => "bottom-left",
+            Corner::BottomRight => "bottom-right",
+        }
+    }
+}
+
+impl FromStr for Corner {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "top-left" => Ok(Corner::TopLeft),
+            "top-right" => Ok(Corner::TopRight),
+            "bottom-left" => Ok(Corner::BottomLeft),
+            "bottom-right" => Ok(Corner::BottomRight),
+            other => Err(format!(
+                "unknown corner `{}` (expected top-left, top-right, bottom-left or bottom-right)",
+                other
+            )),
+        }
+    }
+}
+
+/// Where and how large the inset is drawn; changed at runtime by `pip` messages
+#[derive(Debug, Clone, Copy)]
+pub struct PipLayout {
+    pub corner: Corner,
+    /// Inset width as a fraction of the primary's width
+    pub size: f32,
+    pub visible: bool,
+}
+
+impl PipLayout {
+    pub fn to_json(self) -> serde_json::Value {
+        serde_json::json!({
+            "corner": self.corner.as_str(),
+            "size": self.size,
+            "visible": self.visible,
+        })
+    }
+}
+
+/// A second capture source drawn as an inset over the primary (--pip-window/--pip-monitor)
+pub struct Pip {
+    recorder: Arc<Recorder>,
+    layout: Mutex<PipLayout>,
+    /// Most recent secondary frame; reused until the next one arrives, so the
+    /// two sources can run at different rates
+    latest: Mutex<Option<CapturedFrame>>,
+    /// Compositors currently drawing the inset; the secondary is only captured while > 0
+    users: AtomicUsize,
+    user_joined: Notify,
+}
+
+impl Pip {
+    pub fn start(recorder: Arc<Recorder>, corner: Corner, size: f32) -> Arc<Self> {
+        let pip = Arc::new(Self {
+            recorder,
+            layout: Mutex::new(PipLayout {
+                corner,
+                size: size.clamp(MIN_SIZE, MAX_SIZE),
+                visible: true,
+            }),
+            latest: Mutex::new(None),
+            users: AtomicUsize::new(0),
+            user_joined: Notify::new(),
+        });
+        tokio::spawn(run_feed(pip.clone()));
+        pip
+    }
+
+    pub fn layout(&self) -> PipLayout {
+        *self.layout.lock().unwrap()
+    }
+
+    /// Apply a `pip` message: any of `corner`, `size` and `visible`; missing
+    /// fields keep their current value
+    pub fn update(&self, value: &serde_json::Value) -> Result<PipLayout, String> {
+        let mut layout = self.layout();
+        if let Some(corner) = value.get("corner") {
+            layout.corner = corner
+                .as_str()
+                .ok_or_else(|| "corner must be a string".to_string())?
+                .parse()?;
+        }
+        if let Some(size) = value.get("size") {
+            let size = size
+                .as_f64()
+                .ok_or_else(|| "size must be a number".to_string())? as f32;
+            if !(MIN_SIZE..=MAX_SIZE).contains(&size) {
+                return Err(format!("size must be between {} and {}", MIN_SIZE, MAX_SIZE));
+            }
+            layout.size = size;
+        }
+        if let Some(visible) = value.get("visible") {
+            layout.visible = visible
+                .as_bool()
+                .ok_or_else(|| "visible must be a boolean".to_string())?;
+        }
+        *self.layout.lock().unwrap() = layout;
+        Ok(layout)
+    }
+}
+
+/// Keeps `latest` current while any encoder is compositing
+async fn run_feed(pip: Arc<Pip>) {
+    loop {
+        while pip.users.load(Ordering::Relaxed) == 0 {
+            pip.user_joined.notified().await;
+        }
+
+        let mut listen_frames = pip.recorder.new_listener();
+        while let Some(captured) = listen_frames.recv().await {
+            if pip.users.load(Ordering::Relaxed) == 0 {
+                break;
+            }
+            *pip.latest.lock().unwrap() = Some(captured);
+        }
+
+        // Dropping the listener lets the secondary Recorder stop capturing
+        *pip.latest.lock().unwrap() = None;
+    }
+}
+
+/// Draws the inset into captured frames for one encoder. The secondary is
+/// captured for as long as a Compositor exists.
+pub struct Compositor {
+    pip: Arc<Pip>,
+    pool: FramePool,
+}
+
+impl Compositor {
+    pub fn new(pip: Arc<Pip>) -> Self {
+        pip.users.fetch_add(1, Ordering::Relaxed);
+        pip.user_joined.notify_one();
+        Self {
+            pip,
+            pool: FramePool::default(),
+        }
+    }
+
+    /// `primary` (rows `stride` bytes apart) with the latest secondary frame
+    /// scaled into its corner, laid out the same; the primary is passed
+    /// through when the inset is hidden or hasn't arrived yet
+    pub fn composite(&mut self, primary: Arc<Frame>, stride: usize) -> Arc<Frame> {
+        let layout = self.pip.layout();
+        if !layout.visible {
+            return primary;
+        }
+        let Some(CapturedFrame {
+            frame: secondary,
+            stride_bytes: secondary_stride,
+            ..
+        }) = self.pip.latest.lock().unwrap().clone()
+        else {
+            return primary;
+        };
+        let (width, height) = (primary.width, primary.height);
+        if width == 0 || height == 0 || secondary.width == 0 || secondary.height == 0 {
+            return primary;
+        }
+
+        let inset_w = ((width as f32 * layout.size).round() as u32).clamp(1, width);
+        let inset_h = ((inset_w as u64 * secondary.height as u64 / secondary.width as u64) as u32).clamp(1, height);
+        let margin = (width as f32 * MARGIN_FRACTION) as u32;
+        let left = margin.min(width - inset_w);
+        let right = width.saturating_sub(inset_w + margin);
+        let top = margin.min(height - inset_h);
+        let bottom = height.saturating_sub(inset_h + margin);
+        let (x, y) = match layout.corner {
+            Corner::TopLeft => (left, top),
+            Corner::TopRight => (right, top),
+            Corner::BottomLeft => (left, bottom),
+            Corner::BottomRight => (right, bottom),
+        };
+
+        self.pool.edit(&primary, |frame| {
+            blit_scaled(&secondary, secondary_stride, frame, stride, x, y, inset_w, inset_h)
+        })
+    }
+}
+
+impl Drop for Compositor {
+    fn drop(&mut self) {
+        self.pip.users.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Nearest-neighbour scale `src` into the `width` x `height` rect of `dst` at
+/// (`x`, `y`); rows are `src_stride` and `dst_stride` bytes apart
+#[allow(clippy::too_many_arguments)]
+fn blit_scaled(
+    src: &Frame,
+    src_stride: usize,
+    dst: &mut Frame,
+    dst_stride: usize,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+) {
+    let (src_w, src_h) = (src.width as usize, src.height as usize);
+    let (x, y, width, height) = (x as usize, y as usize, width as usize, height as usize);
+    for row in 0..height {
+        let src_row = (row * src_h / height) * src_stride;
+        let dst_row = (y + row) * dst_stride + x * 4;
+        let out = &mut dst.raw[dst_row..dst_row + width * 4];
+        for (col, pixel) in out.chunks_exact_mut(4).enumerate() {
+            let src_idx = src_row + (col * src_w / width) * 4;
+            pixel.copy_from_slice(&src.raw[src_idx..src_idx + 4]);
+        }
+    }
+}
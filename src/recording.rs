@@ -1,144 +1,1747 @@
 use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
     },
-    thread,
+    thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 
+use serde::{Deserialize, Serialize};
+use tokio::sync::{watch, Notify};
 use xcap::{Frame, Monitor, Window};
 
-pub type Listener = tokio::sync::mpsc::Receiver<Arc<Frame>>;
-type ListenerSender = tokio::sync::mpsc::Sender<Arc<Frame>>;
+#[cfg(all(feature = "screencapturekit", target_os = "macos"))]
+use crate::sck;
+use crate::{
+    cursor::{CaptureRegion, CursorOverlay},
+    permissions,
+    video_pipeline::PixelFormat,
+};
+
+/// Mailbox between a capture thread and one consumer, holding up to `depth`
+/// frames. A new frame pushes out the oldest one that hasn't been taken yet,
+/// so a consumer that falls behind gets recent captures rather than stale ones.
+#[derive(Default)]
+struct FrameSlot {
+    frames: Mutex<VecDeque<CapturedFrame>>,
+    depth: usize,
+    ready: Notify,
+    /// SourceLost, SourceRestored and SourceChanged, delivered ahead of frames
+    notices: Mutex<VecDeque<FrameEvent>>,
+    listener_gone: AtomicBool,
+    sender_gone: AtomicBool,
+}
+
+/// A frame as listeners get it, stamped and numbered when it was captured
+#[derive(Debug, Clone)]
+pub struct CapturedFrame {
+    pub frame: Arc<Frame>,
+    /// Bytes per row of `frame`, as the capture backend laid it out; more
+    /// than `width * 4` where it pads its rows
+    pub stride_bytes: usize,
+    /// When capture_image() returned, or xcap handed over a monitor frame
+    pub captured_at: Instant,
+    /// Counts the frames this capture has handed to listeners, from 0, so a
+    /// listener sees gaps where it was skipped or fell behind. A new source
+    /// starts again from 0.
+    pub sequence: u64,
+}
+
+impl CapturedFrame {
+    fn new(frame: Frame, stride_bytes: usize, captured_at: Instant) -> Self {
+        debug_assert!(stride_bytes >= frame.width as usize * 4);
+        debug_assert_eq!(frame.raw.len(), stride_bytes * frame.height as usize);
+        Self {
+            frame: Arc::new(frame),
+            stride_bytes,
+            captured_at,
+            // Numbered as it goes out, by fan_out
+            sequence: 0,
+        }
+    }
+}
+
+/// What `Listener::recv_event` hands out
+#[derive(Debug, Clone)]
+pub enum FrameEvent {
+    Frame(CapturedFrame),
+    /// The next frame's size differs from the previous one's, e.g. the
+    /// captured window is being resized
+    ResolutionChanged { width: u32, height: u32 },
+    /// The captured window closed or capture failed; no frames follow until
+    /// a SourceRestored
+    SourceLost { reason: String },
+    /// --reattach-by-title found the window again
+    SourceRestored,
+    /// `Recorder::switch_source` replaced the source; frames after this come
+    /// from the new one (behind a ResolutionChanged if the size differs)
+    SourceChanged,
+}
+
+/// Whether the capture source is still there, for /status and viewers
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourceState {
+    Capturing,
+    /// Gone, and --reattach-by-title is looking for a window with the same
+    /// app and title
+    Lost { reason: String },
+    /// Gone for good; nothing is captured until the source is switched
+    Ended { reason: String },
+}
+
+impl SourceState {
+    /// The `capture` object in /status
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            SourceState::Capturing => serde_json::json!({ "state": "capturing" }),
+            SourceState::Lost { reason } => serde_json::json!({ "state": "lost", "reason": reason }),
+            SourceState::Ended { reason } => serde_json::json!({ "state": "ended", "reason": reason }),
+        }
+    }
+}
+
+/// How a listener wants its frames, for `Recorder::new_listener_with_options`
+#[derive(Debug, Clone, Copy)]
+pub struct ListenerOptions {
+    /// Frames beyond this rate are skipped at the fan-out; the first frame
+    /// and the first of each new size always get through
+    pub max_fps: Option<f32>,
+    /// Frames held for a listener that falls behind; 1 keeps only the newest
+    pub channel_depth: usize,
+}
+
+impl Default for ListenerOptions {
+    fn default() -> Self {
+        Self {
+            max_fps: None,
+            channel_depth: 1,
+        }
+    }
+}
+
+/// Receiving end of a FrameSlot, from `Recorder::new_listener`
+pub struct Listener {
+    slot: Arc<FrameSlot>,
+    /// Size of the last frame from `recv_event`
+    size: Option<(u32, u32)>,
+    /// Frame held back behind its ResolutionChanged
+    pending: Option<CapturedFrame>,
+}
+
+impl Listener {
+    /// Like `recv`, with a ResolutionChanged ahead of the first frame of each
+    /// new size (not ahead of the very first frame), and the source's
+    /// SourceLost and SourceRestored as they happen
+    pub async fn recv_event(&mut self) -> Option<FrameEvent> {
+        if let Some(frame) = self.pending.take() {
+            return Some(FrameEvent::Frame(frame));
+        }
+        let frame = loop {
+            if let Some(notice) = self.slot.notices.lock().unwrap().pop_front() {
+                return Some(notice);
+            }
+            if let Some(frame) = self.slot.frames.lock().unwrap().pop_front() {
+                break frame;
+            }
+            if self.slot.sender_gone.load(Ordering::Acquire) {
+                return None;
+            }
+            self.slot.ready.notified().await;
+        };
+        let size = (frame.frame.width, frame.frame.height);
+        match self.size.replace(size) {
+            Some(previous) if previous != size => {
+                self.pending = Some(frame);
+                Some(FrameEvent::ResolutionChanged {
+                    width: size.0,
+                    height: size.1,
+                })
+            }
+            _ => Some(FrameEvent::Frame(frame)),
+        }
+    }
+
+    /// The oldest frame held (the newest, at the default depth of one); None
+    /// once capture has stopped handing frames to this listener
+    pub async fn recv(&mut self) -> Option<CapturedFrame> {
+        loop {
+            if let Some(frame) = self.slot.frames.lock().unwrap().pop_front() {
+                return Some(frame);
+            }
+            if self.slot.sender_gone.load(Ordering::Acquire) {
+                return None;
+            }
+            self.slot.ready.notified().await;
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.slot.listener_gone.store(true, Ordering::Release);
+    }
+}
+
+struct ListenerSender {
+    slot: Arc<FrameSlot>,
+    /// From ListenerOptions::max_fps
+    min_interval: Option<Duration>,
+    /// When the next frame is due under `min_interval`
+    next_due: Option<Instant>,
+    /// Size of the last frame offered
+    size: Option<(u32, u32)>,
+}
+
+impl ListenerSender {
+    fn new(slot: Arc<FrameSlot>, options: ListenerOptions) -> Self {
+        let min_interval = options
+            .max_fps
+            .filter(|fps| *fps > 0.0)
+            .map(|fps| Duration::from_secs_f64(1.0 / fps as f64));
+        Self {
+            slot,
+            min_interval,
+            next_due: None,
+            size: None,
+        }
+    }
+
+    /// Hand `frame` to the listener unless it comes sooner than the
+    /// listener's frame rate allows; false once the listener has been dropped
+    fn offer(&mut self, frame: CapturedFrame) -> bool {
+        if self.slot.listener_gone.load(Ordering::Acquire) {
+            return false;
+        }
+        let now = frame.captured_at;
+        let size = (frame.frame.width, frame.frame.height);
+        let resized = self.size.replace(size).is_some_and(|previous| previous != size);
+        if let Some(interval) = self.min_interval {
+            if !resized && self.next_due.is_some_and(|due| now < due) {
+                return true;
+            }
+            // Due times advance by whole intervals so capture jitter doesn't
+            // lower the rate; after a gap they start over from now
+            self.next_due = Some(match self.next_due {
+                Some(due) if !resized && due + interval >= now => due + interval,
+                _ => now + interval,
+            });
+        }
+        let mut frames = self.slot.frames.lock().unwrap();
+        if frames.len() >= self.slot.depth {
+            frames.pop_front();
+        }
+        frames.push_back(frame);
+        drop(frames);
+        self.slot.ready.notify_one();
+        true
+    }
+
+    /// Queue a SourceLost or SourceRestored; false once the listener has been dropped
+    fn notify(&self, event: FrameEvent) -> bool {
+        if self.slot.listener_gone.load(Ordering::Acquire) {
+            return false;
+        }
+        self.slot.notices.lock().unwrap().push_back(event);
+        self.slot.ready.notify_one();
+        true
+    }
+
+    /// Discard frames from the old source the listener hasn't taken yet and
+    /// queue a SourceChanged, so the new source's first frame is the next one
+    /// it gets and isn't held back by max_fps; false once the listener has
+    /// been dropped
+    fn source_changed(&mut self) -> bool {
+        self.slot.frames.lock().unwrap().clear();
+        self.next_due = None;
+        self.notify(FrameEvent::SourceChanged)
+    }
+}
+
+impl Drop for ListenerSender {
+    fn drop(&mut self) {
+        self.slot.sender_gone.store(true, Ordering::Release);
+        self.slot.ready.notify_one();
+    }
+}
+
+/// Bytes per row of a frame with no padding, as capture_image() and our
+/// own crops and scales produce
+pub fn packed_stride(frame: &Frame) -> usize {
+    frame.width as usize * 4
+}
+
+/// Bytes per row of a frame from xcap's video recorder. Its Frame has no
+/// stride field, and on Windows the rows keep Direct3D's row pitch, so the
+/// pitch is all that's left over once the buffer is split into `height` rows.
+fn xcap_stride(frame: &Frame) -> usize {
+    frame
+        .raw
+        .len()
+        .checked_div(frame.height as usize)
+        .unwrap_or_default()
+        .max(packed_stride(frame))
+}
+
+/// Region of the captured source to stream, in captured-source pixels
+/// (physical pixels on Retina, before any downsampling)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CropRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl CropRect {
+    /// Clamp to a `width` x `height` frame; None if nothing is left
+    pub(crate) fn clamp_to(self, width: u32, height: u32) -> Option<CropRect> {
+        let x = self.x.min(width);
+        let y = self.y.min(height);
+        let clamped = CropRect {
+            x,
+            y,
+            width: self.width.min(width - x),
+            height: self.height.min(height - y),
+        };
+        (clamped.width >= 2 && clamped.height >= 2).then_some(clamped)
+    }
+
+    /// Copy this rect, which must lie inside `frame` (rows `src_stride`
+    /// bytes apart), out as a packed frame. Shared by the session crop and
+    /// Region capture.
+    pub fn crop(self, frame: &Frame, src_stride: usize) -> Frame {
+        let row_bytes = self.width as usize * 4;
+        let mut raw = Vec::with_capacity(row_bytes * self.height as usize);
+        for y in self.y as usize..(self.y + self.height) as usize {
+            let start = y * src_stride + self.x as usize * 4;
+            raw.extend_from_slice(&frame.raw[start..start + row_bytes]);
+        }
+        Frame {
+            width: self.width,
+            height: self.height,
+            raw,
+        }
+    }
+
+    pub fn to_json(self) -> serde_json::Value {
+        serde_json::json!({ "x": self.x, "y": self.y, "width": self.width, "height": self.height })
+    }
+}
+
+/// X,Y,WIDTHxHEIGHT in points from a monitor's top-left, as given to --region
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    /// Smallest width and height accepted; I420 needs at least 2x2
+    const MIN_SIZE: u32 = 2;
+
+    /// A region, or an error if it is smaller than MIN_SIZE either way
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Result<Self, String> {
+        if width < Self::MIN_SIZE || height < Self::MIN_SIZE {
+            return Err(format!(
+                "region {}x{} is too small (at least {2}x{2})",
+                width,
+                height,
+                Self::MIN_SIZE
+            ));
+        }
+        Ok(Region { x, y, width, height })
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid region `{}` (expected X,Y,WIDTHxHEIGHT, e.g. 0,0,1280x720)", s);
+        let mut parts = s.splitn(3, ',');
+        let (Some(x), Some(y), Some(size)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        let (width, height) = size.split_once(['x', 'X']).ok_or_else(invalid)?;
+        let number = |value: &str| value.trim().parse::<u32>().map_err(|_| invalid());
+        Region::new(number(x)?, number(y)?, number(width)?, number(height)?)
+    }
+}
+
+impl TryFrom<String> for Region {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Region> for String {
+    fn from(region: Region) -> Self {
+        region.to_string()
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{}x{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+type ControlSender = std::sync::mpsc::Sender<CaptureControl>;
+type ControlReceiver = std::sync::mpsc::Receiver<CaptureControl>;
+
+/// Window polling rate when none is given (--capture-fps)
+pub const DEFAULT_WINDOW_FPS: u32 = 60;
+/// Fastest window polling rate accepted
+pub const MAX_WINDOW_FPS: u32 = 240;
+/// How often the achieved capture rate is recomputed
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// How often a captured window's position is re-read for cursor placement
+const WINDOW_REGION_REFRESH: Duration = Duration::from_millis(100);
+
+/// How long snapshot() waits for the capture to produce a frame
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often --follow-focus looks up the frontmost window
+const FOCUS_POLL: Duration = Duration::from_millis(250);
+/// How long a newly focused window must stay in front before capture follows
+/// it, so Spotlight and other brief popups don't restart the encoder
+const FOCUS_DEBOUNCE: Duration = Duration::from_millis(500);
+/// How often --reattach-by-title looks for the closed window to come back
+const REATTACH_POLL: Duration = Duration::from_millis(500);
+/// How long --app waits for the app to open a window before giving up
+const APP_WAIT: Duration = Duration::from_secs(15);
+/// How often a window that isn't changing is still delivered with
+/// CaptureOptions::dedupe, for keyframe requests and new listeners
+const DEDUPE_KEEPALIVE: Duration = Duration::from_secs(1);
+/// Every Nth row is compared first; most changes are caught without a full compare
+const CHANGE_SAMPLE_ROW_STRIDE: usize = 64;
+
+/// Specifies what to capture
+#[derive(Debug, Clone)]
+pub enum CaptureSource {
+    /// Capture the primary monitor
+    PrimaryMonitor,
+    /// Capture a specific monitor by xcap monitor ID (see --list-monitors)
+    Monitor(u32),
+    /// Capture a specific window by ID, polled `fps` times a second
+    Window { id: u32, fps: u32 },
+    /// Capture whichever window is in front, following focus (--follow-focus)
+    FrontmostWindow { fps: u32 },
+    /// Capture the focused (else largest) window of the app called `name`,
+    /// following it across the app's windows (--app)
+    App { name: String, fps: u32 },
+    /// Capture a fixed part of a monitor (the primary one if `display_id` is
+    /// None), cropped from the monitor capture (--region)
+    Region { display_id: Option<u32>, region: Region },
+}
+
+impl CaptureSource {
+    /// Description sent to clients in the mode-ack message
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            CaptureSource::PrimaryMonitor => serde_json::json!({ "kind": "monitor" }),
+            CaptureSource::Monitor(id) => serde_json::json!({ "kind": "monitor", "id": id }),
+            CaptureSource::Window { id, fps } => serde_json::json!({ "kind": "window", "id": id, "fps": fps }),
+            CaptureSource::FrontmostWindow { fps } => serde_json::json!({ "kind": "frontmost-window", "fps": fps }),
+            CaptureSource::App { name, fps } => serde_json::json!({ "kind": "app", "name": name, "fps": fps }),
+            CaptureSource::Region { display_id, region } => serde_json::json!({
+                "kind": "region",
+                "id": display_id,
+                "x": region.x,
+                "y": region.y,
+                "width": region.width,
+                "height": region.height,
+            }),
+        }
+    }
+
+    /// Parse the `source` object of a `set-source` message (inverse of
+    /// `to_json`); windows without an `fps` are polled at `default_fps`
+    pub fn from_json(value: &serde_json::Value, default_fps: u32) -> Result<Self, String> {
+        let fps = match value.get("fps") {
+            None => default_fps,
+            Some(fps) => fps
+                .as_u64()
+                .map(|fps| fps.clamp(1, MAX_WINDOW_FPS as u64) as u32)
+                .ok_or_else(|| "fps must be numeric".to_string())?,
+        };
+        match value.get("kind").and_then(|k| k.as_str()) {
+            Some("monitor") => match value.get("id") {
+                None => Ok(CaptureSource::PrimaryMonitor),
+                Some(id) => id
+                    .as_u64()
+                    .and_then(|id| u32::try_from(id).ok())
+                    .map(CaptureSource::Monitor)
+                    .ok_or_else(|| "monitor id must be numeric".to_string()),
+            },
+            Some("window") => value
+                .get("id")
+                .and_then(|id| id.as_u64())
+                .and_then(|id| u32::try_from(id).ok())
+                .map(|id| CaptureSource::Window { id, fps })
+                .ok_or_else(|| "window source needs a numeric id".to_string()),
+            Some("frontmost-window") => Ok(CaptureSource::FrontmostWindow { fps }),
+            Some("app") => value
+                .get("name")
+                .and_then(|name| name.as_str())
+                .filter(|name| !name.is_empty())
+                .map(|name| CaptureSource::App {
+                    name: name.to_string(),
+                    fps,
+                })
+                .ok_or_else(|| "app source needs a name".to_string()),
+            Some("region") => {
+                let number = |name: &str| {
+                    value
+                        .get(name)
+                        .and_then(|v| v.as_u64())
+                        .and_then(|v| u32::try_from(v).ok())
+                        .ok_or_else(|| format!("region source needs a numeric {}", name))
+                };
+                let display_id = match value.get("id") {
+                    None | Some(serde_json::Value::Null) => None,
+                    Some(_) => Some(number("id")?),
+                };
+                let region = Region::new(number("x")?, number("y")?, number("width")?, number("height")?)?;
+                Ok(CaptureSource::Region { display_id, region })
+            }
+            Some(other) => Err(format!("unknown source kind: {}", other)),
+            None => Err("source.kind is missing".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for CaptureSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureSource::PrimaryMonitor => write!(f, "primary monitor"),
+            CaptureSource::Monitor(id) => write!(f, "monitor {}", id),
+            CaptureSource::Window { id, .. } => write!(f, "window {}", id),
+            CaptureSource::FrontmostWindow { .. } => write!(f, "frontmost window"),
+            CaptureSource::App { name, .. } => write!(f, "app {}", name),
+            CaptureSource::Region {
+                display_id: Some(id),
+                region,
+            } => write!(f, "region {} of monitor {}", region, id),
+            CaptureSource::Region { display_id: None, region } => write!(f, "region {} of the primary monitor", region),
+        }
+    }
+}
+
+/// What captures the screen (--capture-backend)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CaptureBackend {
+    /// ScreenCaptureKit where it is built in and starts, xcap otherwise
+    #[default]
+    Auto,
+    Xcap,
+    /// macOS 12.3+ with `--features screencapturekit`; less CPU, and frames
+    /// timed by the compositor. Can't follow focus or reattach windows.
+    #[serde(rename = "screencapturekit")]
+    ScreenCaptureKit,
+}
+
+impl FromStr for CaptureBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(CaptureBackend::Auto),
+            "xcap" => Ok(CaptureBackend::Xcap),
+            "screencapturekit" => Ok(CaptureBackend::ScreenCaptureKit),
+            other => Err(format!(
+                "unknown capture backend `{}` (expected auto, xcap or screencapturekit)",
+                other
+            )),
+        }
+    }
+}
+
+/// How often a ScreenCaptureKit window capture checks its window still exists
+#[cfg(all(feature = "screencapturekit", target_os = "macos"))]
+const WINDOW_CHECK: Duration = Duration::from_secs(1);
+
+/// Commands for a capture thread's control loop
+enum CaptureControl {
+    Start,
+    Stop,
+    /// Poll a window this many times a second from now on
+    SetFps(u32),
+    /// Stop capturing and exit (the source is being replaced)
+    Shutdown,
+}
+
+/// Capture rate of the current source, for /status
+#[derive(Default)]
+struct CaptureRate {
+    /// Window polling rate; 0 for monitors, which deliver at the display's rate
+    target_fps: AtomicU32,
+    /// Frames per second handed to listeners over the last RATE_WINDOW, times 100
+    achieved_centi_fps: AtomicU32,
+    /// Captures held back by CaptureOptions::dedupe as unchanged
+    skipped_frames: AtomicU64,
+    /// `CapturedFrame::sequence` of the next frame handed to listeners
+    next_sequence: AtomicU64,
+}
+
+/// Holds back window captures identical to the last frame delivered
+/// (CaptureOptions::dedupe), letting one through every DEDUPE_KEEPALIVE
+#[derive(Default)]
+struct Dedupe {
+    /// Last frame delivered, and when it was captured
+    last: Option<(Arc<Frame>, Instant)>,
+}
+
+impl Dedupe {
+    /// Whether `frame` can be skipped; if not, it becomes the one compared against
+    fn skip(&mut self, frame: &CapturedFrame) -> bool {
+        if let Some((last, delivered_at)) = &self.last {
+            let fresh = frame.captured_at.saturating_duration_since(*delivered_at) < DEDUPE_KEEPALIVE;
+            if fresh && same_picture(last, &frame.frame) {
+                return true;
+            }
+        }
+        self.last = Some((frame.frame.clone(), frame.captured_at));
+        false
+    }
+}
+
+/// Counts frames towards CaptureRate::achieved_centi_fps
+struct RateMeter {
+    rate: Arc<CaptureRate>,
+    frames: u32,
+    since: Instant,
+}
+
+impl RateMeter {
+    fn new(rate: Arc<CaptureRate>) -> Self {
+        Self {
+            rate,
+            frames: 0,
+            since: Instant::now(),
+        }
+    }
+
+    fn frame(&mut self, now: Instant) {
+        self.frames += 1;
+        let elapsed = now.saturating_duration_since(self.since);
+        if elapsed >= RATE_WINDOW {
+            let centi_fps = self.frames as f64 * 100.0 / elapsed.as_secs_f64();
+            self.rate.achieved_centi_fps.store(centi_fps.round() as u32, Ordering::Relaxed);
+            self.frames = 0;
+            self.since = now;
+        }
+    }
+}
+
+/// The capture thread currently feeding the listeners
+struct ActiveCapture {
+    source: CaptureSource,
+    /// Monitor name or window title, for banners and mode-ack; changes as
+    /// a FrontmostWindow capture follows focus
+    name: Arc<Mutex<Option<String>>>,
+    video_startstop: ControlSender,
+    /// Control thread, joined by shutdown()
+    thread: Option<JoinHandle<()>>,
+    /// Byte order this capture backend delivers
+    pixel_format: PixelFormat,
+    rate: Arc<CaptureRate>,
+    feed: Arc<Feed>,
+    /// A monitor's (or region's) size in pixels: its points times the scale factor
+    native_size: Option<(u32, u32)>,
+}
+
+/// How a Recorder captures, whichever source it is given
+#[derive(Debug, Clone, Copy)]
+pub struct CaptureOptions {
+    /// Include the mouse pointer: ScreenCaptureKit's showsCursor, or stamped
+    /// into xcap frames (only inside a window's bounds). Cleared by --no-cursor.
+    pub show_cursor: bool,
+    /// --reattach-by-title: a closed window is waited for, not given up on
+    pub reattach_by_title: bool,
+    pub backend: CaptureBackend,
+    /// --skip-unchanged: polled windows only deliver frames that differ from
+    /// the last one, plus one every DEDUPE_KEEPALIVE. Off for consumers that
+    /// want every tick.
+    pub dedupe: bool,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        Self {
+            show_cursor: true,
+            reattach_by_title: false,
+            backend: CaptureBackend::default(),
+            dedupe: false,
+        }
+    }
+}
+
+pub struct Recorder {
+    listeners: Arc<Mutex<Vec<ListenerSender>>>,
+    capture: Mutex<ActiveCapture>,
+    options: CaptureOptions,
+    /// --pixel-format: replaces the capture backend's default for every source
+    pixel_format: Option<PixelFormat>,
+    /// In-flight snapshot that concurrent snapshot() calls wait on
+    snapshot: Arc<Mutex<Option<tokio::sync::broadcast::Sender<CapturedFrame>>>>,
+    /// Bumped each time a FrontmostWindow capture moves to another window
+    window_changes: Arc<watch::Sender<u64>>,
+    /// Whether the current source is still there
+    source_state: Arc<watch::Sender<SourceState>>,
+}
+
+/// What a FrontmostWindow or App capture thread updates when it follows focus
+struct FocusFollow {
+    /// Follow this app's windows rather than whichever window is in front
+    app: Option<String>,
+    name: Arc<Mutex<Option<String>>>,
+    changes: Arc<watch::Sender<u64>>,
+}
+
+/// How a window capture thread finds its window again once it has gone
+struct SourceWatch {
+    /// App and title of the window to look for; None gives up on it
+    reattach: Option<(String, String)>,
+    name: Arc<Mutex<Option<String>>>,
+}
+
+/// One capture's way to the Recorder's listeners. `switch_source` retires
+/// it while holding the listeners lock, so nothing the old capture sends
+/// afterwards (a late frame, a failure) reaches the new source's listeners.
+struct Feed {
+    listeners: Arc<Mutex<Vec<ListenerSender>>>,
+    retired: AtomicBool,
+}
+
+impl Feed {
+    /// The listeners, or None once this capture has been replaced
+    fn lock(&self) -> Option<MutexGuard<'_, Vec<ListenerSender>>> {
+        let listeners = self.listeners.lock().unwrap();
+        (!self.retired.load(Ordering::Relaxed)).then_some(listeners)
+    }
+
+    /// Cut this capture off from `listeners` (held locked by the caller) and
+    /// tell them the source changed, dropping what it queued for them
+    fn retire(&self, listeners: &mut Vec<ListenerSender>) {
+        self.retired.store(true, Ordering::Relaxed);
+        listeners.retain_mut(ListenerSender::source_changed);
+    }
+}
+
+/// What every capture thread gets, whichever backend runs it
+struct CaptureContext {
+    listeners: Arc<Feed>,
+    /// For the thread's own Stop once the last listener has gone
+    video_startstop: ControlSender,
+    startstop_receiver: ControlReceiver,
+    show_cursor: bool,
+    source_state: Arc<watch::Sender<SourceState>>,
+    rate: Arc<CaptureRate>,
+    /// Part of the monitor to keep, in pixels, for a Region source
+    region: Option<CropRect>,
+    /// CaptureOptions::dedupe, which only the window polling thread applies
+    dedupe: bool,
+}
+
+/// Debounced view of the frontmost window (or of an app's chosen window):
+/// a change is reported only once the new window has stayed in front for
+/// FOCUS_DEBOUNCE
+struct FocusTracker {
+    current: u32,
+    app: Option<String>,
+    candidate: Option<(u32, Instant)>,
+    polled_at: Instant,
+}
+
+impl FocusTracker {
+    fn new(current: u32, app: Option<String>) -> Self {
+        Self {
+            current,
+            app,
+            candidate: None,
+            polled_at: Instant::now(),
+        }
+    }
+
+    /// The window to switch to, if focus has settled on a different one
+    fn poll(&mut self, now: Instant) -> Option<u32> {
+        if now - self.polled_at < FOCUS_POLL {
+            return None;
+        }
+        self.polled_at = now;
+        let front = match &self.app {
+            Some(app) => find_app_window(app)?.id().ok()?,
+            None => window_pick::frontmost_window()?.id,
+        };
+        self.settle(front, now)
+    }
+
+    /// `front` as the window to switch to once it has been in front since a
+    /// poll FOCUS_DEBOUNCE ago
+    fn settle(&mut self, front: u32, now: Instant) -> Option<u32> {
+        if front == self.current {
+            self.candidate = None;
+            return None;
+        }
+        match self.candidate {
+            Some((id, since)) if id == front => {
+                if now - since < FOCUS_DEBOUNCE {
+                    return None;
+                }
+                self.current = front;
+                self.candidate = None;
+                Some(front)
+            }
+            _ => {
+                self.candidate = Some((front, now));
+                None
+            }
+        }
+    }
+}
+
+impl Recorder {
+    /// Start the capture thread for `source`; fails if the window doesn't exist
+    pub fn new(
+        source: CaptureSource,
+        pixel_format: Option<PixelFormat>,
+        options: CaptureOptions,
+    ) -> anyhow::Result<Self> {
+        let listeners: Vec<ListenerSender> = Vec::new();
+        let listeners = Arc::new(Mutex::new(listeners));
+
+        let window_changes = Arc::new(watch::channel(0).0);
+        let source_state = Arc::new(watch::channel(SourceState::Capturing).0);
+
+        let capture = spawn_capture(
+            source,
+            listeners.clone(),
+            window_changes.clone(),
+            source_state.clone(),
+            options,
+        )?;
+
+        Ok(Self {
+            listeners,
+            capture: Mutex::new(capture),
+            options,
+            pixel_format,
+            snapshot: Arc::new(Mutex::new(None)),
+            window_changes,
+            source_state,
+        })
+    }
+
+    pub fn source(&self) -> CaptureSource {
+        self.capture.lock().unwrap().source.clone()
+    }
+
+    /// Byte order of the frames listeners get from the current source
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+            .unwrap_or_else(|| self.capture.lock().unwrap().pixel_format)
+    }
+
+    /// Name of the monitor or window being captured, if known
+    pub fn source_name(&self) -> Option<String> {
+        self.capture.lock().unwrap().name.lock().unwrap().clone()
+    }
+
+    /// Changes each time --follow-focus moves the capture to another window;
+    /// the new window may have other dimensions
+    pub fn subscribe_window_changes(&self) -> watch::Receiver<u64> {
+        self.window_changes.subscribe()
+    }
+
+    /// Changes when the source is lost, found again or given up on
+    pub fn subscribe_source_state(&self) -> watch::Receiver<SourceState> {
+        self.source_state.subscribe()
+    }
+
+    /// The `capture` object in /status: whether the source is still there,
+    /// and the window polling rate asked for and the rate actually achieved
+    pub fn capture_json(&self) -> serde_json::Value {
+        let mut json = self.source_state.borrow().to_json();
+        let capture = self.capture.lock().unwrap();
+        let target_fps = capture.rate.target_fps.load(Ordering::Relaxed);
+        if target_fps > 0 {
+            json["target_fps"] = serde_json::json!(target_fps);
+        }
+        json["fps"] = serde_json::json!(capture.rate.achieved_centi_fps.load(Ordering::Relaxed) as f64 / 100.0);
+        if self.options.dedupe {
+            json["skipped_frames"] = serde_json::json!(capture.rate.skipped_frames.load(Ordering::Relaxed));
+        }
+        json
+    }
+
+    /// Poll the captured window `fps` times a second (clamped to
+    /// 1..=MAX_WINDOW_FPS) until the source is switched. Monitors deliver
+    /// frames at the display's rate, so they ignore this.
+    pub fn set_capture_fps(&self, fps: u32) -> u32 {
+        let fps = fps.clamp(1, MAX_WINDOW_FPS);
+        let mut capture = self.capture.lock().unwrap();
+        if let CaptureSource::Window { fps: current, .. }
+        | CaptureSource::FrontmostWindow { fps: current }
+        | CaptureSource::App { fps: current, .. } = &mut capture.source
+        {
+            *current = fps;
+        }
+        _ = capture.video_startstop.send(CaptureControl::SetFps(fps));
+        fps
+    }
+
+    /// Polling rate of the current source; the default for a window picked by
+    /// `set-source` without an `fps`
+    pub fn capture_fps(&self) -> u32 {
+        match self.capture.lock().unwrap().source {
+            CaptureSource::Window { fps, .. }
+            | CaptureSource::FrontmostWindow { fps }
+            | CaptureSource::App { fps, .. } => fps,
+            CaptureSource::PrimaryMonitor | CaptureSource::Monitor(_) | CaptureSource::Region { .. } => {
+                DEFAULT_WINDOW_FPS
+            }
+        }
+    }
+
+    /// Pixel size of the monitor or region being captured; None for windows
+    pub fn native_size(&self) -> Option<(u32, u32)> {
+        self.capture.lock().unwrap().native_size
+    }
+
+    /// `CaptureSource::to_json` plus the source's name, and a monitor's or
+    /// region's pixel size
+    pub fn source_json(&self) -> serde_json::Value {
+        let capture = self.capture.lock().unwrap();
+        let mut json = capture.source.to_json();
+        json["name"] = serde_json::json!(*capture.name.lock().unwrap());
+        if let Some((width, height)) = capture.native_size {
+            json["width"] = serde_json::json!(width);
+            json["height"] = serde_json::json!(height);
+        }
+        json
+    }
+
+    /// Replace the capture thread with one for `source`.
+    ///
+    /// Existing listeners stay attached and get a SourceChanged, then frames
+    /// from the new source only: the old capture is cut off, and its queued
+    /// frames dropped, before the new one starts. On error the current
+    /// source keeps running.
+    pub fn switch_source(&self, source: CaptureSource) -> anyhow::Result<()> {
+        let replacement = spawn_capture(
+            source,
+            self.listeners.clone(),
+            self.window_changes.clone(),
+            self.source_state.clone(),
+            self.options,
+        )?;
+
+        // Same lock order as new_listener: listeners, then capture
+        let mut listeners = self.listeners.lock().unwrap();
+        let mut capture = self.capture.lock().unwrap();
+        capture.feed.retire(&mut listeners);
+        _ = capture.video_startstop.send(CaptureControl::Shutdown);
+        if !listeners.is_empty() {
+            start_capture(&replacement.video_startstop);
+        }
+        println!("Switched capture from {} to {}", capture.source, replacement.source);
+        *capture = replacement;
+        self.source_state.send_replace(SourceState::Capturing);
+        Ok(())
+    }
+
+    /// Stop capturing and wait for the capture thread to exit (server shutdown)
+    pub fn shutdown(&self) {
+        let thread = {
+            let mut capture = self.capture.lock().unwrap();
+            _ = capture.video_startstop.send(CaptureControl::Shutdown);
+            capture.thread.take()
+        };
+        if let Some(thread) = thread {
+            _ = thread.join();
+        }
+    }
+
+    /// A listener with the default options: every frame, newest only
+    pub fn new_listener(&self) -> Listener {
+        self.new_listener_with_options(ListenerOptions::default())
+    }
+
+    pub fn new_listener_with_options(&self, options: ListenerOptions) -> Listener {
+        let slot = Arc::new(FrameSlot {
+            depth: options.channel_depth.max(1),
+            ..Default::default()
+        });
+
+        let mut listeners = self.listeners.lock().unwrap();
+        listeners.push(ListenerSender::new(slot.clone(), options));
+        if listeners.len() == 1 {
+            let capture = self.capture.lock().unwrap();
+            start_capture(&capture.video_startstop);
+        }
+
+        Listener {
+            slot,
+            size: None,
+            pending: None,
+        }
+    }
+
+    /// Grab a single frame via a temporary listener.
+    ///
+    /// Concurrent callers share the same in-flight capture.
+    pub async fn snapshot(&self) -> anyhow::Result<CapturedFrame> {
+        let mut waiter = {
+            let mut in_flight = self.snapshot.lock().unwrap();
+            match in_flight.as_ref() {
+                Some(pending) => pending.subscribe(),
+                None => {
+                    let (pending, waiter) = tokio::sync::broadcast::channel(1);
+                    *in_flight = Some(pending.clone());
+
+                    let mut listener = self.new_listener();
+                    let slot = self.snapshot.clone();
+                    tokio::spawn(async move {
+                        let frame = tokio::time::timeout(SNAPSHOT_TIMEOUT, listener.recv())
+                            .await
+                            .ok()
+                            .flatten();
+                        slot.lock().unwrap().take();
+                        // Dropping the listener detaches it on the next frame
+                        if let Some(captured) = frame {
+                            _ = pending.send(captured);
+                        }
+                    });
+                    waiter
+                }
+            }
+        };
+
+        waiter.recv().await.map_err(|_| {
+            anyhow::anyhow!("no frame captured within {}s", SNAPSHOT_TIMEOUT.as_secs())
+        })
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let capture = self.capture.lock().unwrap();
+        _ = capture.video_startstop.send(CaptureControl::Shutdown);
+        println!("Video recorder dropped");
+    }
+}
+
+#[cfg(test)]
+impl Recorder {
+    /// A Recorder with no capture thread behind it, whose listeners get
+    /// what `capture_test_frame` hands them
+    pub(crate) fn without_capture(pixel_format: PixelFormat) -> Self {
+        let listeners = Arc::new(Mutex::new(Vec::new()));
+        let capture = ActiveCapture {
+            source: CaptureSource::PrimaryMonitor,
+            name: Arc::default(),
+            // Start and Stop go nowhere
+            video_startstop: std::sync::mpsc::channel().0,
+            thread: None,
+            pixel_format,
+            rate: Arc::default(),
+            feed: Arc::new(Feed {
+                listeners: listeners.clone(),
+                retired: AtomicBool::new(false),
+            }),
+            native_size: None,
+        };
+        Self {
+            listeners,
+            capture: Mutex::new(capture),
+            options: CaptureOptions::default(),
+            pixel_format: None,
+            snapshot: Arc::default(),
+            window_changes: Arc::new(watch::channel(0).0),
+            source_state: Arc::new(watch::channel(SourceState::Capturing).0),
+        }
+    }
+
+    /// Hand `frame` to the listeners the way a capture thread does; false
+    /// while there are none
+    pub(crate) fn capture_test_frame(&self, frame: Frame) -> bool {
+        let (feed, rate, control) = {
+            let capture = self.capture.lock().unwrap();
+            (capture.feed.clone(), capture.rate.clone(), capture.video_startstop.clone())
+        };
+        if feed.lock().is_none_or(|listeners| listeners.is_empty()) {
+            return false;
+        }
+        let stride = packed_stride(&frame);
+        let frame = CapturedFrame::new(frame, stride, Instant::now());
+        fan_out(&feed, frame, &mut RateMeter::new(rate), &control, "test capture");
+        true
+    }
+}
+
+/// Tell a capture thread to start, unless macOS would only give it black frames;
+/// listeners then get nothing rather than a black stream
+fn start_capture(control: &ControlSender) {
+    if permissions::screen_recording().is_denied() {
+        eprintln!("Not capturing: Screen Recording permission is not granted");
+        return;
+    }
+    _ = control.send(CaptureControl::Start);
+}
+
+/// Spawn the capture thread for `source`
+fn spawn_capture(
+    source: CaptureSource,
+    listeners: Arc<Mutex<Vec<ListenerSender>>>,
+    window_changes: Arc<watch::Sender<u64>>,
+    source_state: Arc<watch::Sender<SourceState>>,
+    options: CaptureOptions,
+) -> anyhow::Result<ActiveCapture> {
+    let CaptureOptions {
+        show_cursor,
+        reattach_by_title,
+        backend,
+        dedupe,
+    } = options;
+    // Resolve windows and monitors up front so a bad ID is an error for the
+    // caller, not a panic inside the capture thread. Monitors aren't Send, so
+    // the thread looks its monitor up again by ID.
+    let mut region = None;
+    let (window, monitor_id, name, native_size) = match &source {
+        CaptureSource::PrimaryMonitor => {
+            let monitor = find_monitor(None)?;
+            (None, monitor.id()?, monitor.name().ok(), native_size(&monitor))
+        }
+        CaptureSource::Monitor(id) => {
+            let monitor = find_monitor(Some(*id))?;
+            (None, *id, monitor.name().ok(), native_size(&monitor))
+        }
+        CaptureSource::Region {
+            display_id,
+            region: requested,
+        } => {
+            let monitor = find_monitor(*display_id)?;
+            let rect = region_rect(&monitor, *requested)?;
+            region = Some(rect);
+            (None, monitor.id()?, monitor.name().ok(), Some((rect.width, rect.height)))
+        }
+        CaptureSource::Window { id: window_id, .. } => {
+            let window = find_window(*window_id)?;
+            let title = window.title().ok().filter(|t| !t.is_empty());
+            (Some(window), 0, title, None)
+        }
+        CaptureSource::FrontmostWindow { .. } => {
+            let front = window_pick::frontmost_window()
+                .ok_or_else(|| anyhow::anyhow!("no frontmost window found (following focus needs macOS)"))?;
+            let window = find_window(front.id)?;
+            let title = window.title().ok().filter(|t| !t.is_empty());
+            (Some(window), 0, title, None)
+        }
+        CaptureSource::App { name: app, .. } => {
+            let window = wait_for_app_window(app)?;
+            let title = window.title().ok().filter(|t| !t.is_empty());
+            (Some(window), 0, title, None)
+        }
+    };
+    let name = Arc::new(Mutex::new(name));
+    let follow = match &source {
+        CaptureSource::FrontmostWindow { .. } => Some(None),
+        CaptureSource::App { name: app, .. } => Some(Some(app.clone())),
+        _ => None,
+    }
+    .map(|app| FocusFollow {
+        app,
+        name: name.clone(),
+        changes: window_changes,
+    });
+    // A followed window that closes is replaced by whatever takes focus
+    let reattach = window
+        .as_ref()
+        .filter(|_| reattach_by_title && follow.is_none())
+        .map(|window| (window.app_name().unwrap_or_default(), window.title().unwrap_or_default()));
+    let source_watch = SourceWatch {
+        reattach,
+        name: name.clone(),
+    };
+
+    // Monitors deliver at the display's rate
+    let fps = match &source {
+        CaptureSource::Window { fps, .. }
+        | CaptureSource::FrontmostWindow { fps }
+        | CaptureSource::App { fps, .. } => (*fps).clamp(1, MAX_WINDOW_FPS),
+        CaptureSource::PrimaryMonitor | CaptureSource::Monitor(_) | CaptureSource::Region { .. } => 0,
+    };
+    let rate = Arc::new(CaptureRate::default());
+    rate.target_fps.store(fps, Ordering::Relaxed);
+
+    let feed = Arc::new(Feed {
+        listeners,
+        retired: AtomicBool::new(false),
+    });
+    let (video_startstop, receive_startstop) = std::sync::mpsc::channel();
+    let context = CaptureContext {
+        listeners: feed.clone(),
+        video_startstop: video_startstop.clone(),
+        startstop_receiver: receive_startstop,
+        show_cursor,
+        source_state,
+        rate: rate.clone(),
+        region,
+        dedupe,
+    };
+
+    // Focus following and reattaching swap windows under a running capture,
+    // which only the polling thread does
+    let sck_usable = follow.is_none() && source_watch.reattach.is_none();
+    if backend == CaptureBackend::ScreenCaptureKit && !sck_usable {
+        anyhow::bail!("ScreenCaptureKit capture can't follow focus or reattach windows; use --capture-backend xcap");
+    }
+    #[cfg(not(all(feature = "screencapturekit", target_os = "macos")))]
+    if backend == CaptureBackend::ScreenCaptureKit {
+        anyhow::bail!("built without ScreenCaptureKit capture (needs macOS and --features screencapturekit)");
+    }
+    #[cfg(all(feature = "screencapturekit", target_os = "macos"))]
+    let context = if backend != CaptureBackend::Xcap && sck_usable {
+        let target = match &window {
+            Some(window) => sck::Target::Window(window.id()?),
+            None => sck::Target::Display(monitor_id),
+        };
+        match spawn_sck_capture(target, fps, context) {
+            Ok(thread) => {
+                println!("Capturing {} with ScreenCaptureKit", source);
+                return Ok(ActiveCapture {
+                    source,
+                    name,
+                    video_startstop,
+                    thread: Some(thread),
+                    pixel_format: PixelFormat::Bgra8888,
+                    rate,
+                    feed,
+                    native_size,
+                });
+            }
+            Err((err, Some(context))) if backend == CaptureBackend::Auto => {
+                eprintln!("ScreenCaptureKit capture unavailable, using xcap: {}", err);
+                context
+            }
+            Err((err, _)) => return Err(err),
+        }
+    } else {
+        context
+    };
+
+    // capture_image() always returns an RgbaImage; the macOS monitor
+    // recorder passes ScreenCaptureKit's BGRA buffers straight through
+    let pixel_format = if window.is_none() && cfg!(target_os = "macos") {
+        PixelFormat::Bgra8888
+    } else {
+        PixelFormat::Rgba8888
+    };
+
+    let thread = thread::spawn(move || match window {
+        None => create_monitor_recorder_thread(monitor_id, context),
+        Some(window) => create_window_recorder_thread(window, follow, source_watch, context),
+    });
+
+    Ok(ActiveCapture {
+        source,
+        name,
+        video_startstop,
+        thread: Some(thread),
+        pixel_format,
+        rate,
+        feed,
+        native_size,
+    })
+}
+
+/// Start a ScreenCaptureKit capture thread; when the stream can't be set
+/// up, the context comes back for the xcap path
+#[cfg(all(feature = "screencapturekit", target_os = "macos"))]
+fn spawn_sck_capture(
+    target: sck::Target,
+    fps: u32,
+    context: CaptureContext,
+) -> Result<JoinHandle<()>, (anyhow::Error, Option<CaptureContext>)> {
+    let (ready, started) = std::sync::mpsc::channel();
+    let thread = thread::spawn(move || create_sck_recorder_thread(target, fps, context, ready));
+    match started.recv() {
+        Ok(Ok(())) => Ok(thread),
+        Ok(Err((err, context))) => Err((err, Some(context))),
+        Err(_) => Err((anyhow::anyhow!("ScreenCaptureKit capture thread panicked"), None)),
+    }
+}
+
+/// ScreenCaptureKit capture: frames come from the stream's own queue, and
+/// this thread runs the control loop. The stream is created here because it
+/// can't move between threads; `ready` gets the context back if it can't be.
+#[cfg(all(feature = "screencapturekit", target_os = "macos"))]
+fn create_sck_recorder_thread(
+    target: sck::Target,
+    fps: u32,
+    context: CaptureContext,
+    ready: std::sync::mpsc::Sender<Result<(), (anyhow::Error, CaptureContext)>>,
+) {
+    let window_id = match target {
+        sck::Target::Window(id) => Some(id),
+        sck::Target::Display(_) => None,
+    };
+    let listeners = context.listeners.clone();
+    let video_startstop = context.video_startstop.clone();
+    let meter = Mutex::new(RateMeter::new(context.rate.clone()));
+    let region = context.region;
+    let capture = sck::SckCapture::new(target, fps, context.show_cursor, move |frame, stride, captured_at| {
+        let (frame, stride) = crop_to_region(frame, stride, region);
+        let frame = CapturedFrame::new(frame, stride, captured_at);
+        fan_out(&listeners, frame, &mut meter.lock().unwrap(), &video_startstop, "ScreenCaptureKit capture");
+    });
+    let mut capture = match capture {
+        Ok(capture) => capture,
+        Err(err) => {
+            _ = ready.send(Err((err, context)));
+            return;
+        }
+    };
+    _ = ready.send(Ok(()));
+
+    let CaptureContext {
+        listeners,
+        startstop_receiver,
+        source_state,
+        rate,
+        ..
+    } = context;
+    let mut started = false;
+    loop {
+        match startstop_receiver.recv_timeout(WINDOW_CHECK) {
+            Ok(CaptureControl::Start) => {
+                let ended = match &*source_state.borrow() {
+                    SourceState::Ended { reason } => Some(reason.clone()),
+                    _ => None,
+                };
+                if let Some(reason) = ended {
+                    capture_failed(&listeners, &source_state, &reason);
+                } else if !started {
+                    match capture.start() {
+                        Ok(()) => {
+                            println!("ScreenCaptureKit capture started");
+                            started = true;
+                        }
+                        Err(err) => capture_failed(&listeners, &source_state, &err),
+                    }
+                }
+            }
+            Ok(CaptureControl::Stop) => {
+                if started {
+                    if let Err(err) = capture.stop() {
+                        eprintln!("Stopping ScreenCaptureKit capture failed: {}", err);
+                    }
+                    println!("ScreenCaptureKit capture stopped");
+                    started = false;
+                }
+            }
+            Ok(CaptureControl::SetFps(fps)) => match capture.set_fps(fps) {
+                Ok(()) => {
+                    println!("Capture rate set to {} fps", fps);
+                    rate.target_fps.store(fps, Ordering::Relaxed);
+                }
+                Err(err) => eprintln!("Can't change the capture rate: {}", err),
+            },
+            Ok(CaptureControl::Shutdown) | Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                if started {
+                    _ = capture.stop();
+                }
+                break;
+            }
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                // The stream goes quiet rather than failing when its window closes
+                if let Some(id) = window_id.filter(|_| started) {
+                    if find_window(id).is_err() {
+                        _ = capture.stop();
+                        started = false;
+                        capture_failed(&listeners, &source_state, &format!("window {} closed", id));
+                    }
+                }
+            }
+        }
+    }
+    println!("ScreenCaptureKit capture shut down");
+}
+
+/// Monitor capture using xcap's built-in VideoRecorder
+fn create_monitor_recorder_thread(monitor_id: u32, context: CaptureContext) {
+    let CaptureContext {
+        listeners,
+        video_startstop,
+        startstop_receiver,
+        show_cursor,
+        source_state,
+        rate,
+        region,
+        ..
+    } = context;
+    // The monitor can vanish between spawn_capture's check and here
+    let setup = find_monitor(Some(monitor_id)).and_then(|monitor| {
+        let recorder = monitor.video_recorder()?;
+        Ok((monitor, recorder))
+    });
+    let (monitor, (video_recorder, frame_receiver)) = match setup {
+        Ok(setup) => setup,
+        Err(err) => {
+            // Every start ends the listeners' streams until the source is replaced
+            for control in startstop_receiver {
+                match control {
+                    CaptureControl::Start => capture_failed(&listeners, &source_state, &err),
+                    CaptureControl::Stop | CaptureControl::SetFps(_) => {}
+                    CaptureControl::Shutdown => break,
+                }
+            }
+            return;
+        }
+    };
+
+    println!(
+        "Creating video recorder for monitor: {} [id {}]",
+        monitor.name().unwrap_or_default(),
+        monitor_id
+    );
+    let video_recorder = Arc::new(video_recorder);
+    let cursor = show_cursor.then(|| CursorOverlay::new(CaptureRegion::of_monitor(&monitor)));
 
-/// Target frame rate for window capture polling
-const WINDOW_CAPTURE_FPS: u32 = 60;
+    let listeners_for_errors = listeners.clone();
+    thread::spawn(move || {
+        create_frame_receiver_thread(frame_receiver, listeners, video_startstop, cursor, rate, region)
+    });
 
-/// Specifies what to capture
-#[derive(Debug, Clone)]
-pub enum CaptureSource {
-    /// Capture the primary monitor
-    PrimaryMonitor,
-    /// Capture a specific window by ID
-    Window(u32),
+    let mut started = false;
+
+    loop {
+        match startstop_receiver.recv() {
+            Ok(CaptureControl::Start) => {
+                if !started {
+                    match video_recorder.start() {
+                        Ok(()) => {
+                            println!("Video recorder started");
+                            started = true;
+                            source_state.send_if_modified(|state| {
+                                let was_down = *state != SourceState::Capturing;
+                                *state = SourceState::Capturing;
+                                was_down
+                            });
+                        }
+                        Err(err) => capture_failed(&listeners_for_errors, &source_state, &err),
+                    }
+                }
+            }
+            Ok(CaptureControl::Stop) => {
+                if started {
+                    if let Err(err) = video_recorder.stop() {
+                        eprintln!("Stopping the video recorder failed: {}", err);
+                    }
+                    println!("Video recorder stopped");
+                    started = false;
+                }
+            }
+            Ok(CaptureControl::SetFps(_)) => {
+                println!("Monitor capture runs at the display's rate; ignoring the capture rate");
+            }
+            Ok(CaptureControl::Shutdown) | Err(_) => {
+                if started {
+                    _ = video_recorder.stop();
+                }
+                break;
+            }
+        }
+    }
+    // Dropping the recorder ends the frame receiver thread
+    println!("Monitor capture shut down");
 }
 
-pub struct Recorder {
-    listeners: Arc<Mutex<Vec<ListenerSender>>>,
-    video_startstop: std::sync::mpsc::Sender<bool>,
+/// Tell the listeners capture isn't possible with a SourceLost, then end
+/// their streams (`recv` returns None) rather than leave them waiting for
+/// frames. The source stays Ended until it is switched.
+fn capture_failed(listeners: &Feed, source_state: &watch::Sender<SourceState>, err: &dyn std::fmt::Display) {
+    eprintln!("Capture failed: {}", err);
+    // A replaced capture no longer speaks for the source
+    let Some(mut listeners) = listeners.lock() else {
+        return;
+    };
+    let reason = err.to_string();
+    for listener in listeners.drain(..) {
+        listener.notify(FrameEvent::SourceLost { reason: reason.clone() });
+    }
+    source_state.send_replace(SourceState::Ended { reason });
 }
 
-impl Recorder {
-    pub fn new(source: CaptureSource) -> Self {
-        let listeners: Vec<ListenerSender> = Vec::new();
-        let listeners = Arc::new(Mutex::new(listeners));
+/// A polled window's capture failed. With `reattach` the listeners hear
+/// SourceLost and stay, and the source is Lost while the thread looks for
+/// the window again (true); otherwise capture ends as in `capture_failed`.
+fn window_lost(
+    listeners: &Feed,
+    source_state: &watch::Sender<SourceState>,
+    reattach: bool,
+    err: &dyn std::fmt::Display,
+) -> bool {
+    if !reattach {
+        capture_failed(listeners, source_state, err);
+        return false;
+    }
+    eprintln!("Window capture failed, waiting for the window to reopen: {}", err);
+    let reason = err.to_string();
+    if announce(listeners, FrameEvent::SourceLost { reason: reason.clone() }) {
+        source_state.send_replace(SourceState::Lost { reason });
+    }
+    true
+}
 
-        let (video_startstop, receive_startstop) = std::sync::mpsc::channel();
+/// --reattach-by-title found the window `window_lost` waited for
+fn window_restored(listeners: &Feed, source_state: &watch::Sender<SourceState>) {
+    if announce(listeners, FrameEvent::SourceRestored) {
+        source_state.send_replace(SourceState::Capturing);
+    }
+}
 
-        let listeners_clone = listeners.clone();
-        let video_startstop_clone = video_startstop.clone();
+/// Hand `frame` to every listener, and stop `capture` once the last one has gone
+fn fan_out(
+    listeners: &Feed,
+    frame: CapturedFrame,
+    meter: &mut RateMeter,
+    video_startstop: &ControlSender,
+    capture: &str,
+) {
+    let Some(mut listeners) = listeners.lock().filter(|listeners| !listeners.is_empty()) else {
+        return;
+    };
+    // Numbered under the listeners lock, so in the order listeners get them
+    let frame = CapturedFrame {
+        sequence: meter.rate.next_sequence.fetch_add(1, Ordering::Relaxed),
+        ..frame
+    };
+    // Slow listeners lose their oldest frame, and count it
+    meter.frame(frame.captured_at);
+    listeners.retain_mut(|listener| listener.offer(frame.clone()));
 
-        thread::spawn(move || match source {
-            CaptureSource::PrimaryMonitor => {
-                create_monitor_recorder_thread(
-                    listeners_clone,
-                    video_startstop_clone,
-                    receive_startstop,
-                )
-            }
-            CaptureSource::Window(window_id) => {
-                create_window_recorder_thread(
-                    window_id,
-                    listeners_clone,
-                    video_startstop_clone,
-                    receive_startstop,
-                )
-            }
-        });
+    if listeners.is_empty() {
+        println!("no listeners left, stopping {}", capture);
+        _ = video_startstop.send(CaptureControl::Stop);
+    }
+}
 
-        Self {
-            listeners,
-            video_startstop,
-        }
+/// Sampled rows reject most changed frames cheaply; the full compare behind them
+/// makes sure a one-character edit on an unsampled row still counts as a change
+pub(crate) fn same_picture(a: &Frame, b: &Frame) -> bool {
+    if a.width != b.width || a.height != b.height || a.raw.len() != b.raw.len() {
+        return false;
     }
+    let row_bytes = (a.width as usize * 4).max(1);
+    let sampled_equal = a
+        .raw
+        .chunks(row_bytes)
+        .zip(b.raw.chunks(row_bytes))
+        .step_by(CHANGE_SAMPLE_ROW_STRIDE)
+        .all(|(x, y)| x == y);
+    sampled_equal && a.raw == b.raw
+}
 
-    pub fn new_listener(&self) -> Listener {
-        let (tx, rx) = tokio::sync::mpsc::channel(1);
+/// Queue `event` for every listener, dropping the ones that are gone; false
+/// if the capture has been replaced and the event went nowhere
+fn announce(listeners: &Feed, event: FrameEvent) -> bool {
+    let Some(mut listeners) = listeners.lock() else {
+        return false;
+    };
+    listeners.retain(|listener| listener.notify(event.clone()));
+    true
+}
 
-        let mut listeners = self.listeners.lock().unwrap();
-        listeners.push(tx);
-        if listeners.len() == 1 {
-            self.video_startstop.send(true).unwrap();
+/// The monitor with xcap ID `monitor_id`, or the primary monitor for None.
+/// The error for an unknown ID lists the monitors that do exist.
+fn find_monitor(monitor_id: Option<u32>) -> anyhow::Result<Monitor> {
+    let monitors = Monitor::all()?.into_iter().map(|m| {
+        let (id, primary) = (m.id().ok(), m.is_primary().unwrap_or(false));
+        (m, id, primary)
+    });
+    pick_monitor(monitors, monitor_id, || describe_monitors().unwrap_or_default())
+}
+
+/// find_monitor's choice among `monitors`, each with its ID and whether it is
+/// the primary one; `available` lists them for the error
+fn pick_monitor<M>(
+    monitors: impl IntoIterator<Item = (M, Option<u32>, bool)>,
+    monitor_id: Option<u32>,
+    available: impl FnOnce() -> Vec<String>,
+) -> anyhow::Result<M> {
+    let found = monitors.into_iter().find(|(_, id, primary)| match monitor_id {
+        Some(wanted) => *id == Some(wanted),
+        None => *primary,
+    });
+    found.map(|(monitor, _, _)| monitor).ok_or_else(|| {
+        let available = available().join("\n  ");
+        match monitor_id {
+            Some(id) => anyhow::anyhow!("Monitor with ID {} not found. Available monitors:\n  {}", id, available),
+            None => anyhow::anyhow!("No primary monitor found. Available monitors:\n  {}", available),
         }
+    })
+}
+
+/// `monitor`'s size in pixels; xcap reports points, which Retina displays double
+fn native_size(monitor: &Monitor) -> Option<(u32, u32)> {
+    let scale = monitor.scale_factor().ok()?;
+    let scaled = |points: u32| (points as f32 * scale).round() as u32;
+    Some((scaled(monitor.width().ok()?), scaled(monitor.height().ok()?)))
+}
 
-        rx
+/// `region` (points from `monitor`'s top-left) in pixels, checked against the
+/// monitor's bounds. Width and height are rounded down to even, so encoders
+/// get exactly the region's frames.
+fn region_rect(monitor: &Monitor, region: Region) -> anyhow::Result<CropRect> {
+    region_in_pixels(region, monitor.width()?, monitor.height()?, monitor.scale_factor()?)
+}
+
+/// region_rect for a monitor `width` x `height` points at `scale` pixels per point
+fn region_in_pixels(region: Region, width: u32, height: u32, scale: f32) -> anyhow::Result<CropRect> {
+    if region.x.saturating_add(region.width) > width || region.y.saturating_add(region.height) > height {
+        anyhow::bail!("region {} is outside the {}x{} monitor", region, width, height);
     }
+    let scaled = |points: u32| (points as f32 * scale).round() as u32;
+    let (x, y) = (scaled(region.x), scaled(region.y));
+    let rect = CropRect {
+        x,
+        y,
+        width: scaled(region.width).min(scaled(width) - x) & !1,
+        height: scaled(region.height).min(scaled(height) - y) & !1,
+    };
+    if rect.width < 2 || rect.height < 2 {
+        anyhow::bail!("region {} is smaller than 2x2 pixels", region);
+    }
+    Ok(rect)
 }
 
-impl Drop for Recorder {
-    fn drop(&mut self) {
-        _ = self.video_startstop.send(false);
-        println!("Video recorder dropped");
+/// Cut a Region source's rect out of a monitor frame with rows `stride`
+/// bytes apart, along with the stride of what comes out. A frame the rect no
+/// longer fits (the display changed mode) is cropped to what is left of it.
+fn crop_to_region(frame: Frame, stride: usize, region: Option<CropRect>) -> (Frame, usize) {
+    match region.and_then(|rect| rect.clamp_to(frame.width, frame.height)) {
+        Some(rect) => {
+            let cropped = rect.crop(&frame, stride);
+            let stride = packed_stride(&cropped);
+            (cropped, stride)
+        }
+        None => (frame, stride),
     }
 }
 
-/// Monitor capture using xcap's built-in VideoRecorder
-fn create_monitor_recorder_thread(
-    listeners: Arc<Mutex<Vec<ListenerSender>>>,
-    video_startstop: std::sync::mpsc::Sender<bool>,
-    startstop_receiver: std::sync::mpsc::Receiver<bool>,
-) {
-    let monitors = Monitor::all().unwrap();
-    let monitor = monitors
+/// One line per monitor: id, name, resolution and whether it's primary (--list-monitors)
+pub fn describe_monitors() -> anyhow::Result<Vec<String>> {
+    Ok(Monitor::all()?
         .iter()
-        .filter(|&monitor| monitor.is_primary().unwrap())
-        .next()
-        .unwrap();
+        .map(|m| {
+            format!(
+                "{}\t{}\t{}x{}{}",
+                m.id().unwrap_or(0),
+                m.name().unwrap_or_default(),
+                m.width().unwrap_or(0),
+                m.height().unwrap_or(0),
+                if m.is_primary().unwrap_or(false) { "\tprimary" } else { "" }
+            )
+        })
+        .collect())
+}
 
-    println!(
-        "Creating video recorder for monitor: {} [id {}]",
-        monitor.name().unwrap(),
-        monitor.id().unwrap()
-    );
-    let (video_recorder, frame_receiver) = monitor.video_recorder().unwrap();
-    let video_recorder = Arc::new(video_recorder);
+fn find_window(window_id: u32) -> anyhow::Result<Window> {
+    let windows = Window::all()?.into_iter().map(|w| (w.id().unwrap_or(0), w));
+    pick_window(windows, window_id)
+}
 
-    thread::spawn(move || create_frame_receiver_thread(frame_receiver, listeners, video_startstop));
+/// find_window's choice among `windows`, each with its ID
+fn pick_window<W>(windows: impl IntoIterator<Item = (u32, W)>, window_id: u32) -> anyhow::Result<W> {
+    windows
+        .into_iter()
+        .find(|(id, _)| *id == window_id)
+        .map(|(_, window)| window)
+        .ok_or_else(|| anyhow::anyhow!("Window with ID {} not found", window_id))
+}
 
-    let mut started = false;
+/// A window of `app` titled `title`, for --reattach-by-title
+fn find_window_by_title(app: &str, title: &str) -> Option<Window> {
+    Window::all().ok()?.into_iter().find(|w| {
+        w.app_name().ok().as_deref() == Some(app) && w.title().ok().as_deref() == Some(title)
+    })
+}
 
+/// The window of `app` (matched case-insensitively) to capture: the focused
+/// one, else the largest. Minimized and zero-sized windows are skipped.
+fn find_app_window(app: &str) -> Option<Window> {
+    let app = app.to_lowercase();
+    let windows = Window::all()
+        .ok()?
+        .into_iter()
+        .filter(|w| w.app_name().is_ok_and(|name| name.to_lowercase() == app))
+        .filter(|w| !w.is_minimized().unwrap_or(false))
+        .filter_map(|w| {
+            let area = w.width().ok()? as u64 * w.height().ok()? as u64;
+            Some((w.is_focused().unwrap_or(false), area, w))
+        });
+    pick_app_window(windows)
+}
+
+/// find_app_window's choice among an app's visible windows, each with
+/// whether it has focus and its area: the focused one, else the largest
+fn pick_app_window<W>(windows: impl IntoIterator<Item = (bool, u64, W)>) -> Option<W> {
+    windows
+        .into_iter()
+        .filter(|(_, area, _)| *area > 0)
+        .max_by_key(|(focused, area, _)| (*focused, *area))
+        .map(|(_, _, w)| w)
+}
+
+/// find_app_window, retried for up to APP_WAIT: an app launched alongside
+/// foundry may not have opened a window yet
+fn wait_for_app_window(app: &str) -> anyhow::Result<Window> {
+    let deadline = Instant::now() + APP_WAIT;
     loop {
-        match startstop_receiver.recv() {
-            Ok(start) => {
-                if start && !started {
-                    video_recorder.start().unwrap();
-                    println!("Video recorder started");
-                    started = true;
-                }
-                if !start && started {
-                    video_recorder.stop().unwrap();
-                    println!("Video recorder stopped");
-                    started = false;
-                }
-            }
-            Err(_) => break,
+        if let Some(window) = find_app_window(app) {
+            return Ok(window);
+        }
+        if Instant::now() >= deadline {
+            anyhow::bail!("no window of app `{}` after {}s", app, APP_WAIT.as_secs());
         }
+        thread::sleep(REATTACH_POLL);
     }
 }
 
-/// Window capture using polling with capture_image()
+/// Window capture using polling with capture_image(); with `follow`, the
+/// window is swapped for whichever one takes focus (or, for an app, for
+/// whichever of its windows find_app_window picks). When the window closes,
+/// `source` either waits for one with the same app and title or gives up.
 fn create_window_recorder_thread(
-    window_id: u32,
-    listeners: Arc<Mutex<Vec<ListenerSender>>>,
-    video_startstop: std::sync::mpsc::Sender<bool>,
-    startstop_receiver: std::sync::mpsc::Receiver<bool>,
+    mut window: Window,
+    follow: Option<FocusFollow>,
+    source: SourceWatch,
+    context: CaptureContext,
 ) {
-    let windows = Window::all().unwrap();
-    let window = windows
-        .into_iter()
-        .find(|w| w.id().unwrap_or(0) == window_id)
-        .expect(&format!("Window with ID {} not found", window_id));
+    let CaptureContext {
+        listeners,
+        video_startstop,
+        startstop_receiver,
+        show_cursor,
+        source_state,
+        rate,
+        dedupe,
+        ..
+    } = context;
+    let window_id = window.id().unwrap_or(0);
 
     println!(
         "Creating video recorder for window: {} [id {}] (app: {})",
@@ -149,69 +1752,134 @@ fn create_window_recorder_thread(
 
     let running = Arc::new(AtomicBool::new(false));
     let running_clone = running.clone();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let shutdown_clone = shutdown.clone();
     let listeners_clone = listeners.clone();
     let video_startstop_clone = video_startstop.clone();
+    let thread_state = source_state.clone();
+    let thread_rate = rate.clone();
 
     // Capture thread - polls window at target FPS
     thread::spawn(move || {
-        let frame_duration = Duration::from_secs_f64(1.0 / WINDOW_CAPTURE_FPS as f64);
+        let mut meter = RateMeter::new(thread_rate.clone());
+        // When the next capture is due; None restarts the schedule
+        let mut next_at: Option<Instant> = None;
+        let mut cursor = show_cursor.then(|| CursorOverlay::new(CaptureRegion::of_window(&window)));
+        let mut region_read_at = Instant::now();
+        let mut focus = follow.map(|follow| (FocusTracker::new(window_id, follow.app.clone()), follow));
+        let mut unchanged = dedupe.then(Dedupe::default);
+        let mut lost = false;
 
         loop {
+            if shutdown_clone.load(Ordering::Relaxed) {
+                break;
+            }
             if !running_clone.load(Ordering::Relaxed) {
                 thread::sleep(Duration::from_millis(10));
+                next_at = None;
                 continue;
             }
 
+            // Only set with --reattach-by-title
+            if let Some((app, title)) = source.reattach.as_ref().filter(|_| lost) {
+                thread::sleep(REATTACH_POLL);
+                let Some(found) = find_window_by_title(app, title) else {
+                    continue;
+                };
+                println!("Reattached to window {} ({})", found.id().unwrap_or(0), title);
+                if let Some(cursor) = cursor.as_mut() {
+                    cursor.set_region(CaptureRegion::of_window(&found));
+                    region_read_at = Instant::now();
+                }
+                window = found;
+                *source.name.lock().unwrap() = Some(title.clone()).filter(|t| !t.is_empty());
+                lost = false;
+                window_restored(&listeners_clone, &thread_state);
+            }
+
             let start = Instant::now();
 
+            if let Some((tracker, follow)) = focus.as_mut() {
+                if let Some(next_id) = tracker.poll(start) {
+                    match find_window(next_id) {
+                        Ok(next) => {
+                            let title = next.title().ok().filter(|t| !t.is_empty());
+                            println!(
+                                "Following {} to window {} ({})",
+                                follow.app.as_deref().unwrap_or("focus"),
+                                next_id,
+                                title.as_deref().unwrap_or("<untitled>")
+                            );
+                            if let Some(cursor) = cursor.as_mut() {
+                                cursor.set_region(CaptureRegion::of_window(&next));
+                                region_read_at = start;
+                            }
+                            window = next;
+                            *follow.name.lock().unwrap() = title;
+                            // Sessions restart from an IDR with the new window's config
+                            follow.changes.send_modify(|changes| *changes += 1);
+                        }
+                        Err(err) => eprintln!("Can't follow focus: {}", err),
+                    }
+                }
+            }
+
             // Capture the window
             match window.capture_image() {
                 Ok(image) => {
+                    let captured_at = Instant::now();
                     // Use image dimensions (includes Retina 2x scaling)
-                    let frame = Frame {
+                    let mut frame = Frame {
                         width: image.width(),
                         height: image.height(),
                         raw: image.into_raw(),
                     };
-                    let frame = Arc::new(frame);
-
-                    let mut listeners = listeners_clone.lock().unwrap();
-                    if !listeners.is_empty() {
-                        static DROPPED_COUNT: std::sync::atomic::AtomicU64 =
-                            std::sync::atomic::AtomicU64::new(0);
-
-                        listeners.retain(|listener| match listener.try_send(frame.clone()) {
-                            Ok(_) => {
-                                DROPPED_COUNT.store(0, Ordering::Relaxed);
-                                true
-                            }
-                            Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => {
-                                let count = DROPPED_COUNT.fetch_add(1, Ordering::Relaxed);
-                                if count % 60 == 0 {
-                                    eprintln!("encoder can't keep up, dropped {} frames", count + 1);
-                                }
-                                true
-                            }
-                            Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => false,
-                        });
-
-                        if listeners.is_empty() {
-                            println!("no listeners left, stopping window capture");
-                            video_startstop_clone.send(false).unwrap();
+                    let stride = packed_stride(&frame);
+                    if let Some(cursor) = cursor.as_mut() {
+                        // The window may have moved since we last looked
+                        if region_read_at.elapsed() >= WINDOW_REGION_REFRESH {
+                            cursor.set_region(CaptureRegion::of_window(&window));
+                            region_read_at = Instant::now();
                         }
+                        cursor.stamp(&mut frame, stride);
+                    }
+                    let frame = CapturedFrame::new(frame, stride, captured_at);
+                    if unchanged.as_mut().is_some_and(|unchanged| unchanged.skip(&frame)) {
+                        thread_rate.skipped_frames.fetch_add(1, Ordering::Relaxed);
+                    } else {
+                        fan_out(&listeners_clone, frame, &mut meter, &video_startstop_clone, "window capture");
                     }
                 }
-                Err(e) => {
+                Err(e) if focus.is_some() => {
+                    // The followed window closed; wait for focus to land
+                    // elsewhere, or for the app to pick another of its windows
                     eprintln!("Window capture failed: {}", e);
-                    // Window might have closed - stop capturing
-                    break;
+                    thread::sleep(FOCUS_POLL);
+                    continue;
+                }
+                Err(e) => {
+                    // Most likely closed
+                    if !window_lost(&listeners_clone, &thread_state, source.reattach.is_some(), &e) {
+                        break;
+                    }
+                    lost = true;
+                    continue;
                 }
             }
 
-            // Sleep for remaining frame time
-            let elapsed = start.elapsed();
-            if elapsed < frame_duration {
-                thread::sleep(frame_duration - elapsed);
+            // Captures are scheduled a whole frame apart, so time spent in
+            // capture_image() shortens the sleep instead of lowering the rate.
+            // Falling more than a frame behind starts the schedule over
+            // rather than capturing back to back to catch up.
+            let fps = thread_rate.target_fps.load(Ordering::Relaxed).max(1);
+            let frame_duration = Duration::from_secs_f64(1.0 / fps as f64);
+            let due = next_at.unwrap_or(start) + frame_duration;
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+                next_at = Some(due);
+            } else {
+                next_at = Some(now);
             }
         }
         println!("window capture thread stopped");
@@ -220,72 +1888,61 @@ fn create_window_recorder_thread(
     // Control thread - handles start/stop commands
     loop {
         match startstop_receiver.recv() {
-            Ok(start) => {
-                let was_running = running.load(Ordering::Relaxed);
-                if start && !was_running {
-                    running.store(true, Ordering::Relaxed);
+            Ok(CaptureControl::Start) => {
+                // The capture thread is gone; new listeners only hear why
+                let ended = match &*source_state.borrow() {
+                    SourceState::Ended { reason } => Some(reason.clone()),
+                    _ => None,
+                };
+                if let Some(reason) = ended {
+                    capture_failed(&listeners, &source_state, &reason);
+                } else if !running.swap(true, Ordering::Relaxed) {
                     println!("Window capture started");
                 }
-                if !start && was_running {
-                    running.store(false, Ordering::Relaxed);
+            }
+            Ok(CaptureControl::Stop) => {
+                if running.swap(false, Ordering::Relaxed) {
                     println!("Window capture stopped");
                 }
             }
-            Err(_) => break,
+            Ok(CaptureControl::SetFps(fps)) => {
+                println!("Window capture rate set to {} fps", fps);
+                rate.target_fps.store(fps, Ordering::Relaxed);
+            }
+            Ok(CaptureControl::Shutdown) | Err(_) => {
+                shutdown.store(true, Ordering::Relaxed);
+                break;
+            }
         }
     }
 }
 
 fn create_frame_receiver_thread(
     frame_receiver: std::sync::mpsc::Receiver<Frame>,
-    listeners: Arc<Mutex<Vec<ListenerSender>>>,
-    video_startstop: std::sync::mpsc::Sender<bool>,
+    listeners: Arc<Feed>,
+    video_startstop: ControlSender,
+    cursor: Option<CursorOverlay>,
+    rate: Arc<CaptureRate>,
+    region: Option<CropRect>,
 ) {
+    let mut meter = RateMeter::new(rate);
     loop {
         match frame_receiver.recv() {
-            Ok(frame) => {
+            Ok(mut frame) => {
+                let captured_at = Instant::now();
                 // println!(
                 //     "frame: {} x {} ({} bytes)",
                 //     frame.width,
                 //     frame.height,
                 //     frame.raw.len()
                 // );
-                let frame = Arc::new(frame);
-
-                let mut listeners = listeners.lock().unwrap();
-                if !listeners.is_empty() {
-                    // println!("sending frame to {} listeners", listeners.len());
-                    static DROPPED_COUNT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
-                    
-                    listeners.retain(|listener| match listener.try_send(frame.clone()) {
-                        Ok(_) => {
-                            // Reset drop counter on successful send
-                            DROPPED_COUNT.store(0, std::sync::atomic::Ordering::Relaxed);
-                            true
-                        },
-                        Err(tokio::sync::mpsc::error::TrySendError::Full(_frame)) => {
-                            // Only log occasionally to avoid spam
-                            let count = DROPPED_COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                            if count % 60 == 0 {
-                                eprintln!("encoder can't keep up, dropped {} frames", count + 1);
-                            }
-                            true
-                        },
-                        Err(tokio::sync::mpsc::error::TrySendError::Closed(frame)) => {
-                            println!("listener closed: frame: {} x {} ({} bytes)",
-                                frame.width,
-                                frame.height,
-                                frame.raw.len()
-                            );
-                            false
-                        },
-                    });
-
-                    if listeners.is_empty() {
-                        println!("no listeners left, stopping video recorder");
-                        video_startstop.send(false).unwrap();
-                    }
+                let stride = xcap_stride(&frame);
+                if let Some(cursor) = &cursor {
+                    cursor.stamp(&mut frame, stride);
                 }
+                let (frame, stride) = crop_to_region(frame, stride, region);
+                let frame = CapturedFrame::new(frame, stride, captured_at);
+                fan_out(&listeners, frame, &mut meter, &video_startstop, "video recorder");
             }
             Err(err) => {
                 eprintln!("frame receiver error: {}", err);
@@ -296,3 +1953,656 @@ fn create_frame_receiver_thread(
     }
     println!("recorder stopped");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+
+    /// `width` x `height` packed frame whose pixel (x, y) is [x, y, 0, 255]
+    fn coordinate_frame(width: u32, height: u32) -> Frame {
+        let mut raw = Vec::with_capacity((width * height * 4) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                raw.extend_from_slice(&[x as u8, y as u8, 0, 255]);
+            }
+        }
+        Frame { width, height, raw }
+    }
+
+    /// `frame` as a packed capture taken `at`
+    fn captured(frame: Frame, at: Instant) -> CapturedFrame {
+        let stride = packed_stride(&frame);
+        CapturedFrame::new(frame, stride, at)
+    }
+
+    /// `frame` with `pad` junk pixels after every row, as some capture backends deliver
+    fn padded(frame: &Frame, pad: usize) -> Frame {
+        let row_bytes = frame.width as usize * 4;
+        let mut raw = Vec::new();
+        for row in frame.raw.chunks_exact(row_bytes) {
+            raw.extend_from_slice(row);
+            raw.extend(std::iter::repeat_n(0xee, pad * 4));
+        }
+        Frame { raw, ..frame.clone() }
+    }
+
+    /// A sender and listener joined by their own slot, without a Recorder
+    fn listener_pair(options: ListenerOptions) -> (ListenerSender, Listener) {
+        let slot = Arc::new(FrameSlot {
+            depth: options.channel_depth.max(1),
+            ..Default::default()
+        });
+        let listener = Listener {
+            slot: slot.clone(),
+            size: None,
+            pending: None,
+        };
+        (ListenerSender::new(slot, options), listener)
+    }
+
+    #[tokio::test]
+    async fn a_slow_listener_holds_only_the_newest_frames() {
+        for depth in [1, 3] {
+            let (mut sender, mut listener) = listener_pair(ListenerOptions {
+                channel_depth: depth,
+                ..Default::default()
+            });
+            let start = Instant::now();
+            let frames: Vec<_> = (0..10)
+                .map(|i| CapturedFrame {
+                    sequence: i,
+                    ..captured(coordinate_frame(4, 4), start + Duration::from_millis(i * 16))
+                })
+                .collect();
+            for frame in &frames {
+                assert!(sender.offer(frame.clone()));
+                assert!(listener.slot.frames.lock().unwrap().len() <= depth);
+            }
+            drop(sender);
+            let mut received = Vec::new();
+            while let Some(frame) = listener.recv().await {
+                received.push(frame.sequence);
+            }
+            let newest: Vec<_> = frames[10 - depth..].iter().map(|frame| frame.sequence).collect();
+            assert_eq!(received, newest);
+        }
+    }
+
+    #[test]
+    fn monitors_are_picked_by_id_or_as_the_primary_one() {
+        let monitors = || [("left", Some(1), false), ("main", Some(2), true), ("broken", None, false)];
+        let listed = || vec!["1\tleft".to_string(), "2\tmain\tprimary".to_string()];
+        assert_eq!(pick_monitor(monitors(), Some(1), listed).unwrap(), "left");
+        assert_eq!(pick_monitor(monitors(), None, listed).unwrap(), "main");
+        let err = pick_monitor(monitors(), Some(7), listed).unwrap_err().to_string();
+        assert!(err.starts_with("Monitor with ID 7 not found") && err.contains("2\tmain\tprimary"), "{err}");
+    }
+
+    #[test]
+    fn no_monitors_is_an_error_not_a_panic() {
+        let none: [((), Option<u32>, bool); 0] = [];
+        let err = pick_monitor(none, None, Vec::new).unwrap_err();
+        assert!(err.to_string().starts_with("No primary monitor found"), "{err}");
+        let err = pick_monitor(none, Some(1), Vec::new).unwrap_err();
+        assert!(err.to_string().starts_with("Monitor with ID 1 not found"), "{err}");
+    }
+
+    #[test]
+    fn unknown_window_ids_are_an_error() {
+        let windows = || [(0, "untitled"), (41, "editor"), (42, "terminal")];
+        assert_eq!(pick_window(windows(), 42).unwrap(), "terminal");
+        let err = pick_window(windows(), 43).unwrap_err();
+        assert_eq!(err.to_string(), "Window with ID 43 not found");
+    }
+
+    #[test]
+    fn a_missing_window_fails_the_recorder_up_front() {
+        let options = CaptureOptions {
+            show_cursor: false,
+            reattach_by_title: false,
+            backend: CaptureBackend::Xcap,
+            dedupe: false,
+        };
+        let source = CaptureSource::Window { id: u32::MAX, fps: DEFAULT_WINDOW_FPS };
+        assert!(Recorder::new(source, None, options).is_err());
+    }
+
+    #[tokio::test]
+    async fn a_new_size_is_announced_before_its_first_frame() {
+        let (mut sender, mut listener) = listener_pair(ListenerOptions {
+            channel_depth: 8,
+            ..Default::default()
+        });
+        let start = Instant::now();
+        for (i, (width, height)) in [(64, 48), (64, 48), (96, 64), (96, 64), (64, 48)].into_iter().enumerate() {
+            sender.offer(captured(coordinate_frame(width, height), start + Duration::from_millis(i as u64)));
+        }
+        drop(sender);
+        let mut events = Vec::new();
+        while let Some(event) = listener.recv_event().await {
+            events.push(match event {
+                FrameEvent::Frame(captured) => format!("{}x{}", captured.frame.width, captured.frame.height),
+                FrameEvent::ResolutionChanged { width, height } => format!("resize {}x{}", width, height),
+                other => panic!("unexpected {other:?}"),
+            });
+        }
+        assert_eq!(
+            events,
+            ["64x48", "64x48", "resize 96x64", "96x64", "96x64", "resize 64x48", "64x48"]
+        );
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[tokio::test]
+    async fn a_resize_reaches_the_encoder_as_a_new_config_and_keyframe() {
+        use crate::video_pipeline::{PipelineFrame, PipelineOptions, VideoCodec, VideoPipeline};
+
+        let (mut sender, mut listener) = listener_pair(ListenerOptions {
+            channel_depth: 8,
+            ..Default::default()
+        });
+        let start = Instant::now();
+        for (i, (width, height)) in [(64, 48), (64, 48), (96, 64), (96, 64)].into_iter().enumerate() {
+            sender.offer(captured(coordinate_frame(width, height), start + Duration::from_millis(i as u64)));
+        }
+        drop(sender);
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, PipelineOptions::default()).unwrap();
+        let mut out = Vec::new();
+        while let Some(event) = listener.recv_event().await {
+            let FrameEvent::Frame(captured) = event else { continue };
+            let frame = PipelineFrame::Rgba {
+                frame: captured.frame,
+                stride: captured.stride_bytes,
+                format: PixelFormat::Bgra8888,
+            };
+            let encoded = pipeline.encode(frame, captured.captured_at, false).unwrap().unwrap();
+            let config = encoded.new_config.map(|config| (config.width, config.height));
+            out.push((config, encoded.chunks[0].is_keyframe));
+        }
+        assert_eq!(out, [(Some((64, 48)), true), (None, false), (Some((96, 64)), true), (None, false)]);
+    }
+
+    /// The events waiting for `listener`, each with the source state as it
+    /// is now
+    fn drain_events(listener: &mut Listener, state: &watch::Sender<SourceState>) -> Vec<(String, SourceState)> {
+        let mut events = Vec::new();
+        while let Some(event) = listener.recv_event().now_or_never().flatten() {
+            let event = match event {
+                FrameEvent::Frame(captured) => format!("frame {}", captured.frame.width),
+                FrameEvent::SourceLost { reason } => format!("lost: {}", reason),
+                other => format!("{:?}", other),
+            };
+            events.push((event, state.borrow().clone()));
+        }
+        events
+    }
+
+    /// What a listener sees from a window thread whose captures give
+    /// `captures` in turn, each event with the source state right after it
+    fn poll_window(captures: Vec<Result<Frame, &str>>, reattach: bool) -> Vec<(String, SourceState)> {
+        let (sender, mut listener) = listener_pair(ListenerOptions {
+            channel_depth: 8,
+            ..Default::default()
+        });
+        let feed = Feed {
+            listeners: Arc::new(Mutex::new(vec![sender])),
+            retired: AtomicBool::new(false),
+        };
+        let state = watch::channel(SourceState::Capturing).0;
+        let mut lost = false;
+        let mut events = Vec::new();
+        for capture in captures {
+            match capture {
+                Ok(frame) => {
+                    if lost {
+                        window_restored(&feed, &state);
+                        lost = false;
+                    }
+                    for listener in feed.lock().unwrap().iter_mut() {
+                        listener.offer(captured(frame.clone(), Instant::now()));
+                    }
+                }
+                Err(err) => {
+                    if !window_lost(&feed, &state, reattach, &err) {
+                        break;
+                    }
+                    lost = true;
+                }
+            }
+            events.extend(drain_events(&mut listener, &state));
+        }
+        // Still attached unless capture ended
+        let ended = matches!(*state.borrow(), SourceState::Ended { .. });
+        assert_eq!(feed.lock().unwrap().is_empty(), ended);
+        drop(feed);
+        events.extend(drain_events(&mut listener, &state));
+        assert!(matches!(listener.recv_event().now_or_never(), Some(None)), "stream still open");
+        events
+    }
+
+    #[test]
+    fn a_closed_window_ends_capture_without_reattach() {
+        let captures = vec![Ok(coordinate_frame(4, 4)), Err("window closed"), Ok(coordinate_frame(4, 4))];
+        let events = poll_window(captures, false);
+        let ended = SourceState::Ended {
+            reason: "window closed".to_string(),
+        };
+        assert_eq!(
+            events,
+            [
+                ("frame 4".to_string(), SourceState::Capturing),
+                ("lost: window closed".to_string(), ended),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_reopened_window_is_reattached() {
+        let captures = vec![
+            Ok(coordinate_frame(4, 4)),
+            Err("window closed"),
+            Err("window closed"),
+            Ok(coordinate_frame(6, 4)),
+        ];
+        let events = poll_window(captures, true);
+        let lost = SourceState::Lost {
+            reason: "window closed".to_string(),
+        };
+        let events: Vec<_> = events.iter().map(|(event, state)| (event.as_str(), state.clone())).collect();
+        assert_eq!(
+            events,
+            [
+                ("frame 4", SourceState::Capturing),
+                ("lost: window closed", lost.clone()),
+                ("lost: window closed", lost),
+                ("SourceRestored", SourceState::Capturing),
+                // It came back at another size
+                ("ResolutionChanged { width: 6, height: 4 }", SourceState::Capturing),
+                ("frame 6", SourceState::Capturing),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_replaced_capture_no_longer_speaks_for_the_source() {
+        let (sender, listener) = listener_pair(ListenerOptions::default());
+        let feed = Feed {
+            listeners: Arc::new(Mutex::new(vec![sender])),
+            retired: AtomicBool::new(true),
+        };
+        let state = watch::channel(SourceState::Capturing).0;
+        assert!(!window_lost(&feed, &state, false, &"window closed"));
+        assert!(window_lost(&feed, &state, true, &"window closed"));
+        assert_eq!(*state.borrow(), SourceState::Capturing);
+        assert!(listener.slot.notices.lock().unwrap().is_empty());
+    }
+
+    /// Offers 60 Hz captures, `jitter_ms` late at most, to a listener with
+    /// `options` that takes each one straight away; returns the indices of
+    /// the frames it got. Capture `resize_at` and later are wider.
+    fn deliver_60hz(options: ListenerOptions, count: u64, jitter_ms: u64, resize_at: u64) -> Vec<u64> {
+        let (mut sender, mut listener) = listener_pair(options);
+        let start = Instant::now();
+        let mut state = 3u32;
+        let mut received = Vec::new();
+        for i in 0..count {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let late = Duration::from_micros((state >> 8) as u64 % (jitter_ms * 1000 + 1));
+            let width = if i < resize_at { 4 } else { 6 };
+            let at = start + Duration::from_micros(i * 1_000_000 / 60) + late;
+            sender.offer(captured(coordinate_frame(width, 4), at));
+            if let Some(frame) = listener.recv().now_or_never().flatten() {
+                assert_eq!(frame.captured_at, at);
+                received.push(i);
+            }
+        }
+        received
+    }
+
+    #[test]
+    fn a_10fps_listener_gets_one_frame_in_six_of_60hz() {
+        let options = ListenerOptions {
+            max_fps: Some(10.0),
+            ..Default::default()
+        };
+        for jitter_ms in [0, 4] {
+            let received = deliver_60hz(options, 600, jitter_ms, u64::MAX);
+            assert_eq!(received[0], 0, "first frame");
+            // 10 s at 10 fps, give or take the frame at either end
+            assert!((99..=101).contains(&received.len()), "{} frames with {} ms jitter", received.len(), jitter_ms);
+            assert!(received.windows(2).all(|pair| (5..=7).contains(&(pair[1] - pair[0]))), "{received:?}");
+        }
+    }
+
+    #[test]
+    fn a_new_size_gets_past_the_cap() {
+        let options = ListenerOptions {
+            max_fps: Some(10.0),
+            ..Default::default()
+        };
+        let received = deliver_60hz(options, 60, 0, 32);
+        assert!(received.contains(&32), "{received:?}");
+    }
+
+    #[test]
+    fn default_options_deliver_every_frame() {
+        assert_eq!(deliver_60hz(ListenerOptions::default(), 120, 4, 60), (0..120).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn each_capture_numbers_its_own_frames() {
+        let primary = Recorder::without_capture(PixelFormat::Bgra8888);
+        let inset = Recorder::without_capture(PixelFormat::Bgra8888);
+        let (mut primary_frames, mut inset_frames) = (primary.new_listener(), inset.new_listener());
+        let (mut primary_sequences, mut inset_sequences) = (Vec::new(), Vec::new());
+        // Interleaved, like a picture-in-picture capture beside the main one
+        for _ in 0..5 {
+            assert!(primary.capture_test_frame(coordinate_frame(2, 2)));
+            assert!(inset.capture_test_frame(coordinate_frame(2, 2)));
+            primary_sequences.push(primary_frames.recv().now_or_never().flatten().unwrap().sequence);
+            inset_sequences.push(inset_frames.recv().now_or_never().flatten().unwrap().sequence);
+        }
+        assert_eq!(primary_sequences, [0, 1, 2, 3, 4]);
+        assert_eq!(inset_sequences, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn a_lagging_listener_sees_the_gaps_in_sequence_and_time() {
+        let (mut sender, mut listener) = listener_pair(ListenerOptions::default());
+        let start = Instant::now();
+        let mut received = Vec::new();
+        for i in 0..30u64 {
+            let at = start + Duration::from_micros(i * 16_667);
+            sender.offer(CapturedFrame {
+                sequence: i,
+                ..captured(coordinate_frame(2, 2), at)
+            });
+            // Takes only every third capture
+            if i % 3 == 2 {
+                received.push(listener.recv().now_or_never().flatten().unwrap());
+            }
+        }
+        for pair in received.windows(2) {
+            assert_eq!(pair[1].sequence - pair[0].sequence, 3);
+            let delta = pair[1].captured_at - pair[0].captured_at;
+            assert_eq!(delta, Duration::from_micros(3 * 16_667));
+        }
+    }
+
+    #[test]
+    fn an_apps_focused_window_wins_then_its_largest() {
+        let windows = [(false, 800 * 600, "document"), (false, 1440 * 900, "main"), (false, 300 * 200, "dialog")];
+        assert_eq!(pick_app_window(windows), Some("main"));
+        let windows = [(false, 1440 * 900, "main"), (true, 300 * 200, "dialog")];
+        assert_eq!(pick_app_window(windows), Some("dialog"));
+        // Zero-sized windows (e.g. offscreen helpers) are never picked, even focused
+        assert_eq!(pick_app_window([(true, 0, "helper"), (false, 640 * 480, "main")]), Some("main"));
+        assert_eq!(pick_app_window([(true, 0, "helper")]), None);
+        assert_eq!(pick_app_window(Vec::<(bool, u64, &str)>::new()), None);
+    }
+
+    #[test]
+    fn an_app_switches_windows_once_the_new_one_settles() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut tracker = FocusTracker::new(1, Some("Editor".to_string()));
+        assert_eq!(tracker.settle(1, at(250)), None);
+        // A dialog opens and keeps focus
+        assert_eq!(tracker.settle(2, at(500)), None);
+        assert_eq!(tracker.settle(2, at(750)), None);
+        assert_eq!(tracker.settle(2, at(1000)), Some(2));
+        assert_eq!(tracker.settle(2, at(1250)), None);
+        // Focus flickers to another window and back: no switch
+        assert_eq!(tracker.settle(3, at(1500)), None);
+        assert_eq!(tracker.settle(2, at(1750)), None);
+        assert_eq!(tracker.settle(3, at(2000)), None);
+        assert_eq!(tracker.settle(3, at(2250)), None);
+        assert_eq!(tracker.settle(3, at(2500)), Some(3));
+    }
+
+    #[test]
+    fn app_sources_parse_from_set_source() {
+        let source = CaptureSource::from_json(&serde_json::json!({ "kind": "app", "name": "Safari" }), 12).unwrap();
+        assert!(matches!(&source, CaptureSource::App { name, fps: 12 } if name == "Safari"));
+        assert_eq!(source.to_json(), serde_json::json!({ "kind": "app", "name": "Safari", "fps": 12 }));
+        for missing in [serde_json::json!({ "kind": "app" }), serde_json::json!({ "kind": "app", "name": "" })] {
+            assert!(CaptureSource::from_json(&missing, 12).is_err());
+        }
+    }
+
+    /// Which of `frames`, captured at 60 Hz, Dedupe lets through
+    fn deduped(frames: impl IntoIterator<Item = Frame>) -> Vec<usize> {
+        let start = Instant::now();
+        let mut dedupe = Dedupe::default();
+        frames
+            .into_iter()
+            .enumerate()
+            .filter(|(i, frame)| {
+                let at = start + Duration::from_micros(*i as u64 * 16_667);
+                !dedupe.skip(&captured(frame.clone(), at))
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    #[test]
+    fn a_static_window_is_delivered_once_a_second() {
+        let still = coordinate_frame(64, 100);
+        let delivered = deduped(std::iter::repeat_n(still, 600));
+        // The first capture, then the keep-alive: 10 s at 1 fps
+        assert_eq!(delivered.len(), 10, "{delivered:?}");
+        assert_eq!(delivered[0], 0);
+        assert!(delivered.windows(2).all(|pair| pair[1] - pair[0] == 60), "{delivered:?}");
+    }
+
+    #[test]
+    fn a_change_is_delivered_straight_away() {
+        let still = coordinate_frame(64, 100);
+        // One pixel on a row the sampled compare skips
+        let mut edited = still.clone();
+        edited.raw[(5 * 64 + 7) * 4] ^= 0xff;
+        let mut frames = vec![still.clone(); 20];
+        frames[10] = edited.clone();
+        frames[11] = edited;
+        assert_eq!(deduped(frames), [0, 10, 12]);
+        // A moving picture is never held back
+        let moving = (0..30).map(|i| coordinate_frame(64 + i % 2, 100));
+        assert_eq!(deduped(moving).len(), 30);
+    }
+
+    /// A `width` x `height` frame filled with `source`, to tell captures apart
+    fn source_frame(width: u32, height: u32, source: u8) -> Frame {
+        Frame {
+            width,
+            height,
+            raw: vec![source; (width * height * 4) as usize],
+        }
+    }
+
+    /// Switches `listener` from a capture delivering `old` frames in a loop on
+    /// its own thread to one delivering three `new` frames, the way
+    /// switch_source does; returns what the listener got after its first old
+    /// frame, as size and source for frames and the event otherwise
+    fn switch_between(old: Frame, new: Frame, options: ListenerOptions) -> Vec<String> {
+        let (sender, mut listener) = listener_pair(options);
+        let listeners = Arc::new(Mutex::new(vec![sender]));
+        let feed = |listeners: &Arc<Mutex<Vec<ListenerSender>>>| {
+            Arc::new(Feed {
+                listeners: listeners.clone(),
+                retired: AtomicBool::new(false),
+            })
+        };
+        let (old_feed, new_feed) = (feed(&listeners), feed(&listeners));
+        let offer = |feed: &Feed, frame: &Frame| {
+            for listener in feed.lock().iter_mut().flat_map(|listeners| listeners.iter_mut()) {
+                listener.offer(captured(frame.clone(), Instant::now()));
+            }
+        };
+
+        // The viewer is already watching the old source
+        offer(&old_feed, &old);
+        assert!(matches!(listener.recv_event().now_or_never(), Some(Some(FrameEvent::Frame(_)))));
+
+        // The old capture keeps going until it notices it was replaced
+        let old_thread = {
+            let old_feed = old_feed.clone();
+            thread::spawn(move || {
+                for _ in 0..2000 {
+                    offer(&old_feed, &old);
+                    announce(&old_feed, FrameEvent::SourceLost { reason: "late".into() });
+                }
+            })
+        };
+        thread::sleep(Duration::from_millis(2));
+        old_feed.retire(&mut listeners.lock().unwrap());
+        for _ in 0..3 {
+            offer(&new_feed, &new);
+        }
+        old_thread.join().unwrap();
+        drop(new_feed);
+        drop(old_feed);
+        drop(listeners);
+
+        let mut events = Vec::new();
+        while let Some(event) = listener.recv_event().now_or_never().flatten() {
+            events.push(match event {
+                FrameEvent::Frame(captured) => {
+                    let frame = captured.frame;
+                    format!("{}x{} from {}", frame.width, frame.height, frame.raw[0])
+                }
+                FrameEvent::SourceLost { .. } => "SourceLost".to_string(),
+                FrameEvent::SourceChanged => "SourceChanged".to_string(),
+                FrameEvent::ResolutionChanged { width, height } => format!("resize {}x{}", width, height),
+                FrameEvent::SourceRestored => "SourceRestored".to_string(),
+            });
+        }
+        events
+    }
+
+    #[test]
+    fn nothing_from_the_old_source_follows_the_switch() {
+        let options = ListenerOptions {
+            channel_depth: 4,
+            ..Default::default()
+        };
+        // Monitor to window: another size
+        let events = switch_between(source_frame(64, 36, 1), source_frame(40, 30, 2), options);
+        let switched = events.iter().position(|event| event == "SourceChanged").expect("no SourceChanged");
+        assert_eq!(events[switched + 1..], ["resize 40x30", "40x30 from 2", "40x30 from 2", "40x30 from 2"]);
+        // Window to window of the same size
+        let events = switch_between(source_frame(40, 30, 1), source_frame(40, 30, 2), options);
+        let switched = events.iter().position(|event| event == "SourceChanged").expect("no SourceChanged");
+        assert_eq!(events[switched + 1..], ["40x30 from 2", "40x30 from 2", "40x30 from 2"]);
+    }
+
+    #[test]
+    fn the_new_sources_first_frame_is_not_held_by_the_frame_cap() {
+        let options = ListenerOptions {
+            max_fps: Some(1.0),
+            channel_depth: 4,
+        };
+        let events = switch_between(source_frame(40, 30, 1), source_frame(40, 30, 2), options);
+        let switched = events.iter().position(|event| event == "SourceChanged").expect("no SourceChanged");
+        assert_eq!(events[switched + 1..], ["40x30 from 2"]);
+    }
+
+    #[test]
+    fn region_parses_x_y_width_height() {
+        let region: Region = "0,0,1280x720".parse().unwrap();
+        assert_eq!(region, Region::new(0, 0, 1280, 720).unwrap());
+        let region: Region = " 10, 20 ,640X480".parse().unwrap();
+        assert_eq!((region.x, region.y, region.width, region.height), (10, 20, 640, 480));
+        assert_eq!(region.to_string(), "10,20,640x480");
+    }
+
+    #[test]
+    fn region_rejects_malformed_and_tiny_input() {
+        for bad in ["", "0,0", "0,0,1280", "0,0,1280x", "a,0,10x10", "-1,0,10x10", "0,0,1x720", "0,0,1280x0"] {
+            assert!(bad.parse::<Region>().is_err(), "{bad:?} parsed");
+        }
+    }
+
+    #[test]
+    fn set_source_region_is_validated_like_the_flag() {
+        let source = |width: u32| {
+            serde_json::json!({ "kind": "region", "x": 0, "y": 0, "width": width, "height": 720 })
+        };
+        let parsed = CaptureSource::from_json(&source(1280), DEFAULT_WINDOW_FPS).unwrap();
+        assert!(matches!(
+            parsed,
+            CaptureSource::Region { display_id: None, region } if region == "0,0,1280x720".parse().unwrap()
+        ));
+        assert!(CaptureSource::from_json(&source(1), DEFAULT_WINDOW_FPS).is_err());
+    }
+
+    #[test]
+    fn region_in_pixels_scales_and_rounds() {
+        let region = |s: &str| s.parse::<Region>().unwrap();
+        let rect = region_in_pixels(region("10,20,100x50"), 1440, 900, 2.0).unwrap();
+        assert_eq!(rect, CropRect { x: 20, y: 40, width: 200, height: 100 });
+        // 1.5 pixels per point: 1 -> 2 (rounded) and 3 -> 5, trimmed to even
+        let rect = region_in_pixels(region("1,1,3x3"), 1440, 900, 1.5).unwrap();
+        assert_eq!(rect, CropRect { x: 2, y: 2, width: 4, height: 4 });
+        let rect = region_in_pixels(region("0,0,101x51"), 1440, 900, 1.0).unwrap();
+        assert_eq!(rect, CropRect { x: 0, y: 0, width: 100, height: 50 });
+        // Up to the far edge is still inside
+        let rect = region_in_pixels(region("1340,800,100x100"), 1440, 900, 1.0).unwrap();
+        assert_eq!(rect, CropRect { x: 1340, y: 800, width: 100, height: 100 });
+    }
+
+    #[test]
+    fn region_in_pixels_rejects_out_of_bounds_and_too_small() {
+        let region = |s: &str| s.parse::<Region>().unwrap();
+        assert!(region_in_pixels(region("0,0,1441x10"), 1440, 900, 1.0).is_err());
+        assert!(region_in_pixels(region("1439,0,2x10"), 1440, 900, 1.0).is_err());
+        assert!(region_in_pixels(region("0,0,2x2"), 1440, 900, 0.5).is_err());
+    }
+
+    #[test]
+    fn clamp_to_trims_to_the_frame() {
+        let rect = CropRect { x: 90, y: 5, width: 20, height: 10 };
+        assert_eq!(rect.clamp_to(100, 100), Some(CropRect { x: 90, y: 5, width: 10, height: 10 }));
+        assert_eq!(rect.clamp_to(200, 200), Some(rect));
+        // Less than 2 pixels left either way
+        assert_eq!(CropRect { x: 99, ..rect }.clamp_to(100, 100), None);
+        assert_eq!(CropRect { x: 120, ..rect }.clamp_to(100, 100), None);
+        assert_eq!(rect.clamp_to(100, 6), None);
+    }
+
+    #[test]
+    fn crop_copies_the_rect() {
+        let frame = coordinate_frame(4, 3);
+        let cropped = CropRect { x: 1, y: 1, width: 2, height: 2 }.crop(&frame, packed_stride(&frame));
+        assert_eq!((cropped.width, cropped.height), (2, 2));
+        assert_eq!(cropped.raw, [1, 1, 0, 255, 2, 1, 0, 255, 1, 2, 0, 255, 2, 2, 0, 255]);
+    }
+
+    #[test]
+    fn crop_to_region_clamps_or_passes_through() {
+        let rect = CropRect { x: 2, y: 2, width: 8, height: 8 };
+        let (frame, stride) = crop_to_region(padded(&coordinate_frame(4, 4), 2), 24, Some(rect));
+        assert_eq!((frame.width, frame.height, stride), (2, 2, 8));
+        assert_eq!(&frame.raw[..4], [2, 2, 0, 255]);
+        let (frame, stride) = crop_to_region(padded(&coordinate_frame(4, 4), 2), 24, None);
+        assert_eq!((frame.width, frame.height, stride), (4, 4, 24));
+    }
+
+    #[test]
+    fn xcap_stride_covers_row_padding() {
+        let frame = coordinate_frame(5, 3);
+        assert_eq!(xcap_stride(&frame), 20);
+        assert_eq!(xcap_stride(&padded(&frame, 3)), 32);
+        assert_eq!(xcap_stride(&Frame { width: 5, height: 0, raw: Vec::new() }), 20);
+    }
+
+    #[test]
+    fn padded_frames_crop_without_shear() {
+        let rect = CropRect { x: 1, y: 0, width: 3, height: 3 };
+        let frame = coordinate_frame(5, 3);
+        let cropped = rect.crop(&padded(&frame, 3), 32);
+        assert_eq!(cropped.raw.len(), 12 * 3);
+        assert_eq!(cropped.raw, rect.crop(&frame, 20).raw);
+        assert!(cropped.raw.chunks_exact(4).all(|pixel| pixel != [0xee; 4]));
+    }
+}
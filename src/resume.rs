@@ -0,0 +1,85 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{auth::Role, video_pipeline::VideoCodec};
+
+/// How long a dropped session's settings wait for the client to reconnect
+pub const RESUME_GRACE: Duration = Duration::from_secs(15);
+
+/// Per-session choices restored by `{"type":"resume","token":...}`.
+/// Crop is server-wide, so it survives reconnects without being parked.
+#[derive(Debug, Clone)]
+pub struct SessionSettings {
+    /// Granted by the token at connect; a resume keeps it
+    pub role: Role,
+    pub codec: VideoCodec,
+    /// Resolution cap from the mode message, set-resolution or a quality preset
+    pub max_pixels: Option<usize>,
+    /// Frame rate cap from the mode message or set-fps; 0 = uncapped
+    pub max_fps: u32,
+    /// Outgoing bandwidth cap from the mode message
+    pub max_kbps: Option<u32>,
+    pub preset: Option<&'static str>,
+    /// Audio goes out as OPS0 Opus packets instead of AUD0 PCM
+    pub opus_audio: bool,
+    /// Set by audio-mute / audio-unmute; only this connection stops hearing audio
+    pub audio_muted: bool,
+    /// Milliseconds audio is held back behind video (negative: video behind audio)
+    pub audio_offset_ms: i64,
+    /// `"mode":"audio"`: no video at all, just the audio stream
+    pub audio_only: bool,
+    /// `"transport":"fmp4"`: video goes out as fragmented MP4 for MSE instead of VID0
+    pub fmp4: bool,
+}
+
+/// Settings of recently closed sessions, keyed by resume token
+#[derive(Default)]
+pub struct ResumeRegistry {
+    parked: Mutex<HashMap<String, (SessionSettings, Instant)>>,
+}
+
+impl ResumeRegistry {
+    /// Claim a parked session; None if the token is unknown or has expired
+    pub fn take(&self, token: &str) -> Option<SessionSettings> {
+        let mut parked = self.parked.lock().unwrap();
+        parked.retain(|_, (_, parked_at)| parked_at.elapsed() < RESUME_GRACE);
+        parked.remove(token).map(|(settings, _)| settings)
+    }
+
+    fn park(&self, token: String, settings: SessionSettings) {
+        let mut parked = self.parked.lock().unwrap();
+        parked.retain(|_, (_, parked_at)| parked_at.elapsed() < RESUME_GRACE);
+        parked.insert(token, (settings, Instant::now()));
+    }
+}
+
+/// A live session's resume token and current settings; parks them when the
+/// session ends, however it ends
+pub struct ResumeGuard {
+    registry: Arc<ResumeRegistry>,
+    token: String,
+    pub settings: SessionSettings,
+}
+
+impl ResumeGuard {
+    pub fn new(registry: &Arc<ResumeRegistry>, token: String, settings: SessionSettings) -> Self {
+        Self {
+            registry: registry.clone(),
+            token,
+            settings,
+        }
+    }
+}
+
+impl Drop for ResumeGuard {
+    fn drop(&mut self) {
+        if self.token.is_empty() {
+            return; // token generation failed; nothing a client could present
+        }
+        self.registry
+            .park(std::mem::take(&mut self.token), self.settings.clone());
+    }
+}
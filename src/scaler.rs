@@ -0,0 +1,401 @@
+//! Resizes captured frames to an encoder's pixel budget by any factor, not
+//! just whole divisors. Separable: a horizontal pass into an intermediate
+//! buffer, then a vertical one, each spread over rows with rayon. Bilinear
+//! by default, Lanczos-3 with `--features lanczos`.
+
+use std::sync::Arc;
+
+use rayon::prelude::*;
+use xcap::Frame;
+
+use crate::frame_pool::FramePool;
+
+/// Fixed-point precision of the filter weights
+const WEIGHT_BITS: u32 = 14;
+
+/// Filter radius in source pixels at 1:1; widened by the ratio when shrinking
+#[cfg(not(feature = "lanczos"))]
+const SUPPORT: f64 = 1.0;
+#[cfg(feature = "lanczos")]
+const SUPPORT: f64 = 3.0;
+
+/// Triangle filter: bilinear, averaging over the whole footprint when shrinking
+#[cfg(not(feature = "lanczos"))]
+fn kernel(x: f64) -> f64 {
+    (1.0 - x.abs()).max(0.0)
+}
+
+#[cfg(feature = "lanczos")]
+fn kernel(x: f64) -> f64 {
+    let x = x.abs();
+    if x < 1e-8 {
+        return 1.0;
+    }
+    if x >= SUPPORT {
+        return 0.0;
+    }
+    let pi_x = std::f64::consts::PI * x;
+    SUPPORT * pi_x.sin() * (pi_x / SUPPORT).sin() / (pi_x * pi_x)
+}
+
+/// Filter taps along one axis: output pixel `i` is the weighted sum of
+/// `counts[i]` source pixels from `starts[i]`
+struct Taps {
+    starts: Vec<usize>,
+    counts: Vec<usize>,
+    /// `per_pixel` weights for each output pixel, summing to 1 << WEIGHT_BITS
+    weights: Vec<i32>,
+    per_pixel: usize,
+}
+
+impl Taps {
+    fn new(src_len: usize, dst_len: usize) -> Self {
+        let scale = src_len as f64 / dst_len as f64;
+        let filter_scale = scale.max(1.0);
+        let support = SUPPORT * filter_scale;
+        let per_pixel = support.ceil() as usize * 2 + 2;
+        let mut starts = Vec::with_capacity(dst_len);
+        let mut counts = Vec::with_capacity(dst_len);
+        let mut weights = vec![0i32; dst_len * per_pixel];
+        for (i, out) in weights.chunks_exact_mut(per_pixel).enumerate() {
+            let center = (i as f64 + 0.5) * scale;
+            let start = ((center - support).floor().max(0.0) as usize).min(src_len - 1);
+            let end = ((center + support).ceil() as usize).clamp(start + 1, src_len).min(start + per_pixel);
+            let raw: Vec<f64> = (start..end)
+                .map(|s| kernel((s as f64 + 0.5 - center) / filter_scale))
+                .collect();
+            let total: f64 = raw.iter().sum();
+            let one = 1i32 << WEIGHT_BITS;
+            if total.abs() < 1e-12 {
+                // Narrower than a pixel; take the nearest one
+                out[0] = one;
+            } else {
+                for (w, r) in out.iter_mut().zip(&raw) {
+                    *w = (r / total * one as f64).round() as i32;
+                }
+                // Rounding leftovers go to the biggest tap so flat areas stay exact
+                let sum: i32 = out.iter().sum();
+                let biggest = (0..raw.len()).max_by_key(|&k| out[k]).unwrap_or(0);
+                out[biggest] += one - sum;
+            }
+            starts.push(start);
+            counts.push(end - start);
+        }
+        Self {
+            starts,
+            counts,
+            weights,
+            per_pixel,
+        }
+    }
+
+    fn tap(&self, i: usize) -> (usize, &[i32]) {
+        let weights = &self.weights[i * self.per_pixel..][..self.counts[i]];
+        (self.starts[i], weights)
+    }
+}
+
+/// One weighted sum of 4-byte pixels back to a byte per channel
+fn finish(acc: [i32; 4], out: &mut [u8]) {
+    for (o, a) in out.iter_mut().zip(acc) {
+        *o = ((a + (1 << (WEIGHT_BITS - 1))) >> WEIGHT_BITS).clamp(0, 255) as u8;
+    }
+}
+
+/// (src_w, src_h, dst_w, dst_h)
+type ScaleSize = (usize, usize, usize, usize);
+
+/// Scales frames to fit a pixel budget, keeping the aspect ratio and even
+/// dimensions for I420. Filter taps, the intermediate buffer and output
+/// frames are reused while the sizes stay the same.
+pub struct Scaler {
+    /// Pixel budget; None never scales
+    max_pixels: Option<usize>,
+    /// Sizes the taps were built for, and the taps
+    taps: Option<(ScaleSize, Taps, Taps)>,
+    /// Horizontal pass output: dst_w x src_h
+    intermediate: Vec<u8>,
+    pool: FramePool,
+}
+
+impl Scaler {
+    pub fn new(max_pixels: Option<usize>) -> Self {
+        Self {
+            max_pixels,
+            taps: None,
+            intermediate: Vec::new(),
+            pool: FramePool::default(),
+        }
+    }
+
+    /// Largest even size within the budget with `src_w`:`src_h`'s aspect ratio
+    fn target_size(&self, src_w: usize, src_h: usize) -> (usize, usize) {
+        let pixels = src_w.saturating_mul(src_h);
+        let max_pixels = self.max_pixels.unwrap_or(usize::MAX).max(4);
+        if pixels <= max_pixels {
+            return (src_w & !1, src_h & !1);
+        }
+        let factor = (max_pixels as f64 / pixels as f64).sqrt();
+        let mut dst_w = ((src_w as f64 * factor) as usize & !1).max(2);
+        let mut dst_h = ((src_h as f64 * factor) as usize & !1).max(2);
+        while dst_w * dst_h > max_pixels && (dst_w > 2 || dst_h > 2) {
+            if dst_w >= dst_h {
+                dst_w -= 2;
+            } else {
+                dst_h -= 2;
+            }
+        }
+        (dst_w, dst_h)
+    }
+
+    /// `frame`, with rows `src_stride` bytes apart, resized to fit the budget,
+    /// and the stride of what comes out: packed once resized, `src_stride`
+    /// when passed through. Within the budget, only a trailing odd row or
+    /// column is dropped, rather than resampling the picture for one pixel.
+    pub fn scale(&mut self, frame: Arc<Frame>, src_stride: usize) -> (Arc<Frame>, usize) {
+        let (src_w, src_h) = (frame.width as usize, frame.height as usize);
+        let (dst_w, dst_h) = self.target_size(src_w, src_h);
+        if (dst_w, dst_h) == (src_w, src_h) || dst_w == 0 || dst_h == 0 {
+            return (frame, src_stride);
+        }
+        if (dst_w, dst_h) == (src_w & !1, src_h & !1) {
+            let trimmed = self.pool.render(dst_w as u32, dst_h as u32, |out| {
+                for (y, row) in out.raw.chunks_exact_mut(dst_w * 4).enumerate() {
+                    row.copy_from_slice(&frame.raw[y * src_stride..][..dst_w * 4]);
+                }
+            });
+            return (trimmed, dst_w * 4);
+        }
+
+        let size = (src_w, src_h, dst_w, dst_h);
+        if self.taps.as_ref().map(|(built, _, _)| *built) != Some(size) {
+            self.taps = Some((size, Taps::new(src_w, dst_w), Taps::new(src_h, dst_h)));
+        }
+        let Some((_, horizontal, vertical)) = &self.taps else {
+            return (frame, src_stride);
+        };
+
+        self.intermediate.resize(dst_w * src_h * 4, 0);
+        self.intermediate
+            .par_chunks_mut(dst_w * 4)
+            .enumerate()
+            .for_each(|(y, out_row)| {
+                let src_row = &frame.raw[y * src_stride..][..src_w * 4];
+                for (x, out) in out_row.chunks_exact_mut(4).enumerate() {
+                    let (start, weights) = horizontal.tap(x);
+                    let mut acc = [0i32; 4];
+                    for (px, &w) in src_row[start * 4..].chunks_exact(4).zip(weights) {
+                        for (a, &p) in acc.iter_mut().zip(px) {
+                            *a += p as i32 * w;
+                        }
+                    }
+                    finish(acc, out);
+                }
+            });
+
+        let intermediate = &self.intermediate;
+        let scaled = self.pool.render(dst_w as u32, dst_h as u32, |out| {
+            out.raw
+                .par_chunks_mut(dst_w * 4)
+                .enumerate()
+                .for_each(|(y, out_row)| {
+                    let (start, weights) = vertical.tap(y);
+                    for (x, out) in out_row.chunks_exact_mut(4).enumerate() {
+                        let mut acc = [0i32; 4];
+                        for (k, &w) in weights.iter().enumerate() {
+                            let px = &intermediate[((start + k) * dst_w + x) * 4..][..4];
+                            for (a, &p) in acc.iter_mut().zip(px) {
+                                *a += p as i32 * w;
+                            }
+                        }
+                        finish(acc, out);
+                    }
+                });
+        });
+        (scaled, dst_w * 4)
+    }
+}
+
+/// Box-filter an RGBA image with rows `src_stride` bytes apart down to
+/// `dst_w` x `dst_h` (packed). Each destination pixel averages its share of
+/// the source, so when the sizes don't divide evenly the leftover rows and
+/// columns are folded into neighbouring pixels instead of dropped. Single
+/// threaded; screenshots and MJPEG stills are shrunk with it, by whole factors.
+pub fn average_area(
+    src: &[u8],
+    src_w: usize,
+    src_h: usize,
+    src_stride: usize,
+    dst: &mut [u8],
+    dst_w: usize,
+    dst_h: usize,
+) {
+    for y in 0..dst_h {
+        let sy0 = y * src_h / dst_h;
+        let sy1 = ((y + 1) * src_h / dst_h).max(sy0 + 1);
+        for x in 0..dst_w {
+            let sx0 = x * src_w / dst_w;
+            let sx1 = ((x + 1) * src_w / dst_w).max(sx0 + 1);
+            let mut acc = [0u32; 4];
+            for sy in sy0..sy1 {
+                let row = &src[sy * src_stride + sx0 * 4..sy * src_stride + sx1 * 4];
+                for pixel in row.chunks_exact(4) {
+                    acc[0] += pixel[0] as u32;
+                    acc[1] += pixel[1] as u32;
+                    acc[2] += pixel[2] as u32;
+                    acc[3] += pixel[3] as u32;
+                }
+            }
+            let area = ((sy1 - sy0) * (sx1 - sx0)) as u32;
+            let out_idx = (y * dst_w + x) * 4;
+            dst[out_idx] = (acc[0] / area) as u8;
+            dst[out_idx + 1] = (acc[1] / area) as u8;
+            dst[out_idx + 2] = (acc[2] / area) as u8;
+            dst[out_idx + 3] = (acc[3] / area) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `width` x `height` BGRA frame with `pad` bytes after every row, and
+    /// its stride; the first and last column are white, the rest `fill`
+    fn framed(width: u32, height: u32, pad: usize, fill: u8) -> (Arc<Frame>, usize) {
+        let stride = width as usize * 4 + pad;
+        let mut raw = vec![0xee; stride * height as usize];
+        for row in raw.chunks_exact_mut(stride) {
+            for (x, pixel) in row[..width as usize * 4].chunks_exact_mut(4).enumerate() {
+                let value = if x == 0 || x == width as usize - 1 { 255 } else { fill };
+                pixel.copy_from_slice(&[value, value, value, 255]);
+            }
+        }
+        (Arc::new(Frame { width, height, raw }), stride)
+    }
+
+    #[test]
+    fn retina_capture_fits_1080p_at_its_aspect_ratio() {
+        let scaler = Scaler::new(Some(1920 * 1080));
+        let (width, height) = scaler.target_size(3456, 2234);
+        assert_eq!((width % 2, height % 2), (0, 0));
+        assert!(width * height <= 1920 * 1080);
+        // Not a whole divisor of the source, and filling most of the budget
+        assert!(width * height > 1920 * 1080 * 95 / 100, "{}x{}", width, height);
+        let aspect = |w: usize, h: usize| w as f64 / h as f64;
+        assert!((aspect(width, height) - aspect(3456, 2234)).abs() < 0.01);
+    }
+
+    #[test]
+    fn within_the_budget_only_an_odd_edge_is_trimmed() {
+        let mut scaler = Scaler::new(Some(1920 * 1080));
+        assert_eq!(scaler.target_size(1279, 719), (1278, 718));
+        let (frame, stride) = framed(7, 5, 8, 40);
+        let (scaled, scaled_stride) = scaler.scale(frame.clone(), stride);
+        assert_eq!((scaled.width, scaled.height, scaled_stride), (6, 4, 6 * 4));
+        assert_eq!(scaled.raw.len(), 6 * 4 * 4);
+        assert_eq!(&scaled.raw[..24], &frame.raw[..24]);
+        let (even, stride) = framed(6, 4, 4, 40);
+        let (passed, passed_stride) = scaler.scale(even.clone(), stride);
+        assert!(Arc::ptr_eq(&passed, &even));
+        assert_eq!(passed_stride, stride);
+    }
+
+    #[test]
+    fn taps_cover_the_whole_source() {
+        for (src, dst) in [(3456, 1662), (3359, 1920), (2099, 1080), (5120, 2560)] {
+            let taps = Taps::new(src, dst);
+            assert_eq!(taps.starts[0], 0);
+            assert_eq!(taps.starts[dst - 1] + taps.counts[dst - 1], src);
+            for i in 0..dst {
+                let (_, weights) = taps.tap(i);
+                assert_eq!(weights.iter().sum::<i32>(), 1 << WEIGHT_BITS, "{} -> {} tap {}", src, dst, i);
+            }
+        }
+    }
+
+    #[test]
+    fn edges_survive_scaling_and_flat_areas_stay_exact() {
+        let mut scaler = Scaler::new(Some(640 * 360));
+        let (frame, stride) = framed(1279, 719, 0, 40);
+        let (scaled, _) = scaler.scale(frame, stride);
+        let width = scaled.width as usize;
+        assert!(width * scaled.height as usize <= 640 * 360);
+        for row in scaled.raw.chunks_exact(width * 4) {
+            // The white first and last columns still show at both edges
+            assert!(row[0] > 40 && row[(width - 1) * 4] > 40, "{:?}", &row[..4]);
+            assert_eq!(&row[width * 2..width * 2 + 4], [40, 40, 40, 255]);
+        }
+    }
+
+    #[test]
+    fn padded_rows_scale_like_packed_ones() {
+        let mut packed = Scaler::new(Some(320 * 180));
+        let mut padded = Scaler::new(Some(320 * 180));
+        let (frame, stride) = framed(1001, 563, 0, 90);
+        let (expected, _) = packed.scale(frame, stride);
+        let (frame, stride) = framed(1001, 563, 44, 90);
+        let (actual, _) = padded.scale(frame, stride);
+        assert_eq!((actual.width, actual.height), (expected.width, expected.height));
+        assert_eq!(actual.raw, expected.raw);
+    }
+
+    /// An RGBA `width` x `height` image with rows `stride` bytes apart, `edge`
+    /// in the last row and column and `fill` everywhere else
+    fn edged_image(width: usize, height: usize, stride: usize, fill: u8, edge: u8) -> Vec<u8> {
+        let mut raw = vec![0u8; stride * height];
+        for y in 0..height {
+            for x in 0..width {
+                let value = if x == width - 1 || y == height - 1 { edge } else { fill };
+                raw[y * stride + x * 4..][..4].copy_from_slice(&[value, value, value, 255]);
+            }
+        }
+        raw
+    }
+
+    #[test]
+    fn flat_color_survives_odd_sizes() {
+        let (src_w, src_h) = (3359, 2099);
+        let src = edged_image(src_w, src_h, src_w * 4, 200, 200);
+        for (dst_w, dst_h) in [(1920, 1080), (1679, 1049), (1280, 720)] {
+            let mut dst = vec![0u8; dst_w * dst_h * 4];
+            average_area(&src, src_w, src_h, src_w * 4, &mut dst, dst_w, dst_h);
+            assert!(dst.chunks_exact(4).all(|pixel| pixel == [200, 200, 200, 255]), "{}x{}", dst_w, dst_h);
+        }
+    }
+
+    #[test]
+    fn remainder_rows_and_columns_are_not_cropped() {
+        let (src_w, src_h) = (3359, 2099);
+        // Padded rows, as captures often have
+        let stride = (src_w + 5) * 4;
+        let src = edged_image(src_w, src_h, stride, 0, 255);
+        let (dst_w, dst_h) = (1920, 1080);
+        let mut dst = vec![0u8; dst_w * dst_h * 4];
+        average_area(&src, src_w, src_h, stride, &mut dst, dst_w, dst_h);
+        let pixel = |x: usize, y: usize| dst[(y * dst_w + x) * 4];
+        for y in 0..dst_h {
+            assert!(pixel(dst_w - 1, y) > 0, "right edge lost in row {}", y);
+            assert_eq!(pixel(dst_w - 2, y.min(dst_h - 2)), 0);
+        }
+        for x in 0..dst_w {
+            assert!(pixel(x, dst_h - 1) > 0, "bottom edge lost in column {}", x);
+        }
+    }
+
+    #[test]
+    fn padded_rows_average_without_shear() {
+        // 4x2 of two gray levels per 2x2 block, then 3 pixels of padding
+        let stride = 7 * 4;
+        let mut src = vec![0xee; stride * 2];
+        for y in 0..2 {
+            for x in 0..4 {
+                let value = if x < 2 { 10 + y as u8 * 20 } else { 100 + x as u8 * 10 };
+                src[y * stride + x * 4..][..4].copy_from_slice(&[value, value, value, 255]);
+            }
+        }
+        let mut dst = vec![0u8; 2 * 4];
+        average_area(&src, 4, 2, stride, &mut dst, 2, 1);
+        assert_eq!(dst, [20, 20, 20, 255, 125, 125, 125, 255]);
+    }
+}
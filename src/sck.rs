@@ -0,0 +1,206 @@
+//! Display and window capture through ScreenCaptureKit (`--features
+//! screencapturekit`, macOS 12.3+). Frames arrive on ScreenCaptureKit's own
+//! queue as BGRA pixel buffers, already at the display's pixel size and with
+//! the pointer drawn in if asked, and are timed by their presentation
+//! timestamps rather than by when the callback ran.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use screencapturekit::{
+    output::{CMSampleBuffer, CMTime, LockTrait},
+    shareable_content::SCShareableContent,
+    stream::{
+        configuration::{pixel_format::PixelFormat, SCStreamConfiguration},
+        content_filter::SCContentFilter,
+        output_trait::SCStreamOutputTrait,
+        output_type::SCStreamOutputType,
+        SCStream,
+    },
+};
+use xcap::{Frame, Monitor, Window};
+
+/// What to capture, by the IDs xcap uses on macOS
+pub enum Target {
+    /// CGDirectDisplayID, the xcap monitor ID
+    Display(u32),
+    /// CGWindowID, the xcap window ID
+    Window(u32),
+}
+
+/// Anchors presentation timestamps (host clock seconds) to an Instant at
+/// the first frame, so later frames keep the capture's own spacing
+#[derive(Default)]
+struct Clock {
+    anchor: Option<(f64, Instant)>,
+}
+
+impl Clock {
+    fn instant(&mut self, pts_secs: f64) -> Instant {
+        let now = Instant::now();
+        match self.anchor {
+            Some((anchor_pts, anchor_at)) if pts_secs >= anchor_pts => {
+                // Never later than now, whatever the two clocks make of it
+                (anchor_at + Duration::from_secs_f64(pts_secs - anchor_pts)).min(now)
+            }
+            _ => {
+                self.anchor = Some((pts_secs, now));
+                now
+            }
+        }
+    }
+}
+
+struct Output<F> {
+    on_frame: F,
+    clock: Mutex<Clock>,
+}
+
+impl<F: Fn(Frame, usize, Instant) + Send + Sync + 'static> SCStreamOutputTrait for Output<F> {
+    fn did_output_sample_buffer(&self, sample: CMSampleBuffer, of_type: SCStreamOutputType) {
+        if !matches!(of_type, SCStreamOutputType::Screen) {
+            return;
+        }
+        // Idle and blank updates carry no pixels; the previous frame still stands
+        let Ok(pixels) = sample.get_pixel_buffer() else {
+            return;
+        };
+        let pts = sample.get_presentation_timestamp();
+        if pts.timescale <= 0 {
+            return;
+        }
+        let captured_at = self
+            .clock
+            .lock()
+            .unwrap()
+            .instant(pts.value as f64 / pts.timescale as f64);
+        let (width, height) = (pixels.get_width(), pixels.get_height());
+        let stride = pixels.get_bytes_per_row() as usize;
+        let Ok(guard) = pixels.lock() else {
+            return;
+        };
+        // Rows keep their padding; the stride goes along with the frame
+        let Some(raw) = guard.as_slice().get(..stride * height as usize) else {
+            return;
+        };
+        let frame = Frame {
+            width,
+            height,
+            raw: raw.to_vec(),
+        };
+        (self.on_frame)(frame, stride, captured_at);
+    }
+}
+
+/// A configured SCStream; frames go to `on_frame`, with their bytes per row,
+/// between start and stop
+pub struct SckCapture {
+    stream: SCStream,
+    config: SCStreamConfiguration,
+}
+
+impl SckCapture {
+    /// Fails where ScreenCaptureKit isn't available (before macOS 12.3), or
+    /// when the display or window can't be found. An `fps` of 0 delivers at
+    /// the display's rate.
+    pub fn new<F>(target: Target, fps: u32, show_cursor: bool, on_frame: F) -> Result<Self>
+    where
+        F: Fn(Frame, usize, Instant) + Send + Sync + 'static,
+    {
+        let content = SCShareableContent::get().map_err(|err| anyhow!("ScreenCaptureKit unavailable: {:?}", err))?;
+        let (filter, (width, height)) = match target {
+            Target::Display(id) => {
+                let display = content
+                    .displays()
+                    .into_iter()
+                    .find(|display| display.display_id() == id)
+                    .ok_or_else(|| anyhow!("display {} not shareable", id))?;
+                let monitor = Monitor::all()?
+                    .into_iter()
+                    .find(|m| m.id().ok() == Some(id))
+                    .ok_or_else(|| anyhow!("display {} not found", id))?;
+                let scale = monitor.scale_factor()?;
+                let size = (monitor.width()?, monitor.height()?);
+                (
+                    SCContentFilter::new().with_display_excluding_windows(&display, &[]),
+                    pixel_size(size, scale),
+                )
+            }
+            Target::Window(id) => {
+                let shared = content
+                    .windows()
+                    .into_iter()
+                    .find(|window| window.window_id() == id)
+                    .ok_or_else(|| anyhow!("window {} not shareable", id))?;
+                let window = Window::all()?
+                    .into_iter()
+                    .find(|w| w.id().ok() == Some(id))
+                    .ok_or_else(|| anyhow!("window {} not found", id))?;
+                let scale = window.current_monitor()?.scale_factor()?;
+                let size = (window.width()?, window.height()?);
+                (
+                    SCContentFilter::new().with_desktop_independent_window(&shared),
+                    pixel_size(size, scale),
+                )
+            }
+        };
+        let config = SCStreamConfiguration::new()
+            .set_width(width)?
+            .set_height(height)?
+            .set_pixel_format(PixelFormat::BGRA)?
+            .set_shows_cursor(show_cursor)?
+            .set_minimum_frame_interval(&frame_interval(fps))?;
+        let mut stream = SCStream::new(&filter, &config);
+        stream.add_output_handler(
+            Output {
+                on_frame,
+                clock: Mutex::new(Clock::default()),
+            },
+            SCStreamOutputType::Screen,
+        );
+        Ok(Self { stream, config })
+    }
+
+    pub fn start(&self) -> Result<()> {
+        self.stream
+            .start_capture()
+            .map_err(|err| anyhow!("SCStream start: {:?}", err))
+    }
+
+    pub fn stop(&self) -> Result<()> {
+        self.stream
+            .stop_capture()
+            .map_err(|err| anyhow!("SCStream stop: {:?}", err))
+    }
+
+    /// Deliver at most `fps` frames a second from now on
+    pub fn set_fps(&mut self, fps: u32) -> Result<()> {
+        let config = self.config.clone().set_minimum_frame_interval(&frame_interval(fps))?;
+        self.stream
+            .update_configuration(&config)
+            .map_err(|err| anyhow!("SCStream reconfigure: {:?}", err))?;
+        self.config = config;
+        Ok(())
+    }
+}
+
+/// Points to pixels; ScreenCaptureKit would otherwise scale to the point size
+fn pixel_size((width, height): (u32, u32), scale: f32) -> (u32, u32) {
+    (
+        (width as f32 * scale).round() as u32,
+        (height as f32 * scale).round() as u32,
+    )
+}
+
+/// Shortest time between frames; zero (for an `fps` of 0) leaves it to the display
+fn frame_interval(fps: u32) -> CMTime {
+    CMTime {
+        value: (fps > 0) as i64,
+        timescale: fps.max(1) as i32,
+        flags: 1,
+        epoch: 0,
+    }
+}
@@ -1,14 +1,32 @@
 import { createAudioController } from "./audio.js";
 import { createGuiController } from "./gui.js";
 import { createStatsTracker } from "./stats.js";
+import { createMseVideoController, isMseSupported } from "./mse.js";
 import { createVideoController } from "./video.js";
 
-const REQUESTED_CODEC = "avc"; // "avc" or "hevc" (not implemented yet)
 const STATS_WINDOW_MS = 1000;
 const BACKOFF_STEPS_MS = [250, 1000, 2000, 5000];
 
 const wsScheme = location.protocol === "https:" ? "wss" : "ws";
 const endpoint = `${wsScheme}://${location.host}/ws`;
+// Servers started with --token/--require-auth print links ending in #token=...
+const hashParams = new URLSearchParams(location.hash.slice(1));
+const authToken = hashParams.get("token");
+// #codec=av1 asks for AV1 (servers built with --features av1); H.264 otherwise
+const REQUESTED_CODEC = hashParams.get("codec") === "av1" ? "av1" : "avc";
+// #mode=audio opens an audio-only session: no video-config ever arrives
+const REQUESTED_MODE = hashParams.get("mode") === "audio" ? "audio" : "video";
+// #transport=fmp4, or no WebCodecs: fragmented MP4 through Media Source Extensions
+const REQUESTED_TRANSPORT =
+  isMseSupported() &&
+  (hashParams.get("transport") === "fmp4" || typeof VideoDecoder !== "function")
+    ? "fmp4"
+    : "vid0";
+const createVideo =
+  REQUESTED_TRANSPORT === "fmp4" ? createMseVideoController : createVideoController;
+const socketUrl = authToken
+  ? `${endpoint}?token=${encodeURIComponent(authToken)}`
+  : endpoint;
 
 let reconnectAttempts = 0;
 let reconnectTimer = null;
@@ -21,10 +39,12 @@ const logList = document.getElementById("log");
 const endpointEl = document.getElementById("endpoint");
 const statsBw = document.getElementById("stats-bw");
 const statsFps = document.getElementById("stats-fps");
+const statsServer = document.getElementById("stats-server");
 const micIconToggle = document.getElementById("mic-icon-toggle");
 const micMeter = document.getElementById("mic-meter");
 const micIconLevel = document.getElementById("mic-icon-level");
 const audioToggle = document.getElementById("audio-toggle");
+const saveFrameButton = document.getElementById("save-frame");
 const audioStatus = document.getElementById("audio-status");
 const uvMeter = document.getElementById("uv-meter");
 
@@ -42,6 +62,7 @@ const stats = createStatsTracker({
   windowMs: STATS_WINDOW_MS,
   statsBwEl: statsBw,
   statsFpsEl: statsFps,
+  statsServerEl: statsServer,
 });
 const recordChunkSample = stats.recordChunkSample;
 const recordFrameSample = stats.recordFrameSample;
@@ -61,7 +82,7 @@ const audioController = createAudioController({
 });
 
 const videoController = canvas
-  ? createVideoController({
+  ? createVideo({
       canvas,
       log,
       requestKeyframe,
@@ -75,10 +96,47 @@ if (audioToggle) {
 if (micIconToggle) {
   micIconToggle.onclick = () => audioController.handleMicToggle();
 }
+if (saveFrameButton) {
+  saveFrameButton.onclick = () => requestScreenshot();
+}
+// A background tab needs no video; the server stops sending it (audio continues)
+document.addEventListener("visibilitychange", () => {
+  sendJson({ type: "visibility", visible: !document.hidden });
+});
 
 setConnectedState(false);
 openSocket();
 
+// Clipboard sync (server needs --allow-clipboard). The browser only lets us
+// read the clipboard while the page is focused, so push on focus.
+let lastClipboardText = null;
+
+async function applyRemoteClipboard(text) {
+  lastClipboardText = text;
+  try {
+    await navigator.clipboard.writeText(text);
+  } catch (err) {
+    log(`clipboard write failed: ${err.message ?? err}`);
+  }
+}
+
+async function pushLocalClipboard() {
+  if (!isSocketOpen() || !navigator.clipboard?.readText) return;
+  let text;
+  try {
+    text = await navigator.clipboard.readText();
+  } catch (_) {
+    return; // permission denied or non-text content
+  }
+  if (!text || text === lastClipboardText) return;
+  lastClipboardText = text;
+  sendJson({ type: "clipboard", text });
+}
+
+window.addEventListener("focus", () => {
+  pushLocalClipboard();
+});
+
 window.addEventListener("beforeunload", () => {
   audioController.stop("page-unload");
   videoController?.dispose();
@@ -134,6 +192,42 @@ function sendJson(message, socket = ws) {
   return true;
 }
 
+const IMAGE_MAGIC_BYTES = [0x49, 0x4d, 0x47, 0x30]; // "IMG0"
+let nextScreenshotId = 1;
+
+// Full-resolution PNG of the capture, answered with an IMG0 message
+function requestScreenshot() {
+  const id = `frame-${nextScreenshotId++}`;
+  if (!sendJson({ type: "screenshot", id })) {
+    log("screenshot skipped (socket not open)");
+  }
+}
+
+function isImageBuffer(data) {
+  if (!(data instanceof ArrayBuffer) || data.byteLength < 5) return false;
+  const head = new Uint8Array(data, 0, 4);
+  return IMAGE_MAGIC_BYTES.every((b, i) => head[i] === b);
+}
+
+// JPEG fallback frames are IMG0 with an empty id; screenshots always have one
+function isStillFrame(data) {
+  return new Uint8Array(data, 4, 1)[0] === 0;
+}
+
+// IMG0, id length (1 byte), id, PNG: download it named after the capture time
+function saveScreenshot(data) {
+  const idLength = new Uint8Array(data, 4, 1)[0];
+  const id = new TextDecoder().decode(new Uint8Array(data, 5, idLength));
+  const png = new Blob([data.slice(5 + idLength)], { type: "image/png" });
+  const url = URL.createObjectURL(png);
+  const link = document.createElement("a");
+  link.href = url;
+  link.download = `foundry-${new Date().toISOString().replace(/[:.]/g, "-")}.png`;
+  link.click();
+  setTimeout(() => URL.revokeObjectURL(url), 1000);
+  log(`saved screenshot ${id} (${Math.round(png.size / 1024)} KB)`);
+}
+
 function requestKeyframe(context = "") {
   const ok = sendJson({ type: "force-keyframe" });
   if (!ok) {
@@ -143,8 +237,13 @@ function requestKeyframe(context = "") {
   }
 }
 
+// Resume token from the last mode-ack, so a quick reconnect keeps its settings
+let resumeToken = null;
+let resumeDeadline = 0;
+let resumeGraceMs = 0;
+
 function openSocket() {
-  const socket = new WebSocket(endpoint);
+  const socket = new WebSocket(socketUrl);
   ws = socket;
   socket.binaryType = "arraybuffer";
 
@@ -155,16 +254,45 @@ function openSocket() {
     resetStats();
     setConnectedState(true);
     audioController.onSocketOpen();
-    sendJson({ type: "mode", mode: "video", codec: REQUESTED_CODEC }, socket);
-    requestKeyframe("socket-open");
+    if (resumeToken && performance.now() < resumeDeadline) {
+      sendJson({ type: "resume", token: resumeToken }, socket);
+    } else {
+      sendJson(
+        {
+          type: "mode",
+          mode: REQUESTED_MODE,
+          codec: REQUESTED_CODEC,
+          audio: audioController.preferredFormat(),
+          transport: REQUESTED_TRANSPORT,
+        },
+        socket,
+      );
+    }
+    if (document.hidden) {
+      sendJson({ type: "visibility", visible: false }, socket);
+    }
+    if (REQUESTED_MODE === "video") {
+      requestKeyframe("socket-open");
+    }
   };
 
   socket.onclose = (ev) => {
     if (ws !== socket) return;
     const reason = ev.reason ? `${ev.code} ${ev.reason}` : `${ev.code}`;
     log(`socket closed (${reason})`);
+    if (resumeToken) {
+      resumeDeadline = performance.now() + resumeGraceMs;
+    }
     setConnectedState(false);
     audioController.onSocketClosed();
+    if (ev.code === 4401) {
+      log("not authorized: open the link the server printed (it ends in #token=...)");
+      return;
+    }
+    if (ev.code === 4509) {
+      // Keep retrying: a place frees up when someone else leaves
+      log("stream full: the server has reached its session limit");
+    }
     scheduleReconnect(reason);
   };
 
@@ -182,9 +310,32 @@ function openSocket() {
       try {
         const msg = JSON.parse(ev.data);
         if (msg.type === "mode-ack") {
-          log(`mode-ack: ${msg.mode} codec: ${msg.codec}`);
+          log(`mode-ack: ${msg.mode} codec: ${msg.codec} audio: ${msg.audio ?? "pcm"}`);
+          if (msg.codec === "mjpeg" && !videoController?.paintImage) {
+            log("server sends JPEG stills, which this player can't show (try without #transport=fmp4)");
+          }
+          resumeToken = msg.resume_token ?? null;
+          resumeGraceMs = (msg.resume_grace_secs ?? 0) * 1000;
         } else if (msg.type === "video-config") {
           videoController?.configureDecoder(msg.config);
+        } else if (msg.type === "audio-config") {
+          audioController.configureRemoteAudio(msg);
+        } else if (msg.type === "viewers") {
+          log(`${msg.count} viewer${msg.count === 1 ? "" : "s"} connected`);
+        } else if (msg.type === "stream-state") {
+          log(`stream ${msg.state}`);
+          // Dim the frozen frame so it's obvious the stream is paused
+          gui.setCanvasConnected(msg.state !== "paused", "0.8");
+        } else if (msg.type === "audio-state") {
+          log(`audio ${msg.muted ? "muted" : "unmuted"}`);
+        } else if (msg.type === "keyframe-throttled") {
+          log(`keyframe requests throttled, next in ${msg.retry_ms}ms`);
+        } else if (msg.type === "error") {
+          log(`error (${msg.code ?? msg.command}): ${msg.message}`);
+        } else if (msg.type === "server-stats") {
+          stats.recordServerStats(msg);
+        } else if (msg.type === "clipboard") {
+          applyRemoteClipboard(msg.text);
         } else {
           log(`received: ${ev.data}`);
         }
@@ -193,6 +344,15 @@ function openSocket() {
       }
       return;
     }
+    if (isImageBuffer(ev.data)) {
+      if (isStillFrame(ev.data)) {
+        recordChunkSample(ev.data.byteLength);
+        videoController?.paintImage?.(ev.data);
+      } else {
+        saveScreenshot(ev.data);
+      }
+      return;
+    }
     if (audioController.isAudioBuffer(ev.data)) {
       audioController.handleIncomingAudio(ev.data);
       return;
@@ -0,0 +1,58 @@
+use anyhow::{anyhow, Result};
+use xcap::{image::ImageFormat, Frame};
+
+use crate::{
+    recording::packed_stride,
+    scaler::average_area,
+    video_pipeline::{PixelFormat, VideoPipeline},
+};
+
+/// Encode a captured frame laid out as `pixel_format`, with rows `stride`
+/// bytes apart, as PNG, optionally shrunk to at most `max_width`.
+///
+/// Scaling is a box filter with an integer block size, so the result may be
+/// somewhat narrower than requested.
+pub fn encode_png(frame: &Frame, stride: usize, max_width: Option<u32>, pixel_format: PixelFormat) -> Result<Vec<u8>> {
+    let shrunk = match max_width {
+        Some(max_width) if max_width > 0 && max_width < frame.width => {
+            let block = frame.width.div_ceil(max_width) as usize;
+            let dst_w = frame.width as usize / block;
+            let dst_h = frame.height as usize / block;
+            if dst_w == 0 || dst_h == 0 {
+                return Err(anyhow!("width {} is too small", max_width));
+            }
+            let mut dst = vec![0u8; dst_w * dst_h * 4];
+            average_area(
+                &frame.raw,
+                frame.width as usize,
+                frame.height as usize,
+                stride,
+                &mut dst,
+                dst_w,
+                dst_h,
+            );
+            Some(Frame {
+                width: dst_w as u32,
+                height: dst_h as u32,
+                raw: dst,
+            })
+        }
+        _ => None,
+    };
+    let (frame, stride) = match &shrunk {
+        Some(shrunk) => (shrunk, packed_stride(shrunk)),
+        None => (frame, stride),
+    };
+    VideoPipeline::snapshot(frame, stride, pixel_format, ImageFormat::Png, 0)
+}
+
+/// Build a screenshot reply: "IMG0", the request id's length as one byte,
+/// the id (UTF-8), then the PNG
+pub fn build_image_packet(id: &str, png: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(5 + id.len() + png.len());
+    out.extend_from_slice(b"IMG0");
+    out.push(id.len() as u8);
+    out.extend_from_slice(id.as_bytes());
+    out.extend_from_slice(png);
+    out
+}
@@ -1,146 +1,243 @@
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use axum::{body::Bytes, extract::ws::{Message, Utf8Bytes, WebSocket}};
+use axum::{body::Bytes, extract::ws::{CloseFrame, Message, Utf8Bytes, WebSocket}};
 use futures_util::{stream::SplitStream, StreamExt};
 use serde::Deserialize;
 use serde_json::Value;
-use tokio::sync::mpsc;
-use xcap::Frame;
+use tokio::sync::{broadcast::{self, error::RecvError}, mpsc};
 
 use crate::{
     AppState,
+    auth::{self, Role},
+    fmp4::{self, Fmp4Muxer},
+    media_queue::{MediaQueue, VideoPush},
+    mjpeg::{self, MjpegStream},
     audio_mixer::{MixerInput, MixedChunk},
     audio_capture::AudioChunk,
-    video_pipeline::{VideoCodec, VideoPipeline},
+    delay_line::{self, DelayLine},
+    annotate,
+    opus_audio::{self, OpusStream},
+    recording::{CaptureSource, CropRect, Recorder, SourceState},
+    resume::{ResumeGuard, SessionSettings, RESUME_GRACE},
+    screenshot,
+    shared_encoder::{EncoderLadder, SharedChunk, SharedEncoder},
+    status::{SessionHandle, SessionRegistry, SessionSlot},
+    throttle::{KeyframeDecision, KeyframeThrottle},
+    video_pipeline::{VideoCodec, VideoConfig},
 };
 
-// Keep resolution manageable for software encoding (~1080p equivalent)
-const MAX_PIXELS: usize = 1_920 * 1_080;
-
 #[derive(Debug, Deserialize)]
 struct ModeRequest {
     #[serde(rename = "type")]
     msg_type: String,
+    /// "video" (the default) or "audio" for an audio-only session
+    mode: Option<String>,
     codec: Option<String>,
+    /// Auth token (alternative to `?token=`) in a mode message; the resume
+    /// token in a resume message
+    token: Option<String>,
+    /// "opus" to receive OPS0 audio when the server is built with it; PCM otherwise
+    audio: Option<String>,
+    /// "fmp4" for fragmented MP4 video (Media Source Extensions); VID0 otherwise
+    transport: Option<String>,
+    /// Frame rate cap, e.g. for metered connections; 0 or absent = uncapped
+    max_fps: Option<u32>,
+    /// Cap on media sent to this viewer, in kilobits per second
+    max_kbps: Option<u32>,
+    #[serde(flatten)]
+    resolution: ResolutionRequest,
 }
 
-#[derive(Debug, Clone)]
-struct DownsampledFrame {
-    frame: Arc<Frame>,
-    #[allow(dead_code)]
-    scale: u32,
-}
+/// Messages that change what every viewer sees (or the host's clipboard);
+/// refused for view-only sessions
+const CONTROL_MESSAGES: [&str; 6] = ["set-source", "set-crop", "quality", "pip", "annotate", "clipboard"];
 
-#[derive(Default)]
-struct Downsampler {
-    buffer: Vec<u8>,
+/// Screenshot requests a session may have waiting; more are refused
+const SCREENSHOT_QUEUE: usize = 4;
+/// Longest screenshot id echoed back in IMG0 (its length is one byte)
+const MAX_SCREENSHOT_ID: usize = 255;
+
+/// Highest frame rate cap a client may ask for
+const MAX_FPS_LIMIT: u32 = 240;
+
+/// A `quality` message preset: resolution cap, bitrate and frame rate applied together
+struct QualityPreset {
+    name: &'static str,
+    /// Pixel budget for picking the encoder rung (usize::MAX = native)
+    max_pixels: usize,
+    bitrate_bps: u32,
+    max_fps: u32,
 }
 
-impl Downsampler {
-    fn new() -> Self {
-        Self { buffer: Vec::new() }
-    }
+/// Presets for viewers who want a simple knob. Bitrate and fps apply to the
+/// shared encoder, so they affect everyone watching the same rung.
+static QUALITY_PRESETS: [QualityPreset; 4] = [
+    QualityPreset { name: "low", max_pixels: 1_280 * 720, bitrate_bps: 1_500_000, max_fps: 30 },
+    QualityPreset { name: "medium", max_pixels: 1_920 * 1_080, bitrate_bps: 4_000_000, max_fps: 30 },
+    QualityPreset { name: "high", max_pixels: 2_560 * 1_440, bitrate_bps: 12_000_000, max_fps: 60 },
+    // Slides and code: full resolution so text stays sharp, few frames
+    QualityPreset { name: "lossy-text", max_pixels: usize::MAX, bitrate_bps: 6_000_000, max_fps: 10 },
+];
 
-    fn downsample(&mut self, frame: Arc<Frame>) -> DownsampledFrame {
-        let src_w = frame.width as usize;
-        let src_h = frame.height as usize;
-        let pixels = src_w.saturating_mul(src_h);
-
-        // Choose integer scale >=1 such that the downsampled pixel count fits the target.
-        let mut scale: usize = 1;
-        if pixels > MAX_PIXELS {
-            let ratio = (pixels + MAX_PIXELS - 1) / MAX_PIXELS; // ceil division
-            let approx = (ratio as f64).sqrt().ceil() as usize;
-            scale = approx.max(2);
-            while scale < 16
-                && (src_w / scale).saturating_mul(src_h / scale) > MAX_PIXELS
-            {
-                scale += 1;
-            }
-        }
+/// Share of a stats interval the pacer may hold media back before the
+/// encoder is asked for less, rather than adding latency
+const PACED_HOLD_FRACTION: f64 = 0.2;
 
-        if scale <= 1 {
-            // Even without downscaling, ensure even dimensions for H.264
-            let even_w = src_w & !1;
-            let even_h = src_h & !1;
-            if even_w == src_w && even_h == src_h {
-                return DownsampledFrame { frame, scale: 1 };
+/// How long congestion must persist before the bitrate steps down
+const CONGESTION_HOLD: Duration = Duration::from_millis(300);
+/// How long frames must keep finding the queue empty before the bitrate steps up
+const DRAINED_HOLD: Duration = Duration::from_secs(3);
+
+/// Watches this session's outbound queue and nudges the encoder's bitrate
+#[derive(Default)]
+struct CongestionMonitor {
+    congested_since: Option<Instant>,
+    drained_since: Option<Instant>,
+}
+
+impl CongestionMonitor {
+    /// Call with the outcome of each frame handed to the media queue
+    fn observe(&mut self, encoder: &SharedEncoder, push: VideoPush) {
+        let now = Instant::now();
+        match push {
+            // Frames had to be thrown away: the socket is well behind
+            VideoPush::Overrun => {
+                self.drained_since = None;
+                encoder.reduce_bitrate();
+                self.congested_since = Some(now);
             }
-            // Need to crop to even dimensions
-            let needed = even_w * even_h * 4;
-            if self.buffer.len() < needed {
-                self.buffer.resize(needed, 0);
+            // The previous frame was still unsent
+            VideoPush::Queued { was_idle: false } => {
+                self.drained_since = None;
+                let since = *self.congested_since.get_or_insert(now);
+                if now - since >= CONGESTION_HOLD {
+                    encoder.reduce_bitrate();
+                    self.congested_since = Some(now);
+                }
             }
-            let src = &frame.raw;
-            let dst = &mut self.buffer[..needed];
-            for y in 0..even_h {
-                let src_start = y * src_w * 4;
-                let dst_start = y * even_w * 4;
-                dst[dst_start..dst_start + even_w * 4]
-                    .copy_from_slice(&src[src_start..src_start + even_w * 4]);
+            VideoPush::Queued { was_idle: true } => {
+                self.congested_since = None;
+                let since = *self.drained_since.get_or_insert(now);
+                if now - since >= DRAINED_HOLD {
+                    encoder.raise_bitrate();
+                    self.drained_since = Some(now);
+                }
             }
-            let cropped = Frame {
-                width: even_w as u32,
-                height: even_h as u32,
-                raw: dst[..needed].to_vec(),
-            };
-            return DownsampledFrame {
-                frame: Arc::new(cropped),
-                scale: 1,
-            };
         }
+    }
+}
 
-        // Ensure even output dimensions for H.264 compatibility
-        let dst_w = (src_w / scale) & !1;
-        let dst_h = (src_h / scale) & !1;
-        if dst_w == 0 || dst_h == 0 {
-            return DownsampledFrame { frame, scale: 1 };
-        }
+/// Per-session counters, sent and reset once a second as `server-stats`
+#[derive(Default)]
+struct PipelineStats {
+    /// SharedEncoder::frames_captured at the start of the interval
+    captured_base: u64,
+    /// SharedEncoder::frames_skipped at the start of the interval
+    skipped_base: u64,
+    frames_sent: u64,
+    bytes_sent: u64,
+    encode_us_min: Option<u64>,
+    encode_us_max: u64,
+    encode_us_total: u64,
+    /// Chunks this viewer never got: lagged past, dropped under backpressure,
+    /// or skipped waiting for a keyframe
+    frames_dropped: u64,
+    audio_chunks: u64,
+}
 
-        let needed = dst_w * dst_h * 4;
-        if self.buffer.len() < needed {
-            self.buffer.resize(needed, 0);
+impl PipelineStats {
+    fn new(encoder: &SharedEncoder) -> Self {
+        Self {
+            captured_base: encoder.frames_captured(),
+            skipped_base: encoder.frames_skipped(),
+            ..Self::default()
         }
+    }
 
-        let src = &frame.raw;
-        let dst = &mut self.buffer[..needed];
-        let block = scale as usize;
-        let block_area = (block * block) as u32;
-
-        for y in 0..dst_h {
-            let sy0 = y * block;
-            for x in 0..dst_w {
-                let sx0 = x * block;
-                let mut acc = [0u32; 4];
-                for ky in 0..block {
-                    let row_base = (sy0 + ky) * src_w * 4;
-                    let start = row_base + sx0 * 4;
-                    for kx in 0..block {
-                        let idx = start + kx * 4;
-                        acc[0] += src[idx] as u32;
-                        acc[1] += src[idx + 1] as u32;
-                        acc[2] += src[idx + 2] as u32;
-                        acc[3] += src[idx + 3] as u32;
-                    }
-                }
-                let out_idx = (y * dst_w + x) * 4;
-                dst[out_idx] = (acc[0] / block_area) as u8;
-                dst[out_idx + 1] = (acc[1] / block_area) as u8;
-                dst[out_idx + 2] = (acc[2] / block_area) as u8;
-                dst[out_idx + 3] = (acc[3] / block_area) as u8;
-            }
-        }
+    /// Count capture from `encoder` from now on, after switching rungs
+    fn rebase(&mut self, encoder: &SharedEncoder) {
+        self.captured_base = encoder.frames_captured();
+        self.skipped_base = encoder.frames_skipped();
+    }
+
+    fn record_frame(&mut self, chunk: &SharedChunk) {
+        self.frames_sent += 1;
+        self.bytes_sent += chunk.packet.len() as u64;
+        self.encode_us_min = Some(self.encode_us_min.map_or(chunk.encode_us, |min| min.min(chunk.encode_us)));
+        self.encode_us_max = self.encode_us_max.max(chunk.encode_us);
+        self.encode_us_total += chunk.encode_us;
+    }
 
-        let down_frame = Frame {
-            width: dst_w as u32,
-            height: dst_h as u32,
-            raw: dst[..needed].to_vec(),
+    /// Serialize the interval that just ended and start a new one
+    fn take_json(&mut self, encoder: &SharedEncoder, elapsed: Duration) -> Value {
+        let captured = encoder.frames_captured();
+        let skipped = encoder.frames_skipped();
+        let secs = elapsed.as_secs_f64().max(0.001);
+        let ms = |us: u64| us as f64 / 1000.0;
+        let json = serde_json::json!({
+            "type": "server-stats",
+            "interval_ms": elapsed.as_millis() as u64,
+            "frames_captured": captured.saturating_sub(self.captured_base),
+            // Unchanged frames the shared encoder didn't bother encoding
+            "frames_skipped": skipped.saturating_sub(self.skipped_base),
+            "frames_sent": self.frames_sent,
+            "frames_dropped": self.frames_dropped,
+            "encode_ms": (self.frames_sent > 0).then(|| serde_json::json!({
+                "min": ms(self.encode_us_min.unwrap_or(0)),
+                "avg": ms(self.encode_us_total / self.frames_sent),
+                "max": ms(self.encode_us_max),
+            })),
+            "bytes_per_sec": (self.bytes_sent as f64 / secs).round() as u64,
+            "audio_chunks": self.audio_chunks,
+            "bitrate_kbps": encoder.target_bitrate().map(|bps| bps / 1000),
+            // What the encoder is running at; lags bitrate_kbps until a change lands
+            "encoder_kbps": encoder.applied_bitrate().map(|bps| bps / 1000),
+            // Cap the shared encoder is running at (null = every captured frame)
+            "effective_fps": fps_json(encoder.effective_max_fps()),
+        });
+        *self = Self {
+            captured_base: captured,
+            skipped_base: skipped,
+            ..Self::default()
         };
+        json
+    }
+}
 
-        DownsampledFrame {
-            frame: Arc::new(down_frame),
-            scale: scale as u32,
-        }
+/// Close code for sockets that failed authentication (HTTP 401 in the
+/// application-defined 4000-4999 range)
+const CLOSE_UNAUTHORIZED: u16 = 4401;
+/// Close code for sessions ended by --idle-timeout (HTTP 408)
+const CLOSE_IDLE: u16 = 4408;
+/// Close code for sessions refused by --max-sessions
+const CLOSE_FULL: u16 = 4509;
+
+/// How often sessions are pinged and checked against --idle-timeout; browsers
+/// answer pings on their own, so only a suspended or vanished client goes quiet
+const LIVENESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Client resolution cap, from the mode message or `set-resolution`
+#[derive(Debug, Default, Deserialize)]
+struct ResolutionRequest {
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+    max_pixels: Option<u64>,
+}
+
+impl ResolutionRequest {
+    /// Pixel budget; a lone width or height assumes 16:9
+    fn max_pixels(&self) -> Option<usize> {
+        let pixels = match (self.max_pixels, self.max_width, self.max_height) {
+            (Some(pixels), _, _) => pixels,
+            (None, Some(w), Some(h)) => w as u64 * h as u64,
+            (None, Some(w), None) => w as u64 * w as u64 * 9 / 16,
+            (None, None, Some(h)) => h as u64 * h as u64 * 16 / 9,
+            (None, None, None) => return None,
+        };
+        Some(pixels as usize)
     }
 }
 
@@ -206,23 +303,73 @@ fn build_direct_audio_chunk(chunk: &AudioChunk) -> Bytes {
     Bytes::from(out)
 }
 
+/// One liveness tick: close the session if the client has been silent past
+/// --idle-timeout, otherwise ping it. False once the session should end.
+async fn check_liveness(tx: &mpsc::Sender<Message>, registration: &SessionHandle) -> bool {
+    if registration.idle_expired() {
+        println!("closing idle session");
+        let _ = tx
+            .send(Message::Close(Some(CloseFrame {
+                code: CLOSE_IDLE,
+                reason: Utf8Bytes::from("idle timeout"),
+            })))
+            .await;
+        return false;
+    }
+    tx.send(Message::Ping(Bytes::new())).await.is_ok()
+}
+
 pub async fn start(
     mut receiver: SplitStream<WebSocket>,
     tx: mpsc::Sender<Message>,
+    media: Arc<MediaQueue>,
     state: AppState,
+    role: Option<Role>,
 ) {
     println!("session started");
 
-    let codec = negotiate_mode(&mut receiver, &tx).await;
+    // The slot is held until the session ends
+    let Some((settings, token, _slot)) = negotiate_mode(&mut receiver, &tx, &state, role).await else {
+        return;
+    };
+    let codec = settings.codec;
+    let audio_only = settings.audio_only;
+    media.set_max_kbps(settings.max_kbps);
+    let resume = ResumeGuard::new(&state.resumable, token, settings);
+
+    // The session still runs (audio works), but no video will arrive
+    if !audio_only && state.screen_permission.is_denied() {
+        let error = serde_json::json!({
+            "type": "error",
+            "code": "no-screen-permission",
+            "message": "the server isn't allowed to record the screen (macOS Screen Recording permission)",
+        });
+        if tx.send(Message::Text(Utf8Bytes::from(error.to_string()))).await.is_err() {
+            return;
+        }
+    }
 
-    match VideoPipeline::new(codec) {
-        Ok(pipeline) => {
-            if let Err(err) = run_video(receiver, tx, state, codec, pipeline).await {
+    if audio_only {
+        if let Err(err) = run_audio(receiver, tx, media, state, resume, false).await {
+            eprintln!("audio session error: {err}");
+        }
+        return;
+    }
+
+    match (state.encoders.clone(), codec) {
+        (Some(encoders), codec) if encoders.supports(codec) => {
+            if let Err(err) = run_video(receiver, tx, media, state, encoders, resume).await {
                 eprintln!("video pipeline error: {err}");
             }
         }
-        Err(err) => {
-            eprintln!("video pipeline not available: {err}");
+        (None, VideoCodec::Avc) if mjpeg::AVAILABLE => {
+            println!("no H.264 encoder, sending JPEG stills");
+            if let Err(err) = run_audio(receiver, tx, media, state, resume, true).await {
+                eprintln!("jpeg fallback session error: {err}");
+            }
+        }
+        _ => {
+            eprintln!("video pipeline not available for {:?}", codec);
             let _ = tx.send(Message::Text(Utf8Bytes::from("{\"type\":\"mode-ack\",\"mode\":\"video\",\"reason\":\"video-unavailable\"}"))).await;
         }
     }
@@ -231,72 +378,790 @@ pub async fn start(
 async fn negotiate_mode(
     receiver: &mut SplitStream<WebSocket>,
     tx: &mpsc::Sender<Message>,
-) -> VideoCodec {
+    state: &AppState,
+    mut role: Option<Role>,
+) -> Option<(SessionSettings, String, SessionSlot)> {
     use tokio::time::{timeout, Duration};
 
+    let mut codec = VideoCodec::Avc;
+    let mut max_pixels = None;
+    let mut max_fps = 0;
+    let mut max_kbps = None;
+    let mut opus_audio = false;
+    let mut audio_only = false;
+    let mut fmp4 = false;
+    let mut resumed: Option<(SessionSettings, String)> = None;
     if let Ok(Some(Ok(Message::Text(text)))) =
         timeout(Duration::from_millis(500), receiver.next()).await
     {
         if let Ok(req) = serde_json::from_str::<ModeRequest>(&text) {
-            if req.msg_type == "mode" {
-                let codec = match req.codec.as_deref() {
+            if req.msg_type == "resume" {
+                // Unknown or expired tokens fall through to a fresh session
+                // with default settings
+                resumed = req
+                    .token
+                    .and_then(|token| state.resumable.take(&token).map(|settings| (settings, token)));
+                match &resumed {
+                    Some(_) => println!("resuming session"),
+                    None => println!("resume token unknown or expired, starting a new session"),
+                }
+                // Only an authenticated session could have been given the
+                // token; it comes back with the role it had
+                role = role.max(resumed.as_ref().map(|(settings, _)| settings.role));
+            } else if req.msg_type == "mode" {
+                codec = match req.codec.as_deref() {
                     Some("hevc") => VideoCodec::Hevc,
+                    Some("av1") => VideoCodec::Av1,
                     _ => VideoCodec::Avc,
                 };
-                let _ = tx
-                    .send(Message::Text(Utf8Bytes::from(format!(
-                        "{{\"type\":\"mode-ack\",\"mode\":\"video\",\"codec\":\"{}\"}}",
-                        match codec {
-                            VideoCodec::Avc => "avc",
-                            VideoCodec::Hevc => "hevc",
-                        }
-                    ))))
-                    .await;
-                return codec;
+                max_pixels = req.resolution.max_pixels();
+                max_fps = req.max_fps.unwrap_or(0).min(MAX_FPS_LIMIT);
+                max_kbps = req.max_kbps.filter(|kbps| *kbps > 0);
+                opus_audio = opus_audio::AVAILABLE && req.audio.as_deref() == Some("opus");
+                audio_only = req.mode.as_deref() == Some("audio");
+                // The fMP4 muxer only writes avcC sample entries
+                fmp4 = req.transport.as_deref() == Some("fmp4") && codec == VideoCodec::Avc;
+                if let (Some(tokens), Some(supplied)) = (&state.auth, &req.token) {
+                    role = role.max(tokens.role_for(supplied));
+                }
             }
         }
     }
 
-    // Default to AVC if no mode message received quickly.
+    let Some(role) = role else {
+        println!("rejecting unauthenticated session");
+        let error = serde_json::json!({
+            "type": "error",
+            "command": "mode",
+            "message": "authentication required: pass ?token= or a token field in the mode message",
+        });
+        let _ = tx.send(Message::Text(Utf8Bytes::from(error.to_string()))).await;
+        let _ = tx
+            .send(Message::Close(Some(CloseFrame {
+                code: CLOSE_UNAUTHORIZED,
+                reason: Utf8Bytes::from("unauthorized"),
+            })))
+            .await;
+        return None;
+    };
+
+    let Some(slot) = admit(&state.sessions, tx).await else {
+        // A refused resume keeps its settings parked for another try
+        if let Some((settings, token)) = resumed {
+            drop(ResumeGuard::new(&state.resumable, token, settings));
+        }
+        return None;
+    };
+
+    let (mut settings, token) = match resumed {
+        Some(resumed) => resumed,
+        None => {
+            let token = match auth::generate_token() {
+                Ok(token) => token,
+                Err(err) => {
+                    eprintln!("failed to generate resume token: {err}");
+                    String::new()
+                }
+            };
+            let settings = SessionSettings {
+                role,
+                codec,
+                max_pixels,
+                max_fps,
+                max_kbps,
+                preset: None,
+                opus_audio,
+                audio_muted: state.start_muted,
+                audio_offset_ms: state.audio_offset_ms,
+                audio_only,
+                fmp4,
+            };
+            (settings, token)
+        }
+    };
+    settings.role = role;
+    let (codec, max_pixels) = (settings.codec, settings.max_pixels);
+    // Matches the arm start() takes when there is no H.264 encoder
+    let stills = !settings.audio_only && codec == VideoCodec::Avc && state.encoders.is_none() && mjpeg::AVAILABLE;
+
+    // Defaults to AVC if no mode message is received quickly.
+    let ack = serde_json::json!({
+        "type": "mode-ack",
+        "mode": if settings.audio_only { "audio" } else { "video" },
+        "role": role.as_str(),
+        "codec": match codec {
+            _ if stills => "mjpeg",
+            VideoCodec::Avc => "avc",
+            VideoCodec::Hevc => "hevc",
+            VideoCodec::Av1 => "av1",
+        },
+        "framing": if stills { "img0" } else if settings.fmp4 { "fmp4" } else { "vid0" },
+        "fallback_reason": stills.then(mjpeg::fallback_reason),
+        "audio": if settings.opus_audio { "opus" } else { "pcm" },
+        "source": state.recorder.source_json(),
+        "resolution": state.encoders.as_ref().map(|encoders| encoders.pick_codec(codec, max_pixels).0),
+        "preset": settings.preset,
+        "max_fps": (settings.max_fps > 0).then_some(settings.max_fps),
+        "max_kbps": settings.max_kbps,
+        "audio_muted": settings.audio_muted,
+        "av_offset_ms": settings.audio_offset_ms,
+        // Present {"type":"resume","token":...} as the first message after a
+        // reconnect to pick up where this session left off
+        "resume_token": (!token.is_empty()).then_some(token.as_str()),
+        "resume_grace_secs": RESUME_GRACE.as_secs(),
+    });
+    let _ = tx.send(Message::Text(Utf8Bytes::from(ack.to_string()))).await;
+    Some((settings, token, slot))
+}
+
+/// Take a --max-sessions slot, or tell the client the stream is full and close
+async fn admit(sessions: &Arc<SessionRegistry>, tx: &mpsc::Sender<Message>) -> Option<SessionSlot> {
+    if let Some(slot) = sessions.admit() {
+        return Some(slot);
+    }
+    println!("rejecting session: --max-sessions reached");
+    let error = serde_json::json!({
+        "type": "error",
+        "command": "mode",
+        "code": "stream-full",
+        "message": "the server has reached its session limit",
+    });
+    let _ = tx.send(Message::Text(Utf8Bytes::from(error.to_string()))).await;
     let _ = tx
-        .send(Message::Text(Utf8Bytes::from(
-            "{\"type\":\"mode-ack\",\"mode\":\"video\",\"codec\":\"avc\"}",
-        )))
+        .send(Message::Close(Some(CloseFrame {
+            code: CLOSE_FULL,
+            reason: Utf8Bytes::from("stream full"),
+        })))
         .await;
-    VideoCodec::Avc
+    None
+}
+
+/// Use direct audio capture if it's a single device, otherwise the mixer
+fn subscribe_audio(
+    state: &AppState,
+) -> (Option<broadcast::Receiver<AudioChunk>>, Option<broadcast::Receiver<MixedChunk>>) {
+    let direct = state
+        .audio_broadcast
+        .as_ref()
+        .filter(|c| !c.is_mixed())
+        .map(|c| c.subscribe());
+    let mixer = if direct.is_none() { Some(state.mixer.subscribe()) } else { None };
+    (direct, mixer)
+}
+
+/// Negotiated Opus; a failed encoder falls back to PCM, which clients always accept
+fn start_opus(settings: &SessionSettings) -> Option<OpusStream> {
+    if !settings.opus_audio {
+        return None;
+    }
+    match OpusStream::new() {
+        Ok(opus) => Some(opus),
+        Err(err) => {
+            eprintln!("opus encoder not available, sending PCM: {err}");
+            None
+        }
+    }
+}
+
+/// `"mode":"audio"` session: forwards audio and accepts mic uploads and mute
+/// messages, but never touches the encoders, so it works without openh264.
+/// Capture itself is shared and keeps running for other viewers.
+/// With `stills` it also carries the JPEG fallback video (`"codec":"mjpeg"`).
+async fn run_audio(
+    mut receiver: SplitStream<WebSocket>,
+    tx: mpsc::Sender<Message>,
+    media: Arc<MediaQueue>,
+    state: AppState,
+    mut resume: ResumeGuard,
+    stills: bool,
+) -> anyhow::Result<()> {
+    // Listed in /status until this function returns or the task is aborted
+    let registration = state.sessions.register(if stills { "mjpeg" } else { "audio" }, "none", resume.settings.role);
+    let (mut direct_audio_rx, mut mixer_audio_rx) = subscribe_audio(&state);
+    let audio_tx = state.mixer.input_sender();
+    let mut opus = start_opus(&resume.settings);
+    // Dropped while the tab is hidden, which stops its capture and encoding
+    let mut jpeg = stills.then(|| MjpegStream::start(&state.recorder, media.clone()));
+    let mut hidden = false;
+
+    println!("{} session started (audio: {})",
+        if stills { "jpeg fallback" } else { "audio-only" },
+        if direct_audio_rx.is_some() { "direct capture" } else { "mixer" });
+
+    let mut liveness = tokio::time::interval(LIVENESS_INTERVAL);
+    liveness.tick().await; // the first tick is immediate
+    let mut stats_ticker = tokio::time::interval(Duration::from_secs(1));
+    stats_ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    stats_ticker.tick().await; // the first tick is immediate
+    let mut stats_started = Instant::now();
+
+    loop {
+        tokio::select! {
+            ws_msg = receiver.next() => {
+                if let Some(Ok(_)) = &ws_msg {
+                    registration.touch();
+                }
+                match ws_msg {
+                    Some(Ok(Message::Text(text))) => {
+                        let Ok(val) = serde_json::from_str::<Value>(&text) else {
+                            continue;
+                        };
+                        let msg_type = val.get("type").and_then(|v| v.as_str()).unwrap_or_default();
+                        if msg_type == "visibility" && stills {
+                            let visible = val.get("visible").and_then(|v| v.as_bool()).unwrap_or(true);
+                            if visible != hidden {
+                                continue;
+                            }
+                            hidden = !visible;
+                            if hidden {
+                                jpeg = None;
+                                media.clear_video();
+                            } else {
+                                jpeg = Some(MjpegStream::start(&state.recorder, media.clone()));
+                            }
+                            let state_msg = serde_json::json!({
+                                "type": "stream-state",
+                                "state": if hidden { "hidden" } else { "playing" },
+                            });
+                            if tx.send(Message::Text(Utf8Bytes::from(state_msg.to_string()))).await.is_err() {
+                                break;
+                            }
+                        } else if msg_type == "audio-mute" || msg_type == "audio-unmute" {
+                            resume.settings.audio_muted = msg_type == "audio-mute";
+                            let state_msg = serde_json::json!({
+                                "type": "audio-state",
+                                "muted": resume.settings.audio_muted,
+                            });
+                            if tx.send(Message::Text(Utf8Bytes::from(state_msg.to_string()))).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    Some(Ok(Message::Binary(data))) => {
+                        if let Some(input) = parse_audio_chunk(&data) {
+                            if let Err(err) = audio_tx.send(input).await {
+                                eprintln!("failed to forward audio chunk: {err}");
+                            }
+                        }
+                    }
+                    Some(Ok(Message::Ping(payload))) => {
+                        if tx.send(Message::Pong(payload)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Ok(Message::Close(frame))) => {
+                        let _ = tx.send(Message::Close(frame)).await;
+                        break;
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => {
+                        eprintln!("websocket error: {err}");
+                        break;
+                    }
+                    None => break,
+                }
+            }
+            _ = liveness.tick() => {
+                if !check_liveness(&tx, &registration).await {
+                    break;
+                }
+            }
+            _ = stats_ticker.tick(), if stills => {
+                let elapsed = stats_started.elapsed();
+                stats_started = Instant::now();
+                let (frames, bytes) = jpeg.as_ref().map_or((0, 0), |jpeg| jpeg.take_counts());
+                let secs = elapsed.as_secs_f64().max(0.001);
+                let report = serde_json::json!({
+                    "type": "server-stats",
+                    "interval_ms": elapsed.as_millis() as u64,
+                    "fallback": "mjpeg",
+                    "fallback_reason": mjpeg::fallback_reason(),
+                    "frames_sent": frames,
+                    "fps": (frames as f64 / secs * 10.0).round() / 10.0,
+                    "bytes_per_sec": (bytes as f64 / secs).round() as u64,
+                    "hidden": hidden,
+                });
+                if tx.send(Message::Text(Utf8Bytes::from(report.to_string()))).await.is_err() {
+                    break;
+                }
+            }
+            Some(Ok(chunk)) = async {
+                match &mut direct_audio_rx {
+                    Some(rx) => Some(rx.recv().await),
+                    None => None,
+                }
+            } => {
+                if resume.settings.audio_muted {
+                    continue;
+                }
+                let packets = match &mut opus {
+                    Some(opus) => opus.push(chunk.sample_rate, chunk.channels, &chunk.samples),
+                    None => vec![build_direct_audio_chunk(&chunk)],
+                };
+                media.push_audio(packets);
+            }
+            Some(Ok(chunk)) = async {
+                match &mut mixer_audio_rx {
+                    Some(rx) => Some(rx.recv().await),
+                    None => None,
+                }
+            } => {
+                if resume.settings.audio_muted {
+                    continue;
+                }
+                let packets = match &mut opus {
+                    Some(opus) => opus.push(chunk.sample_rate, chunk.channels, &chunk.samples),
+                    None => vec![build_audio_chunk(&chunk)],
+                };
+                media.push_audio(packets);
+            }
+        }
+    }
+
+    println!("{} session ended", if stills { "jpeg fallback" } else { "audio-only" });
+    Ok(())
 }
 
 async fn run_video(
     mut receiver: SplitStream<WebSocket>,
     tx: mpsc::Sender<Message>,
+    media: Arc<MediaQueue>,
     state: AppState,
-    _codec: VideoCodec,
-    mut pipeline: VideoPipeline,
+    encoders: Arc<EncoderLadder>,
+    mut resume: ResumeGuard,
 ) -> anyhow::Result<()> {
-    let mut listen_frames = state.recorder.new_listener();
-    let mut pending_config_sent = false;
-    let mut force_idr_next = false;
-    let mut downsampler = Downsampler::new();
+    let codec = resume.settings.codec;
+    let (rung, mut encoder) = encoders.pick_codec(codec, resume.settings.max_pixels);
+    println!("viewer on {rung} encoder");
+    let codec_name = match codec {
+        VideoCodec::Avc => "avc",
+        VideoCodec::Hevc => "hevc",
+        VideoCodec::Av1 => "av1",
+    };
+    // Listed in /status until this function returns or the task is aborted
+    let registration = state.sessions.register(codec_name, rung, resume.settings.role);
+    let mut viewers = state.sessions.subscribe_viewers();
+    let mut window_changes = state.recorder.subscribe_window_changes();
+    let mut source_state = state.recorder.subscribe_source_state();
+    // A viewer joining while the source is gone hears so straight away
+    if *source_state.borrow() != SourceState::Capturing {
+        source_state.mark_changed();
+    }
+    // Start out changed so the current count goes out with the first loop turn
+    viewers.mark_changed();
+    let mut fps_cap = encoder.cap_fps(resume.settings.max_fps);
+    // None while the tab is hidden, so an encoder whose viewers are all
+    // hidden stops encoding
+    let mut chunks = Some(encoder.subscribe());
+    // Config the client is currently decoding with
+    let mut sent_config: Option<Arc<VideoConfig>> = None;
+    // Deltas are useless to a decoder until it has seen a keyframe
+    let mut waiting_for_keyframe = true;
+    // Viewers capped at half the encoded rate or less only get temporal layer 0,
+    // in packets numbered among that layer so the client doesn't see gaps
+    let mut base_layer_only = false;
+    // While paused, chunks and audio are still received (so nothing backs up) but not sent;
+    // audio mute works the same way for audio alone
+    let mut paused = false;
+    // Set by `visibility` messages from the page; audio keeps flowing
+    let mut hidden = false;
+    join_encoder(&tx, &encoder, &mut sent_config).await;
+    encoder.request_keyframe();
+    // Client-triggered IDRs from here on go through the throttle; a crop
+    // that arrives while throttled waits with the pending IDR
+    let mut throttle = KeyframeThrottle::new(state.keyframe_request_interval);
+    let mut pending_crop: Option<Option<CropRect>> = None;
     
-    // Use direct audio capture if available, otherwise fall back to mixer
-    let mut direct_audio_rx = state.audio_broadcast.as_ref().map(|c| c.subscribe());
-    let mut mixer_audio_rx = if direct_audio_rx.is_none() { Some(state.mixer.subscribe()) } else { None };
+    let (mut direct_audio_rx, mut mixer_audio_rx) = subscribe_audio(&state);
     let audio_tx = state.mixer.input_sender();
+    let mut opus = start_opus(&resume.settings);
+    let mut muxer = resume.settings.fmp4.then(Fmp4Muxer::default);
+    // --audio-offset-ms / av-offset: whichever of audio and video runs ahead is held back
+    let (audio, video) = delay_line::offset_delays(resume.settings.audio_offset_ms);
+    let mut direct_audio_delay = DelayLine::new(audio);
+    let mut mixer_audio_delay = DelayLine::new(audio);
+    let mut video_delay = DelayLine::new(video);
+    let mut clipboard_rx = state.clipboard.as_ref().map(|c| c.subscribe());
+    // Taken one at a time; the worker ends when this sender is dropped
+    let (screenshots, screenshot_rx) = mpsc::channel::<String>(SCREENSHOT_QUEUE);
+    tokio::spawn(run_screenshots(screenshot_rx, tx.clone(), state.recorder.clone()));
 
     println!("video pipeline started (audio: {})", 
         if direct_audio_rx.is_some() { "direct capture" } else { "mixer" });
 
+    let mut stats = PipelineStats::new(&encoder);
+    let mut congestion = CongestionMonitor::default();
+    let mut stats_started = Instant::now();
+    let mut stats_ticker = tokio::time::interval(Duration::from_secs(1));
+    stats_ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    stats_ticker.tick().await; // the first tick is immediate
+    let mut liveness = tokio::time::interval(LIVENESS_INTERVAL);
+    liveness.tick().await;
+
     loop {
+        let keyframe_due = throttle.pending_until();
         tokio::select! {
             ws_msg = receiver.next() => {
+                if let Some(Ok(_)) = &ws_msg {
+                    registration.touch();
+                }
                 match ws_msg {
                     Some(Ok(msg)) => match msg {
                         Message::Text(text) => {
                             if let Ok(val) = serde_json::from_str::<Value>(&text) {
                                 if let Some(msg_type) = val.get("type").and_then(|v| v.as_str()) {
+                                    if resume.settings.role == Role::View && CONTROL_MESSAGES.contains(&msg_type) {
+                                        let reply = serde_json::json!({
+                                            "type": "error",
+                                            "command": msg_type,
+                                            "code": "forbidden",
+                                            "message": "this session can only watch (view token)",
+                                        });
+                                        if tx.send(Message::Text(Utf8Bytes::from(reply.to_string()))).await.is_err() {
+                                            break;
+                                        }
+                                        continue;
+                                    }
                                     match msg_type {
-                                        "force-keyframe" => {
-                                            force_idr_next = true;
+                                        "force-keyframe"
+                                            if !request_keyframe(&tx, &mut throttle, &encoder).await =>
+                                        {
+                                            break;
+                                        }
+                                        "pause" | "resume" => {
+                                            let pause = msg_type == "pause";
+                                            if paused && !pause {
+                                                // Deltas since the pause are gone; restart from an IDR
+                                                waiting_for_keyframe = true;
+                                                if !request_keyframe(&tx, &mut throttle, &encoder).await {
+                                                    break;
+                                                }
+                                            }
+                                            paused = pause;
+                                            let state_msg = serde_json::json!({
+                                                "type": "stream-state",
+                                                "state": if paused { "paused" } else { "playing" },
+                                            });
+                                            if tx.send(Message::Text(Utf8Bytes::from(state_msg.to_string()))).await.is_err() {
+                                                break;
+                                            }
+                                        }
+                                        // {"type":"visibility","visible":false} when the tab is hidden
+                                        "visibility" => {
+                                            let visible = val.get("visible").and_then(|v| v.as_bool()).unwrap_or(true);
+                                            if visible != hidden {
+                                                continue;
+                                            }
+                                            hidden = !visible;
+                                            if hidden {
+                                                println!("viewer tab hidden, not sending video");
+                                                chunks = None;
+                                                video_delay.clear();
+                                                // Don't hold a shared rate cap for a stream this viewer isn't watching
+                                                fps_cap.set(0);
+                                                media.clear_video();
+                                            } else {
+                                                println!("viewer tab visible again");
+                                                chunks = Some(encoder.subscribe());
+                                                video_delay.clear();
+                                                fps_cap.set(resume.settings.max_fps);
+                                                // Deltas since hiding are gone; restart from an IDR
+                                                waiting_for_keyframe = true;
+                                                if !request_keyframe(&tx, &mut throttle, &encoder).await {
+                                                    break;
+                                                }
+                                            }
+                                            let state_msg = serde_json::json!({
+                                                "type": "stream-state",
+                                                "state": if hidden { "hidden" } else if paused { "paused" } else { "playing" },
+                                            });
+                                            if tx.send(Message::Text(Utf8Bytes::from(state_msg.to_string()))).await.is_err() {
+                                                break;
+                                            }
+                                        }
+                                        "audio-mute" | "audio-unmute" => {
+                                            resume.settings.audio_muted = msg_type == "audio-mute";
+                                            let state_msg = serde_json::json!({
+                                                "type": "audio-state",
+                                                "muted": resume.settings.audio_muted,
+                                            });
+                                            if tx.send(Message::Text(Utf8Bytes::from(state_msg.to_string()))).await.is_err() {
+                                                break;
+                                            }
+                                        }
+                                        "set-resolution" => {
+                                            let request: ResolutionRequest =
+                                                serde_json::from_value(val.clone()).unwrap_or_default();
+                                            resume.settings.max_pixels = request.max_pixels();
+                                            let (rung, picked) = encoders.pick_codec(codec, resume.settings.max_pixels);
+                                            println!("viewer switching to {rung} encoder");
+                                            registration.set_resolution(rung);
+                                            if !Arc::ptr_eq(&picked, &encoder) {
+                                                encoder = picked;
+                                                fps_cap = encoder.cap_fps(if hidden { 0 } else { resume.settings.max_fps });
+                                                if !hidden {
+                                                    chunks = Some(encoder.subscribe());
+                                                }
+                                                video_delay.clear();
+                                                stats.rebase(&encoder);
+                                                congestion = CongestionMonitor::default();
+                                            }
+                                            // Always restart cleanly: fresh config, then an IDR
+                                            resume.settings.preset = None;
+                                            sent_config = None;
+                                            waiting_for_keyframe = true;
+                                            media.clear_video();
+                                            join_encoder(&tx, &encoder, &mut sent_config).await;
+                                            if !request_keyframe(&tx, &mut throttle, &encoder).await {
+                                                break;
+                                            }
+                                            let ack = serde_json::json!({
+                                                "type": "resolution-ack",
+                                                "resolution": rung,
+                                            });
+                                            if tx.send(Message::Text(Utf8Bytes::from(ack.to_string()))).await.is_err() {
+                                                break;
+                                            }
+                                        }
+                                        "quality" => {
+                                            let requested = val.get("preset").and_then(|p| p.as_str()).unwrap_or_default();
+                                            let quality = match find_preset(requested) {
+                                                Ok(quality) => quality,
+                                                Err(message) => {
+                                                    let reply = serde_json::json!({
+                                                        "type": "error",
+                                                        "command": "quality",
+                                                        "message": message,
+                                                    });
+                                                    if tx.send(Message::Text(Utf8Bytes::from(reply.to_string()))).await.is_err() {
+                                                        break;
+                                                    }
+                                                    continue;
+                                                }
+                                            };
+                                            let (rung, picked) = encoders.pick_codec(codec, Some(quality.max_pixels));
+                                            println!("viewer chose {} quality ({rung} encoder)", quality.name);
+                                            registration.set_resolution(rung);
+                                            picked.set_target_bitrate(quality.bitrate_bps);
+                                            picked.set_max_fps(quality.max_fps);
+                                            if !Arc::ptr_eq(&picked, &encoder) {
+                                                encoder = picked;
+                                                fps_cap = encoder.cap_fps(if hidden { 0 } else { resume.settings.max_fps });
+                                                if !hidden {
+                                                    chunks = Some(encoder.subscribe());
+                                                }
+                                                video_delay.clear();
+                                                stats.rebase(&encoder);
+                                                congestion = CongestionMonitor::default();
+                                            }
+                                            sent_config = None;
+                                            waiting_for_keyframe = true;
+                                            media.clear_video();
+                                            join_encoder(&tx, &encoder, &mut sent_config).await;
+                                            if !request_keyframe(&tx, &mut throttle, &encoder).await {
+                                                break;
+                                            }
+                                            resume.settings.max_pixels = Some(quality.max_pixels);
+                                            resume.settings.preset = Some(quality.name);
+                                            let ack = serde_json::json!({
+                                                "type": "quality-ack",
+                                                "preset": quality.name,
+                                                "resolution": rung,
+                                            });
+                                            if tx.send(Message::Text(Utf8Bytes::from(ack.to_string()))).await.is_err() {
+                                                break;
+                                            }
+                                        }
+                                        // {"type":"av-offset","ms":120}: positive holds audio back,
+                                        // negative holds video back; tuned by ear during a session
+                                        "av-offset" => {
+                                            let reply = match val
+                                                .get("ms")
+                                                .and_then(|ms| ms.as_i64())
+                                                .filter(|ms| ms.abs() <= delay_line::MAX_OFFSET_MS)
+                                            {
+                                                Some(offset_ms) => {
+                                                    resume.settings.audio_offset_ms = offset_ms;
+                                                    let (audio, video) = delay_line::offset_delays(offset_ms);
+                                                    direct_audio_delay.set_delay(audio);
+                                                    mixer_audio_delay.set_delay(audio);
+                                                    video_delay.set_delay(video);
+                                                    serde_json::json!({ "type": "av-offset-ack", "ms": offset_ms })
+                                                }
+                                                None => serde_json::json!({
+                                                    "type": "error",
+                                                    "command": "av-offset",
+                                                    "message": format!("ms must be an integer from -{0} to {0}", delay_line::MAX_OFFSET_MS),
+                                                }),
+                                            };
+                                            if tx.send(Message::Text(Utf8Bytes::from(reply.to_string()))).await.is_err() {
+                                                break;
+                                            }
+                                        }
+                                        // {"type":"set-fps","max_fps":15}; 0 or null lifts the cap.
+                                        // The encoder just skips more or fewer captured frames, so
+                                        // no IDR is needed.
+                                        "set-fps" => {
+                                            let reply = match parse_fps(&val) {
+                                                Ok(max_fps) => {
+                                                    resume.settings.max_fps = max_fps;
+                                                    if !hidden {
+                                                        fps_cap.set(max_fps);
+                                                    }
+                                                    serde_json::json!({
+                                                        "type": "fps-ack",
+                                                        "max_fps": (max_fps > 0).then_some(max_fps),
+                                                        "effective_fps": fps_json(encoder.effective_max_fps()),
+                                                    })
+                                                }
+                                                Err(message) => serde_json::json!({
+                                                    "type": "error",
+                                                    "command": "set-fps",
+                                                    "message": message,
+                                                }),
+                                            };
+                                            if tx.send(Message::Text(Utf8Bytes::from(reply.to_string()))).await.is_err() {
+                                                break;
+                                            }
+                                        }
+                                        // {"type":"set-crop","x":0,"y":0,"width":1920,"height":2160}
+                                        // in captured-source pixels (physical pixels on Retina, before
+                                        // downsampling), or {"type":"set-crop","clear":true}. Applies
+                                        // to every viewer, like set-source.
+                                        "set-crop" => {
+                                            let rect = match parse_crop(&val) {
+                                                Ok(rect) => rect,
+                                                Err(message) => {
+                                                    let reply = crop_error(message);
+                                                    if tx.send(Message::Text(Utf8Bytes::from(reply.to_string()))).await.is_err() {
+                                                        break;
+                                                    }
+                                                    continue;
+                                                }
+                                            };
+                                            // A crop restarts every rung from an IDR, so it is throttled like one
+                                            let reply = match throttle.request() {
+                                                KeyframeDecision::Allowed => apply_crop(&encoders, rect, &mut waiting_for_keyframe),
+                                                KeyframeDecision::Throttled { newly } => {
+                                                    pending_crop = Some(rect);
+                                                    if newly && !send_throttled(&tx, &mut throttle).await {
+                                                        break;
+                                                    }
+                                                    continue;
+                                                }
+                                            };
+                                            if tx.send(Message::Text(Utf8Bytes::from(reply.to_string()))).await.is_err() {
+                                                break;
+                                            }
+                                        }
+                                        "pip" => {
+                                            let result = match &state.pip {
+                                                Some(pip) => pip.update(&val),
+                                                None => Err("no inset source (start with --pip-window or --pip-monitor)".to_string()),
+                                            };
+                                            let reply = match result {
+                                                Ok(layout) => serde_json::json!({
+                                                    "type": "pip-ack",
+                                                    "pip": layout.to_json(),
+                                                }),
+                                                Err(message) => serde_json::json!({
+                                                    "type": "error",
+                                                    "command": "pip",
+                                                    "message": message,
+                                                }),
+                                            };
+                                            if tx.send(Message::Text(Utf8Bytes::from(reply.to_string()))).await.is_err() {
+                                                break;
+                                            }
+                                        }
+                                        // {"type":"annotate","shapes":[{"kind":"arrow","points":[[x,y],[x,y]],
+                                        // "color":"#ff0000","ttl_ms":4000}]} in this viewer's stream pixels;
+                                        // drawn into the capture for every viewer
+                                        "annotate" => {
+                                            let result = match (&state.annotations, &sent_config) {
+                                                (None, _) => Err("annotations are disabled (start with --allow-annotations)".to_string()),
+                                                (Some(_), None) => Err("no video yet".to_string()),
+                                                (Some(annotations), Some(config)) => annotate::parse_shapes(&val, |point| {
+                                                    encoders.stream_to_source(point, config.width, config.height)
+                                                })
+                                                .map(|shapes| annotations.add(shapes)),
+                                            };
+                                            if let Err(message) = result {
+                                                let reply = serde_json::json!({
+                                                    "type": "error",
+                                                    "command": "annotate",
+                                                    "message": message,
+                                                });
+                                                if tx.send(Message::Text(Utf8Bytes::from(reply.to_string()))).await.is_err() {
+                                                    break;
+                                                }
+                                            }
+                                        }
+                                        // {"type":"screenshot","id":"abc"}: a full-resolution PNG of the
+                                        // next captured frame, sent back as IMG0 with the same id
+                                        "screenshot" => {
+                                            let id = val.get("id").and_then(|id| id.as_str()).unwrap_or_default();
+                                            // An empty id marks JPEG fallback frames
+                                            let refused = if id.is_empty() {
+                                                Some("screenshot needs an id".to_string())
+                                            } else if id.len() > MAX_SCREENSHOT_ID {
+                                                Some(format!("screenshot id must be at most {MAX_SCREENSHOT_ID} bytes"))
+                                            } else if screenshots.try_send(id.to_string()).is_err() {
+                                                Some(format!("at most {SCREENSHOT_QUEUE} screenshots may be pending"))
+                                            } else {
+                                                None
+                                            };
+                                            if let Some(message) = refused {
+                                                if tx.send(Message::Text(Utf8Bytes::from(screenshot_error(id, message).to_string()))).await.is_err() {
+                                                    break;
+                                                }
+                                            }
+                                        }
+                                        "clipboard" => {
+                                            let text = val.get("text").and_then(|t| t.as_str());
+                                            let result = match (&state.clipboard, text) {
+                                                (Some(clipboard), Some(text)) => clipboard.set(text.to_string()),
+                                                (None, _) => Err(anyhow::anyhow!("clipboard sync is disabled (start with --allow-clipboard)")),
+                                                (_, None) => Err(anyhow::anyhow!("clipboard message needs a text field")),
+                                            };
+                                            if let Err(err) = result {
+                                                let reply = serde_json::json!({
+                                                    "type": "error",
+                                                    "command": "clipboard",
+                                                    "message": err.to_string(),
+                                                });
+                                                if tx.send(Message::Text(Utf8Bytes::from(reply.to_string()))).await.is_err() {
+                                                    break;
+                                                }
+                                            }
+                                        }
+                                        "set-source" => {
+                                            let reply = match set_source(&state, &val).await {
+                                                Ok(_) => {
+                                                    // Shapes were placed on the old source
+                                                    if let Some(annotations) = &state.annotations {
+                                                        annotations.clear();
+                                                    }
+                                                    // New dimensions: the encoder restarts from an IDR with a
+                                                    // new config, which is re-sent below when it arrives
+                                                    encoder.request_keyframe();
+                                                    serde_json::json!({
+                                                        "type": "source-changed",
+                                                        "source": state.recorder.source_json(),
+                                                    })
+                                                }
+                                                Err(err) => {
+                                                    eprintln!("set-source failed: {err}");
+                                                    serde_json::json!({
+                                                        "type": "error",
+                                                        "command": "set-source",
+                                                        "message": err.to_string(),
+                                                    })
+                                                }
+                                            };
+                                            if tx.send(Message::Text(Utf8Bytes::from(reply.to_string()))).await.is_err() {
+                                                break;
+                                            }
                                         }
                                         _ => {}
                                     }
@@ -328,74 +1193,212 @@ async fn run_video(
                     None => break,
                 }
             }
+            _ = liveness.tick() => {
+                if !check_liveness(&tx, &registration).await {
+                    break;
+                }
+            }
+            // Throttled IDR (and crop) requests, issued once the bucket refills
+            _ = tokio::time::sleep_until(keyframe_due.unwrap_or_else(Instant::now).into()), if keyframe_due.is_some() => {
+                if throttle.fire() {
+                    match pending_crop.take() {
+                        Some(rect) => {
+                            let reply = apply_crop(&encoders, rect, &mut waiting_for_keyframe);
+                            if tx.send(Message::Text(Utf8Bytes::from(reply.to_string()))).await.is_err() {
+                                break;
+                            }
+                        }
+                        None => encoder.request_keyframe(),
+                    }
+                }
+            }
             // Direct audio capture (low latency, stereo)
             Some(Ok(chunk)) = async { 
                 match &mut direct_audio_rx {
+                    Some(rx) => Some(direct_audio_delay.recv(rx).await),
+                    None => None,
+                }
+            } => {
+                // Still received while muted so the broadcast doesn't lag
+                if paused || resume.settings.audio_muted {
+                    continue;
+                }
+                let packets = match &mut opus {
+                    Some(opus) => opus.push(chunk.sample_rate, chunk.channels, &chunk.samples),
+                    None => vec![build_direct_audio_chunk(&chunk)],
+                };
+                media.push_audio(packets);
+                stats.audio_chunks += 1;
+            }
+            // Host clipboard changed
+            Some(Ok(text)) = async {
+                match &mut clipboard_rx {
                     Some(rx) => Some(rx.recv().await),
                     None => None,
                 }
             } => {
-                if tx.send(Message::Binary(build_direct_audio_chunk(&chunk))).await.is_err() {
+                let msg = serde_json::json!({ "type": "clipboard", "text": text });
+                if tx.send(Message::Text(Utf8Bytes::from(msg.to_string()))).await.is_err() {
                     break;
                 }
             }
-            // Mixer audio (fallback, higher latency)
+            // Mixer audio (system + mic, or fallback; higher latency)
             Some(Ok(chunk)) = async {
                 match &mut mixer_audio_rx {
-                    Some(rx) => Some(rx.recv().await),
+                    Some(rx) => Some(mixer_audio_delay.recv(rx).await),
                     None => None,
                 }
             } => {
-                if tx.send(Message::Binary(build_audio_chunk(&chunk))).await.is_err() {
-                    break;
+                // Still received while muted so the broadcast doesn't lag
+                if paused || resume.settings.audio_muted {
+                    continue;
                 }
+                let packets = match &mut opus {
+                    Some(opus) => opus.push(chunk.sample_rate, chunk.channels, &chunk.samples),
+                    None => vec![build_audio_chunk(&chunk)],
+                };
+                media.push_audio(packets);
+                stats.audio_chunks += 1;
             }
-            frame = listen_frames.recv() => {
-                match frame {
-                    Some(frame) => {
-                        let DownsampledFrame { frame, scale: _ } = downsampler.downsample(frame);
-                        // if scale > 1 {
-                        //     println!("downsampled frame by {scale}x -> {}x{}", frame.width, frame.height);
-                        // }
-                        let force = force_idr_next;
-                        force_idr_next = false;
-                        let maybe_chunk = pipeline.encode(frame, force)?;
-                        if let Some(chunk) = maybe_chunk {
-                            // println!("sending encoded video chunk: {} bytes", chunk.data.len());
-
-                            if !pending_config_sent {
-                                let config = pipeline.config();
-                                println!("video config: {:?}", config);
-                                if !config.description_b64.is_empty() && config.width > 0 && config.height > 0 {
-                                    let config_json = serde_json::json!({
-                                        "type": "video-config",
-                                        "config": {
-                                            "codec": match config.codec {
-                                                VideoCodec::Avc => "avc1.42E01E",
-                                                VideoCodec::Hevc => "hev1.1.6.L93.B0",
-                                            },
-                                            "description": config.description_b64,
-                                            "width": config.width,
-                                            "height": config.height,
-                                        }
-                                    });
-                                    println!("sending video config: {}", config_json.to_string());
-                                    let _ = tx.send(Message::Text(Utf8Bytes::from(config_json.to_string()))).await;
-                                    pending_config_sent = true;
+            Some(chunk) = async {
+                match &mut chunks {
+                    Some(rx) => Some(video_delay.recv(rx).await),
+                    None => None,
+                }
+            } => {
+                match chunk {
+                    Ok(chunk) => {
+                        if paused {
+                            continue;
+                        }
+                        let layer_filter = encoder.base_layer_only(resume.settings.max_fps);
+                        if layer_filter != base_layer_only {
+                            // The numbering changes either way; restart at a keyframe so it isn't read as a gap
+                            base_layer_only = layer_filter;
+                            if !waiting_for_keyframe && !chunk.is_keyframe {
+                                waiting_for_keyframe = true;
+                                if throttle.request() == KeyframeDecision::Allowed {
+                                    encoder.request_keyframe();
                                 }
                             }
-
-                            if !pending_config_sent {
-                                // Wait until config is available.
+                        }
+                        if base_layer_only && chunk.temporal_id > 0 {
+                            continue;
+                        }
+                        if waiting_for_keyframe {
+                            if !chunk.is_keyframe {
+                                stats.frames_dropped += 1;
                                 continue;
                             }
+                            waiting_for_keyframe = false;
+                        }
 
-                            if tx.send(Message::Binary(Bytes::from(chunk.data.clone()))).await.is_err() {
-                                break;
+                        // The config travels with the frame so it can't overtake older frames
+                        let mut prefix = Vec::new();
+                        if !sent_config.as_ref().is_some_and(|sent| Arc::ptr_eq(sent, &chunk.config)) {
+                            prefix.push(video_config_message(&chunk.config));
+                            sent_config = Some(chunk.config.clone());
+                        }
+
+                        let payload = match &mut muxer {
+                            Some(muxer) => match muxer.push(&chunk) {
+                                Ok(segments) => segments.into_iter().map(Message::Binary).collect(),
+                                Err(err) => {
+                                    eprintln!("fmp4 mux failed: {err}");
+                                    continue;
+                                }
+                            },
+                            None if base_layer_only => vec![Message::Binary(chunk.base_layer_packet())],
+                            None => vec![Message::Binary(chunk.packet.clone())],
+                        };
+                        let push = media.push_video(prefix, payload, chunk.is_keyframe);
+                        congestion.observe(&encoder, push);
+                        if push == VideoPush::Overrun {
+                            // Skip to a keyframe rather than deliver frames that are already late
+                            stats.frames_dropped += 2;
+                            waiting_for_keyframe = true;
+                            if throttle.request() == KeyframeDecision::Allowed {
+                                encoder.request_keyframe();
                             }
+                            continue;
                         }
+                        stats.record_frame(&chunk);
+                        registration.record_frame(chunk.packet.len());
                     }
-                    None => break,
+                    Err(RecvError::Lagged(skipped)) => {
+                        eprintln!("viewer lagging, skipped {skipped} chunks");
+                        stats.frames_dropped += skipped;
+                        waiting_for_keyframe = true;
+                        if throttle.request() == KeyframeDecision::Allowed {
+                            encoder.request_keyframe();
+                        }
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+            // --follow-focus moved the capture to another window
+            Ok(()) = window_changes.changed() => {
+                // Shapes were placed on the old window
+                if let Some(annotations) = &state.annotations {
+                    annotations.clear();
+                }
+                // A new size restarts the encoder with a new config anyway;
+                // same-size windows get a clean IDR too
+                encoder.request_keyframe();
+                let msg = serde_json::json!({
+                    "type": "source-changed",
+                    "source": state.recorder.source_json(),
+                });
+                if tx.send(Message::Text(Utf8Bytes::from(msg.to_string()))).await.is_err() {
+                    break;
+                }
+            }
+            // The captured window closed, or --reattach-by-title found it again
+            Ok(()) = source_state.changed() => {
+                let msg = match &*source_state.borrow_and_update() {
+                    SourceState::Capturing => {
+                        encoder.request_keyframe();
+                        serde_json::json!({ "type": "source-restored" })
+                    }
+                    SourceState::Lost { reason } => {
+                        serde_json::json!({ "type": "source-lost", "reason": reason, "reattaching": true })
+                    }
+                    SourceState::Ended { reason } => {
+                        serde_json::json!({ "type": "source-lost", "reason": reason, "reattaching": false })
+                    }
+                };
+                if tx.send(Message::Text(Utf8Bytes::from(msg.to_string()))).await.is_err() {
+                    break;
+                }
+            }
+            Ok(()) = viewers.changed() => {
+                let count = *viewers.borrow_and_update();
+                let msg = serde_json::json!({ "type": "viewers", "count": count });
+                if tx.send(Message::Text(Utf8Bytes::from(msg.to_string()))).await.is_err() {
+                    break;
+                }
+            }
+            _ = stats_ticker.tick() => {
+                let elapsed = stats_started.elapsed();
+                let mut report = stats.take_json(&encoder, elapsed);
+                report["preset"] = serde_json::json!(resume.settings.preset);
+                report["paused"] = serde_json::json!(paused);
+                report["hidden"] = serde_json::json!(hidden);
+                report["encoder"] = encoder.stats_json();
+                // Byte order the encoders read captured frames as (--pixel-format)
+                report["pixel_format"] = serde_json::json!(state.recorder.pixel_format().as_str());
+                if let Some(pacing) = media.take_pacing() {
+                    report["send_kbps"] = serde_json::json!(pacing.bytes_sent * 8 / elapsed.as_millis().max(1) as u64);
+                    report["max_kbps"] = serde_json::json!(pacing.max_kbps);
+                    report["paced_ms"] = serde_json::json!(pacing.delayed.as_millis() as u64);
+                    // Held back for much of the interval: the encoder is outrunning the cap
+                    if pacing.delayed.as_secs_f64() > elapsed.as_secs_f64() * PACED_HOLD_FRACTION {
+                        encoder.reduce_bitrate();
+                    }
+                }
+                stats_started = Instant::now();
+                if tx.send(Message::Text(Utf8Bytes::from(report.to_string()))).await.is_err() {
+                    break;
                 }
             }
         }
@@ -405,3 +1408,270 @@ async fn run_video(
     Ok(())
 }
 
+/// Parse a `set-fps` message; 0 lifts the cap
+fn parse_fps(msg: &Value) -> Result<u32, String> {
+    match msg.get("max_fps") {
+        None | Some(Value::Null) => Ok(0),
+        Some(value) => value
+            .as_u64()
+            .filter(|fps| *fps <= MAX_FPS_LIMIT as u64)
+            .map(|fps| fps as u32)
+            .ok_or_else(|| format!("max_fps must be an integer from 0 to {MAX_FPS_LIMIT}")),
+    }
+}
+
+/// An fps cap for JSON replies; null when uncapped
+fn fps_json(max_fps: u32) -> Value {
+    serde_json::json!((max_fps > 0).then_some(max_fps))
+}
+
+/// Answer one session's screenshot requests in order. Capture and PNG
+/// encoding happen here so the session keeps streaming meanwhile.
+async fn run_screenshots(mut requests: mpsc::Receiver<String>, tx: mpsc::Sender<Message>, recorder: Arc<Recorder>) {
+    while let Some(id) = requests.recv().await {
+        let pixel_format = recorder.pixel_format();
+        let png = match recorder.snapshot().await {
+            Ok(captured) => tokio::task::spawn_blocking(move || {
+                screenshot::encode_png(&captured.frame, captured.stride_bytes, None, pixel_format)
+            })
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|png| png),
+            Err(err) => Err(err),
+        };
+        let reply = match png {
+            Ok(png) => Message::Binary(Bytes::from(screenshot::build_image_packet(&id, &png))),
+            Err(err) => {
+                eprintln!("screenshot failed: {err}");
+                Message::Text(Utf8Bytes::from(screenshot_error(&id, err.to_string()).to_string()))
+            }
+        };
+        if tx.send(reply).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn screenshot_error(id: &str, message: String) -> Value {
+    serde_json::json!({
+        "type": "error",
+        "command": "screenshot",
+        "id": id,
+        "message": message,
+    })
+}
+
+/// Look up a `quality` preset by name
+fn find_preset(requested: &str) -> Result<&'static QualityPreset, String> {
+    QUALITY_PRESETS
+        .iter()
+        .find(|q| q.name == requested)
+        .ok_or_else(|| format!("unknown preset {:?} (low, medium, high, lossy-text)", requested))
+}
+
+/// Apply a preset's bitrate and frame rate to the rung it maps to without
+/// moving any viewer (control socket); returns the rung
+pub(crate) fn apply_quality(encoders: &EncoderLadder, requested: &str) -> Result<&'static str, String> {
+    let quality = find_preset(requested)?;
+    let (rung, encoder) = encoders.pick(Some(quality.max_pixels));
+    encoder.set_target_bitrate(quality.bitrate_bps);
+    encoder.set_max_fps(quality.max_fps);
+    Ok(rung)
+}
+
+/// Parse a `set-crop` message; Ok(None) clears the crop
+pub(crate) fn parse_crop(msg: &Value) -> Result<Option<CropRect>, String> {
+    if msg.get("clear").and_then(|c| c.as_bool()) == Some(true) {
+        return Ok(None);
+    }
+    let field = |name: &str| {
+        msg.get(name)
+            .and_then(|v| v.as_u64())
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| format!("set-crop needs a non-negative integer {name} (or clear: true)"))
+    };
+    Ok(Some(CropRect {
+        x: field("x")?,
+        y: field("y")?,
+        width: field("width")?,
+        height: field("height")?,
+    }))
+}
+
+/// Start following `encoder`: send its cached config (if any). The caller
+/// asks for the IDR, through the session's throttle once it is running.
+async fn join_encoder(
+    tx: &mpsc::Sender<Message>,
+    encoder: &SharedEncoder,
+    sent_config: &mut Option<Arc<VideoConfig>>,
+) {
+    if let Some(config) = encoder.latest_config() {
+        send_video_config(tx, &config).await;
+        *sent_config = Some(config);
+    }
+}
+
+/// Ask `encoder` for an IDR on the client's behalf, subject to the throttle.
+/// False if the socket has closed.
+async fn request_keyframe(
+    tx: &mpsc::Sender<Message>,
+    throttle: &mut KeyframeThrottle,
+    encoder: &SharedEncoder,
+) -> bool {
+    match throttle.request() {
+        KeyframeDecision::Allowed => {
+            encoder.request_keyframe();
+            true
+        }
+        KeyframeDecision::Throttled { newly } => !newly || send_throttled(tx, throttle).await,
+    }
+}
+
+/// Tell the client its IDR requests are being coalesced, and when the pending one goes out
+async fn send_throttled(tx: &mpsc::Sender<Message>, throttle: &mut KeyframeThrottle) -> bool {
+    let retry_ms = throttle
+        .pending_until()
+        .map_or(0, |at| at.saturating_duration_since(Instant::now()).as_millis() as u64);
+    let notice = serde_json::json!({
+        "type": "keyframe-throttled",
+        "retry_ms": retry_ms,
+    });
+    tx.send(Message::Text(Utf8Bytes::from(notice.to_string()))).await.is_ok()
+}
+
+/// Crop every viewer's stream and build the reply: crop-ack, or an error
+fn apply_crop(encoders: &EncoderLadder, rect: Option<CropRect>, waiting_for_keyframe: &mut bool) -> Value {
+    match encoders.set_crop(rect) {
+        Ok(()) => {
+            // New dimensions: config is re-sent when it arrives
+            *waiting_for_keyframe = true;
+            serde_json::json!({
+                "type": "crop-ack",
+                "crop": rect.map(CropRect::to_json),
+            })
+        }
+        Err(message) => crop_error(message),
+    }
+}
+
+fn crop_error(message: String) -> Value {
+    serde_json::json!({
+        "type": "error",
+        "command": "set-crop",
+        "message": message,
+    })
+}
+
+async fn send_video_config(tx: &mpsc::Sender<Message>, config: &VideoConfig) {
+    let _ = tx.send(video_config_message(config)).await;
+}
+
+fn video_config_message(config: &VideoConfig) -> Message {
+    let config_json = serde_json::json!({
+        "type": "video-config",
+        "config": {
+            "codec": config.codec_string(),
+            "description": config.description_b64,
+            "width": config.width,
+            "height": config.height,
+            "color_matrix": config.color_matrix.as_str(),
+            // For MediaSource.addSourceBuffer on the fmp4 transport
+            "mse_codec": fmp4::codec_string(config),
+        }
+    });
+    println!("sending video config: {}", config_json);
+    Message::Text(Utf8Bytes::from(config_json.to_string()))
+}
+
+/// Handle a `set-source` message: switch the shared recorder to the new source
+pub(crate) async fn set_source(state: &AppState, msg: &Value) -> anyhow::Result<CaptureSource> {
+    let source = msg
+        .get("source")
+        .ok_or_else(|| anyhow::anyhow!("set-source needs a source object"))
+        .and_then(|value| {
+            CaptureSource::from_json(value, state.recorder.capture_fps()).map_err(anyhow::Error::msg)
+        })?;
+
+    // Window lookup and thread setup block, so keep them off the runtime
+    let recorder = state.recorder.clone();
+    let switched = source.clone();
+    tokio::task::spawn_blocking(move || recorder.switch_source(switched)).await??;
+    Ok(source)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::WebSocketUpgrade, routing::get, Router};
+    use tokio::{sync::watch, time::timeout};
+    use tokio_tungstenite::tungstenite;
+
+    #[tokio::test]
+    async fn sessions_beyond_the_limit_are_refused() {
+        const MAX: usize = 3;
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let sessions = Arc::new(SessionRegistry::new(None, Some(MAX)));
+        let registry = sessions.clone();
+        // Admitted sessions hold their slot until the client goes away
+        let app = Router::new().route(
+            "/ws",
+            get(move |ws: WebSocketUpgrade| async move {
+                ws.on_upgrade(move |socket| {
+                    crate::serve_socket(socket, shutdown_rx, move |mut receiver, tx, _| async move {
+                        let Some(_slot) = admit(&sessions, &tx).await else {
+                            return;
+                        };
+                        let _ = tx.send(Message::Text(Utf8Bytes::from("admitted"))).await;
+                        while let Some(Ok(_)) = receiver.next().await {}
+                    })
+                })
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let connect = || async {
+            let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+                .await
+                .unwrap();
+            let first = timeout(Duration::from_secs(1), client.next())
+                .await
+                .expect("no reply within a second")
+                .unwrap()
+                .unwrap();
+            (client, first)
+        };
+        let mut admitted = Vec::new();
+        for _ in 0..MAX {
+            let (client, first) = connect().await;
+            assert_eq!(first, tungstenite::Message::Text("admitted".into()));
+            admitted.push(client);
+        }
+        assert_eq!(registry.to_json()["admitted"], MAX);
+
+        let (mut refused, error) = connect().await;
+        let error: Value = serde_json::from_str(error.to_text().unwrap()).unwrap();
+        assert_eq!(error["code"], "stream-full");
+        match refused.next().await {
+            Some(Ok(tungstenite::Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), CLOSE_FULL),
+            other => panic!("expected a Close, got {:?}", other),
+        }
+        assert_eq!(registry.to_json()["admitted"], MAX);
+
+        // Leaving frees a slot
+        admitted.pop().unwrap().close(None).await.unwrap();
+        let mut freed = false;
+        for _ in 0..100 {
+            if registry.to_json()["admitted"] == MAX - 1 {
+                freed = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(freed, "slot still held after the client left");
+        let (_client, first) = connect().await;
+        assert_eq!(first, tungstenite::Message::Text("admitted".into()));
+    }
+}
@@ -0,0 +1,1352 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::body::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, Notify};
+use xcap::Frame;
+
+use crate::{
+    annotate::{Annotations, Annotator},
+    pip::{Compositor, Pip},
+    recording::{packed_stride, same_picture, CropRect, FrameEvent, Recorder, SourceState},
+    scaler::Scaler,
+    video_pipeline::{
+        self, EncodedChunk, EncoderStats, PipelineFrame, PipelineOptions, VideoCodec, VideoConfig, VideoPipeline,
+    },
+};
+
+// Keep resolution manageable for software encoding (~1080p equivalent)
+const MAX_PIXELS: usize = 1_920 * 1_080;
+
+/// Resolution rungs viewers choose from: name and pixel budget (None = native).
+/// Each rung is a separate shared encoder that only runs while it has viewers.
+const LADDER: [(&str, Option<usize>); 4] = [
+    ("720p", Some(1_280 * 720)),
+    ("1080p", Some(MAX_PIXELS)),
+    ("1440p", Some(2_560 * 1_440)),
+    ("native", None),
+];
+
+/// Rung used when the client doesn't ask for a resolution and the server has no --max-pixels
+const DEFAULT_RUNG: usize = 1;
+
+/// Name of the top rung when --max-pixels doesn't match a fixed one
+const CAPPED_RUNG: &str = "max";
+
+/// Consecutive unchanged frames still encoded after a change, letting the
+/// encoder refine a static picture before it goes idle
+const IDLE_AFTER_FRAMES: u32 = 10;
+
+/// While the picture is unchanged, one frame is still encoded this often so late
+/// joiners and the IDR timer keep working
+const IDLE_KEEPALIVE: Duration = Duration::from_secs(1);
+/// A resized capture is only encoded once its size has held this long, so a
+/// live window resize doesn't recreate the encoder on every frame
+const RESIZE_SETTLE: Duration = Duration::from_millis(250);
+
+/// Encoded chunks buffered per viewer before it is considered lagging
+const CHUNK_BROADCAST_DEPTH: usize = 120;
+
+/// Multiplier applied to the bitrate each time a viewer reports congestion
+const BITRATE_STEP_DOWN: f64 = 0.7;
+/// Multiplier applied when viewers have drained for a while
+const BITRATE_STEP_UP: f64 = 1.1;
+/// Minimum gap between step-downs, so several congested viewers don't compound
+const STEP_DOWN_INTERVAL: Duration = Duration::from_millis(500);
+/// No step-up within this long of the last change
+const STEP_UP_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Video packet flag: chunk is a keyframe (same bit as foundry-player)
+const VIDEO_FLAG_KEYFRAME: u8 = 0x01;
+
+/// One encoded frame, shared by every viewer. Consumers clone the `Bytes`
+/// (a reference count), never the payload.
+#[derive(Debug)]
+pub struct SharedChunk {
+    /// "VID0" header plus AVCC payload, framed once for all viewers
+    pub packet: Bytes,
+    /// The encoder's AVCC output as it came out, for muxers
+    pub data: Bytes,
+    /// Capture time in ms since the stream epoch, as in `packet`
+    pub captured_ms: f64,
+    pub is_keyframe: bool,
+    /// Time spent in the encoder for this frame, in microseconds
+    pub encode_us: u64,
+    /// Temporal layer; viewers on half rate or less only get layer 0
+    pub temporal_id: u8,
+    /// Decoder config this chunk belongs to; a new Arc means a new config
+    pub config: Arc<VideoConfig>,
+    /// Number of this chunk among the layer 0 chunks alone
+    base_sequence: u64,
+    /// `packet` numbered by `base_sequence`, built for the first viewer that needs it
+    base_packet: OnceLock<Bytes>,
+}
+
+impl SharedChunk {
+    /// Frame `chunk` as VID0 packet number `sequence`, keeping its data as it
+    /// is; `base_sequence` numbers it among layer 0 chunks
+    fn new(sequence: u64, base_sequence: u64, chunk: EncodedChunk, config: Arc<VideoConfig>) -> Self {
+        let captured_ms = chunk.capture_ts.saturating_duration_since(epoch()).as_secs_f64() * 1000.0;
+        Self {
+            packet: build_video_packet(sequence, captured_ms, chunk.is_keyframe, &chunk.data),
+            data: chunk.data,
+            captured_ms,
+            is_keyframe: chunk.is_keyframe,
+            encode_us: chunk.encode_duration.as_micros() as u64,
+            temporal_id: chunk.temporal_id,
+            config,
+            base_sequence,
+            base_packet: OnceLock::new(),
+        }
+    }
+
+    /// `packet` numbered among layer 0 chunks, for viewers that skip the other
+    /// layers and would otherwise see every other number missing. Renumbered
+    /// once, then shared like `packet`.
+    pub fn base_layer_packet(&self) -> Bytes {
+        self.base_packet
+            .get_or_init(|| renumber_video_packet(&self.packet, self.base_sequence))
+            .clone()
+    }
+}
+
+/// Bitrate limits for adaptive rate control (--min-bitrate-kbps/--max-bitrate-kbps)
+#[derive(Debug, Clone, Copy)]
+pub struct BitrateBounds {
+    pub min_bps: u32,
+    pub max_bps: u32,
+}
+
+/// WIDTHxHEIGHT, as given to --max-resolution
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Resolution {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Resolution {
+    pub fn pixels(self) -> usize {
+        self.width as usize * self.height as usize
+    }
+}
+
+impl FromStr for Resolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid resolution `{}` (expected WIDTHxHEIGHT, e.g. 1280x720)", s);
+        let (width, height) = s.split_once(['x', 'X']).ok_or_else(invalid)?;
+        let width: u32 = width.trim().parse().map_err(|_| invalid())?;
+        let height: u32 = height.trim().parse().map_err(|_| invalid())?;
+        if width < 2 || height < 2 {
+            return Err(invalid());
+        }
+        Ok(Resolution { width, height })
+    }
+}
+
+impl TryFrom<String> for Resolution {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Resolution> for String {
+    fn from(resolution: Resolution) -> Self {
+        resolution.to_string()
+    }
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+/// One --simulcast rung, WIDTHxHEIGHT@KBPS: a pixel budget and the bitrate
+/// its encoder starts at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SimulcastRung {
+    pub resolution: Resolution,
+    pub bitrate_kbps: u32,
+}
+
+impl FromStr for SimulcastRung {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (resolution, kbps) = s
+            .split_once('@')
+            .ok_or_else(|| format!("invalid simulcast rung `{}` (expected WIDTHxHEIGHT@KBPS, e.g. 1280x720@1500)", s))?;
+        let bitrate_kbps = kbps
+            .trim()
+            .parse()
+            .ok()
+            .filter(|kbps| *kbps > 0)
+            .ok_or_else(|| format!("invalid bitrate in simulcast rung `{}` (kbit/s, above 0)", s))?;
+        Ok(SimulcastRung {
+            resolution: resolution.parse()?,
+            bitrate_kbps,
+        })
+    }
+}
+
+impl TryFrom<String> for SimulcastRung {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<SimulcastRung> for String {
+    fn from(rung: SimulcastRung) -> Self {
+        rung.to_string()
+    }
+}
+
+impl fmt::Display for SimulcastRung {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.resolution, self.bitrate_kbps)
+    }
+}
+
+/// Crop applied by every encoder, plus the capture size it is checked against
+#[derive(Default)]
+struct CropState {
+    rect: Mutex<Option<CropRect>>,
+    /// Size of the most recent captured frame
+    frame_size: Mutex<Option<(u32, u32)>>,
+}
+
+/// Drawn over every captured frame before cropping, in this order
+#[derive(Clone, Default)]
+pub struct Overlays {
+    /// Inset from a second source (--pip-window/--pip-monitor)
+    pub pip: Option<Arc<Pip>>,
+    /// Viewer-drawn shapes (--allow-annotations)
+    pub annotations: Option<Arc<Annotations>>,
+}
+
+/// Target bitrate shared by every viewer of an encoder
+struct RateControl {
+    /// 0 until the first frame gives the pipeline a size-based default
+    target_bps: u32,
+    last_change: Option<Instant>,
+}
+
+impl RateControl {
+    /// Scale the target by `factor` within `bounds`, unless it last changed
+    /// less than `min_interval` before `now`. The old and new target if it moved.
+    fn step(&mut self, factor: f64, min_interval: Duration, bounds: BitrateBounds, now: Instant) -> Option<(u32, u32)> {
+        if self.target_bps == 0 || self.last_change.is_some_and(|at| now.saturating_duration_since(at) < min_interval) {
+            return None;
+        }
+        let target = ((self.target_bps as f64 * factor) as u32).clamp(bounds.min_bps, bounds.max_bps);
+        if target == self.target_bps {
+            return None;
+        }
+        let from = std::mem::replace(&mut self.target_bps, target);
+        self.last_change = Some(now);
+        Some((from, target))
+    }
+}
+
+/// A single encoder fed by the Recorder whose output is broadcast to all
+/// sessions, so CPU cost doesn't grow with the number of viewers
+pub struct SharedEncoder {
+    /// Ladder rung, for logs
+    name: &'static str,
+    /// "openh264", "videotoolbox" or "rav1e"; may change if VideoToolbox fails
+    backend: Mutex<&'static str>,
+    /// Copied from the pipeline after every encoded frame
+    stats: Mutex<EncoderStats>,
+    chunks: broadcast::Sender<Arc<SharedChunk>>,
+    latest_config: Mutex<Option<Arc<VideoConfig>>>,
+    /// Set by any viewer; several requests before the next frame coalesce into one IDR
+    force_idr: AtomicBool,
+    viewer_joined: Notify,
+    /// Frames received from the Recorder since start, for server-stats
+    frames_captured: AtomicU64,
+    /// Captured frames not encoded because nothing on screen changed
+    frames_skipped: AtomicU64,
+    /// Sequence number of the next packet; viewers see gaps when they drop chunks
+    next_sequence: AtomicU64,
+    /// The same for temporal layer 0 alone, as base-layer viewers get it
+    next_base_sequence: AtomicU64,
+    bounds: BitrateBounds,
+    rate: Mutex<RateControl>,
+    /// What the pipeline is actually running at; trails the target while a
+    /// change waits for the encoder (rav1e: the next keyframe)
+    applied_bps: AtomicU32,
+    /// Frame rate cap from a quality preset; 0 encodes every captured frame
+    max_fps: AtomicU32,
+    /// Rate the encoder was configured for (--encoder-fps), when uncapped
+    nominal_fps: f32,
+    /// Caps viewers asked for (max_fps / set-fps), keyed by FpsCap id
+    viewer_fps: Mutex<HashMap<u64, u32>>,
+    next_fps_id: AtomicU64,
+    crop: Arc<CropState>,
+    overlays: Overlays,
+}
+
+/// One viewer's frame rate cap on a SharedEncoder; removed on drop
+pub struct FpsCap {
+    encoder: Arc<SharedEncoder>,
+    id: u64,
+}
+
+impl FpsCap {
+    /// Change the cap (0 = uncapped)
+    pub fn set(&self, max_fps: u32) {
+        let mut caps = self.encoder.viewer_fps.lock().unwrap();
+        if max_fps > 0 {
+            caps.insert(self.id, max_fps);
+        } else {
+            caps.remove(&self.id);
+        }
+    }
+}
+
+impl Drop for FpsCap {
+    fn drop(&mut self) {
+        self.encoder.viewer_fps.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// One shared encoder per resolution rung
+pub struct EncoderLadder {
+    rungs: Vec<(&'static str, Option<usize>, Arc<SharedEncoder>)>,
+    /// Index of the rung viewers get when they don't ask for a resolution
+    default_rung: usize,
+    /// AV1 at the default rung's size (`--features av1`), for viewers that ask for it
+    av1: Option<Arc<SharedEncoder>>,
+    crop: Arc<CropState>,
+}
+
+impl EncoderLadder {
+    /// `max_pixels` (--max-pixels/--max-resolution) caps every rung and becomes
+    /// the default; None keeps the full ladder with 1080p as the default.
+    /// A non-empty `simulcast` (--simulcast) replaces the ladder and
+    /// `max_pixels`: one rung each, starting at its own bitrate, with the
+    /// largest within 1080p as the default. Otherwise every rung's encoder
+    /// gets the same `options`.
+    pub fn start(
+        recorder: Arc<Recorder>,
+        bounds: BitrateBounds,
+        options: PipelineOptions,
+        max_pixels: Option<usize>,
+        simulcast: &[SimulcastRung],
+        overlays: Overlays,
+    ) -> anyhow::Result<Arc<Self>> {
+        let mut ladder: Vec<(&'static str, Option<usize>, PipelineOptions)> =
+            LADDER.iter().map(|&(name, limit)| (name, limit, options)).collect();
+        let mut default_rung = DEFAULT_RUNG;
+        if !simulcast.is_empty() {
+            let mut rungs = simulcast.to_vec();
+            rungs.sort_by_key(|rung| rung.resolution.pixels());
+            rungs.dedup_by_key(|rung| rung.resolution.pixels());
+            ladder = rungs
+                .iter()
+                .map(|rung| {
+                    let rate_control = match options.rate_control {
+                        video_pipeline::RateControl::Bitrate(_) => {
+                            video_pipeline::RateControl::Bitrate(rung.bitrate_kbps.saturating_mul(1000))
+                        }
+                        other => other,
+                    };
+                    // Named once at startup, so leaking the few names is fine
+                    let name: &'static str = Box::leak(rung.resolution.to_string().into_boxed_str());
+                    (name, Some(rung.resolution.pixels()), PipelineOptions { rate_control, ..options })
+                })
+                .collect();
+            default_rung = rungs
+                .iter()
+                .rposition(|rung| rung.resolution.pixels() <= MAX_PIXELS)
+                .unwrap_or(0);
+        } else if let Some(cap) = max_pixels {
+            ladder.retain(|(_, limit, _)| limit.is_some_and(|limit| limit < cap));
+            let top = LADDER
+                .iter()
+                .find(|(_, limit)| *limit == Some(cap))
+                .map_or(CAPPED_RUNG, |&(name, _)| name);
+            ladder.push((top, Some(cap), options));
+            default_rung = ladder.len() - 1;
+        }
+        let crop = Arc::new(CropState::default());
+        let rungs = ladder
+            .into_iter()
+            .map(|(name, max_pixels, options)| {
+                SharedEncoder::start(
+                    name,
+                    VideoCodec::Avc,
+                    recorder.clone(),
+                    max_pixels,
+                    bounds,
+                    options,
+                    crop.clone(),
+                    overlays.clone(),
+                )
+                .map(|encoder| (name, max_pixels, encoder))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        // Only ever chosen explicitly, so a failure here leaves H.264 alone
+        let av1 = if cfg!(feature = "av1") {
+            let (_, max_pixels, _) = rungs[default_rung];
+            match SharedEncoder::start(
+                "av1",
+                VideoCodec::Av1,
+                recorder.clone(),
+                max_pixels,
+                bounds,
+                options,
+                crop.clone(),
+                overlays.clone(),
+            ) {
+                Ok(encoder) => Some(encoder),
+                Err(err) => {
+                    eprintln!("AV1 encoder unavailable: {err}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        Ok(Arc::new(Self { rungs, default_rung, av1, crop }))
+    }
+
+    /// Stream only `rect` of the captured source (None = full frame), for
+    /// every viewer. Every rung restarts from an IDR; the new dimensions
+    /// reach clients as a fresh video-config.
+    pub fn set_crop(&self, rect: Option<CropRect>) -> Result<(), String> {
+        if let Some(rect) = rect {
+            if rect.width < 2 || rect.height < 2 {
+                return Err("crop must be at least 2x2 pixels".to_string());
+            }
+            // Before the first frame there is nothing to check against; the
+            // encoders clamp it later
+            if let Some((width, height)) = *self.crop.frame_size.lock().unwrap() {
+                let right = rect.x as u64 + rect.width as u64;
+                let bottom = rect.y as u64 + rect.height as u64;
+                if right > width as u64 || bottom > height as u64 {
+                    return Err(format!(
+                        "crop {}x{} at ({}, {}) is outside the {}x{} capture",
+                        rect.width, rect.height, rect.x, rect.y, width, height
+                    ));
+                }
+            }
+        }
+        *self.crop.rect.lock().unwrap() = rect;
+        for (_, _, encoder) in &self.rungs {
+            encoder.request_keyframe();
+        }
+        if let Some(encoder) = &self.av1 {
+            encoder.request_keyframe();
+        }
+        Ok(())
+    }
+
+    /// Map a point in a `stream_width` x `stream_height` picture back to
+    /// captured-source pixels, undoing the downsample and crop. Clamped to the
+    /// capture; None before the first frame.
+    pub fn stream_to_source(&self, (x, y): (f32, f32), stream_width: u32, stream_height: u32) -> Option<(f32, f32)> {
+        let (width, height) = (*self.crop.frame_size.lock().unwrap())?;
+        let full = CropRect { x: 0, y: 0, width, height };
+        let region = self
+            .crop
+            .rect
+            .lock()
+            .unwrap()
+            .and_then(|rect| rect.clamp_to(width, height))
+            .unwrap_or(full);
+        let source_x = region.x as f32 + x * region.width as f32 / stream_width.max(1) as f32;
+        let source_y = region.y as f32 + y * region.height as f32 / stream_height.max(1) as f32;
+        Some((source_x.clamp(0.0, width as f32), source_y.clamp(0.0, height as f32)))
+    }
+
+    /// The largest rung within `max_pixels` (the smallest rung if none fits);
+    /// native only when the budget exceeds every fixed rung
+    pub fn pick(&self, max_pixels: Option<usize>) -> (&'static str, Arc<SharedEncoder>) {
+        let index = match max_pixels {
+            None => self.default_rung,
+            Some(budget) => self
+                .rungs
+                .iter()
+                .rposition(|(_, limit, _)| match limit {
+                    Some(limit) => *limit <= budget,
+                    None => self.rungs.iter().all(|(_, l, _)| l.is_none_or(|l| l < budget)),
+                })
+                .unwrap_or(0),
+        };
+        let (name, _, encoder) = &self.rungs[index];
+        (name, encoder.clone())
+    }
+
+    /// Every encoder's counters, for /status
+    pub fn stats_json(&self) -> serde_json::Value {
+        let encoders: Vec<_> = self
+            .rungs
+            .iter()
+            .map(|(_, _, encoder)| encoder)
+            .chain(&self.av1)
+            .map(|encoder| encoder.stats_json())
+            .collect();
+        serde_json::json!(encoders)
+    }
+
+    /// Whether viewers asking for `codec` can be served
+    pub fn supports(&self, codec: VideoCodec) -> bool {
+        match codec {
+            VideoCodec::Avc => true,
+            VideoCodec::Hevc => false,
+            VideoCodec::Av1 => self.av1.is_some(),
+        }
+    }
+
+    /// Like `pick`, for a viewer's codec. AV1 has a single encoder at the
+    /// default rung's size, so `max_pixels` only applies to H.264.
+    pub fn pick_codec(&self, codec: VideoCodec, max_pixels: Option<usize>) -> (&'static str, Arc<SharedEncoder>) {
+        match (codec, &self.av1) {
+            (VideoCodec::Av1, Some(encoder)) => ("av1", encoder.clone()),
+            _ => self.pick(max_pixels),
+        }
+    }
+}
+
+impl SharedEncoder {
+    /// Create the encoder and spawn its encoding task (idle until someone subscribes).
+    ///
+    /// Frames are downsampled to fit `max_pixels`; None encodes at capture size.
+    #[allow(clippy::too_many_arguments)]
+    fn start(
+        name: &'static str,
+        codec: VideoCodec,
+        recorder: Arc<Recorder>,
+        max_pixels: Option<usize>,
+        bounds: BitrateBounds,
+        options: PipelineOptions,
+        crop: Arc<CropState>,
+        overlays: Overlays,
+    ) -> anyhow::Result<Arc<Self>> {
+        let pipeline = VideoPipeline::new(codec, options)?;
+        println!("{name} encoder using {}", pipeline.backend());
+        let encoder = Self::new(name, &pipeline, bounds, options, crop, overlays);
+        spawn_encoder_thread(name, run_encoder(encoder.clone(), recorder, pipeline, max_pixels))?;
+        Ok(encoder)
+    }
+
+    /// State shared with the task that will run `pipeline`, before it is spawned
+    fn new(
+        name: &'static str,
+        pipeline: &VideoPipeline,
+        bounds: BitrateBounds,
+        options: PipelineOptions,
+        crop: Arc<CropState>,
+        overlays: Overlays,
+    ) -> Arc<Self> {
+        let (chunks, _) = broadcast::channel(CHUNK_BROADCAST_DEPTH);
+        Arc::new(Self {
+            name,
+            backend: Mutex::new(pipeline.backend()),
+            stats: Mutex::new(EncoderStats::default()),
+            chunks,
+            latest_config: Mutex::new(None),
+            force_idr: AtomicBool::new(true),
+            viewer_joined: Notify::new(),
+            frames_captured: AtomicU64::new(0),
+            frames_skipped: AtomicU64::new(0),
+            next_sequence: AtomicU64::new(0),
+            next_base_sequence: AtomicU64::new(0),
+            bounds,
+            rate: Mutex::new(RateControl {
+                target_bps: 0,
+                last_change: None,
+            }),
+            applied_bps: AtomicU32::new(0),
+            max_fps: AtomicU32::new(0),
+            nominal_fps: options.max_fps,
+            viewer_fps: Mutex::new(HashMap::new()),
+            next_fps_id: AtomicU64::new(0),
+            crop,
+            overlays,
+        })
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<SharedChunk>> {
+        let chunks = self.chunks.subscribe();
+        self.viewer_joined.notify_one();
+        chunks
+    }
+
+    /// The most recent video config, for viewers that join mid-stream
+    pub fn latest_config(&self) -> Option<Arc<VideoConfig>> {
+        self.latest_config.lock().unwrap().clone()
+    }
+
+    pub fn request_keyframe(&self) {
+        self.force_idr.store(true, Ordering::Relaxed);
+    }
+
+    /// Running count of frames this encoder has received from the Recorder
+    pub fn frames_captured(&self) -> u64 {
+        self.frames_captured.load(Ordering::Relaxed)
+    }
+
+    pub fn frames_skipped(&self) -> u64 {
+        self.frames_skipped.load(Ordering::Relaxed)
+    }
+
+    /// Current target bitrate, once the encoder has started
+    pub fn target_bitrate(&self) -> Option<u32> {
+        let target = self.rate.lock().unwrap().target_bps;
+        (target > 0).then_some(target)
+    }
+
+    /// The pipeline's counters as of the last encoded frame
+    pub fn encoder_stats(&self) -> EncoderStats {
+        *self.stats.lock().unwrap()
+    }
+
+    /// `encoder_stats` with the rung and backend, for /status and server-stats
+    pub fn stats_json(&self) -> serde_json::Value {
+        let mut json = serde_json::to_value(self.encoder_stats()).unwrap_or_default();
+        json["name"] = serde_json::json!(self.name);
+        json["backend"] = serde_json::json!(*self.backend.lock().unwrap());
+        json
+    }
+
+    /// Bitrate the encoder is running at, once it has started
+    pub fn applied_bitrate(&self) -> Option<u32> {
+        let applied = self.applied_bps.load(Ordering::Relaxed);
+        (applied > 0).then_some(applied)
+    }
+
+    /// Set the target bitrate outright (quality presets); adaptive rate control
+    /// continues from here
+    pub fn set_target_bitrate(&self, bitrate_bps: u32) {
+        let mut rate = self.rate.lock().unwrap();
+        rate.target_bps = bitrate_bps.clamp(self.bounds.min_bps, self.bounds.max_bps);
+        rate.last_change = Some(Instant::now());
+    }
+
+    /// Skip captured frames to stay under `max_fps` (0 = no cap)
+    pub fn set_max_fps(&self, max_fps: u32) {
+        self.max_fps.store(max_fps, Ordering::Relaxed);
+    }
+
+    /// Register a viewer's frame rate cap (0 = uncapped); it is lifted when
+    /// the returned guard drops, e.g. when the viewer moves to another rung
+    pub fn cap_fps(self: &Arc<Self>, max_fps: u32) -> FpsCap {
+        let cap = FpsCap {
+            encoder: self.clone(),
+            id: self.next_fps_id.fetch_add(1, Ordering::Relaxed),
+        };
+        cap.set(max_fps);
+        cap
+    }
+
+    /// Frame rate the encoder is currently held to; 0 when uncapped.
+    /// Viewers share the stream, so their caps only apply once every
+    /// subscriber has one, and then the highest wins: nobody gets fewer
+    /// frames than they asked for. A quality preset's cap applies on top.
+    pub fn effective_max_fps(&self) -> u32 {
+        let preset = self.max_fps.load(Ordering::Relaxed);
+        let caps = self.viewer_fps.lock().unwrap();
+        let viewers = if !caps.is_empty() && caps.len() >= self.chunks.receiver_count() {
+            caps.values().copied().max().unwrap_or(0)
+        } else {
+            0
+        };
+        match (preset, viewers) {
+            (0, fps) | (fps, 0) => fps,
+            (preset, viewers) => preset.min(viewers),
+        }
+    }
+
+    /// Whether a viewer capped at `viewer_max_fps` (0 = uncapped) is served
+    /// by temporal layer 0 alone, i.e. asked for half the encoded rate or less
+    pub fn base_layer_only(&self, viewer_max_fps: u32) -> bool {
+        let encoded_fps = match self.effective_max_fps() {
+            0 => self.nominal_fps,
+            fps => fps as f32,
+        };
+        viewer_max_fps > 0 && viewer_max_fps as f32 <= encoded_fps / 2.0
+    }
+
+    /// A viewer's outbound queue stayed backed up: encode at a lower bitrate
+    pub fn reduce_bitrate(&self) {
+        self.step_bitrate(BITRATE_STEP_DOWN, STEP_DOWN_INTERVAL, "congested");
+    }
+
+    /// A viewer has been keeping up: creep back towards the maximum
+    pub fn raise_bitrate(&self) {
+        self.step_bitrate(BITRATE_STEP_UP, STEP_UP_INTERVAL, "drained");
+    }
+
+    fn step_bitrate(&self, factor: f64, min_interval: Duration, reason: &str) {
+        let mut rate = self.rate.lock().unwrap();
+        if let Some((from, to)) = rate.step(factor, min_interval, self.bounds, Instant::now()) {
+            println!("{} encoder bitrate {} -> {} kbps ({})", self.name, from / 1000, to / 1000, reason);
+        }
+    }
+}
+
+/// Drive `work` on a thread of its own rather than as a task: encoding a
+/// frame can take tens of ms, which would stall every session sharing a
+/// runtime worker
+fn spawn_encoder_thread(name: &str, work: impl Future<Output = ()> + Send + 'static) -> std::io::Result<()> {
+    let runtime = tokio::runtime::Handle::current();
+    std::thread::Builder::new()
+        .name(format!("encoder-{name}"))
+        .spawn(move || runtime.block_on(work))?;
+    Ok(())
+}
+
+/// Owns the pipeline on the encoder's thread. Frames arrive through the
+/// Recorder's one-slot listener, so while an encode runs long, older
+/// captures are replaced by the newest instead of piling up, and the
+/// pipeline's input policy thins them out if that keeps happening; keyframe
+/// and bitrate requests reach it through `encoder`'s shared state.
+async fn run_encoder(
+    encoder: Arc<SharedEncoder>,
+    recorder: Arc<Recorder>,
+    mut pipeline: VideoPipeline,
+    max_pixels: Option<usize>,
+) {
+    let mut cropper = Cropper::default();
+    let mut change_detector = ChangeDetector::default();
+    let mut last_crop: Option<CropRect> = None;
+    let mut scaler = Scaler::new(max_pixels);
+    let mut last_encoded: Option<Instant> = None;
+
+    loop {
+        // Don't capture or encode while nobody is watching
+        while encoder.chunks.receiver_count() == 0 {
+            encoder.viewer_joined.notified().await;
+        }
+        // A source that is gone for good ends every listener straight away;
+        // wait for another one instead of starting over in a loop
+        let mut source_state = recorder.subscribe_source_state();
+        loop {
+            let ended = matches!(*source_state.borrow_and_update(), SourceState::Ended { .. });
+            if !ended {
+                break;
+            }
+            if source_state.changed().await.is_err() {
+                return;
+            }
+        }
+
+        let mut listen_frames = recorder.new_listener();
+        // Captures this listener never got, from the gaps in their sequence numbers
+        let (mut last_sequence, mut missed): (Option<u64>, u64) = (None, 0);
+        // Only holds the secondary capture open while this encoder runs
+        let mut compositor = encoder.overlays.pip.clone().map(Compositor::new);
+        let mut annotator = encoder.overlays.annotations.clone().map(Annotator::new);
+        println!("shared encoder started");
+
+        let mut resize = ResizeSettle::default();
+        while let Some(event) = listen_frames.recv_event().await {
+            let captured = match event {
+                FrameEvent::Frame(captured) => captured,
+                FrameEvent::ResolutionChanged { width, height } => {
+                    println!("capture resized to {}x{}", width, height);
+                    resize.resized(Instant::now());
+                    continue;
+                }
+                // Sessions hear about it from the Recorder; the last frame stays up
+                FrameEvent::SourceLost { reason } => {
+                    println!("capture source lost: {}", reason);
+                    continue;
+                }
+                FrameEvent::SourceRestored => {
+                    println!("capture source restored");
+                    continue;
+                }
+                // Sessions request the keyframe; a new size arrives as ResolutionChanged
+                FrameEvent::SourceChanged => {
+                    println!("capture source changed");
+                    // The new capture numbers its frames from 0
+                    last_sequence = None;
+                    continue;
+                }
+            };
+            missed += last_sequence.map_or(0, |last| captured.sequence.saturating_sub(last + 1));
+            last_sequence = Some(captured.sequence);
+            // Stamped by the capture thread, so encode timestamps and the
+            // fMP4 sample durations follow the capture's own pacing
+            let (frame, stride, captured_at) = (captured.frame, captured.stride_bytes, captured.captured_at);
+            if encoder.chunks.receiver_count() == 0 {
+                break;
+            }
+            encoder.frames_captured.fetch_add(1, Ordering::Relaxed);
+            // The last frame stays up meanwhile; the first one encoded at the
+            // new size recreates the encoder and brings a new config and IDR
+            if resize.holds(captured_at) {
+                continue;
+            }
+            if !pipeline.admit(missed, captured_at) {
+                continue;
+            }
+
+            // Skipping captured frames keeps the reference chain intact, so a
+            // new cap takes effect without an IDR
+            let max_fps = encoder.effective_max_fps();
+            if max_fps > 0 {
+                let interval = Duration::from_secs_f64(1.0 / max_fps as f64);
+                if last_encoded.is_some_and(|at| at.elapsed() < interval) {
+                    continue;
+                }
+            }
+
+            let frame = match &mut compositor {
+                Some(compositor) => compositor.composite(frame, stride),
+                None => frame,
+            };
+            let frame = match &mut annotator {
+                Some(annotator) => annotator.apply(frame, stride),
+                None => frame,
+            };
+            *encoder.crop.frame_size.lock().unwrap() = Some((frame.width, frame.height));
+            let crop = *encoder.crop.rect.lock().unwrap();
+            let periodic = pipeline.keyframe_due(captured_at);
+            // An idle picture still gets encoded for keyframe requests, crop changes and the keep-alive
+            let idle = change_detector.is_idle(&frame);
+            let must_encode = periodic || crop != last_crop || encoder.force_idr.load(Ordering::Relaxed);
+            if idle && !must_encode && last_encoded.is_some_and(|at| at.elapsed() < IDLE_KEEPALIVE) {
+                encoder.frames_skipped.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            last_crop = crop;
+            last_encoded = Some(Instant::now());
+
+            let (frame, stride) = cropper.crop(frame, stride, crop);
+            let (frame, stride) = scaler.scale(frame, stride);
+            let force = encoder.force_idr.swap(false, Ordering::Relaxed);
+            let frame = PipelineFrame::Rgba {
+                frame,
+                stride,
+                format: recorder.pixel_format(),
+            };
+            let encoded = pipeline.encode(frame, captured_at, force);
+            sync_bitrate(&encoder, &mut pipeline);
+            *encoder.stats.lock().unwrap() = pipeline.stats();
+            *encoder.backend.lock().unwrap() = pipeline.backend();
+            let encoded = match encoded {
+                Ok(Some(encoded)) => encoded,
+                // Nothing out, or held until the decoder config is ready
+                Ok(None) => continue,
+                Err(err) => {
+                    eprintln!("shared encoder error: {err}");
+                    encoder.request_keyframe();
+                    continue;
+                }
+            };
+
+            let config = {
+                let mut latest = encoder.latest_config.lock().unwrap();
+                if let Some(current) = encoded.new_config {
+                    println!("video config: {:?}", current);
+                    *latest = Some(Arc::new(current));
+                }
+                latest.clone()
+            };
+            // The pipeline hands out a config before any chunk
+            let Some(config) = config else {
+                continue;
+            };
+
+            for chunk in encoded.chunks {
+                let sequence = encoder.next_sequence.fetch_add(1, Ordering::Relaxed);
+                let base_sequence = match chunk.temporal_id {
+                    0 => encoder.next_base_sequence.fetch_add(1, Ordering::Relaxed),
+                    _ => encoder.next_base_sequence.load(Ordering::Relaxed),
+                };
+                let chunk = SharedChunk::new(sequence, base_sequence, chunk, config.clone());
+                let _ = encoder.chunks.send(Arc::new(chunk));
+            }
+        }
+
+        // Dropping the listener (and compositor) lets the Recorders stop capturing
+        drop(compositor);
+        println!("shared encoder idle");
+    }
+}
+
+/// Apply the shared target bitrate to the pipeline, seeding the target from
+/// the pipeline's size-based default once the first frame has been encoded.
+/// Runs between frames, so the encoder is retuned in place rather than recreated.
+fn sync_bitrate(encoder: &SharedEncoder, pipeline: &mut VideoPipeline) {
+    let current = pipeline.current_bitrate();
+    if current == 0 {
+        return;
+    }
+    let mut rate = encoder.rate.lock().unwrap();
+    if rate.target_bps == 0 {
+        rate.target_bps = current.clamp(encoder.bounds.min_bps, encoder.bounds.max_bps);
+    }
+    if rate.target_bps != current {
+        if let Err(err) = pipeline.set_bitrate(rate.target_bps) {
+            eprintln!("{} encoder: {err}", encoder.name);
+        }
+    }
+    encoder.applied_bps.store(pipeline.current_bitrate(), Ordering::Relaxed);
+}
+
+/// Shared time base for packet timestamps, so they stay monotonic when a
+/// viewer moves between encoders
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+/// Build a video packet: "VID0" header followed by the AVCC payload.
+///
+/// Layout (little-endian): magic[4], sequence u64, timestamp_ms f64,
+/// flags u8, payload_len u32, payload.
+fn build_video_packet(sequence: u64, timestamp_ms: f64, is_keyframe: bool, payload: &[u8]) -> Bytes {
+    let flags = if is_keyframe { VIDEO_FLAG_KEYFRAME } else { 0 };
+
+    let mut out = Vec::with_capacity(25 + payload.len());
+    out.extend_from_slice(b"VID0");
+    out.extend_from_slice(&sequence.to_le_bytes());
+    out.extend_from_slice(&timestamp_ms.to_le_bytes());
+    out.push(flags);
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(payload);
+    Bytes::from(out)
+}
+
+/// Copy of a VID0 packet with another sequence number
+fn renumber_video_packet(packet: &Bytes, sequence: u64) -> Bytes {
+    let mut out = packet.to_vec();
+    out[4..12].copy_from_slice(&sequence.to_le_bytes());
+    Bytes::from(out)
+}
+
+/// Holds frames back after a resize until the size has settled for RESIZE_SETTLE
+#[derive(Default)]
+struct ResizeSettle {
+    resized_at: Option<Instant>,
+}
+
+impl ResizeSettle {
+    fn resized(&mut self, at: Instant) {
+        self.resized_at = Some(at);
+    }
+
+    /// Whether a frame captured at `captured_at` is still inside the settle
+    /// time of the last resize
+    fn holds(&mut self, captured_at: Instant) -> bool {
+        if self.resized_at.is_some_and(|at| captured_at.saturating_duration_since(at) < RESIZE_SETTLE) {
+            return true;
+        }
+        self.resized_at = None;
+        false
+    }
+}
+
+/// Spots runs of captured frames identical to the one before, so an idle screen isn't re-encoded
+#[derive(Default)]
+struct ChangeDetector {
+    previous: Option<Arc<Frame>>,
+    unchanged_frames: u32,
+}
+
+impl ChangeDetector {
+    /// True once the picture has been unchanged for more than IDLE_AFTER_FRAMES frames
+    fn is_idle(&mut self, frame: &Arc<Frame>) -> bool {
+        let unchanged = self
+            .previous
+            .as_ref()
+            .is_some_and(|previous| same_picture(previous, frame));
+        self.unchanged_frames = if unchanged { self.unchanged_frames.saturating_add(1) } else { 0 };
+        // Holding the Arc is free; the Recorder allocates a fresh frame per capture
+        // and the overlay frame pools don't reuse a buffer while it is held
+        self.previous = Some(frame.clone());
+        self.unchanged_frames > IDLE_AFTER_FRAMES
+    }
+}
+
+/// Cuts the crop rect out of captured frames, ahead of the Scaler
+#[derive(Default)]
+struct Cropper {
+    /// Last clamped rect we warned about, so the notice is logged once
+    warned: Option<CropRect>,
+}
+
+impl Cropper {
+    /// `frame` (rows `stride` bytes apart) cut down to `rect`, and the stride
+    /// of what comes out
+    fn crop(&mut self, frame: Arc<Frame>, stride: usize, rect: Option<CropRect>) -> (Arc<Frame>, usize) {
+        let Some(requested) = rect else {
+            return (frame, stride);
+        };
+        let Some(rect) = requested.clamp_to(frame.width, frame.height) else {
+            if self.warned != Some(requested) {
+                eprintln!(
+                    "crop {:?} is outside the {}x{} capture, streaming the full frame",
+                    requested, frame.width, frame.height
+                );
+                self.warned = Some(requested);
+            }
+            return (frame, stride);
+        };
+        if rect != requested && self.warned != Some(requested) {
+            println!(
+                "crop {:?} clamped to {:?} for the {}x{} capture",
+                requested, rect, frame.width, frame.height
+            );
+            self.warned = Some(requested);
+        }
+        if rect.x == 0 && rect.y == 0 && rect.width == frame.width && rect.height == frame.height {
+            return (frame, stride);
+        }
+        let cropped = rect.crop(&frame, stride);
+        let stride = packed_stride(&cropped);
+        (Arc::new(cropped), stride)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock_encoder::MockEncoder, test_frames, video_pipeline::PixelFormat};
+
+    /// A shared encoder running `encoder` on its own thread, fed by a
+    /// Recorder without a capture; idle until someone subscribes
+    fn start_mock(encoder: MockEncoder) -> (Arc<SharedEncoder>, Arc<Recorder>) {
+        let recorder = Arc::new(Recorder::without_capture(PixelFormat::Bgra8888));
+        let options = PipelineOptions::default();
+        let pipeline = VideoPipeline::with_mock(encoder, options).unwrap();
+        let bounds = BitrateBounds {
+            min_bps: 100_000,
+            max_bps: 10_000_000,
+        };
+        let shared = SharedEncoder::new("mock", &pipeline, bounds, options, Arc::default(), Overlays::default());
+        spawn_encoder_thread("mock", run_encoder(shared.clone(), recorder.clone(), pipeline, None)).unwrap();
+        (shared, recorder)
+    }
+
+    /// Capture `frame` once the encoder is listening for frames
+    async fn capture(recorder: &Recorder, frame: Frame) {
+        while !recorder.capture_test_frame(frame.clone()) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    /// The next chunk `viewer` gets, failing the test if none comes
+    async fn next_chunk(viewer: &mut broadcast::Receiver<Arc<SharedChunk>>) -> Arc<SharedChunk> {
+        tokio::time::timeout(Duration::from_secs(5), viewer.recv())
+            .await
+            .expect("no chunk within 5s")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn two_viewers_share_each_encode() {
+        let mock = MockEncoder::new(Duration::ZERO);
+        let encodes = mock.encodes.clone();
+        let (encoder, recorder) = start_mock(mock);
+        let mut first = encoder.subscribe();
+        let mut second = encoder.subscribe();
+
+        const FRAMES: u32 = 10;
+        for step in 0..FRAMES {
+            // Moving, so none is held back as idle; each waits for the one
+            // before it to come out, so none is replaced in the listener
+            capture(&recorder, test_frames::moving_gradient(64, 48, step)).await;
+            let (a, b) = (next_chunk(&mut first).await, next_chunk(&mut second).await);
+            assert!(Arc::ptr_eq(&a, &b), "frame {step} reached the viewers as two chunks");
+            assert_eq!(a.data[..], u64::from(step).to_le_bytes());
+        }
+        assert_eq!(encodes.load(Ordering::Relaxed), u64::from(FRAMES));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn slow_encodes_leave_the_runtime_free() {
+        const TICK: Duration = Duration::from_millis(10);
+        const FRAMES: u32 = 3;
+        // Each encode blocks for 200 ms; the runtime has a single worker
+        let mock = MockEncoder::new(Duration::from_millis(200));
+        let encodes = mock.encodes.clone();
+        let (encoder, recorder) = start_mock(mock);
+        let mut viewer = encoder.subscribe();
+        let frames = tokio::spawn(async move {
+            for step in 0..FRAMES {
+                capture(&recorder, test_frames::moving_gradient(64, 48, step)).await;
+                next_chunk(&mut viewer).await;
+            }
+        });
+
+        // Audio forwarding on the runtime keeps its cadence meanwhile
+        let mut ticker = tokio::time::interval(TICK);
+        let mut last = Instant::now();
+        let mut longest = Duration::ZERO;
+        let mut frames = std::pin::pin!(frames);
+        loop {
+            tokio::select! {
+                done = &mut frames => break done.unwrap(),
+                _ = ticker.tick() => {
+                    longest = longest.max(last.elapsed());
+                    last = Instant::now();
+                }
+            }
+        }
+        assert_eq!(encodes.load(Ordering::Relaxed), u64::from(FRAMES));
+        assert!(longest < Duration::from_millis(40), "audio stalled for {:?}", longest);
+    }
+
+    #[tokio::test]
+    async fn captures_missed_during_an_encode_count_as_dropped() {
+        let (encoder, recorder) = start_mock(MockEncoder::new(Duration::from_millis(100)));
+        let mut viewer = encoder.subscribe();
+        capture(&recorder, test_frames::moving_gradient(64, 48, 0)).await;
+        // Three more while the first encodes; only the newest is still there after
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        for step in 1..=3 {
+            assert!(recorder.capture_test_frame(test_frames::moving_gradient(64, 48, step)));
+        }
+        assert_eq!(next_chunk(&mut viewer).await.data[..], 0u64.to_le_bytes());
+        assert_eq!(next_chunk(&mut viewer).await.data[..], 1u64.to_le_bytes());
+        assert_eq!(encoder.encoder_stats().frames_dropped, 2);
+    }
+
+    /// What each rung of `ladder` runs at once it has encoded a frame
+    #[cfg(feature = "openh264-encoder")]
+    async fn rung_bitrates(ladder: &EncoderLadder, recorder: &Recorder) -> Vec<(&'static str, Option<u32>)> {
+        let mut bitrates = Vec::new();
+        for (step, (name, _, encoder)) in ladder.rungs.iter().enumerate() {
+            let mut viewer = encoder.subscribe();
+            // The rung starts listening some time after it gets a viewer
+            let chunk = loop {
+                capture(recorder, test_frames::moving_gradient(64, 48, step as u32)).await;
+                if let Ok(chunk) = tokio::time::timeout(Duration::from_millis(50), viewer.recv()).await {
+                    break chunk;
+                }
+            };
+            chunk.unwrap();
+            bitrates.push((*name, encoder.applied_bitrate()));
+        }
+        bitrates
+    }
+
+    /// Wide enough that no rung's bitrate is clamped
+    #[cfg(feature = "openh264-encoder")]
+    const ANY_BITRATE: BitrateBounds = BitrateBounds {
+        min_bps: 1,
+        max_bps: u32::MAX,
+    };
+
+    #[cfg(feature = "openh264-encoder")]
+    #[tokio::test]
+    async fn ladder_rungs_share_the_configured_bitrate() {
+        let recorder = Arc::new(Recorder::without_capture(PixelFormat::Bgra8888));
+        let options = PipelineOptions {
+            rate_control: video_pipeline::RateControl::Bitrate(3_000_000),
+            ..PipelineOptions::default()
+        };
+        let overlays = Overlays::default();
+        let ladder = EncoderLadder::start(recorder.clone(), ANY_BITRATE, options, None, &[], overlays).unwrap();
+        let bitrates = rung_bitrates(&ladder, &recorder).await;
+        let names: Vec<_> = LADDER.iter().map(|&(name, _)| name).collect();
+        assert_eq!(bitrates.iter().map(|&(name, _)| name).collect::<Vec<_>>(), names);
+        assert!(bitrates.iter().all(|&(_, bps)| bps == Some(3_000_000)), "{bitrates:?}");
+    }
+
+    #[cfg(feature = "openh264-encoder")]
+    #[tokio::test]
+    async fn simulcast_rungs_start_at_their_own_bitrates() {
+        let recorder = Arc::new(Recorder::without_capture(PixelFormat::Bgra8888));
+        let options = PipelineOptions {
+            rate_control: video_pipeline::RateControl::Bitrate(3_000_000),
+            ..PipelineOptions::default()
+        };
+        let simulcast: Vec<SimulcastRung> = ["1280x720@1500", "640x360@400", "1920x1080@4000"]
+            .iter()
+            .map(|rung| rung.parse().unwrap())
+            .collect();
+        let overlays = Overlays::default();
+        let ladder = EncoderLadder::start(recorder.clone(), ANY_BITRATE, options, None, &simulcast, overlays).unwrap();
+        assert_eq!(
+            rung_bitrates(&ladder, &recorder).await,
+            [
+                ("640x360", Some(400_000)),
+                ("1280x720", Some(1_500_000)),
+                ("1920x1080", Some(4_000_000)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn viewers_share_one_copy_of_each_chunk() {
+        let data = Bytes::from(vec![0x65; 300_000]);
+        let config = Arc::new(VideoConfig {
+            codec: VideoCodec::Avc,
+            width: 1920,
+            height: 1080,
+            description_b64: String::new(),
+            color_matrix: Default::default(),
+        });
+        let chunk = SharedChunk::new(7, 3, EncodedChunk::new(data.clone(), true), config);
+        // The muxers read the encoder's own buffer
+        assert_eq!(chunk.data.as_ptr(), data.as_ptr());
+        assert_eq!(&chunk.packet[..4], b"VID0");
+        assert_eq!(&chunk.packet[25..], &data[..]);
+
+        let (tx, _) = broadcast::channel(4);
+        let mut viewers: Vec<_> = (0..3).map(|_| tx.subscribe()).collect();
+        tx.send(Arc::new(chunk)).unwrap();
+        let mut sent = Vec::new();
+        let mut base = Vec::new();
+        for viewer in &mut viewers {
+            let chunk = viewer.recv().await.unwrap();
+            // What each session wraps in a Message::Binary
+            let packet = chunk.packet.clone();
+            sent.push((packet.as_ptr(), chunk.data.as_ptr()));
+            // ...or, capped to the base layer, this
+            base.push(chunk.base_layer_packet());
+        }
+        assert!(sent.windows(2).all(|pair| pair[0] == pair[1]), "{sent:?}");
+        assert_eq!(sent[0].1, data.as_ptr());
+        assert!(base.iter().all(|packet| packet.as_ptr() == base[0].as_ptr()));
+        assert_eq!(u64::from_le_bytes(base[0][4..12].try_into().unwrap()), 3);
+        assert_eq!(base[0][25..], data[..]);
+    }
+
+    #[test]
+    fn a_live_resize_is_encoded_once_it_settles() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut resize = ResizeSettle::default();
+        assert!(!resize.holds(at(0)));
+        // Dragging the window edge: a new size every 30 ms for 300 ms
+        let mut encoded = Vec::new();
+        for ms in (30..600).step_by(30) {
+            if ms <= 300 {
+                resize.resized(at(ms));
+            }
+            if !resize.holds(at(ms)) {
+                encoded.push(ms);
+            }
+        }
+        // Nothing until the last size has held for RESIZE_SETTLE, then every frame
+        assert_eq!(encoded.first(), Some(&570));
+        assert!(encoded.windows(2).all(|pair| pair[1] - pair[0] == 30));
+    }
+
+    #[test]
+    fn resolutions_parse_as_width_by_height() {
+        assert_eq!(
+            "3359x2099".parse::<Resolution>(),
+            Ok(Resolution {
+                width: 3359,
+                height: 2099
+            })
+        );
+        assert_eq!("1280X720".parse::<Resolution>().map(Resolution::pixels), Ok(1280 * 720));
+        assert_eq!("1280x720".parse::<Resolution>().unwrap().to_string(), "1280x720");
+        for invalid in ["1280", "1x720", "x720", "1280x-720", "wide x tall"] {
+            assert!(invalid.parse::<Resolution>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn congestion_ratchets_the_bitrate_down_to_the_floor() {
+        let bounds = BitrateBounds {
+            min_bps: 500_000,
+            max_bps: 4_000_000,
+        };
+        let mut rate = RateControl {
+            target_bps: 2_000_000,
+            last_change: None,
+        };
+        let start = Instant::now();
+        assert_eq!(
+            rate.step(BITRATE_STEP_DOWN, STEP_DOWN_INTERVAL, bounds, start),
+            Some((2_000_000, 1_400_000))
+        );
+        // Too soon after the last step
+        assert_eq!(rate.step(BITRATE_STEP_DOWN, STEP_DOWN_INTERVAL, bounds, start), None);
+        let mut at = start + STEP_DOWN_INTERVAL;
+        let mut steps = 1;
+        while let Some((from, to)) = rate.step(BITRATE_STEP_DOWN, STEP_DOWN_INTERVAL, bounds, at) {
+            assert!(to < from);
+            steps += 1;
+            at += STEP_DOWN_INTERVAL;
+        }
+        assert_eq!(rate.target_bps, bounds.min_bps);
+        assert!(steps <= 5, "took {steps} steps");
+    }
+
+    #[test]
+    fn drained_queue_creeps_back_up_to_the_ceiling() {
+        let bounds = BitrateBounds {
+            min_bps: 500_000,
+            max_bps: 1_000_000,
+        };
+        let mut rate = RateControl {
+            target_bps: 950_000,
+            last_change: None,
+        };
+        let start = Instant::now();
+        assert_eq!(
+            rate.step(BITRATE_STEP_UP, STEP_UP_INTERVAL, bounds, start),
+            Some((950_000, 1_000_000))
+        );
+        assert_eq!(rate.step(BITRATE_STEP_UP, STEP_UP_INTERVAL, bounds, start + STEP_UP_INTERVAL), None);
+        assert_eq!(rate.target_bps, bounds.max_bps);
+    }
+
+    #[test]
+    fn no_steps_before_the_first_target() {
+        let bounds = BitrateBounds {
+            min_bps: 500_000,
+            max_bps: 4_000_000,
+        };
+        let mut rate = RateControl {
+            target_bps: 0,
+            last_change: None,
+        };
+        assert_eq!(rate.step(BITRATE_STEP_DOWN, STEP_DOWN_INTERVAL, bounds, Instant::now()), None);
+    }
+
+    #[test]
+    fn video_packet_layout() {
+        let packet = build_video_packet(7, 1234.5, true, &[0xAA, 0xBB, 0xCC]);
+        assert_eq!(packet.len(), 25 + 3);
+        assert_eq!(&packet[..4], b"VID0");
+        assert_eq!(u64::from_le_bytes(packet[4..12].try_into().unwrap()), 7);
+        assert_eq!(f64::from_le_bytes(packet[12..20].try_into().unwrap()), 1234.5);
+        assert_eq!(packet[20], VIDEO_FLAG_KEYFRAME);
+        assert_eq!(u32::from_le_bytes(packet[21..25].try_into().unwrap()), 3);
+        assert_eq!(&packet[25..], [0xAA, 0xBB, 0xCC]);
+
+        let delta = build_video_packet(8, 0.0, false, &[]);
+        assert_eq!(delta[20], 0);
+        assert_eq!(delta.len(), 25);
+    }
+
+    #[test]
+    fn renumbering_only_touches_the_sequence() {
+        let packet = build_video_packet(7, 99.0, true, &[1, 2, 3, 4]);
+        let renumbered = renumber_video_packet(&packet, 3);
+        assert_eq!(u64::from_le_bytes(renumbered[4..12].try_into().unwrap()), 3);
+        assert_eq!(renumbered[..4], packet[..4]);
+        assert_eq!(renumbered[12..], packet[12..]);
+    }
+}
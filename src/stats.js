@@ -2,6 +2,7 @@ export function createStatsTracker({
   windowMs = 1000,
   statsBwEl,
   statsFpsEl,
+  statsServerEl,
 } = {}) {
   const chunkSamples = [];
   const frameSamples = [];
@@ -26,6 +27,29 @@ export function createStatsTracker({
     chunkSamples.length = 0;
     frameSamples.length = 0;
     updateStats();
+    if (statsServerEl) {
+      statsServerEl.textContent = "";
+    }
+  }
+
+  // Once-a-second "server-stats" message: where the server spends its time
+  function recordServerStats(msg) {
+    if (!statsServerEl) return;
+    if (msg.fallback) {
+      // No H.264 encoder on the server: JPEG stills
+      statsServerEl.textContent = `Server: ${msg.fallback} fallback (${msg.fallback_reason}), ${msg.fps} fps`;
+      return;
+    }
+    const seconds = Math.max(msg.interval_ms, 1) / 1000;
+    const capFps = (msg.frames_captured / seconds).toFixed(0);
+    const sentFps = (msg.frames_sent / seconds).toFixed(0);
+    const enc = msg.encode_ms
+      ? `enc ${msg.encode_ms.avg.toFixed(1)}ms (max ${msg.encode_ms.max.toFixed(1)})`
+      : "enc --";
+    const drops = msg.frames_dropped ? ` drop ${msg.frames_dropped}` : "";
+    const idle = msg.frames_skipped ? ` idle ${msg.frames_skipped}` : "";
+    const rate = msg.bitrate_kbps ? `, ${(msg.bitrate_kbps / 1000).toFixed(1)} Mbps target` : "";
+    statsServerEl.textContent = `Server: cap ${capFps} / sent ${sentFps} fps, ${enc}${drops}${idle}${rate}`;
   }
 
   function showDisconnected(delayMs) {
@@ -81,5 +105,6 @@ export function createStatsTracker({
     recordFrameSample,
     reset,
     showDisconnected,
+    recordServerStats,
   };
 }
@@ -0,0 +1,213 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::watch;
+
+use crate::auth::Role;
+
+/// Live sessions, for `GET /status` and the `viewers` message
+pub struct SessionRegistry {
+    next_id: AtomicU64,
+    sessions: Mutex<HashMap<u64, Arc<SessionInfo>>>,
+    /// Number of sessions, updated on every register and drop
+    viewers: watch::Sender<usize>,
+    /// Sessions silent for this long are closed (--idle-timeout); None never closes them
+    idle_timeout: Option<Duration>,
+    /// Accepted sessions, video and audio-only alike
+    admitted: AtomicUsize,
+    /// --max-sessions; None admits everyone
+    max_sessions: Option<usize>,
+}
+
+/// A place among the --max-sessions; released when dropped
+pub struct SessionSlot {
+    registry: Arc<SessionRegistry>,
+}
+
+/// What /status reports about one connected viewer
+pub struct SessionInfo {
+    codec: &'static str,
+    role: Role,
+    /// Encoder rung the viewer is on
+    resolution: Mutex<&'static str>,
+    started: Instant,
+    frames_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    /// Last inbound message of any kind, pongs included
+    last_activity: Mutex<Instant>,
+}
+
+/// Registration of one session; removed from the registry when dropped,
+/// including when the session task is aborted
+pub struct SessionHandle {
+    id: u64,
+    info: Arc<SessionInfo>,
+    registry: Arc<SessionRegistry>,
+}
+
+impl SessionRegistry {
+    pub fn new(idle_timeout: Option<Duration>, max_sessions: Option<usize>) -> Self {
+        Self {
+            next_id: AtomicU64::new(0),
+            sessions: Mutex::new(HashMap::new()),
+            viewers: watch::channel(0).0,
+            idle_timeout,
+            admitted: AtomicUsize::new(0),
+            max_sessions,
+        }
+    }
+
+    /// Take a slot for a newly negotiated session; None when the server is full.
+    /// Viewers share encoders, so what's limited is sessions (bandwidth), not encoders.
+    pub fn admit(self: &Arc<Self>) -> Option<SessionSlot> {
+        let max = self.max_sessions.unwrap_or(usize::MAX);
+        self.admitted
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |admitted| (admitted < max).then_some(admitted + 1))
+            .ok()?;
+        Some(SessionSlot { registry: self.clone() })
+    }
+
+    /// Follow the viewer count; only sessions that got past negotiation count
+    pub fn subscribe_viewers(&self) -> watch::Receiver<usize> {
+        self.viewers.subscribe()
+    }
+
+    pub fn register(self: &Arc<Self>, codec: &'static str, resolution: &'static str, role: Role) -> SessionHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let info = Arc::new(SessionInfo {
+            codec,
+            role,
+            resolution: Mutex::new(resolution),
+            started: Instant::now(),
+            frames_sent: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            last_activity: Mutex::new(Instant::now()),
+        });
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.insert(id, info.clone());
+            self.viewers.send_replace(sessions.len());
+        }
+        SessionHandle {
+            id,
+            info,
+            registry: self.clone(),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let sessions = self.sessions.lock().unwrap();
+        let mut list: Vec<_> = sessions
+            .iter()
+            .map(|(id, info)| {
+                serde_json::json!({
+                    "id": id,
+                    "codec": info.codec,
+                    "role": info.role.as_str(),
+                    "resolution": *info.resolution.lock().unwrap(),
+                    "frames_sent": info.frames_sent.load(Ordering::Relaxed),
+                    "bytes_sent": info.bytes_sent.load(Ordering::Relaxed),
+                    "uptime_secs": info.started.elapsed().as_secs(),
+                    "idle_timeout_remaining_secs": self.idle_remaining(info).map(|left| left.as_secs()),
+                })
+            })
+            .collect();
+        list.sort_by_key(|s| s["id"].as_u64());
+        serde_json::json!({
+            "count": list.len(),
+            "admitted": self.admitted.load(Ordering::Relaxed),
+            "max": self.max_sessions,
+            "list": list,
+        })
+    }
+
+    /// Time until `info` is closed for inactivity; None when there is no timeout
+    fn idle_remaining(&self, info: &SessionInfo) -> Option<Duration> {
+        let timeout = self.idle_timeout?;
+        Some(timeout.saturating_sub(info.last_activity.lock().unwrap().elapsed()))
+    }
+}
+
+impl SessionHandle {
+    pub fn record_frame(&self, bytes: usize) {
+        self.info.frames_sent.fetch_add(1, Ordering::Relaxed);
+        self.info.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_resolution(&self, resolution: &'static str) {
+        *self.info.resolution.lock().unwrap() = resolution;
+    }
+
+    /// The client sent something; pushes back the idle timeout
+    pub fn touch(&self) {
+        *self.info.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    /// True once nothing has been heard from the client for --idle-timeout
+    pub fn idle_expired(&self) -> bool {
+        self.registry.idle_remaining(&self.info).is_some_and(|left| left.is_zero())
+    }
+}
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        let mut sessions = self.registry.sessions.lock().unwrap();
+        sessions.remove(&self.id);
+        self.registry.viewers.send_replace(sessions.len());
+    }
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        self.registry.admitted.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_are_counted_while_registered() {
+        let registry = Arc::new(SessionRegistry::new(None, None));
+        let viewers = registry.subscribe_viewers();
+        assert_eq!(registry.to_json()["count"], 0);
+
+        let first = registry.register("h264", "1080p", Role::Control);
+        let second = registry.register("av1", "720p", Role::View);
+        assert_eq!(*viewers.borrow(), 2);
+        let status = registry.to_json();
+        assert_eq!(status["count"], 2);
+        assert_eq!(status["list"][0]["codec"], "h264");
+        assert_eq!(status["list"][1]["resolution"], "720p");
+        assert_eq!(status["list"][1]["role"], "view");
+
+        drop(first);
+        assert_eq!(*viewers.borrow(), 1);
+        let status = registry.to_json();
+        assert_eq!(status["count"], 1);
+        assert_eq!(status["list"][0]["codec"], "av1");
+        drop(second);
+        assert_eq!(registry.to_json()["count"], 0);
+    }
+
+    #[test]
+    fn frames_and_bytes_are_reported_per_session() {
+        let registry = Arc::new(SessionRegistry::new(None, None));
+        let session = registry.register("h264", "1080p", Role::View);
+        session.record_frame(1200);
+        session.record_frame(300);
+        session.set_resolution("540p");
+        let entry = &registry.to_json()["list"][0];
+        assert_eq!(entry["frames_sent"], 2);
+        assert_eq!(entry["bytes_sent"], 1500);
+        assert_eq!(entry["resolution"], "540p");
+        assert!(entry["idle_timeout_remaining_secs"].is_null());
+    }
+}
@@ -0,0 +1,67 @@
+//! Deterministic synthetic captures for tests and benchmarks, so the encode
+//! path can be exercised without a display or capture permissions
+
+use xcap::Frame;
+
+/// The eight 75% colour bars (white, yellow, cyan, green, magenta, red,
+/// blue, black) across a `width` x `height` BGRA frame
+pub fn color_bars(width: u32, height: u32) -> Frame {
+    const BARS: [[u8; 3]; 8] = [
+        [191, 191, 191],
+        [191, 191, 0],
+        [0, 191, 191],
+        [0, 191, 0],
+        [191, 0, 191],
+        [191, 0, 0],
+        [0, 0, 191],
+        [0, 0, 0],
+    ];
+    from_fn(width, height, |x, _| BARS[(x * 8 / width.max(1)) as usize])
+}
+
+/// A diagonal gradient shifted `step` pixels along, for motion between frames
+pub fn moving_gradient(width: u32, height: u32, step: u32) -> Frame {
+    from_fn(width, height, |x, y| {
+        let along = (x + y + step * 4) as u8;
+        [along, along.wrapping_mul(3) / 2, 255 - along]
+    })
+}
+
+/// Black glyph-sized strokes on white, like a terminal or an editor: the
+/// high-frequency detail screen content is mostly made of. `seed` changes
+/// the "text".
+pub fn text_like(width: u32, height: u32, seed: u32) -> Frame {
+    let mut state = seed.wrapping_mul(2_654_435_761) | 1;
+    let glyphs: Vec<u32> = (0..(width / 8 + 1) * (height / 16 + 1))
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        })
+        .collect();
+    let columns = width / 8 + 1;
+    from_fn(width, height, |x, y| {
+        let glyph = glyphs[((y / 16) * columns + x / 8) as usize];
+        // A 6x12 cell in each 8x16 block, one bit per 2x3 pixels
+        let (cx, cy) = (x % 8, y % 16);
+        let ink = cx < 6 && (2..14).contains(&cy) && glyph >> ((cy - 2) / 3 * 3 + cx / 2) & 1 == 1;
+        if ink {
+            [20, 20, 20]
+        } else {
+            [250, 250, 250]
+        }
+    })
+}
+
+/// An opaque `width` x `height` BGRA frame whose pixel (x, y) has the RGB `rgb(x, y)`
+fn from_fn(width: u32, height: u32, rgb: impl Fn(u32, u32) -> [u8; 3]) -> Frame {
+    let mut raw = Vec::with_capacity((width * height * 4) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let [r, g, b] = rgb(x, y);
+            raw.extend_from_slice(&[b, g, r, 255]);
+        }
+    }
+    Frame { width, height, raw }
+}
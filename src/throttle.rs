@@ -0,0 +1,319 @@
+use std::time::{Duration, Instant};
+
+/// Requests a client may make back to back before the rate limit applies
+const KEYFRAME_BURST: f64 = 1.0;
+
+/// Bytes a capped socket may send back to back, in seconds at its cap
+const PACER_BURST_SECS: f64 = 0.25;
+
+/// Classic token bucket: `capacity` tokens, refilled at one per `interval`
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    /// Tokens added per second
+    refill: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full
+    pub fn new(capacity: f64, interval: Duration) -> Self {
+        Self {
+            capacity,
+            tokens: capacity,
+            refill: 1.0 / interval.as_secs_f64().max(f64::EPSILON),
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Take a token if one is available
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take `amount` tokens even if that leaves the bucket in debt; returns
+    /// when the debt is paid off (`now` if there was none)
+    pub fn debit(&mut self, amount: f64, now: Instant) -> Instant {
+        self.refill(now);
+        self.tokens -= amount;
+        now + Duration::from_secs_f64((-self.tokens).max(0.0) / self.refill)
+    }
+
+    /// When the next token will be available (`now` if one already is)
+    pub fn next_token_at(&mut self, now: Instant) -> Instant {
+        self.refill(now);
+        let missing = (1.0 - self.tokens).max(0.0);
+        now + Duration::from_secs_f64(missing / self.refill)
+    }
+}
+
+/// Outcome of asking for a client-triggered IDR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyframeDecision {
+    /// Request it from the encoder now
+    Allowed,
+    /// Folded into the pending IDR; `newly` is set for the request that
+    /// started throttling, so the client is told once rather than per request
+    Throttled { newly: bool },
+}
+
+/// Per-session limit on IDRs a client can cause (--keyframe-request-interval).
+/// Covers explicit force-keyframe requests, the ones implied by resume,
+/// resolution, quality and crop changes, and recovery from backpressure;
+/// excess requests collapse into a single pending IDR that is issued when
+/// the bucket refills.
+pub struct KeyframeThrottle {
+    /// None when unlimited
+    bucket: Option<TokenBucket>,
+    pending: bool,
+}
+
+impl KeyframeThrottle {
+    pub fn new(interval: Option<Duration>) -> Self {
+        Self {
+            bucket: interval.map(|interval| TokenBucket::new(KEYFRAME_BURST, interval)),
+            pending: false,
+        }
+    }
+
+    pub fn request(&mut self) -> KeyframeDecision {
+        self.request_at(Instant::now())
+    }
+
+    fn request_at(&mut self, now: Instant) -> KeyframeDecision {
+        let Some(bucket) = &mut self.bucket else {
+            return KeyframeDecision::Allowed;
+        };
+        // While one is pending, later requests wait behind it rather than jump ahead
+        if !self.pending && bucket.try_take(now) {
+            return KeyframeDecision::Allowed;
+        }
+        let newly = !self.pending;
+        self.pending = true;
+        KeyframeDecision::Throttled { newly }
+    }
+
+    /// When the pending IDR may be issued; None if nothing is pending
+    pub fn pending_until(&mut self) -> Option<Instant> {
+        self.pending_until_at(Instant::now())
+    }
+
+    fn pending_until_at(&mut self, now: Instant) -> Option<Instant> {
+        match (&mut self.bucket, self.pending) {
+            (Some(bucket), true) => Some(bucket.next_token_at(now)),
+            _ => None,
+        }
+    }
+
+    /// The pending deadline passed: true if the IDR should be requested now
+    pub fn fire(&mut self) -> bool {
+        self.fire_at(Instant::now())
+    }
+
+    fn fire_at(&mut self, now: Instant) -> bool {
+        let Some(bucket) = &mut self.bucket else {
+            return false;
+        };
+        if self.pending && bucket.try_take(now) {
+            self.pending = false;
+            return true;
+        }
+        false
+    }
+}
+
+/// Outgoing bandwidth cap for one socket (`max_kbps` in the mode message).
+/// A frame is always sent whole; the pacer then holds back the next one until
+/// the bucket has paid for it, so the average stays under the cap.
+pub struct Pacer {
+    max_kbps: u32,
+    /// One token per byte
+    bucket: TokenBucket,
+    /// Bytes sent and time spent held back since the last `take_window`
+    window_bytes: u64,
+    window_delay: Duration,
+}
+
+impl Pacer {
+    pub fn new(max_kbps: u32) -> Self {
+        let bytes_per_sec = (max_kbps.max(1) as f64 * 1000.0 / 8.0).max(1.0);
+        Self {
+            max_kbps,
+            bucket: TokenBucket::new(bytes_per_sec * PACER_BURST_SECS, Duration::from_secs_f64(1.0 / bytes_per_sec)),
+            window_bytes: 0,
+            window_delay: Duration::ZERO,
+        }
+    }
+
+    pub fn max_kbps(&self) -> u32 {
+        self.max_kbps
+    }
+
+    /// Account for `bytes` just sent; returns when the next media may go out
+    pub fn sent(&mut self, bytes: usize, now: Instant) -> Instant {
+        self.window_bytes += bytes as u64;
+        let ready = self.bucket.debit(bytes as f64, now);
+        self.window_delay += ready - now;
+        ready
+    }
+
+    /// Bytes sent and time held back since the previous call
+    pub fn take_window(&mut self) -> (u64, Duration) {
+        let window = (self.window_bytes, self.window_delay);
+        self.window_bytes = 0;
+        self.window_delay = Duration::ZERO;
+        window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A throttle allowing one IDR per `interval`, its bucket full at `start`
+    fn throttle(interval: Duration, start: Instant) -> KeyframeThrottle {
+        let mut throttle = KeyframeThrottle::new(Some(interval));
+        if let Some(bucket) = &mut throttle.bucket {
+            bucket.last_refill = start;
+        }
+        throttle
+    }
+
+    #[test]
+    fn bucket_runs_dry_after_its_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(3.0, Duration::from_secs(1));
+        bucket.last_refill = start;
+        assert!((0..3).all(|_| bucket.try_take(start)));
+        assert!(!bucket.try_take(start));
+        assert!(!bucket.try_take(start + Duration::from_millis(999)));
+    }
+
+    #[test]
+    fn bucket_refills_at_its_rate_up_to_capacity() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, Duration::from_secs(2));
+        bucket.last_refill = start;
+        assert!(bucket.try_take(start) && bucket.try_take(start));
+        assert_eq!(bucket.next_token_at(start), start + Duration::from_secs(2));
+        assert!(bucket.try_take(start + Duration::from_secs(2)));
+        assert!(!bucket.try_take(start + Duration::from_secs(3)));
+
+        // A long idle spell refills no more than the capacity
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.try_take(later) && bucket.try_take(later));
+        assert!(!bucket.try_take(later));
+    }
+
+    #[test]
+    fn debit_reports_when_the_debt_is_paid() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100.0, Duration::from_millis(10));
+        bucket.last_refill = start;
+        assert_eq!(bucket.debit(50.0, start), start);
+        assert_eq!(bucket.debit(150.0, start), start + Duration::from_secs(1));
+    }
+
+    /// A pacer capped at `max_kbps`, its bucket full at `start`
+    fn pacer(max_kbps: u32, start: Instant) -> Pacer {
+        let mut pacer = Pacer::new(max_kbps);
+        pacer.bucket.last_refill = start;
+        pacer
+    }
+
+    fn close_to(a: Instant, b: Instant) -> bool {
+        a.max(b) - a.min(b) < Duration::from_micros(10)
+    }
+
+    #[test]
+    fn pacer_holds_media_until_the_cap_has_paid_for_it() {
+        // 8 kbps: 1000 bytes a second, 250 of them back to back
+        let start = Instant::now();
+        let mut pacer = pacer(8, start);
+        assert!(close_to(pacer.sent(250, start), start));
+        assert!(close_to(pacer.sent(500, start), start + Duration::from_millis(500)));
+        // Sending early only deepens the debt
+        let ready = pacer.sent(100, start + Duration::from_millis(100));
+        assert!(close_to(ready, start + Duration::from_millis(600)));
+
+        let (bytes, delayed) = pacer.take_window();
+        assert_eq!(bytes, 850);
+        assert!(delayed > Duration::from_millis(999) && delayed < Duration::from_millis(1001));
+        assert_eq!(pacer.take_window(), (0, Duration::ZERO));
+    }
+
+    #[test]
+    fn paced_sends_average_out_at_the_cap() {
+        // 4 Mbps, with 20 KB frames sent whenever the pacer allows
+        let start = Instant::now();
+        let mut pacer = pacer(4000, start);
+        let mut now = start;
+        let mut bytes = 0;
+        while now < start + Duration::from_secs(10) {
+            now = pacer.sent(20_000, now).max(now);
+            bytes += 20_000;
+        }
+        let kbps = bytes as f64 * 8.0 / 1000.0 / (now - start).as_secs_f64();
+        // Only the initial burst goes over
+        let burst_kbps = 4000.0 * PACER_BURST_SECS / 10.0;
+        assert!(kbps <= 4000.0 + burst_kbps + 20.0, "{} kbps", kbps);
+        assert!(kbps >= 4000.0, "{} kbps", kbps);
+    }
+
+    #[test]
+    fn keyframe_requests_are_coalesced_into_one_pending_idr() {
+        let interval = Duration::from_secs(2);
+        let start = Instant::now();
+        let mut throttle = throttle(interval, start);
+        assert_eq!(throttle.request_at(start), KeyframeDecision::Allowed);
+        assert_eq!(throttle.pending_until_at(start), None);
+
+        // A burst of requests: the client is told once, and one IDR is pending
+        assert_eq!(throttle.request_at(start), KeyframeDecision::Throttled { newly: true });
+        for _ in 0..10 {
+            assert_eq!(throttle.request_at(start), KeyframeDecision::Throttled { newly: false });
+        }
+        assert_eq!(throttle.pending_until_at(start), Some(start + interval));
+        assert!(!throttle.fire_at(start + interval / 2));
+
+        assert!(throttle.fire_at(start + interval));
+        assert_eq!(throttle.pending_until_at(start + interval), None);
+        assert!(!throttle.fire_at(start + interval * 2));
+    }
+
+    #[test]
+    fn pending_idr_goes_ahead_of_later_requests() {
+        let interval = Duration::from_secs(2);
+        let start = Instant::now();
+        let mut throttle = throttle(interval, start);
+        assert_eq!(throttle.request_at(start), KeyframeDecision::Allowed);
+        assert_eq!(throttle.request_at(start), KeyframeDecision::Throttled { newly: true });
+        // The bucket has refilled, but the token belongs to the pending IDR
+        let refilled = start + interval;
+        assert_eq!(throttle.request_at(refilled), KeyframeDecision::Throttled { newly: false });
+        assert!(throttle.fire_at(refilled));
+        assert_eq!(throttle.request_at(refilled + interval), KeyframeDecision::Allowed);
+    }
+
+    #[test]
+    fn no_interval_never_throttles() {
+        let mut throttle = KeyframeThrottle::new(None);
+        let now = Instant::now();
+        assert!((0..100).all(|_| throttle.request_at(now) == KeyframeDecision::Allowed));
+        assert_eq!(throttle.pending_until_at(now), None);
+        assert!(!throttle.fire_at(now));
+    }
+}
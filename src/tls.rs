@@ -0,0 +1,36 @@
+use std::{net::IpAddr, path::Path};
+
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+
+/// Load a PEM certificate chain and private key for https/wss
+pub async fn from_pem_files(cert: &Path, key: &Path) -> anyhow::Result<RustlsConfig> {
+    let cert_pem = tokio::fs::read(cert)
+        .await
+        .with_context(|| format!("can't read TLS certificate {}", cert.display()))?;
+    let key_pem = tokio::fs::read(key)
+        .await
+        .with_context(|| format!("can't read TLS key {}", key.display()))?;
+    RustlsConfig::from_pem(cert_pem, key_pem).await.with_context(|| {
+        format!(
+            "{} and {} are not a valid certificate/key pair",
+            cert.display(),
+            key.display()
+        )
+    })
+}
+
+/// Generate a throwaway certificate for localhost and `bind` (--tls-self-signed).
+///
+/// Browsers will warn about it; it exists for quick LAN testing only.
+pub async fn self_signed(bind: IpAddr) -> anyhow::Result<RustlsConfig> {
+    let mut names = vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()];
+    if !bind.is_unspecified() && !bind.is_loopback() {
+        names.push(bind.to_string());
+    }
+    let generated = rcgen::generate_simple_self_signed(names)
+        .context("failed to generate a self-signed certificate")?;
+    let cert_pem = generated.cert.pem();
+    let key_pem = generated.key_pair.serialize_pem();
+    Ok(RustlsConfig::from_pem(cert_pem.into_bytes(), key_pem.into_bytes()).await?)
+}
@@ -103,6 +103,15 @@ export function createVideoController({
     }
   }
 
+  // JPEG fallback ("codec":"mjpeg"): IMG0 stills painted like decoded frames
+  function paintImage(data) {
+    const idLength = new Uint8Array(data, 4, 1)[0];
+    const jpeg = new Blob([data.slice(5 + idLength)], { type: "image/jpeg" });
+    createImageBitmap(jpeg)
+      .then((bitmap) => handleVideoFrame(bitmap, bitmap.width, bitmap.height))
+      .catch((err) => log(`jpeg decode failed: ${err}`));
+  }
+
   function enqueueChunk(chunk) {
     videoWorker.postMessage({ type: "chunk", chunk }, [chunk]);
   }
@@ -121,6 +130,7 @@ export function createVideoController({
   return {
     enqueueChunk,
     configureDecoder,
+    paintImage,
     dispose,
   };
 }
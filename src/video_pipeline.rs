@@ -1,16 +1,373 @@
-use std::sync::Arc;
+use std::{
+    fmt,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Result};
 use base64::Engine;
 use base64::engine::general_purpose::STANDARD as B64;
+use bytes::Bytes;
+#[cfg(feature = "openh264-encoder")]
+use bytes::{BufMut, BytesMut};
+#[cfg(feature = "openh264-encoder")]
 use openh264::encoder::EncodedBitStream;
-use openh264_sys2::SFrameBSInfo;
-use xcap::Frame;
+#[cfg(feature = "openh264-encoder")]
+use openh264_sys2::{
+    SBitrateInfo, SEncParamExt, SFrameBSInfo, ENCODER_OPTION_BITRATE, ENCODER_OPTION_SVC_ENCODE_PARAM_EXT,
+    SM_FIXEDSLCNUM_SLICE, SM_SINGLE_SLICE, SPATIAL_LAYER_ALL,
+};
+use serde::{Deserialize, Serialize};
+use xcap::{
+    image::{ImageFormat, RgbaImage},
+    Frame,
+};
+
+#[cfg(feature = "openh264-encoder")]
+use crate::yuv::{Planes, I420};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum VideoCodec {
     Avc,
     Hevc,
+    /// Only with `--features av1`, and only for viewers that ask for it
+    Av1,
+}
+
+/// Byte order of the captured pixels. Capture backends differ (xcap's macOS
+/// monitor recorder hands over BGRA), so the encoders read the channels
+/// accordingly; --pixel-format overrides the per-backend default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PixelFormat {
+    #[default]
+    #[serde(rename = "rgba")]
+    Rgba8888,
+    #[serde(rename = "bgra")]
+    Bgra8888,
+}
+
+impl PixelFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PixelFormat::Rgba8888 => "rgba",
+            PixelFormat::Bgra8888 => "bgra",
+        }
+    }
+
+    /// Byte offsets of red, green and blue within a pixel
+    pub fn rgb_offsets(self) -> (usize, usize, usize) {
+        match self {
+            PixelFormat::Rgba8888 => (0, 1, 2),
+            PixelFormat::Bgra8888 => (2, 1, 0),
+        }
+    }
+}
+
+impl FromStr for PixelFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rgba" => Ok(PixelFormat::Rgba8888),
+            "bgra" => Ok(PixelFormat::Bgra8888),
+            other => Err(format!("unknown pixel format `{}` (expected rgba or bgra)", other)),
+        }
+    }
+}
+
+/// RGB to YUV matrix and range for the conversions we do ourselves
+/// (openh264 and rav1e), signalled in the bitstream so decoders don't guess
+/// (--color-matrix)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorMatrix {
+    /// What browsers assume for HD video
+    #[default]
+    Bt709,
+    Bt601,
+    Bt709Full,
+    Bt601Full,
+}
+
+impl ColorMatrix {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ColorMatrix::Bt709 => "bt709",
+            ColorMatrix::Bt601 => "bt601",
+            ColorMatrix::Bt709Full => "bt709-full",
+            ColorMatrix::Bt601Full => "bt601-full",
+        }
+    }
+
+    /// Luma weights of red and blue (Kr, Kb)
+    pub fn weights(self) -> (f32, f32) {
+        match self {
+            ColorMatrix::Bt709 | ColorMatrix::Bt709Full => (0.2126, 0.0722),
+            ColorMatrix::Bt601 | ColorMatrix::Bt601Full => (0.299, 0.114),
+        }
+    }
+
+    /// 0-255 rather than 16-235 luma and 16-240 chroma
+    pub fn full_range(self) -> bool {
+        matches!(self, ColorMatrix::Bt709Full | ColorMatrix::Bt601Full)
+    }
+
+    /// H.264/AV1 colour_primaries, transfer_characteristics and
+    /// matrix_coefficients: BT.709 (1) or SMPTE 170M (6)
+    pub fn description_code(self) -> u8 {
+        match self {
+            ColorMatrix::Bt709 | ColorMatrix::Bt709Full => 1,
+            ColorMatrix::Bt601 | ColorMatrix::Bt601Full => 6,
+        }
+    }
+}
+
+impl FromStr for ColorMatrix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bt709" => Ok(ColorMatrix::Bt709),
+            "bt601" => Ok(ColorMatrix::Bt601),
+            "bt709-full" => Ok(ColorMatrix::Bt709Full),
+            "bt601-full" => Ok(ColorMatrix::Bt601Full),
+            other => Err(format!(
+                "unknown color matrix `{}` (expected bt709, bt601, bt709-full or bt601-full)",
+                other
+            )),
+        }
+    }
+}
+
+/// QP `quality` aims for when none is given
+const DEFAULT_QUALITY_QP: u8 = 24;
+/// QP `off` holds when none is given
+const DEFAULT_FIXED_QP: u8 = 26;
+/// How far quality mode may stray from its QP either way
+const QUALITY_QP_SPREAD: u8 = 4;
+/// H.264's largest QP
+const MAX_QP: u8 = 51;
+
+/// How the encoder spends its bits (--rate-control): `bitrate`, `quality[:QP]`
+/// or `off[:QP]`. Lower QP is better quality.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum RateControl {
+    /// Hold this bitrate in bit/s; 0 derives one from the frame size
+    Bitrate(u32),
+    /// Hold picture quality around this QP; the bitrate follows the content
+    Quality(u8),
+    /// No rate control; every frame at a fixed QP
+    Off { qp: u8 },
+}
+
+impl Default for RateControl {
+    fn default() -> Self {
+        RateControl::Bitrate(0)
+    }
+}
+
+impl RateControl {
+    /// Starting bitrate, when one was given
+    pub fn target_bitrate(self) -> Option<u32> {
+        match self {
+            RateControl::Bitrate(bps) if bps > 0 => Some(bps),
+            _ => None,
+        }
+    }
+
+    /// Smallest and largest QP the encoder may use; None leaves it to rate control
+    pub fn qp_bounds(self) -> Option<(u8, u8)> {
+        match self {
+            RateControl::Bitrate(_) => None,
+            RateControl::Quality(qp) => {
+                Some((qp.saturating_sub(QUALITY_QP_SPREAD), (qp + QUALITY_QP_SPREAD).min(MAX_QP)))
+            }
+            RateControl::Off { qp } => Some((qp, qp)),
+        }
+    }
+
+    /// VideoToolbox's Quality property (0.0-1.0) for the QP modes
+    pub fn quality_fraction(self) -> Option<f32> {
+        match self {
+            RateControl::Bitrate(_) => None,
+            RateControl::Quality(qp) | RateControl::Off { qp } => Some(1.0 - qp as f32 / MAX_QP as f32),
+        }
+    }
+}
+
+impl FromStr for RateControl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mode, value) = match s.split_once(':') {
+            Some((mode, value)) => (mode, Some(value)),
+            None => (s, None),
+        };
+        let qp = |default: u8| match value {
+            None => Ok(default),
+            Some(value) => value
+                .parse::<u8>()
+                .ok()
+                .filter(|qp| *qp <= MAX_QP)
+                .ok_or_else(|| format!("QP in `{}` must be 0-{}", s, MAX_QP)),
+        };
+        match mode {
+            "bitrate" => match value {
+                None => Ok(RateControl::Bitrate(0)),
+                Some(kbps) => kbps
+                    .parse::<u32>()
+                    .map(|kbps| RateControl::Bitrate(kbps.saturating_mul(1000)))
+                    .map_err(|_| format!("bitrate in `{}` must be kbit/s", s)),
+            },
+            "quality" => Ok(RateControl::Quality(qp(DEFAULT_QUALITY_QP)?)),
+            "off" => Ok(RateControl::Off { qp: qp(DEFAULT_FIXED_QP)? }),
+            other => Err(format!("unknown rate control `{}` (expected bitrate, quality or off)", other)),
+        }
+    }
+}
+
+impl TryFrom<String> for RateControl {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<RateControl> for String {
+    fn from(rate_control: RateControl) -> Self {
+        rate_control.to_string()
+    }
+}
+
+impl fmt::Display for RateControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateControl::Bitrate(0) => write!(f, "bitrate"),
+            RateControl::Bitrate(bps) => write!(f, "bitrate:{}", bps / 1000),
+            RateControl::Quality(qp) => write!(f, "quality:{}", qp),
+            RateControl::Off { qp } => write!(f, "off:{}", qp),
+        }
+    }
+}
+
+/// What openh264 tunes its encoding tools for (--encoder-usage, or --tune)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UsageType {
+    /// Video and motion: openh264's camera defaults
+    #[default]
+    #[serde(alias = "motion")]
+    Camera,
+    /// Text and flat areas: screen-content tools, no denoising, and a QP
+    /// range that keeps text sharp; cheaper on mostly static desktops
+    Screen,
+}
+
+impl FromStr for UsageType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "camera" | "motion" => Ok(UsageType::Camera),
+            "screen" => Ok(UsageType::Screen),
+            other => Err(format!("unknown encoder usage `{}` (expected camera, motion or screen)", other)),
+        }
+    }
+}
+
+/// QP range for screen content in bitrate mode: the floor stops bits going
+/// to pixel-perfect static areas, the ceiling keeps text legible when the
+/// rate drops
+#[cfg(feature = "openh264-encoder")]
+const SCREEN_QP_RANGE: (u8, u8) = (12, 38);
+
+/// Frame size from which `PipelineOptions::threads` defaults to several
+#[cfg(feature = "openh264-encoder")]
+const THREADED_MIN_PIXELS: u32 = 1920 * 1080;
+/// Most threads picked by default
+#[cfg(feature = "openh264-encoder")]
+const DEFAULT_MAX_THREADS: u16 = 4;
+
+/// How H.264 NAL units are framed in `EncodedChunk::data`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    /// 4-byte length prefixes, with SPS/PPS in `VideoConfig::description_b64`;
+    /// what WebCodecs and MSE viewers decode
+    #[default]
+    Avcc,
+    /// 00 00 00 01 start codes with SPS/PPS in-band before every keyframe,
+    /// for piping into ffmpeg or GStreamer
+    AnnexB,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "avcc" => Ok(OutputFormat::Avcc),
+            "annex-b" => Ok(OutputFormat::AnnexB),
+            other => Err(format!("unknown output format `{}` (expected avcc or annex-b)", other)),
+        }
+    }
+}
+
+/// Encoder settings that outlive any one frame size: they are applied again
+/// whenever the encoder is recreated for new dimensions
+#[derive(Debug, Clone, Copy)]
+pub struct PipelineOptions {
+    /// Bitrate mode's starting bitrate (0 derives one from the frame size:
+    /// w*h*8, 0.5-15 Mbps), or a QP for quality and fixed-QP encoding.
+    /// openh264 and VideoToolbox; rav1e always targets a bitrate.
+    pub rate_control: RateControl,
+    /// Frame rate the encoder budgets bits for
+    pub max_fps: f32,
+    /// Periodic IDR from the encoder itself; None leaves keyframes to requests
+    pub keyframe_interval: Option<Duration>,
+    /// openh264 only
+    pub usage: UsageType,
+    /// openh264 encoding threads, one slice each; None picks one below
+    /// 1080p (slices cost a little compression) and up to 4 from there
+    pub threads: Option<u16>,
+    /// openh264 and rav1e; VideoToolbox converts in hardware, always BT.709
+    pub color_matrix: ColorMatrix,
+    /// Frames lost in a row before the input rate is halved (0 = never)
+    pub drop_streak: u32,
+    /// H.264 framing. Annex-B leaves `VideoConfig::description_b64` empty
+    /// and always encodes with openh264; the viewers' path needs AVCC.
+    pub output_format: OutputFormat,
+}
+
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        Self {
+            rate_control: RateControl::default(),
+            max_fps: 60.0,
+            keyframe_interval: None,
+            usage: UsageType::Camera,
+            threads: None,
+            color_matrix: ColorMatrix::default(),
+            drop_streak: DEFAULT_DROP_STREAK,
+            output_format: OutputFormat::default(),
+        }
+    }
+}
+
+impl PipelineOptions {
+    /// `keyframe_interval` in frames at `max_fps` (0 = only on request)
+    fn keyframe_interval_frames(&self) -> u32 {
+        self.keyframe_interval
+            .map_or(0, |interval| (interval.as_secs_f64() * self.max_fps as f64).round() as u32)
+    }
+}
+
+/// Whether `interval` has passed between the keyframe captured at `last` and `at`
+fn keyframe_due_at(interval: Option<Duration>, last: Option<Instant>, at: Instant) -> bool {
+    interval.is_some_and(|interval| last.is_some_and(|last| at.saturating_duration_since(last) >= interval))
 }
 
 #[derive(Debug)]
@@ -19,32 +376,650 @@ pub struct VideoConfig {
     pub width: u32,
     pub height: u32,
     pub description_b64: String,
+    /// How the frames were converted to YUV, for debugging colour shifts
+    pub color_matrix: ColorMatrix,
+}
+
+impl VideoConfig {
+    /// WebCodecs codec string, from the description record where there is
+    /// one: avc1.<profile><compat><level>, hev1.<profile>.<compat>.<tier><level>.<constraints>
+    /// or av01.<profile>.<level><tier>.<bit depth>
+    pub fn codec_string(&self) -> String {
+        match self.codec {
+            VideoCodec::Avc => {
+                let avcc = B64.decode(&self.description_b64).unwrap_or_default();
+                match avcc.get(1..4) {
+                    Some(profile) => format!("avc1.{:02X}{:02X}{:02X}", profile[0], profile[1], profile[2]),
+                    None => "avc1.42E01E".to_string(),
+                }
+            }
+            VideoCodec::Hevc => {
+                let hvcc = B64.decode(&self.description_b64).unwrap_or_default();
+                codec_string_from_hvcc(&hvcc).unwrap_or_else(|| "hev1.1.6.L93.B0".to_string())
+            }
+            VideoCodec::Av1 => {
+                let av1c = B64.decode(&self.description_b64).unwrap_or_default();
+                let (Some(&profile_level), Some(&flags)) = (av1c.get(1), av1c.get(2)) else {
+                    return "av01.0.08M.08".to_string();
+                };
+                let depth = match (flags & 0x40 != 0, flags & 0x20 != 0) {
+                    (true, true) => 12,
+                    (true, false) => 10,
+                    _ => 8,
+                };
+                format!(
+                    "av01.{}.{:02}{}.{:02}",
+                    profile_level >> 5,
+                    profile_level & 0x1F,
+                    if flags & 0x80 != 0 { 'H' } else { 'M' },
+                    depth
+                )
+            }
+        }
+    }
+}
+
+/// A frame for `VideoPipeline::encode`. Capture backends that deliver YUV
+/// hand it over as such, so the software encoders skip the RGB round trip;
+/// VideoToolbox takes RGB only and hands YUV frames to openh264. (No
+/// capture backend delivers YUV yet.)
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub enum PipelineFrame {
+    /// Captured pixels, 4 bytes each in `format` order, rows `stride` bytes apart
+    Rgba {
+        frame: Arc<Frame>,
+        stride: usize,
+        format: PixelFormat,
+    },
+    /// Planar 4:2:0; `strides` are the bytes per row of Y, U and V
+    I420 {
+        y: Bytes,
+        u: Bytes,
+        v: Bytes,
+        width: u32,
+        height: u32,
+        strides: [usize; 3],
+    },
+    /// Y plane, then U and V interleaved at half resolution; `strides` are
+    /// the bytes per row of each
+    Nv12 {
+        y: Bytes,
+        uv: Bytes,
+        width: u32,
+        height: u32,
+        strides: [usize; 2],
+    },
+}
+
+impl PipelineFrame {
+    pub fn width(&self) -> u32 {
+        match self {
+            PipelineFrame::Rgba { frame, .. } => frame.width,
+            PipelineFrame::I420 { width, .. } | PipelineFrame::Nv12 { width, .. } => *width,
+        }
+    }
+
+    pub fn height(&self) -> u32 {
+        match self {
+            PipelineFrame::Rgba { frame, .. } => frame.height,
+            PipelineFrame::I420 { height, .. } | PipelineFrame::Nv12 { height, .. } => *height,
+        }
+    }
+
+    /// Err when a plane is too short for the frame size and its stride, so
+    /// the encoders can't read past the end
+    fn check(&self) -> Result<()> {
+        let (width, height) = (self.width() as usize & !1, self.height() as usize & !1);
+        let fits = |plane: &[u8], stride: usize, row_bytes: usize, rows: usize| {
+            rows == 0 || (stride >= row_bytes && plane.len() >= stride * (rows - 1) + row_bytes)
+        };
+        let fits = match self {
+            PipelineFrame::Rgba { frame, stride, .. } => fits(&frame.raw, *stride, width * 4, height),
+            PipelineFrame::I420 { y, u, v, strides, .. } => {
+                fits(y, strides[0], width, height)
+                    && fits(u, strides[1], width / 2, height / 2)
+                    && fits(v, strides[2], width / 2, height / 2)
+            }
+            PipelineFrame::Nv12 { y, uv, strides, .. } => {
+                fits(y, strides[0], width, height) && fits(uv, strides[1], width, height / 2)
+            }
+        };
+        if fits {
+            Ok(())
+        } else {
+            Err(anyhow!("{}x{} frame's planes are too short for their strides", width, height))
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct EncodedChunk {
-    pub data: Vec<u8>,
+    /// AVCC (4-byte length-prefixed NAL units) or an AV1 temporal unit;
+    /// openh264's buffer goes back to the encoder once this is dropped
+    pub data: Bytes,
+    /// Contains an IDR slice (or is an AV1 key frame), so decoding can start here
+    pub is_keyframe: bool,
+    /// When the frame was captured, as passed to `VideoPipeline::encode`
+    pub capture_ts: Instant,
+    /// Time spent in the encoder for this frame
+    pub encode_duration: Duration,
+    /// Temporal layer; layer 0 never references the frames above it, so
+    /// those can be dropped without breaking decode. Always 0 except openh264.
+    pub temporal_id: u8,
+}
+
+/// What one `VideoPipeline::encode` call has ready to send
+#[derive(Debug)]
+pub struct Encoded {
+    /// A decoder config the chunks need that hasn't been handed out yet
+    /// (the first frame, or the first at a new size); send it first
+    pub new_config: Option<VideoConfig>,
+    /// Usually one; more when earlier chunks were held until the config was
+    /// ready, oldest first
+    pub chunks: Vec<EncodedChunk>,
+}
+
+/// Most chunks held back while the decoder config isn't ready; older ones go
+const MAX_HELD_CHUNKS: usize = 8;
+
+impl EncodedChunk {
+    /// A chunk from a backend; `VideoPipeline::encode` fills in the timing
+    #[cfg(any(
+        test,
+        feature = "openh264-encoder",
+        feature = "av1",
+        all(feature = "videotoolbox", target_os = "macos")
+    ))]
+    pub(crate) fn new(data: impl Into<Bytes>, is_keyframe: bool) -> Self {
+        Self {
+            data: data.into(),
+            is_keyframe,
+            capture_ts: Instant::now(),
+            encode_duration: Duration::ZERO,
+            temporal_id: 0,
+        }
+    }
+}
+
+/// Weight of the newest frame in `EncoderStats::avg_encode_ms`
+const ENCODE_TIME_SMOOTHING: f64 = 0.1;
+
+/// What the encoder has been doing. All but `total_frames_encoded` and the
+/// input counters start over when the encoder is recreated for a new frame size.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct EncoderStats {
+    pub frames_encoded: u64,
+    pub keyframes: u64,
+    pub bytes_out: u64,
+    /// Rolling average
+    pub avg_encode_ms: f64,
+    pub bitrate_bps: u32,
+    pub last_frame_bytes: usize,
+    /// Since the pipeline was created
+    pub total_frames_encoded: u64,
+    /// Captures replaced by newer ones before the encoder got to them, since
+    /// the pipeline was created
+    pub frames_dropped: u64,
+    /// Capture rate the input policy currently takes frames at
+    pub effective_fps: f32,
+}
+
+/// `PipelineOptions::drop_streak` unless configured
+pub const DEFAULT_DROP_STREAK: u32 = 5;
+/// Frames taken in a row without a loss before the input rate may double again
+const RECOVER_FRAMES: u32 = 30;
+/// Share of the faster frame interval the average encode may use for the rate
+/// to double again; the margin keeps it from flipping straight back
+const RECOVER_HEADROOM: f64 = 0.7;
+/// Slowest input rate: every 8th capture
+const MAX_DIVISOR: u32 = 8;
+/// Weight of the newest gap in the capture interval estimate
+const INTERVAL_SMOOTHING: f64 = 0.1;
+
+/// What happens to captures the encoder can't get to in time. The listener
+/// only keeps the newest one, so a slow encode loses frames rather than
+/// falling behind; once `streak_limit` are lost in a row, only every other
+/// capture is taken (then every 4th, ...) until the average encode fits the
+/// faster rate again with room to spare.
+struct InputPolicy {
+    streak_limit: u32,
+    /// Frames lost since the last one taken without a loss before it
+    streak: u32,
+    /// Frames taken in a row without a loss
+    calm: u32,
+    /// Take every `divisor`th capture
+    divisor: u32,
+    arrivals: u64,
+    /// Listener's drop count at the last arrival
+    listener_dropped: u64,
+    dropped: u64,
+    last_arrival: Option<Instant>,
+    /// Smoothed time between captures, lost ones included
+    capture_interval: Option<f64>,
+}
+
+impl InputPolicy {
+    fn new(streak_limit: u32) -> Self {
+        Self {
+            streak_limit,
+            streak: 0,
+            calm: 0,
+            divisor: 1,
+            arrivals: 0,
+            listener_dropped: 0,
+            dropped: 0,
+            last_arrival: None,
+            capture_interval: None,
+        }
+    }
+
+    /// See `VideoPipeline::admit`
+    fn admit(&mut self, listener_dropped: u64, at: Instant, avg_encode_ms: f64) -> bool {
+        // A new listener counts from zero again
+        let lost = listener_dropped
+            .checked_sub(self.listener_dropped)
+            .unwrap_or(listener_dropped);
+        self.listener_dropped = listener_dropped;
+        self.dropped += lost;
+        if let Some(last) = self.last_arrival {
+            let gap = at.saturating_duration_since(last).as_secs_f64() / (lost + 1) as f64;
+            self.capture_interval = Some(match self.capture_interval {
+                Some(interval) => interval + (gap - interval) * INTERVAL_SMOOTHING,
+                None => gap,
+            });
+        }
+        self.last_arrival = Some(at);
+
+        if lost > 0 {
+            self.calm = 0;
+            self.streak = self.streak.saturating_add(lost as u32);
+            if self.streak_limit > 0 && self.streak >= self.streak_limit && self.divisor < MAX_DIVISOR {
+                self.divisor *= 2;
+                self.streak = 0;
+                self.arrivals = 0;
+                println!("encoder falling behind, taking every {} captures", self.divisor);
+            }
+        } else {
+            self.streak = 0;
+            self.calm += 1;
+            let faster_ms = self.capture_interval.unwrap_or(0.0) * 1000.0 * (self.divisor / 2) as f64;
+            if self.divisor > 1 && self.calm >= RECOVER_FRAMES && avg_encode_ms < faster_ms * RECOVER_HEADROOM {
+                self.divisor /= 2;
+                self.calm = 0;
+                self.arrivals = 0;
+                println!("encoder caught up, taking every {} captures", self.divisor);
+            }
+        }
+
+        self.arrivals += 1;
+        self.arrivals.is_multiple_of(self.divisor as u64)
+    }
+
+    fn effective_fps(&self) -> f32 {
+        match self.capture_interval {
+            Some(interval) if interval > 0.0 => (1.0 / (interval * self.divisor as f64)) as f32,
+            _ => 0.0,
+        }
+    }
+}
+
+impl EncoderStats {
+    fn record(&mut self, chunk: &EncodedChunk) {
+        let encode_ms = chunk.encode_duration.as_secs_f64() * 1000.0;
+        self.avg_encode_ms = if self.frames_encoded == 0 {
+            encode_ms
+        } else {
+            self.avg_encode_ms + (encode_ms - self.avg_encode_ms) * ENCODE_TIME_SMOOTHING
+        };
+        self.frames_encoded += 1;
+        self.total_frames_encoded += 1;
+        self.keyframes += chunk.is_keyframe as u64;
+        self.bytes_out += chunk.data.len() as u64;
+        self.last_frame_bytes = chunk.data.len();
+    }
 }
 
 pub struct VideoPipeline {
-    inner: EncoderImpl,
+    inner: Backend,
+    /// Kept for switching backends mid-stream
+    options: PipelineOptions,
+    stats: EncoderStats,
+    /// Frame size `stats` has been counting for
+    stats_size: (u32, u32),
+    /// Capture time of the last keyframe out, for `options.keyframe_interval`
+    last_keyframe: Option<Instant>,
+    input: InputPolicy,
+    /// Size and description of the last config handed out with `Encoded`
+    config_out: Option<(u32, u32, String)>,
+    /// Chunks encoded before their config was ready
+    held: Vec<EncodedChunk>,
+}
+
+// One per pipeline, so the backends' sizes needn't match
+#[allow(clippy::large_enum_variant)]
+enum Backend {
+    Software(EncoderImpl),
+    #[cfg(feature = "av1")]
+    Av1(crate::av1::Av1Encoder),
+    #[cfg(all(feature = "videotoolbox", target_os = "macos"))]
+    VideoToolbox(crate::videotoolbox::VtEncoder),
+    #[cfg(test)]
+    Mock(crate::mock_encoder::MockEncoder),
 }
 
 impl VideoPipeline {
-    pub fn new(codec: VideoCodec) -> Result<Self> {
-        let inner = EncoderImpl::new(codec)?;
-        Ok(Self { inner })
+    /// AV1 goes to rav1e. For H.264, hardware VideoToolbox when built with
+    /// it and this Mac can create a session; openh264 otherwise
+    pub fn new(codec: VideoCodec, options: PipelineOptions) -> Result<Self> {
+        Self::with_backend(Self::new_backend(codec, options)?, options)
+    }
+
+    /// A pipeline around `encoder` instead of a real backend
+    #[cfg(test)]
+    pub(crate) fn with_mock(encoder: crate::mock_encoder::MockEncoder, options: PipelineOptions) -> Result<Self> {
+        Self::with_backend(Backend::Mock(encoder), options)
+    }
+
+    fn with_backend(inner: Backend, options: PipelineOptions) -> Result<Self> {
+        let mut pipeline = Self {
+            inner,
+            options,
+            stats: EncoderStats::default(),
+            stats_size: (0, 0),
+            last_keyframe: None,
+            input: InputPolicy::new(options.drop_streak),
+            config_out: None,
+            held: Vec::new(),
+        };
+        if let Some(bitrate_bps) = options.rate_control.target_bitrate() {
+            pipeline.set_bitrate(bitrate_bps)?;
+        }
+        pipeline.set_keyframe_interval(options.keyframe_interval_frames());
+        Ok(pipeline)
+    }
+
+    fn new_backend(codec: VideoCodec, options: PipelineOptions) -> Result<Backend> {
+        if codec == VideoCodec::Av1 {
+            #[cfg(feature = "av1")]
+            return Ok(Backend::Av1(crate::av1::Av1Encoder::new(options.color_matrix)?));
+            #[cfg(not(feature = "av1"))]
+            return Err(anyhow!("AV1 needs a build with --features av1"));
+        }
+        // VideoToolbox hands back AVCC only
+        #[cfg(all(feature = "videotoolbox", target_os = "macos"))]
+        if options.output_format == OutputFormat::Avcc {
+            match crate::videotoolbox::VtEncoder::new(codec, options.rate_control.quality_fraction()) {
+                Ok(encoder) => return Ok(Backend::VideoToolbox(encoder)),
+                Err(err) => eprintln!("VideoToolbox encoder not available, using openh264: {}", err),
+            }
+        }
+        Ok(Backend::Software(EncoderImpl::new(codec, options)?))
+    }
+
+    /// "videotoolbox", "openh264" or "rav1e", for logs and /status
+    pub fn backend(&self) -> &'static str {
+        match &self.inner {
+            Backend::Software(_) => "openh264",
+            #[cfg(feature = "av1")]
+            Backend::Av1(_) => "rav1e",
+            #[cfg(all(feature = "videotoolbox", target_os = "macos"))]
+            Backend::VideoToolbox(_) => "videotoolbox",
+            #[cfg(test)]
+            Backend::Mock(_) => "mock",
+        }
     }
 
     pub fn config(&self) -> VideoConfig {
-        self.inner.config()
+        match &self.inner {
+            Backend::Software(encoder) => encoder.config(),
+            #[cfg(feature = "av1")]
+            Backend::Av1(encoder) => encoder.config(),
+            #[cfg(all(feature = "videotoolbox", target_os = "macos"))]
+            Backend::VideoToolbox(encoder) => encoder.config(),
+            #[cfg(test)]
+            Backend::Mock(encoder) => encoder.config(),
+        }
+    }
+
+    /// Whether a frame captured at `at` gets an IDR because
+    /// `options.keyframe_interval` has passed since the last one. The
+    /// encoder's own period counts frames; this catches up when they arrive
+    /// slower than `max_fps` (or not at all while the screen is idle).
+    pub fn keyframe_due(&self, at: Instant) -> bool {
+        keyframe_due_at(self.options.keyframe_interval, self.last_keyframe, at)
+    }
+
+    /// Whether to encode a capture arriving at `at`, from a listener that has
+    /// missed `listener_dropped` captures so far. False skips it to lower the
+    /// input rate while encoding can't keep up; skipping leaves the reference
+    /// chain intact.
+    pub fn admit(&mut self, listener_dropped: u64, at: Instant) -> bool {
+        self.input.admit(listener_dropped, at, self.stats.avg_encode_ms)
+    }
+
+    /// Encode `frame`, captured at `capture_ts`. rav1e may hand back an
+    /// earlier frame's packet; it is stamped with this call's times.
+    ///
+    /// An IDR is forced when asked for or when `keyframe_due`. Chunks that
+    /// come out before the decoder config is ready are held and returned,
+    /// after the config, by the first call that has it.
+    pub fn encode(&mut self, frame: PipelineFrame, capture_ts: Instant, force_idr: bool) -> Result<Option<Encoded>> {
+        frame.check()?;
+        let started = Instant::now();
+        let force_idr = force_idr || self.keyframe_due(capture_ts);
+        let Some(chunk) = self.encode_backend(frame, force_idr)? else {
+            return Ok(None);
+        };
+        let chunk = EncodedChunk {
+            capture_ts,
+            encode_duration: started.elapsed(),
+            ..chunk
+        };
+        let config = self.config();
+        if (config.width, config.height) != self.stats_size {
+            // Recreated for a new frame size
+            self.stats_size = (config.width, config.height);
+            self.stats = EncoderStats {
+                total_frames_encoded: self.stats.total_frames_encoded,
+                ..EncoderStats::default()
+            };
+        }
+        if chunk.is_keyframe {
+            self.last_keyframe = Some(capture_ts);
+        }
+        self.stats.record(&chunk);
+        Ok(self.hand_out(chunk, config))
+    }
+
+    /// `chunk` with any held before it, behind `config` if that hasn't been
+    /// handed out yet; None while the config isn't ready, holding `chunk`
+    fn hand_out(&mut self, chunk: EncodedChunk, config: VideoConfig) -> Option<Encoded> {
+        // Annex-B streams carry their parameter sets in-band
+        let ready = config.width > 0
+            && config.height > 0
+            && (!config.description_b64.is_empty() || self.options.output_format == OutputFormat::AnnexB);
+        if !ready {
+            if chunk.is_keyframe {
+                // Decoding can only start here anyway
+                self.held.clear();
+            }
+            if self.held.len() >= MAX_HELD_CHUNKS {
+                self.held.remove(0);
+            }
+            self.held.push(chunk);
+            return None;
+        }
+        let described = (config.width, config.height, config.description_b64.clone());
+        let new_config = if self.config_out.as_ref() != Some(&described) {
+            self.config_out = Some(described);
+            Some(config)
+        } else {
+            None
+        };
+        let mut chunks = std::mem::take(&mut self.held);
+        chunks.push(chunk);
+        Some(Encoded { new_config, chunks })
+    }
+
+    /// `frame` at full size as PNG, or JPEG at `quality` (1-100), with its
+    /// rows `stride` bytes apart and channels read as `pixel_format`. Touches no encoder state, so the
+    /// snapshot path can call it while `encode` runs; from spawn_blocking,
+    /// as a full-resolution capture takes a while.
+    pub fn snapshot(
+        frame: &Frame,
+        stride: usize,
+        pixel_format: PixelFormat,
+        format: ImageFormat,
+        quality: u8,
+    ) -> Result<Vec<u8>> {
+        let (width, height) = (frame.width as usize, frame.height as usize);
+        if frame.raw.len() < stride * height.saturating_sub(1) + width * 4 {
+            return Err(anyhow!("frame buffer doesn't match {}x{}", width, height));
+        }
+        let (r_at, g_at, b_at) = pixel_format.rgb_offsets();
+        let rows = (0..height).map(|y| &frame.raw[y * stride..][..width * 4]);
+        let mut out = Vec::new();
+        match format {
+            ImageFormat::Png => {
+                let mut rgba = Vec::with_capacity(width * height * 4);
+                for px in rows.flat_map(|row| row.chunks_exact(4)) {
+                    rgba.extend_from_slice(&[px[r_at], px[g_at], px[b_at], px[3]]);
+                }
+                let image = RgbaImage::from_raw(frame.width, frame.height, rgba)
+                    .ok_or_else(|| anyhow!("frame buffer doesn't match {}x{}", width, height))?;
+                image.write_to(&mut std::io::Cursor::new(&mut out), ImageFormat::Png)?;
+            }
+            #[cfg(feature = "mjpeg")]
+            ImageFormat::Jpeg => {
+                use image::{codecs::jpeg::JpegEncoder, ExtendedColorType};
+
+                let mut rgb = Vec::with_capacity(width * height * 3);
+                for px in rows.flat_map(|row| row.chunks_exact(4)) {
+                    rgb.extend_from_slice(&[px[r_at], px[g_at], px[b_at]]);
+                }
+                JpegEncoder::new_with_quality(&mut out, quality.clamp(1, 100)).encode(
+                    &rgb,
+                    frame.width,
+                    frame.height,
+                    ExtendedColorType::Rgb8,
+                )?;
+            }
+            #[cfg(not(feature = "mjpeg"))]
+            ImageFormat::Jpeg => {
+                let _ = quality;
+                return Err(anyhow!("JPEG needs a build with the mjpeg feature"));
+            }
+            other => return Err(anyhow!("snapshots are PNG or JPEG, not {:?}", other)),
+        }
+        Ok(out)
     }
 
-    pub fn encode(&mut self, frame: Arc<Frame>, force_idr: bool) -> Result<Option<EncodedChunk>> {
-        self.inner.encode(frame, force_idr)
+    /// Counters since the encoder was last recreated, plus the running total
+    pub fn stats(&self) -> EncoderStats {
+        EncoderStats {
+            bitrate_bps: self.current_bitrate(),
+            frames_dropped: self.input.dropped,
+            effective_fps: self.input.effective_fps(),
+            ..self.stats
+        }
+    }
+
+    fn encode_backend(&mut self, frame: PipelineFrame, force_idr: bool) -> Result<Option<EncodedChunk>> {
+        match &mut self.inner {
+            Backend::Software(encoder) => encoder.encode(&frame, force_idr),
+            #[cfg(feature = "av1")]
+            Backend::Av1(encoder) => encoder.encode(&frame, force_idr),
+            #[cfg(all(feature = "videotoolbox", target_os = "macos"))]
+            Backend::VideoToolbox(encoder) => {
+                let result = match &frame {
+                    PipelineFrame::Rgba { frame, stride, format } => {
+                        encoder.encode(frame.clone(), *stride, *format, force_idr)
+                    }
+                    _ => {
+                        // Sessions are set up for BGRA input
+                        encoder.session_failed = true;
+                        Err(anyhow!("VideoToolbox takes RGB frames only"))
+                    }
+                };
+                if !encoder.session_failed {
+                    return result;
+                }
+                // e.g. a frame size the hardware doesn't support
+                let err = result.err().unwrap_or_else(|| anyhow!("VideoToolbox session failed"));
+                let Ok(mut software) = EncoderImpl::new(encoder.config().codec, self.options) else {
+                    return Err(err);
+                };
+                eprintln!("VideoToolbox session failed, switching to openh264: {}", err);
+                if let Some(bitrate_bps) = encoder.bitrate_override {
+                    software.set_bitrate(bitrate_bps)?;
+                }
+                software.idr_interval_frames = encoder.idr_interval_frames;
+                self.inner = Backend::Software(software);
+                self.encode_backend(frame, true)
+            }
+            #[cfg(test)]
+            Backend::Mock(encoder) => encoder.encode(&frame, force_idr),
+        }
+    }
+
+    /// Change the target bitrate without restarting the stream: no new
+    /// config and no IDR on openh264 and VideoToolbox, which retune the
+    /// running encoder within a few frames. rav1e applies it at the next
+    /// keyframe. Call between `encode`s.
+    ///
+    /// Also used when the encoder is next recreated for new dimensions.
+    pub fn set_bitrate(&mut self, bitrate_bps: u32) -> Result<()> {
+        match &mut self.inner {
+            Backend::Software(encoder) => encoder.set_bitrate(bitrate_bps),
+            #[cfg(feature = "av1")]
+            Backend::Av1(encoder) => encoder.set_bitrate(bitrate_bps),
+            #[cfg(all(feature = "videotoolbox", target_os = "macos"))]
+            Backend::VideoToolbox(encoder) => encoder.set_bitrate(bitrate_bps),
+            #[cfg(test)]
+            Backend::Mock(encoder) => encoder.set_bitrate(bitrate_bps),
+        }
+    }
+
+    /// Bitrate the running encoder is set to; 0 until the first frame sizes it
+    pub fn current_bitrate(&self) -> u32 {
+        match &self.inner {
+            Backend::Software(encoder) => encoder.bitrate_bps,
+            #[cfg(feature = "av1")]
+            Backend::Av1(encoder) => encoder.bitrate_bps,
+            #[cfg(all(feature = "videotoolbox", target_os = "macos"))]
+            Backend::VideoToolbox(encoder) => encoder.bitrate_bps,
+            #[cfg(test)]
+            Backend::Mock(encoder) => encoder.bitrate_bps,
+        }
+    }
+
+    /// Have the encoder emit an IDR every `frames` frames (0 = only on request)
+    fn set_keyframe_interval(&mut self, frames: u32) {
+        match &mut self.inner {
+            Backend::Software(encoder) => encoder.idr_interval_frames = frames,
+            #[cfg(feature = "av1")]
+            Backend::Av1(encoder) => encoder.idr_interval_frames = frames,
+            #[cfg(all(feature = "videotoolbox", target_os = "macos"))]
+            Backend::VideoToolbox(encoder) => encoder.idr_interval_frames = frames,
+            #[cfg(test)]
+            Backend::Mock(encoder) => encoder.idr_interval_frames = frames,
+        }
     }
 }
 
+/// openh264 temporal layers: layer 0 alone is half the frame rate, for
+/// viewers that asked for that little
+#[cfg(feature = "openh264-encoder")]
+const TEMPORAL_LAYERS: i32 = 2;
+
+/// VUI video_format 5: unspecified
+#[cfg(feature = "openh264-encoder")]
+const VIDEO_FORMAT_UNSPECIFIED: u8 = 5;
+
+#[cfg(feature = "openh264-encoder")]
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
 #[cfg(feature = "openh264-encoder")]
 struct EncoderImpl {
     encoder: openh264::encoder::Encoder,
@@ -52,14 +1027,28 @@ struct EncoderImpl {
     height: u32,
     codec: VideoCodec,
     config_b64: String,
+    /// SPS and PPS with start codes, for Annex-B keyframes that lack them
+    parameter_sets: Vec<u8>,
     pending_idr: bool,
+    bitrate_bps: u32,
+    /// Set by set_bitrate; replaces the size-based default
+    bitrate_override: Option<u32>,
+    /// openh264 intra period, applied whenever the encoder is (re)created
+    idr_interval_frames: u32,
+    /// Frame rate, rate control mode and usage for every (re)created encoder
+    options: PipelineOptions,
+    /// Conversion target, reused while the frame size holds
+    yuv: I420,
+    /// AVCC or Annex-B output; each chunk splits its bytes off, and the
+    /// allocation is reclaimed once every viewer is done with them
+    out: BytesMut,
 }
 
 #[cfg(feature = "openh264-encoder")]
 impl EncoderImpl {
-    fn new(codec: VideoCodec) -> Result<Self> {
-        if codec == VideoCodec::Hevc {
-            return Err(anyhow!("HEVC not available in openh264 encoder; choose avc"));
+    fn new(codec: VideoCodec, options: PipelineOptions) -> Result<Self> {
+        if codec != VideoCodec::Avc {
+            return Err(anyhow!("{:?} not available in openh264 encoder; choose avc", codec));
         }
         let width = 0;
         let height = 0;
@@ -73,43 +1062,95 @@ impl EncoderImpl {
             height,
             codec,
             config_b64: String::new(),
+            parameter_sets: Vec::new(),
             pending_idr: true,
+            bitrate_bps: 0,
+            bitrate_override: None,
+            idr_interval_frames: 0,
+            options,
+            yuv: I420::default(),
+            out: BytesMut::new(),
         })
     }
 
+    fn set_bitrate(&mut self, bitrate_bps: u32) -> Result<()> {
+        self.bitrate_override = Some(bitrate_bps);
+        if self.width == 0 {
+            // Applied when the encoder is created for the first frame
+            return Ok(());
+        }
+        let mut info = SBitrateInfo {
+            iLayer: SPATIAL_LAYER_ALL,
+            iBitrate: bitrate_bps as i32,
+        };
+        let rc = unsafe {
+            self.encoder
+                .raw_api()
+                .set_option(ENCODER_OPTION_BITRATE, &mut info as *mut _ as *mut std::ffi::c_void)
+        };
+        if rc != 0 {
+            return Err(anyhow!("set bitrate failed with code {}", rc));
+        }
+        self.bitrate_bps = bitrate_bps;
+        Ok(())
+    }
+
     fn config(&self) -> VideoConfig {
         VideoConfig {
             codec: self.codec,
             width: self.width,
             height: self.height,
-            description_b64: self.config_b64.clone(),
+            // Annex-B consumers take SPS/PPS from the stream
+            description_b64: match self.options.output_format {
+                OutputFormat::Avcc => self.config_b64.clone(),
+                OutputFormat::AnnexB => String::new(),
+            },
+            color_matrix: self.options.color_matrix,
         }
     }
 
-    fn encode(&mut self, frame: Arc<Frame>, force_idr: bool) -> Result<Option<EncodedChunk>> {
+    fn encode(&mut self, frame: &PipelineFrame, force_idr: bool) -> Result<Option<EncodedChunk>> {
         // Ensure even dimensions for I420.
-        let even_w = frame.width & !1;
-        let even_h = frame.height & !1;
+        let even_w = frame.width() & !1;
+        let even_h = frame.height() & !1;
         if even_w == 0 || even_h == 0 {
             return Ok(None);
         }
 
         if self.width != even_w || self.height != even_h {
-            // Recreate encoder with correct dimensions.
+            // Recreate encoder with correct dimensions, keeping the caller's options.
             // Use higher bitrate for better quality (aim for ~15Mbps for 1080p)
-            let bitrate = (even_w * even_h * 8).clamp(500_000, 15_000_000);
+            let bitrate = self
+                .bitrate_override
+                .unwrap_or_else(|| (even_w * even_h * 8).clamp(500_000, 15_000_000));
+            let rc_mode = match self.options.rate_control {
+                RateControl::Bitrate(_) => openh264::encoder::RateControlMode::Bitrate,
+                RateControl::Quality(_) => openh264::encoder::RateControlMode::Quality,
+                RateControl::Off { .. } => openh264::encoder::RateControlMode::Off,
+            };
+            let usage = match self.options.usage {
+                UsageType::Camera => openh264::encoder::UsageType::CameraVideoRealTime,
+                UsageType::Screen => openh264::encoder::UsageType::ScreenContentRealTime,
+            };
             let cfg = openh264::encoder::EncoderConfig::new(even_w, even_h)
                 .set_bitrate_bps(bitrate)
-                .max_frame_rate(60.0)  // Target 60 FPS
-                .rate_control_mode(openh264::encoder::RateControlMode::Bitrate);
+                .max_frame_rate(self.options.max_fps)
+                .rate_control_mode(rc_mode)
+                .usage_type(usage);
             self.encoder = openh264::encoder::Encoder::with_config(cfg)?;
             self.width = even_w;
             self.height = even_h;
+            if let Err(err) = self.apply_param_ext() {
+                eprintln!("{err}; keeping openh264's defaults for temporal layers, QP range, threads and intra period");
+            }
+            self.bitrate_bps = bitrate;
             self.config_b64.clear();
             self.pending_idr = true;
         }
 
-        let yuv = rgba_to_yuv420(&frame.raw, even_w as usize, even_h as usize);
+        let planes = self
+            .yuv
+            .planes(frame, even_w as usize, even_h as usize, self.options.color_matrix);
 
         // Request an IDR on the first frame or when caller asks for it.
         if self.pending_idr || force_idr {
@@ -117,12 +1158,20 @@ impl EncoderImpl {
             self.pending_idr = false;
         }
 
-        let bitstream = self.encoder.encode(&yuv)?;
-        let nals = collect_nals(&bitstream);
+        let bitstream = self.encoder.encode(&planes)?;
+        let temporal_id = bitstream.raw_info().sLayerInfo[0].uiTemporalId;
+        let (is_keyframe, has_sps) = write_nals(&bitstream, self.options.output_format, &mut self.out);
+        // Separate NAL copies only until SPS/PPS have been found
+        let nals = if self.config_b64.is_empty() {
+            collect_nals(&bitstream)
+        } else {
+            Vec::new()
+        };
 
         // println!("self.config_b64.is_empty(): {}", self.config_b64.is_empty());
         if self.config_b64.is_empty() {
             // println!("building avcc from nals: {:?}", nals.iter().map(|nal| nal.len()).collect::<Vec<_>>());
+            self.parameter_sets = annex_b_parameter_sets(&nals);
             match build_avcc_from_nals(&nals) {
                 Ok(option_cfg) => {
                     if let Some(cfg) = option_cfg {
@@ -145,26 +1194,127 @@ impl EncoderImpl {
         }
 
         // Skip frames with no NAL units (encoder skipped output)
-        if nals.is_empty() {
+        if self.out.is_empty() {
             return Ok(None);
         }
 
-        let avcc = nals_to_avcc(&nals);
-        Ok(Some(EncodedChunk { data: avcc }))
+        if self.options.output_format == OutputFormat::AnnexB && is_keyframe && !has_sps {
+            // Whoever starts decoding here has seen no SPS/PPS before
+            let frame = self.out.split();
+            self.out.extend_from_slice(&self.parameter_sets);
+            self.out.extend_from_slice(&frame);
+        }
+
+        Ok(Some(EncodedChunk {
+            temporal_id,
+            ..EncodedChunk::new(self.out.split().freeze(), is_keyframe)
+        }))
+    }
+
+    /// Settings `EncoderConfig` doesn't cover, on the freshly created encoder:
+    /// `TEMPORAL_LAYERS` layers (still plain AVC with the same SPS/PPS, every
+    /// other frame just isn't used as a reference), the QP range of the
+    /// quality and fixed-QP modes (or of screen content), the screen-content
+    /// preprocessing, threads and slices, the keyframe interval in frames,
+    /// and the VUI colour description matching our RGB to YUV conversion.
+    fn apply_param_ext(&mut self) -> Result<()> {
+        let threads = self.threads();
+        let mut params = SEncParamExt::default();
+        let raw = self.encoder.raw_api();
+        let rc = unsafe {
+            raw.get_option(ENCODER_OPTION_SVC_ENCODE_PARAM_EXT, &mut params as *mut _ as *mut std::ffi::c_void)
+        };
+        if rc != 0 {
+            return Err(anyhow!("reading encoder params failed with code {}", rc));
+        }
+        params.iTemporalLayerNum = TEMPORAL_LAYERS;
+        params.uiIntraPeriod = self.idr_interval_frames;
+        params.iMultipleThreadIdc = threads as _;
+        let slices = &mut params.sSpatialLayers[0].sSliceArgument;
+        if threads > 1 {
+            // Each thread needs a slice of its own to work on
+            slices.uiSliceMode = SM_FIXEDSLCNUM_SLICE;
+            slices.uiSliceNum = threads as u32;
+        } else {
+            slices.uiSliceMode = SM_SINGLE_SLICE;
+        }
+        let color = self.options.color_matrix;
+        let layer = &mut params.sSpatialLayers[0];
+        layer.bVideoSignalTypePresent = true;
+        layer.uiVideoFormat = VIDEO_FORMAT_UNSPECIFIED;
+        layer.bFullRange = color.full_range();
+        layer.bColorDescriptionPresent = true;
+        layer.uiColorPrimaries = color.description_code();
+        layer.uiTransferCharacteristics = color.description_code();
+        layer.uiColorMatrix = color.description_code();
+        if let Some((min_qp, max_qp)) = self.options.rate_control.qp_bounds() {
+            params.iMinQp = min_qp as i32;
+            params.iMaxQp = max_qp as i32;
+            // Where encoding starts, and all of it with rate control off
+            params.sSpatialLayers[0].iDLayerQp = ((min_qp + max_qp) / 2) as i32;
+        } else if self.options.usage == UsageType::Screen {
+            params.iMinQp = SCREEN_QP_RANGE.0 as i32;
+            params.iMaxQp = SCREEN_QP_RANGE.1 as i32;
+        }
+        if self.options.usage == UsageType::Screen {
+            // Denoising smears glyph edges; unchanged background areas can be skipped
+            params.bEnableDenoise = false;
+            params.bEnableBackgroundDetection = true;
+        }
+        let rc = unsafe {
+            raw.set_option(ENCODER_OPTION_SVC_ENCODE_PARAM_EXT, &mut params as *mut _ as *mut std::ffi::c_void)
+        };
+        if rc != 0 {
+            return Err(anyhow!("enabling temporal layers failed with code {}", rc));
+        }
+        Ok(())
+    }
+
+    /// `options.threads`, or the default for the current frame size
+    fn threads(&self) -> u16 {
+        match self.options.threads {
+            Some(threads) => threads.max(1),
+            None if self.width * self.height >= THREADED_MIN_PIXELS => std::thread::available_parallelism()
+                .map_or(1, |cores| cores.get().min(DEFAULT_MAX_THREADS as usize) as u16),
+            None => 1,
+        }
     }
 }
 
+/// Lets openh264 read the planes without another copy
 #[cfg(feature = "openh264-encoder")]
-fn rgba_to_yuv420(src: &[u8], width: usize, height: usize) -> openh264::formats::YUVBuffer {
-    // Drop alpha, keep RGB.
-    let mut rgb = Vec::with_capacity(width * height * 3);
-    for i in 0..(width * height) {
-        let base = i * 4;
-        rgb.push(src[base]);     // R
-        rgb.push(src[base + 1]); // G
-        rgb.push(src[base + 2]); // B
+impl openh264::formats::YUVSource for Planes<'_> {
+    fn width(&self) -> i32 {
+        self.width as i32
+    }
+
+    fn height(&self) -> i32 {
+        self.height as i32
+    }
+
+    fn y(&self) -> &[u8] {
+        self.y
+    }
+
+    fn u(&self) -> &[u8] {
+        self.u
+    }
+
+    fn v(&self) -> &[u8] {
+        self.v
+    }
+
+    fn y_stride(&self) -> i32 {
+        self.strides[0] as i32
+    }
+
+    fn u_stride(&self) -> i32 {
+        self.strides[1] as i32
+    }
+
+    fn v_stride(&self) -> i32 {
+        self.strides[2] as i32
     }
-    openh264::formats::YUVBuffer::with_rgb(width, height, &rgb)
 }
 
 #[cfg(feature = "openh264-encoder")]
@@ -184,12 +1334,36 @@ fn collect_nals(bitstream: &EncodedBitStream) -> Vec<Vec<u8>> {
     nals
 }
 
+/// Append the frame's NAL units to `out` as `format`, straight from
+/// openh264's buffers. Returns whether any is an IDR slice (type 5) and
+/// whether any is an SPS (type 7).
 #[cfg(feature = "openh264-encoder")]
-fn nals_to_avcc(nals: &[Vec<u8>]) -> Vec<u8> {
+fn write_nals(bitstream: &EncodedBitStream, format: OutputFormat, out: &mut BytesMut) -> (bool, bool) {
+    let (mut idr, mut sps) = (false, false);
+    for l in 0..bitstream.num_layers() {
+        if let Some(layer) = bitstream.layer(l) {
+            for n in 0..layer.nal_count() {
+                if let Some(nal) = layer.nal_unit(n).and_then(normalize_nal) {
+                    idr |= nal[0] & 0x1F == 5;
+                    sps |= nal[0] & 0x1F == 7;
+                    match format {
+                        OutputFormat::Avcc => out.put_u32(nal.len() as u32),
+                        OutputFormat::AnnexB => out.extend_from_slice(&START_CODE),
+                    }
+                    out.extend_from_slice(nal);
+                }
+            }
+        }
+    }
+    (idr, sps)
+}
+
+/// The SPS and PPS among `nals`, each after a start code
+#[cfg(feature = "openh264-encoder")]
+fn annex_b_parameter_sets(nals: &[Vec<u8>]) -> Vec<u8> {
     let mut out = Vec::new();
-    for nal in nals {
-        let len = nal.len() as u32;
-        out.extend_from_slice(&len.to_be_bytes());
+    for nal in nals.iter().filter(|nal| matches!(nal.first().map(|b| b & 0x1F), Some(7 | 8))) {
+        out.extend_from_slice(&START_CODE);
         out.extend_from_slice(nal);
     }
     out
@@ -259,12 +1433,32 @@ impl EncoderImpl {
             return Err(anyhow!("encode_parameter_sets failed with code {}", rc));
         }
         let nals = unsafe { collect_nals_from_info(&info) };
+        self.parameter_sets = annex_b_parameter_sets(&nals);
         build_avcc_from_nals(&nals)
     }
 }
 
-#[cfg(feature = "openh264-encoder")]
-fn build_avcc_from_nals(nals: &[Vec<u8>]) -> Result<Option<Vec<u8>>> {
+/// Whether AVCC `data` holds an H.264 IDR slice (NAL type 5), for encoders
+/// that don't say. Stops at the first length running past the end, so
+/// truncated or garbage input is only read as far as it holds together.
+#[cfg_attr(not(all(feature = "videotoolbox", target_os = "macos")), allow(dead_code))]
+pub fn chunk_is_keyframe(avcc_data: &[u8]) -> bool {
+    let mut rest = avcc_data;
+    while let Some((length, after)) = rest.split_first_chunk::<4>() {
+        let length = u32::from_be_bytes(*length) as usize;
+        let Some(nal) = after.get(..length) else {
+            return false;
+        };
+        if nal.first().is_some_and(|header| header & 0x1F == 5) {
+            return true;
+        }
+        rest = &after[length..];
+    }
+    false
+}
+
+#[cfg(any(feature = "openh264-encoder", all(feature = "videotoolbox", target_os = "macos")))]
+pub(crate) fn build_avcc_from_nals(nals: &[Vec<u8>]) -> Result<Option<Vec<u8>>> {
     let mut sps: Option<&[u8]> = None;
     let mut pps: Option<&[u8]> = None;
 
@@ -305,12 +1499,157 @@ fn build_avcc_from_nals(nals: &[Vec<u8>]) -> Result<Option<Vec<u8>>> {
     Ok(Some(avcc))
 }
 
+/// Reads an RBSP (emulation prevention bytes removed) bit by bit
+struct BitReader<'a> {
+    data: &'a [u8],
+    bit: usize,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u32) -> Option<u32> {
+        let mut value = 0u32;
+        for _ in 0..count {
+            let byte = *self.data.get(self.bit / 8)?;
+            value = (value << 1) | ((byte >> (7 - self.bit % 8)) & 1) as u32;
+            self.bit += 1;
+        }
+        Some(value)
+    }
+
+    fn skip(&mut self, count: usize) -> Option<()> {
+        self.bit += count;
+        (self.bit <= self.data.len() * 8).then_some(())
+    }
+
+    /// Exp-Golomb ue(v)
+    fn ue(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while self.bits(1)? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+        Some((1u32 << zeros) - 1 + self.bits(zeros)?)
+    }
+}
+
+/// `nal`'s payload after the 2-byte HEVC header, with 00 00 03 unescaped
+fn hevc_rbsp(nal: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for &byte in nal.iter().skip(2) {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
+}
+
+/// HEVCDecoderConfigurationRecord (hvcC) from a frame's VPS, SPS and PPS
+/// (NAL types 32-34), for `VideoConfig::description_b64`. The profile,
+/// tier, level, chroma format and bit depths come from the SPS.
+#[allow(dead_code)] // no HEVC encoder yet
+pub(crate) fn build_hvcc_from_nals(nals: &[Vec<u8>]) -> Result<Option<Vec<u8>>> {
+    let of_type = |nal_type: u8| -> Vec<&Vec<u8>> {
+        nals.iter()
+            .filter(|nal| nal.len() > 2 && (nal[0] >> 1) & 0x3F == nal_type)
+            .collect()
+    };
+    let (vps, sps, pps) = (of_type(32), of_type(33), of_type(34));
+    let (Some(first_sps), false, false) = (sps.first(), vps.is_empty(), pps.is_empty()) else {
+        return Ok(None);
+    };
+
+    let rbsp = hevc_rbsp(first_sps);
+    let mut reader = BitReader { data: &rbsp, bit: 0 };
+    let malformed = || anyhow!("malformed HEVC SPS");
+    reader.skip(4).ok_or_else(malformed)?; // sps_video_parameter_set_id
+    let max_sub_layers_minus1 = reader.bits(3).ok_or_else(malformed)?;
+    let temporal_id_nested = reader.bits(1).ok_or_else(malformed)?;
+    // general_profile_space .. general_level_idc: 12 bytes, copied as they are
+    let general = rbsp.get(1..13).ok_or_else(malformed)?;
+    reader.skip(96).ok_or_else(malformed)?;
+    let mut sub_layers = Vec::new();
+    for _ in 0..max_sub_layers_minus1 {
+        let profile_present = reader.bits(1).ok_or_else(malformed)?;
+        let level_present = reader.bits(1).ok_or_else(malformed)?;
+        sub_layers.push((profile_present, level_present));
+    }
+    if max_sub_layers_minus1 > 0 {
+        reader.skip(2 * (8 - max_sub_layers_minus1 as usize)).ok_or_else(malformed)?;
+    }
+    for (profile_present, level_present) in sub_layers {
+        reader.skip(88 * profile_present as usize + 8 * level_present as usize).ok_or_else(malformed)?;
+    }
+    reader.ue().ok_or_else(malformed)?; // sps_seq_parameter_set_id
+    let chroma_format_idc = reader.ue().ok_or_else(malformed)?;
+    if chroma_format_idc == 3 {
+        reader.skip(1).ok_or_else(malformed)?; // separate_colour_plane_flag
+    }
+    reader.ue().ok_or_else(malformed)?; // pic_width_in_luma_samples
+    reader.ue().ok_or_else(malformed)?; // pic_height_in_luma_samples
+    if reader.bits(1).ok_or_else(malformed)? == 1 {
+        for _ in 0..4 {
+            reader.ue().ok_or_else(malformed)?; // conformance window offsets
+        }
+    }
+    let bit_depth_luma_minus8 = reader.ue().ok_or_else(malformed)?;
+    let bit_depth_chroma_minus8 = reader.ue().ok_or_else(malformed)?;
+
+    let mut hvcc = Vec::with_capacity(23 + nals.iter().map(|nal| nal.len() + 5).sum::<usize>());
+    hvcc.push(1); // configurationVersion
+    hvcc.extend_from_slice(general);
+    hvcc.extend_from_slice(&0xF000u16.to_be_bytes()); // min_spatial_segmentation_idc 0
+    hvcc.push(0xFC); // parallelismType unknown
+    hvcc.push(0xFC | (chroma_format_idc & 3) as u8);
+    hvcc.push(0xF8 | (bit_depth_luma_minus8 & 7) as u8);
+    hvcc.push(0xF8 | (bit_depth_chroma_minus8 & 7) as u8);
+    hvcc.extend_from_slice(&0u16.to_be_bytes()); // avgFrameRate unspecified
+    // constantFrameRate 0, numTemporalLayers, temporalIdNested, 4-byte NALU lengths
+    hvcc.push((((max_sub_layers_minus1 + 1) & 7) << 3) as u8 | (temporal_id_nested as u8) << 2 | 3);
+    hvcc.push(3); // numOfArrays
+    for (nal_type, sets) in [(32u8, vps), (33, sps), (34, pps)] {
+        hvcc.push(0x80 | nal_type); // array_completeness
+        hvcc.extend_from_slice(&(sets.len() as u16).to_be_bytes());
+        for nal in sets {
+            hvcc.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+            hvcc.extend_from_slice(nal);
+        }
+    }
+    Ok(Some(hvcc))
+}
+
+/// RFC 6381 codec string for an hvcC record (ISO/IEC 14496-15 annex E):
+/// hev1.<space><profile>.<compat, bit-reversed>.<tier><level>.<constraint bytes>
+pub fn codec_string_from_hvcc(hvcc: &[u8]) -> Option<String> {
+    let general = hvcc.get(1..13)?;
+    let profile_space = ["", "A", "B", "C"][(general[0] >> 6) as usize];
+    let tier = if general[0] & 0x20 != 0 { 'H' } else { 'L' };
+    let profile_idc = general[0] & 0x1F;
+    let compatibility = u32::from_be_bytes(general[1..5].try_into().ok()?).reverse_bits();
+    let mut codec = format!("hev1.{}{}.{:X}.{}{}", profile_space, profile_idc, compatibility, tier, general[11]);
+    // Trailing zero constraint bytes are left out
+    let constraints = &general[5..11];
+    let used = constraints.iter().rposition(|&byte| byte != 0).map_or(0, |last| last + 1);
+    for byte in &constraints[..used] {
+        codec.push_str(&format!(".{:X}", byte));
+    }
+    Some(codec)
+}
+
 #[cfg(not(feature = "openh264-encoder"))]
-struct EncoderImpl;
+struct EncoderImpl {
+    bitrate_bps: u32,
+    idr_interval_frames: u32,
+}
 
 #[cfg(not(feature = "openh264-encoder"))]
 impl EncoderImpl {
-    fn new(_codec: VideoCodec) -> Result<Self> {
+    fn new(_codec: VideoCodec, _options: PipelineOptions) -> Result<Self> {
         Err(anyhow!("openh264 encoder feature not enabled"))
     }
 
@@ -320,10 +1659,1002 @@ impl EncoderImpl {
             width: 0,
             height: 0,
             description_b64: String::new(),
+            color_matrix: ColorMatrix::default(),
         }
     }
 
-    fn encode(&mut self, _frame: Arc<Frame>, _force_idr: bool) -> Result<Option<EncodedChunk>> {
+    fn encode(&mut self, _frame: &PipelineFrame, _force_idr: bool) -> Result<Option<EncodedChunk>> {
         Ok(None)
     }
+
+    fn set_bitrate(&mut self, bitrate_bps: u32) -> Result<()> {
+        self.bitrate_bps = bitrate_bps;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{recording::packed_stride, test_frames};
+    /// Capture times at which `encode` would put out a keyframe: the first
+    /// frame, then whenever `keyframe_due_at` says so
+    fn keyframes(interval: Option<Duration>, captures: &[Instant]) -> Vec<Instant> {
+        let mut last = None;
+        let mut out = Vec::new();
+        for &at in captures {
+            if last.is_none() || keyframe_due_at(interval, last, at) {
+                last = Some(at);
+                out.push(at);
+            }
+        }
+        out
+    }
+
+    /// The openh264 encoder behind `pipeline`
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    fn software(pipeline: &VideoPipeline) -> &EncoderImpl {
+        match &pipeline.inner {
+            Backend::Software(encoder) => encoder,
+            #[allow(unreachable_patterns)]
+            _ => panic!("expected the openh264 backend"),
+        }
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[test]
+    fn options_survive_a_dimension_change() {
+        let options = PipelineOptions {
+            rate_control: RateControl::Bitrate(2_000_000),
+            max_fps: 30.0,
+            keyframe_interval: Some(Duration::from_secs(2)),
+            usage: UsageType::Screen,
+            ..PipelineOptions::default()
+        };
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, options).unwrap();
+        for (width, height) in [(320, 240), (640, 360), (160, 90)] {
+            let frame = captured(test_frames::color_bars(width, height));
+            pipeline.encode(frame, Instant::now(), false).unwrap();
+            let encoder = software(&pipeline);
+            assert_eq!((encoder.width, encoder.height), (width, height));
+            assert_eq!(encoder.bitrate_bps, 2_000_000);
+            assert_eq!(encoder.idr_interval_frames, 60);
+            assert_eq!(encoder.options.max_fps, 30.0);
+            assert_eq!(encoder.options.usage, UsageType::Screen);
+        }
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[test]
+    fn default_bitrate_follows_the_frame_size() {
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, PipelineOptions::default()).unwrap();
+        for ((width, height), bitrate) in [((320, 240), 614_400), ((1280, 720), 7_372_800), ((64, 64), 500_000)] {
+            pipeline
+                .encode(captured(test_frames::color_bars(width, height)), Instant::now(), false)
+                .unwrap();
+            assert_eq!(software(&pipeline).bitrate_bps, bitrate);
+        }
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[test]
+    fn set_bitrate_keeps_the_running_encoder() {
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, PipelineOptions::default()).unwrap();
+        let frame = captured(test_frames::text_like(320, 240, 0));
+        let first = pipeline.encode(frame, Instant::now(), false).unwrap().unwrap();
+        assert!(first.new_config.is_some());
+        let description = pipeline.config().description_b64;
+
+        pipeline.set_bitrate(300_000).unwrap();
+        assert_eq!(pipeline.current_bitrate(), 300_000);
+        let frame = captured(test_frames::text_like(320, 240, 1));
+        let next = pipeline.encode(frame, Instant::now(), false).unwrap().unwrap();
+        // No new SPS/PPS and no IDR
+        assert!(next.new_config.is_none());
+        assert!(next.chunks.iter().all(|chunk| !chunk.is_keyframe));
+        assert_eq!(pipeline.config().description_b64, description);
+        assert_eq!((pipeline.config().width, pipeline.config().height), (320, 240));
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[test]
+    fn halving_the_bitrate_lowers_the_byte_rate() {
+        const FPS: u32 = 30;
+        let options = PipelineOptions {
+            rate_control: RateControl::Bitrate(1_000_000),
+            max_fps: FPS as f32,
+            ..PipelineOptions::default()
+        };
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, options).unwrap();
+        let start = Instant::now();
+        let mut seed = 0;
+        // Bytes over the last second of `seconds`, leaving the rate control time to settle
+        let mut encode_seconds = |pipeline: &mut VideoPipeline, seconds: u32| {
+            let mut sizes = Vec::new();
+            for _ in 0..seconds * FPS {
+                let at = start + Duration::from_secs(seed as u64) / FPS;
+                let encoded = pipeline.encode(captured(test_frames::text_like(320, 240, seed)), at, false).unwrap();
+                sizes.push(encoded.map_or(0, |e| e.chunks.iter().map(|c| c.data.len()).sum::<usize>()));
+                seed += 1;
+            }
+            sizes[sizes.len() - FPS as usize..].iter().sum::<usize>()
+        };
+        let full = encode_seconds(&mut pipeline, 2);
+        pipeline.set_bitrate(500_000).unwrap();
+        let halved = encode_seconds(&mut pipeline, 3);
+        assert!(
+            (halved as f64) < full as f64 * 0.7,
+            "{} bytes/s at 1 Mbps, {} at 500 kbps",
+            full,
+            halved
+        );
+    }
+
+    /// `nals` as AVCC: each behind its 4-byte big-endian length
+    fn avcc(nals: &[&[u8]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for nal in nals {
+            out.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            out.extend_from_slice(nal);
+        }
+        out
+    }
+
+    #[test]
+    fn keyframes_are_the_chunks_with_an_idr_slice() {
+        let sps: &[u8] = &[0x67, 0x42, 0xc0, 0x1f];
+        let pps: &[u8] = &[0x68, 0xce, 0x3c, 0x80];
+        let idr: &[u8] = &[0x65, 0x88, 0x84, 0x00];
+        let slice: &[u8] = &[0x41, 0x9a, 0x02];
+        assert!(chunk_is_keyframe(&avcc(&[sps, pps, idr])));
+        assert!(chunk_is_keyframe(&avcc(&[idr])));
+        // nal_ref_idc bits don't matter, only the type
+        assert!(chunk_is_keyframe(&avcc(&[&[0x25, 0x88]])));
+        assert!(!chunk_is_keyframe(&avcc(&[slice])));
+        assert!(!chunk_is_keyframe(&avcc(&[slice, slice])));
+        assert!(!chunk_is_keyframe(&avcc(&[sps, pps])));
+    }
+
+    #[test]
+    fn malformed_chunks_are_read_as_far_as_they_hold_together() {
+        let idr: &[u8] = &[0x65, 0x88, 0x84, 0x00];
+        let slice: &[u8] = &[0x41, 0x9a, 0x02];
+        assert!(!chunk_is_keyframe(&[]));
+        assert!(!chunk_is_keyframe(&[0, 0, 0]));
+        // An empty NAL unit is skipped over
+        assert!(chunk_is_keyframe(&avcc(&[&[], idr])));
+        assert!(chunk_is_keyframe(&avcc(&[slice, slice, idr])));
+        // A length past the end hides what follows
+        let mut overrun = vec![0, 0, 0, 9, 0x41, 0x9a];
+        overrun.extend_from_slice(&avcc(&[idr]));
+        assert!(!chunk_is_keyframe(&overrun));
+        assert!(!chunk_is_keyframe(&[0xff, 0xff, 0xff, 0xff, 0x65]));
+
+        // Every truncation of a keyframe: it is one once the IDR is whole
+        let whole = avcc(&[slice, idr]);
+        for end in 0..=whole.len() {
+            assert_eq!(chunk_is_keyframe(&whole[..end]), end == whole.len(), "{end} bytes");
+        }
+
+        let mut state = 1u32;
+        for len in 0..2000 {
+            let garbage: Vec<u8> = (0..len % 64)
+                .map(|_| {
+                    state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                    // Small lengths now and then, so some parse
+                    if state.is_multiple_of(5) { 0 } else { (state >> 24) as u8 }
+                })
+                .collect();
+            chunk_is_keyframe(&garbage);
+        }
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[test]
+    fn chunks_carry_their_capture_time_and_keyframe_flag() {
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, PipelineOptions::default()).unwrap();
+        let start = Instant::now();
+        let mut encode = |i: u32, force_idr: bool| {
+            let at = start + Duration::from_millis(i as u64 * 16);
+            let encoded = pipeline.encode(captured(test_frames::text_like(64, 48, i)), at, force_idr).unwrap().unwrap();
+            let chunk = encoded.chunks.into_iter().next_back().unwrap();
+            assert_eq!(chunk.capture_ts, at);
+            assert_eq!(chunk.is_keyframe, chunk_is_keyframe(&chunk.data));
+            chunk.is_keyframe
+        };
+        assert!(encode(0, false), "first frame");
+        assert!(!encode(1, false));
+        assert!(encode(2, true), "forced IDR");
+        assert!(!encode(3, false));
+    }
+
+    #[test]
+    fn stats_count_frames_keyframes_and_bytes() {
+        let chunk = |bytes: usize, is_keyframe: bool, encode_ms: u64| EncodedChunk {
+            encode_duration: Duration::from_millis(encode_ms),
+            ..EncodedChunk::new(vec![0; bytes], is_keyframe)
+        };
+        let mut stats = EncoderStats::default();
+        stats.record(&chunk(5000, true, 10));
+        assert_eq!(stats.avg_encode_ms, 10.0);
+        stats.record(&chunk(300, false, 20));
+        stats.record(&chunk(200, false, 20));
+        assert_eq!((stats.frames_encoded, stats.total_frames_encoded, stats.keyframes), (3, 3, 1));
+        assert_eq!((stats.bytes_out, stats.last_frame_bytes), (5500, 200));
+        // Moving towards the newer encode times, not jumping to them
+        assert!(stats.avg_encode_ms > 10.0 && stats.avg_encode_ms < 20.0);
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[test]
+    fn stats_restart_with_the_encoder_but_keep_the_total() {
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, PipelineOptions::default()).unwrap();
+        let mut bytes = 0;
+        for i in 0..3 {
+            let frame = captured(test_frames::text_like(64, 48, i));
+            let encoded = pipeline.encode(frame, Instant::now(), false).unwrap().unwrap();
+            bytes += encoded.chunks.iter().map(|chunk| chunk.data.len() as u64).sum::<u64>();
+        }
+        let stats = pipeline.stats();
+        assert_eq!((stats.frames_encoded, stats.total_frames_encoded, stats.keyframes), (3, 3, 1));
+        assert_eq!(stats.bytes_out, bytes);
+        assert_eq!(stats.bitrate_bps, 500_000);
+
+        pipeline.encode(captured(test_frames::text_like(96, 64, 3)), Instant::now(), false).unwrap();
+        let stats = pipeline.stats();
+        assert_eq!((stats.frames_encoded, stats.total_frames_encoded, stats.keyframes), (1, 4, 1));
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[test]
+    fn base_layer_alone_decodes() {
+        let options = PipelineOptions {
+            output_format: OutputFormat::AnnexB,
+            ..PipelineOptions::default()
+        };
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, options).unwrap();
+        let mut chunks = Vec::new();
+        for i in 0..20 {
+            let encoded = pipeline.encode(captured(test_frames::text_like(64, 48, i)), Instant::now(), false).unwrap();
+            chunks.extend(encoded.into_iter().flat_map(|encoded| encoded.chunks));
+        }
+        assert!(chunks.iter().any(|chunk| chunk.temporal_id > 0), "no enhancement layer");
+
+        // What a half-rate viewer gets
+        let mut decoder = openh264::decoder::Decoder::new().unwrap();
+        let base: Vec<_> = chunks.iter().filter(|chunk| chunk.temporal_id == 0).collect();
+        assert!(base[0].is_keyframe);
+        for (i, chunk) in base.iter().enumerate() {
+            let decoded = decoder.decode(&chunk.data).unwrap_or_else(|err| panic!("base frame {}: {}", i, err));
+            assert!(decoded.is_some(), "base frame {} gave no picture", i);
+        }
+    }
+
+    /// Types of the NAL units in an Annex-B chunk, None if it doesn't start
+    /// with a start code
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    fn annex_b_nal_types(data: &[u8]) -> Option<Vec<u8>> {
+        if !data.starts_with(&START_CODE) {
+            return None;
+        }
+        let starts: Vec<_> = data.windows(4).enumerate().filter(|(_, w)| *w == START_CODE).map(|(i, _)| i).collect();
+        Some(starts.iter().map(|start| data[start + 4] & 0x1F).collect())
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[test]
+    fn annex_b_round_trips_through_the_decoder() {
+        let options = PipelineOptions {
+            output_format: OutputFormat::AnnexB,
+            ..PipelineOptions::default()
+        };
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, options).unwrap();
+        let mut chunks = Vec::new();
+        let mut configs = Vec::new();
+        for i in 0..12 {
+            let encoded = pipeline.encode(captured(test_frames::text_like(64, 48, i)), Instant::now(), i == 6).unwrap();
+            if let Some(encoded) = encoded {
+                configs.extend(encoded.new_config);
+                chunks.extend(encoded.chunks);
+            }
+        }
+        // Consumers parse the parameter sets in-band
+        assert_eq!(configs.len(), 1);
+        assert!(configs[0].description_b64.is_empty());
+
+        let keyframes: Vec<_> = chunks.iter().enumerate().filter(|(_, chunk)| chunk.is_keyframe).collect();
+        assert_eq!(keyframes.len(), 2);
+        for chunk in &chunks {
+            let types = annex_b_nal_types(&chunk.data).expect("chunk without a start code");
+            assert_eq!(chunk.is_keyframe, types.contains(&5), "{types:?}");
+            if chunk.is_keyframe {
+                assert!(types.contains(&7) && types.contains(&8), "keyframe without SPS/PPS: {types:?}");
+            }
+        }
+
+        // From the start, and joining at the forced keyframe with no
+        // parameter sets seen before
+        for (first, _) in keyframes {
+            let mut decoder = openh264::decoder::Decoder::new().unwrap();
+            for (i, chunk) in chunks.iter().enumerate().skip(first) {
+                let decoded = decoder.decode(&chunk.data).unwrap_or_else(|err| panic!("frame {}: {}", i, err));
+                assert!(decoded.is_some(), "frame {} gave no picture", i);
+            }
+        }
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[test]
+    fn sliced_encodes_decode_to_the_source_picture() {
+        let (width, height) = (640, 360);
+        let options = PipelineOptions {
+            output_format: OutputFormat::AnnexB,
+            rate_control: RateControl::Bitrate(8_000_000),
+            threads: Some(4),
+            ..PipelineOptions::default()
+        };
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, options).unwrap();
+        let mut decoder = openh264::decoder::Decoder::new().unwrap();
+        let mut source = I420::default();
+        for step in 0..8 {
+            let frame = test_frames::moving_gradient(width, height, step);
+            let (w, h) = (width as usize, height as usize);
+            source.convert(&frame.raw, PixelFormat::Bgra8888, w * 4, w, h, options.color_matrix);
+            let encoded = pipeline.encode(captured(frame), Instant::now(), false).unwrap();
+            for chunk in encoded.map(|encoded| encoded.chunks).unwrap_or_default() {
+                let types = annex_b_nal_types(&chunk.data).expect("chunk without a start code");
+                let slices = types.iter().filter(|&&t| t == 1 || t == 5).count();
+                assert!(slices > 1, "frame {step} coded as one slice: {types:?}");
+
+                let decoded = decoder.decode(&chunk.data).unwrap().expect("no picture");
+                assert_eq!(decoded.dimension_rgb(), (w, h));
+                let (stride, _, _) = decoded.strides_yuv();
+                let luma = decoded.y_with_stride();
+                let error: u64 = (0..h)
+                    .flat_map(|y| (0..w).map(move |x| (x, y)))
+                    .map(|(x, y)| luma[y * stride + x].abs_diff(source.y[y * w + x]) as u64)
+                    .sum();
+                let mean = error as f64 / (w * h) as f64;
+                assert!(mean < 3.0, "frame {step}: luma off by {mean:.2} on average");
+            }
+        }
+    }
+
+    /// `frame` with `pad` junk pixels after every row, and its stride
+    fn padded(frame: &Frame, pad: usize) -> (Frame, usize) {
+        let row = packed_stride(frame);
+        let stride = row + pad * 4;
+        let mut raw = Vec::with_capacity(stride * frame.height as usize);
+        for line in frame.raw.chunks_exact(row) {
+            raw.extend_from_slice(line);
+            raw.extend(std::iter::repeat_n(0xee, pad * 4));
+        }
+        let (width, height) = (frame.width, frame.height);
+        (Frame { width, height, raw }, stride)
+    }
+
+    #[test]
+    fn png_snapshots_decode_to_the_captured_pixels() {
+        let source = test_frames::moving_gradient(31, 17, 5);
+        for pad in [0, 3] {
+            let (frame, stride) = padded(&source, pad);
+            let png = VideoPipeline::snapshot(&frame, stride, PixelFormat::Bgra8888, ImageFormat::Png, 0).unwrap();
+            let image = xcap::image::load_from_memory_with_format(&png, ImageFormat::Png).unwrap().to_rgba8();
+            assert_eq!(image.dimensions(), (31, 17));
+            for (x, y, px) in image.enumerate_pixels() {
+                let at = (y * 31 + x) as usize * 4;
+                let [b, g, r, a] = source.raw[at..at + 4] else { unreachable!() };
+                assert_eq!(px.0, [r, g, b, a], "({x}, {y}) with {pad} padding");
+            }
+        }
+    }
+
+    #[cfg(feature = "mjpeg")]
+    #[test]
+    fn jpeg_snapshots_decode_at_full_size() {
+        let (frame, stride) = padded(&test_frames::color_bars(128, 20), 2);
+        let jpeg = VideoPipeline::snapshot(&frame, stride, PixelFormat::Bgra8888, ImageFormat::Jpeg, 90).unwrap();
+        let image = xcap::image::load_from_memory_with_format(&jpeg, ImageFormat::Jpeg).unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (128, 20));
+        // The middle of the red bar stays red
+        let px = image.get_pixel(88, 10).0;
+        assert!((px[0] as i32 - 191).abs() < 16 && px[1] < 16 && px[2] < 16, "{px:?}");
+        let small = VideoPipeline::snapshot(&frame, stride, PixelFormat::Bgra8888, ImageFormat::Jpeg, 10).unwrap();
+        assert!(small.len() < jpeg.len());
+    }
+
+    #[test]
+    fn snapshots_refuse_short_buffers_and_other_formats() {
+        let mut frame = test_frames::color_bars(8, 8);
+        assert!(VideoPipeline::snapshot(&frame, 8 * 4, PixelFormat::Rgba8888, ImageFormat::Gif, 80).is_err());
+        frame.raw.truncate(8 * 7 * 4 + 5);
+        assert!(VideoPipeline::snapshot(&frame, 8 * 4, PixelFormat::Rgba8888, ImageFormat::Png, 80).is_err());
+    }
+
+    /// A `width` x `height` I420 frame of mid grey with `pad` bytes after each row
+    fn grey_i420(width: u32, height: u32, pad: usize) -> PipelineFrame {
+        let (w, h) = (width as usize, height as usize);
+        PipelineFrame::I420 {
+            y: Bytes::from(vec![128; (w + pad) * h]),
+            u: Bytes::from(vec![128; (w / 2 + pad) * h / 2]),
+            v: Bytes::from(vec![128; (w / 2 + pad) * h / 2]),
+            width,
+            height,
+            strides: [w + pad, w / 2 + pad, w / 2 + pad],
+        }
+    }
+
+    #[test]
+    fn planes_too_short_for_their_strides_are_refused() {
+        assert!(grey_i420(64, 48, 0).check().is_ok());
+        assert!(grey_i420(64, 48, 16).check().is_ok());
+        let PipelineFrame::I420 { y, u, v, .. } = grey_i420(64, 48, 0) else { unreachable!() };
+        let short_v = PipelineFrame::I420 {
+            y: y.clone(),
+            u: u.clone(),
+            v: v.slice(1..),
+            width: 64,
+            height: 48,
+            strides: [64, 32, 32],
+        };
+        assert!(short_v.check().is_err());
+        // A stride narrower than the row reads the wrong pixels
+        let narrow = PipelineFrame::I420 {
+            y,
+            u,
+            v,
+            width: 64,
+            height: 48,
+            strides: [64, 16, 32],
+        };
+        assert!(narrow.check().is_err());
+        let nv12 = |uv_len: usize| PipelineFrame::Nv12 {
+            y: Bytes::from(vec![0; 64 * 48]),
+            uv: Bytes::from(vec![0; uv_len]),
+            width: 64,
+            height: 48,
+            strides: [64, 64],
+        };
+        assert!(nv12(64 * 24).check().is_ok());
+        assert!(nv12(64 * 24 - 1).check().is_err());
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[test]
+    fn yuv_frames_encode_without_conversion() {
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, PipelineOptions::default()).unwrap();
+        let nv12 = PipelineFrame::Nv12 {
+            y: Bytes::from(vec![128; 80 * 48]),
+            uv: Bytes::from(vec![128; 80 * 24]),
+            width: 64,
+            height: 48,
+            strides: [80, 80],
+        };
+        let mut chunks = Vec::new();
+        for frame in [grey_i420(64, 48, 8), nv12, grey_i420(64, 48, 0)] {
+            let encoded = pipeline.encode(frame, Instant::now(), false).unwrap().unwrap();
+            if let Some(config) = encoded.new_config {
+                assert_eq!((config.width, config.height), (64, 48));
+            }
+            chunks.extend(encoded.chunks);
+        }
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks[0].is_keyframe && !chunks[1].is_keyframe);
+        assert_eq!(pipeline.stats().frames_encoded, 3);
+
+        let short = PipelineFrame::Nv12 {
+            y: Bytes::from(vec![128; 64 * 48]),
+            uv: Bytes::from(vec![128; 100]),
+            width: 64,
+            height: 48,
+            strides: [64, 64],
+        };
+        assert!(pipeline.encode(short, Instant::now(), false).is_err());
+    }
+
+    /// An H.264 config for `width` x `height`, with no description yet
+    fn test_config(width: u32, height: u32) -> VideoConfig {
+        VideoConfig {
+            codec: VideoCodec::Avc,
+            width,
+            height,
+            description_b64: String::new(),
+            color_matrix: ColorMatrix::default(),
+        }
+    }
+
+    /// A synthetic capture as the Recorder hands it to the pipeline
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    fn captured(frame: Frame) -> PipelineFrame {
+        PipelineFrame::Rgba {
+            stride: packed_stride(&frame),
+            frame: Arc::new(frame),
+            format: PixelFormat::Bgra8888,
+        }
+    }
+
+    /// The parameter sets in an avcC record, after checking its layout
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    fn parse_avcc_record(record: &[u8]) -> (Vec<&[u8]>, Vec<&[u8]>) {
+        assert!(record.len() >= 7, "{} byte record", record.len());
+        assert_eq!(record[0], 1, "configurationVersion");
+        assert_eq!(record[4] & 0x03, 3, "4-byte NAL lengths");
+        fn sets<'a>(rest: &mut &'a [u8], nal_type: u8) -> Vec<&'a [u8]> {
+            let count = rest[0] as usize & if nal_type == 7 { 0x1F } else { 0xFF };
+            *rest = &rest[1..];
+            (0..count)
+                .map(|_| {
+                    let length = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                    let nal = &rest[2..2 + length];
+                    assert_eq!(nal[0] & 0x1F, nal_type);
+                    *rest = &rest[2 + length..];
+                    nal
+                })
+                .collect()
+        }
+        let mut rest = &record[5..];
+        let sps = sets(&mut rest, 7);
+        let pps = sets(&mut rest, 8);
+        assert!(rest.is_empty(), "{} trailing bytes", rest.len());
+        (sps, pps)
+    }
+
+    /// The NAL units of an AVCC chunk; panics unless the lengths add up to
+    /// exactly the payload
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    fn avcc_nals(data: &[u8]) -> Vec<&[u8]> {
+        let mut nals = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let (length, after) = rest.split_first_chunk::<4>().expect("truncated length");
+            let length = u32::from_be_bytes(*length) as usize;
+            assert!(length > 0 && length <= after.len(), "NAL of {} bytes with {} left", length, after.len());
+            nals.push(&after[..length]);
+            rest = &after[length..];
+        }
+        nals
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[test]
+    fn the_first_encode_hands_out_a_valid_avcc_record() {
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, PipelineOptions::default()).unwrap();
+        let encoded = pipeline.encode(captured(test_frames::color_bars(320, 240)), Instant::now(), false);
+        let encoded = encoded.unwrap().unwrap();
+        let config = encoded.new_config.expect("no config with the first frame");
+        assert_eq!((config.width, config.height), (320, 240));
+        let record = B64.decode(&config.description_b64).unwrap();
+        let (sps, pps) = parse_avcc_record(&record);
+        assert!(!sps.is_empty() && !pps.is_empty());
+        // Profile, compatibility and level come from the SPS
+        assert_eq!(record[1..4], sps[0][1..4]);
+        assert!(config.codec_string().starts_with("avc1."));
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[test]
+    fn chunk_lengths_account_for_every_byte() {
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, PipelineOptions::default()).unwrap();
+        for step in 0..10 {
+            let frame = match step % 3 {
+                0 => test_frames::moving_gradient(160, 96, step),
+                1 => test_frames::text_like(160, 96, step),
+                _ => test_frames::color_bars(160, 96),
+            };
+            for chunk in pipeline.encode(captured(frame), Instant::now(), false).unwrap().unwrap().chunks {
+                let nals = avcc_nals(&chunk.data);
+                assert!(!nals.is_empty());
+                assert_eq!(chunk.is_keyframe, nals.iter().any(|nal| nal[0] & 0x1F == 5), "frame {}", step);
+            }
+        }
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[test]
+    fn a_forced_idr_mid_stream_is_a_keyframe() {
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, PipelineOptions::default()).unwrap();
+        let keyframes: Vec<bool> = (0..8)
+            .map(|step| {
+                let frame = captured(test_frames::moving_gradient(128, 72, step));
+                let encoded = pipeline.encode(frame, Instant::now(), step == 5).unwrap().unwrap();
+                let chunk = encoded.chunks.last().unwrap();
+                assert_eq!(chunk.is_keyframe, chunk_is_keyframe(&chunk.data));
+                chunk.is_keyframe
+            })
+            .collect();
+        assert_eq!(keyframes, [true, false, false, false, false, true, false, false]);
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[test]
+    fn a_resolution_change_regenerates_the_config() {
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, PipelineOptions::default()).unwrap();
+        let mut configs = Vec::new();
+        for (step, (width, height)) in [(320, 240), (320, 240), (640, 360), (640, 360)].into_iter().enumerate() {
+            let frame = captured(test_frames::text_like(width, height, step as u32));
+            let encoded = pipeline.encode(frame, Instant::now(), false).unwrap().unwrap();
+            if let Some(config) = &encoded.new_config {
+                assert!(encoded.chunks[0].is_keyframe, "new config without a keyframe");
+                parse_avcc_record(&B64.decode(&config.description_b64).unwrap());
+            }
+            configs.push(encoded.new_config.map(|config| (config.width, config.height)));
+        }
+        assert_eq!(configs, [Some((320, 240)), None, Some((640, 360)), None]);
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[test]
+    fn chunks_before_the_config_follow_it_exactly_once() {
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, PipelineOptions::default()).unwrap();
+        let config = |description: &str| VideoConfig {
+            description_b64: description.to_string(),
+            ..test_config(64, 48)
+        };
+        let chunk = |byte: u8, is_keyframe: bool| EncodedChunk::new(vec![byte], is_keyframe);
+
+        // The encoder hasn't produced its parameter sets yet
+        assert!(pipeline.hand_out(chunk(1, true), config("")).is_none());
+        assert!(pipeline.hand_out(chunk(2, false), config("")).is_none());
+        let first = pipeline.hand_out(chunk(3, false), config("AAAA")).unwrap();
+        assert_eq!(first.new_config.unwrap().description_b64, "AAAA");
+        let sent: Vec<_> = first.chunks.iter().map(|chunk| (chunk.data[0], chunk.is_keyframe)).collect();
+        assert_eq!(sent, [(1, true), (2, false), (3, false)]);
+
+        let next = pipeline.hand_out(chunk(4, false), config("AAAA")).unwrap();
+        assert!(next.new_config.is_none());
+        assert_eq!(next.chunks.len(), 1);
+        assert_eq!(next.chunks[0].data[0], 4);
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[test]
+    fn held_chunks_start_at_the_latest_keyframe_and_are_bounded() {
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, PipelineOptions::default()).unwrap();
+        let config = |description: &str| VideoConfig {
+            description_b64: description.to_string(),
+            ..test_config(64, 48)
+        };
+        for byte in 0..4 {
+            assert!(pipeline.hand_out(EncodedChunk::new(vec![byte], byte == 0), config("")).is_none());
+        }
+        // A second keyframe makes the frames before it useless
+        for byte in 4..20 {
+            assert!(pipeline.hand_out(EncodedChunk::new(vec![byte], byte == 4), config("")).is_none());
+        }
+        let released = pipeline.hand_out(EncodedChunk::new(vec![20], false), config("AAAA")).unwrap();
+        let bytes: Vec<_> = released.chunks.iter().map(|chunk| chunk.data[0]).collect();
+        assert_eq!(bytes, (20 - MAX_HELD_CHUNKS as u8..=20).collect::<Vec<_>>());
+    }
+
+    /// Parameter sets x265 writes for 1280x720 Main, level 3.1
+    const HEVC_VPS: &[u8] = &[
+        0x40, 0x01, 0x0c, 0x01, 0xff, 0xff, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00, 0x03, 0x00, 0x00,
+        0x03, 0x00, 0x5d, 0x95, 0x98, 0x09,
+    ];
+    const HEVC_SPS: &[u8] = &[
+        0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x00, 0x5d,
+        0xa0, 0x02, 0x80, 0x80, 0x2d, 0x16, 0x59, 0x59, 0xa4, 0x93, 0x2b, 0xc0, 0x5a, 0x70, 0x20, 0x00, 0x00, 0x03,
+        0x00, 0x20, 0x00, 0x00, 0x03, 0x03, 0xc1,
+    ];
+    const HEVC_PPS: &[u8] = &[0x44, 0x01, 0xc1, 0x72, 0xb4, 0x62, 0x40];
+
+    #[test]
+    fn hvcc_is_built_from_the_parameter_sets() {
+        // An IDR slice alongside is ignored
+        let nals = [HEVC_VPS, HEVC_SPS, HEVC_PPS, &[0x26, 0x01, 0xaf]].map(<[u8]>::to_vec);
+        let hvcc = build_hvcc_from_nals(&nals).unwrap().unwrap();
+        let mut expected = vec![
+            0x01, // configurationVersion
+            // Main profile, main tier, compatible with Main and Main 10,
+            // progressive and frame-only, level 93 (the SPS's emulation
+            // prevention bytes removed)
+            0x01, 0x60, 0x00, 0x00, 0x00, 0x90, 0x00, 0x00, 0x00, 0x00, 0x00, 0x5d,
+            0xf0, 0x00, // min_spatial_segmentation_idc
+            0xfc, // parallelismType
+            0xfd, // 4:2:0
+            0xf8, 0xf8, // 8-bit luma and chroma
+            0x00, 0x00, // avgFrameRate
+            0x0f, // one temporal layer, nested, 4-byte lengths
+            0x03, // numOfArrays
+        ];
+        for (nal_type, nal) in [(0xa0, HEVC_VPS), (0xa1, HEVC_SPS), (0xa2, HEVC_PPS)] {
+            expected.extend_from_slice(&[nal_type, 0x00, 0x01]);
+            expected.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+            expected.extend_from_slice(nal);
+        }
+        assert_eq!(hvcc, expected);
+        assert_eq!(codec_string_from_hvcc(&hvcc).as_deref(), Some("hev1.1.6.L93.90"));
+
+        let config = VideoConfig {
+            codec: VideoCodec::Hevc,
+            description_b64: B64.encode(&hvcc),
+            ..test_config(1280, 720)
+        };
+        assert_eq!(config.codec_string(), "hev1.1.6.L93.90");
+    }
+
+    #[test]
+    fn hvcc_needs_every_parameter_set_and_a_readable_sps() {
+        assert_eq!(build_hvcc_from_nals(&[HEVC_VPS, HEVC_SPS].map(<[u8]>::to_vec)).unwrap(), None);
+        assert_eq!(build_hvcc_from_nals(&[HEVC_SPS, HEVC_PPS].map(<[u8]>::to_vec)).unwrap(), None);
+        let truncated = HEVC_SPS[..20].to_vec();
+        assert!(build_hvcc_from_nals(&[HEVC_VPS.to_vec(), truncated, HEVC_PPS.to_vec()]).is_err());
+        // Without a record the fixed string stands in
+        assert_eq!(codec_string_from_hvcc(&[1, 2, 3]), None);
+        let config = VideoConfig {
+            codec: VideoCodec::Hevc,
+            description_b64: String::new(),
+            ..test_config(1280, 720)
+        };
+        assert_eq!(config.codec_string(), "hev1.1.6.L93.B0");
+    }
+
+    #[test]
+    fn hevc_codec_strings_carry_space_tier_and_constraints() {
+        let mut hvcc = vec![0u8; 23];
+        hvcc[0] = 1;
+        // Profile space 1, high tier, Main 10; compatible with Main 10 only
+        hvcc[1] = 0x40 | 0x20 | 2;
+        hvcc[2..6].copy_from_slice(&0x2000_0000u32.to_be_bytes());
+        hvcc[6] = 0xb0;
+        hvcc[8] = 0x01;
+        hvcc[12] = 153;
+        assert_eq!(codec_string_from_hvcc(&hvcc).as_deref(), Some("hev1.A2.4.H153.B0.0.1"));
+    }
+
+    #[test]
+    fn rate_control_parses_modes_and_qps() {
+        assert_eq!("bitrate".parse(), Ok(RateControl::Bitrate(0)));
+        assert_eq!("bitrate:2500".parse(), Ok(RateControl::Bitrate(2_500_000)));
+        assert_eq!("quality".parse(), Ok(RateControl::Quality(DEFAULT_QUALITY_QP)));
+        assert_eq!("quality:30".parse(), Ok(RateControl::Quality(30)));
+        assert_eq!("off:20".parse(), Ok(RateControl::Off { qp: 20 }));
+        for invalid in ["quality:52", "off:-1", "bitrate:fast", "vbr"] {
+            assert!(invalid.parse::<RateControl>().is_err(), "{}", invalid);
+        }
+        for mode in [RateControl::Bitrate(0), RateControl::Bitrate(800_000), RateControl::Quality(30)] {
+            assert_eq!(mode.to_string().parse(), Ok(mode));
+        }
+    }
+
+    #[test]
+    fn qp_modes_bound_the_quantizer() {
+        assert_eq!(RateControl::Bitrate(1_000_000).qp_bounds(), None);
+        assert_eq!(RateControl::Quality(24).qp_bounds(), Some((20, 28)));
+        assert_eq!(RateControl::Quality(2).qp_bounds(), Some((0, 6)));
+        assert_eq!(RateControl::Quality(50).qp_bounds(), Some((46, MAX_QP)));
+        assert_eq!(RateControl::Off { qp: 26 }.qp_bounds(), Some((26, 26)));
+    }
+
+    /// Average bytes per frame for `frames` encoded with `rate_control`
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    fn average_frame_bytes(rate_control: RateControl, frames: impl Fn(u32) -> PipelineFrame) -> usize {
+        let options = PipelineOptions {
+            rate_control,
+            max_fps: 30.0,
+            ..PipelineOptions::default()
+        };
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, options).unwrap();
+        let start = Instant::now();
+        let mut total = 0;
+        for i in 0..30 {
+            let at = start + Duration::from_millis(i as u64 * 33);
+            let encoded = pipeline.encode(frames(i), at, false).unwrap();
+            // Skip the first frame, a keyframe
+            if i > 0 {
+                total += encoded.map_or(0, |e| e.chunks.iter().map(|c| c.data.len()).sum::<usize>());
+            }
+        }
+        total / 29
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[test]
+    fn quality_mode_spends_bits_where_the_detail_is() {
+        let noisy = |i| captured(test_frames::text_like(320, 240, i));
+        let flat = |_| captured(test_frames::color_bars(320, 240));
+        let quality = RateControl::Quality(DEFAULT_QUALITY_QP);
+        // 100 kbps at 30 fps leaves ~400 bytes a frame
+        let capped = RateControl::Bitrate(100_000);
+        let (quality_noisy, quality_flat) = (average_frame_bytes(quality, noisy), average_frame_bytes(quality, flat));
+        let capped_noisy = average_frame_bytes(capped, noisy);
+        assert!(quality_noisy > capped_noisy * 2, "quality {} vs capped {} bytes", quality_noisy, capped_noisy);
+        assert!(quality_flat * 10 < quality_noisy, "flat {} vs noisy {} bytes", quality_flat, quality_noisy);
+    }
+
+    /// Capture times `start` onwards with gaps of 5-400 ms, and one 3 s stall
+    fn irregular_captures(start: Instant) -> Vec<Instant> {
+        let mut state = 7u32;
+        let mut at = start;
+        (0..200)
+            .map(|i| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let gap = if i == 100 { 3000 } else { 5 + (state >> 16) % 396 };
+                at += Duration::from_millis(gap as u64);
+                at
+            })
+            .collect()
+    }
+
+    #[test]
+    fn irregular_frames_still_get_keyframes_on_time() {
+        let interval = Duration::from_secs(2);
+        let captures = irregular_captures(Instant::now());
+        let idrs = keyframes(Some(interval), &captures);
+        assert!(idrs.len() > 10);
+        for pair in idrs.windows(2) {
+            let gap = pair[1] - pair[0];
+            // Never early, and late by no more than the wait for the next frame
+            assert!(gap >= interval, "{gap:?}");
+            assert!(gap < interval + Duration::from_millis(400) || gap >= Duration::from_secs(3), "{gap:?}");
+        }
+        // The first frame after the stall
+        let stall_end = captures[100];
+        assert!(idrs.contains(&stall_end));
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[test]
+    fn encode_forces_keyframes_at_the_wall_clock_interval() {
+        let interval = Duration::from_secs(2);
+        let options = PipelineOptions {
+            keyframe_interval: Some(interval),
+            max_fps: 30.0,
+            ..PipelineOptions::default()
+        };
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, options).unwrap();
+        let captures = irregular_captures(Instant::now());
+        let mut idrs = Vec::new();
+        for (i, &at) in captures.iter().enumerate() {
+            let frame = captured(test_frames::text_like(64, 48, i as u32));
+            let encoded = pipeline.encode(frame, at, false).unwrap().unwrap();
+            if encoded.chunks.iter().any(|chunk| chunk.is_keyframe) {
+                idrs.push(at);
+            }
+            assert!(!pipeline.keyframe_due(at));
+        }
+        assert_eq!(idrs, keyframes(Some(interval), &captures));
+    }
+
+    #[test]
+    fn color_matrices_name_and_signal_themselves() {
+        assert_eq!(ColorMatrix::default(), ColorMatrix::Bt709);
+        for (matrix, name, code, full_range) in [
+            (ColorMatrix::Bt709, "bt709", 1, false),
+            (ColorMatrix::Bt601, "bt601", 6, false),
+            (ColorMatrix::Bt709Full, "bt709-full", 1, true),
+            (ColorMatrix::Bt601Full, "bt601-full", 6, true),
+        ] {
+            assert_eq!(matrix.as_str(), name);
+            assert_eq!(serde_json::to_value(matrix).unwrap(), name);
+            assert_eq!(matrix.description_code(), code);
+            assert_eq!(matrix.full_range(), full_range);
+        }
+    }
+
+    #[cfg(all(feature = "openh264-encoder", not(feature = "videotoolbox")))]
+    #[test]
+    fn video_config_reports_the_color_matrix() {
+        let options = PipelineOptions {
+            color_matrix: ColorMatrix::Bt601Full,
+            ..PipelineOptions::default()
+        };
+        let mut pipeline = VideoPipeline::new(VideoCodec::Avc, options).unwrap();
+        let frame = captured(test_frames::text_like(64, 48, 0));
+        let encoded = pipeline.encode(frame, Instant::now(), false).unwrap().unwrap();
+        assert_eq!(encoded.new_config.unwrap().color_matrix, ColorMatrix::Bt601Full);
+        assert_eq!(pipeline.config().color_matrix, ColorMatrix::Bt601Full);
+    }
+
+    #[test]
+    fn keyframe_interval_sets_the_encoder_period_in_frames() {
+        let options = |secs: Option<f64>, max_fps: f32| PipelineOptions {
+            keyframe_interval: secs.map(Duration::from_secs_f64),
+            max_fps,
+            ..PipelineOptions::default()
+        };
+        assert_eq!(options(Some(4.0), 30.0).keyframe_interval_frames(), 120);
+        assert_eq!(options(Some(0.5), 25.0).keyframe_interval_frames(), 13);
+        assert_eq!(options(None, 60.0).keyframe_interval_frames(), 0);
+    }
+
+    #[test]
+    fn thirty_seconds_at_30fps_has_a_keyframe_every_4s() {
+        let start = Instant::now();
+        let captures: Vec<_> = (0..30 * 30).map(|i| start + Duration::from_millis(i * 1000 / 30)).collect();
+        let idrs = keyframes(Some(Duration::from_secs(4)), &captures);
+        // 0, 4, 8, ... 28 s
+        assert_eq!(idrs.len(), 8);
+        for pair in idrs.windows(2) {
+            let gap = pair[1] - pair[0];
+            assert!(gap >= Duration::from_secs(4) && gap < Duration::from_millis(4034), "{gap:?}");
+        }
+        assert_eq!(keyframes(None, &captures).len(), 1);
+    }
+
+    /// Runs 60 fps captures through a one-frame mailbox into an encoder that
+    /// takes `encode_ms(i)` for the frame of capture `i` if it is admitted.
+    /// Returns the capture index of each admitted frame, the most frames ever
+    /// waiting, and the policy's divisor after each capture.
+    fn simulate(
+        policy: &mut InputPolicy,
+        captures: usize,
+        encode_ms: impl Fn(usize) -> f64,
+    ) -> (Vec<usize>, usize, Vec<u32>) {
+        let start = Instant::now();
+        let at = |i: usize| start + Duration::from_secs_f64(i as f64 / 60.0);
+        let (mut admitted, mut divisors) = (Vec::new(), Vec::new());
+        let mut mailbox: Option<usize> = None;
+        let mut replaced = 0;
+        let mut busy_until = start;
+        let mut deepest = 0;
+        // One more round after the last capture lets the encoder take it
+        for i in 0..=captures {
+            // The encoder takes whatever is waiting as soon as it is free
+            while let Some(frame) = mailbox.filter(|_| busy_until <= at(i)) {
+                mailbox = None;
+                let taken = busy_until.max(at(frame));
+                if policy.admit(replaced, at(frame), encode_ms(frame)) {
+                    admitted.push(frame);
+                    busy_until = taken + Duration::from_secs_f64(encode_ms(frame) / 1000.0);
+                }
+            }
+            if i == captures {
+                break;
+            }
+            if mailbox.replace(i).is_some() {
+                replaced += 1;
+            }
+            deepest = deepest.max(mailbox.iter().count());
+            divisors.push(policy.divisor);
+        }
+        (admitted, deepest, divisors)
+    }
+
+    #[test]
+    fn a_slow_encoder_settles_on_a_reduced_rate() {
+        let mut policy = InputPolicy::new(DEFAULT_DROP_STREAK);
+        let (admitted, deepest, divisors) = simulate(&mut policy, 60 * 20, |_| 40.0);
+        assert_eq!(deepest, 1);
+        // A 40 ms encode can't keep up with 60 fps but fits 30; the divisor
+        // steps down within the first second and then holds
+        let settled = divisors[60];
+        assert!(settled > 1, "{divisors:?}");
+        assert!(divisors[60..].iter().all(|d| *d == settled), "{divisors:?}");
+        assert!(policy.dropped > 0);
+        // Every later second encodes the same number of frames
+        let per_second: Vec<_> = (2..20).map(|s| admitted.iter().filter(|i| **i / 60 == s).count()).collect();
+        let (fewest, most) = (per_second.iter().min().unwrap(), per_second.iter().max().unwrap());
+        assert!(most - fewest <= 1, "{per_second:?}");
+        assert!(*fewest >= 15 && *most <= 25, "{per_second:?}");
+        assert!((policy.effective_fps() - 60.0 / settled as f32).abs() < 1.0, "{}", policy.effective_fps());
+    }
+
+    #[test]
+    fn a_fast_encoder_takes_every_capture() {
+        let mut policy = InputPolicy::new(DEFAULT_DROP_STREAK);
+        let (admitted, _, divisors) = simulate(&mut policy, 600, |_| 5.0);
+        assert_eq!(admitted.len(), 600);
+        assert!(divisors.iter().all(|d| *d == 1));
+        assert_eq!(policy.dropped, 0);
+        assert!((policy.effective_fps() - 60.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn the_rate_comes_back_once_encoding_speeds_up() {
+        let mut policy = InputPolicy::new(DEFAULT_DROP_STREAK);
+        let (admitted, _, divisors) = simulate(&mut policy, 60 * 20, |i| if i < 600 { 40.0 } else { 5.0 });
+        assert!(divisors[599] > 1);
+        assert_eq!(*divisors.last().unwrap(), 1, "{divisors:?}");
+        assert_eq!(admitted.iter().filter(|i| **i >= 60 * 18).count(), 120);
+    }
+
+    #[test]
+    fn a_zero_streak_limit_never_reduces_the_rate() {
+        let mut policy = InputPolicy::new(0);
+        let (_, deepest, divisors) = simulate(&mut policy, 600, |_| 40.0);
+        assert_eq!(deepest, 1);
+        assert!(divisors.iter().all(|d| *d == 1));
+        assert!(policy.dropped > 0);
+    }
 }
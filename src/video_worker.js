@@ -1,9 +1,20 @@
-// WebCodecs-based decoder worker for H.264/HEVC.
+// WebCodecs-based decoder worker for H.264/HEVC/AV1.
+
+const VIDEO_MAGIC = [0x56, 0x49, 0x44, 0x30]; // "VID0"
+const VIDEO_HEADER_BYTES = 25;
+const FLAG_KEYFRAME = 0x01;
+const FLAG_DISCARD = 0x02;
 
 let decoder = null;
 let configured = false;
+// AVCC payloads can be scanned for IDR NALs; AV1 OBUs rely on the keyframe flag
+let scanNals = true;
 let waitingForKey = true;
 let droppedSinceConfig = 0;
+// Last VID0 sequence number seen, to spot chunks lost in between
+let lastSequence = null;
+// Timestamps (µs) of chunks to decode but not display (preroll before a seek target)
+const discardTimestamps = new Set();
 
 self.onmessage = async (event) => {
   // console.log("videoWorker.onmessage <=", event.data);
@@ -63,15 +74,36 @@ async function configure(config) {
     hardwareAcceleration: "prefer-hardware",
   });
   configured = true;
+  scanNals = config.codec.startsWith("avc1");
   waitingForKey = true;
   droppedSinceConfig = 0;
+  lastSequence = null;
+  discardTimestamps.clear();
   postMessage({ type: "log", message: `configured ${config.codec}` });
 }
 
+// Parse an optional "VID0" header: magic, seq u64, timestamp_ms f64, flags u8, len u32.
+function parseVideoHeader(bytes) {
+  if (bytes.byteLength < VIDEO_HEADER_BYTES) return null;
+  if (!VIDEO_MAGIC.every((code, i) => bytes[i] === code)) return null;
+  const view = new DataView(bytes.buffer, bytes.byteOffset, bytes.byteLength);
+  const sequence = view.getBigUint64(4, true);
+  const timestampMs = view.getFloat64(12, true);
+  const flags = view.getUint8(20);
+  const length = view.getUint32(21, true);
+  const payload = bytes.subarray(
+    VIDEO_HEADER_BYTES,
+    Math.min(bytes.byteLength, VIDEO_HEADER_BYTES + length),
+  );
+  return { sequence, timestampMs, flags, payload };
+}
+
 function decodeChunk(buffer) {
   if (!decoder || decoder.state === "closed") return;
 
-  const data = buffer instanceof ArrayBuffer ? new Uint8Array(buffer) : buffer;
+  const bytes = buffer instanceof ArrayBuffer ? new Uint8Array(buffer) : buffer;
+  const header = parseVideoHeader(bytes);
+  const data = header ? header.payload : bytes;
   if (!data.byteLength) {
     postMessage({ type: "log", message: "empty video chunk" });
     return;
@@ -81,7 +113,7 @@ function decodeChunk(buffer) {
   let cursor = 0;
   let hasIdr = false;
   let firstNalType = null;
-  while (cursor + 4 <= view.byteLength) {
+  while (scanNals && cursor + 4 <= view.byteLength) {
     const nalLen = view.getUint32(cursor);
     cursor += 4;
     if (nalLen === 0 || cursor + nalLen > view.byteLength) break;
@@ -94,7 +126,22 @@ function decodeChunk(buffer) {
     }
     cursor += nalLen;
   }
-  const chunkType = hasIdr ? "key" : "delta";
+  const chunkType =
+    hasIdr || (header && header.flags & FLAG_KEYFRAME) ? "key" : "delta";
+  if (header) {
+    const gap =
+      lastSequence !== null && header.sequence !== lastSequence + 1n;
+    lastSequence = header.sequence;
+    // A delta after a gap references frames we never got; wait for the next keyframe
+    if (gap && chunkType !== "key" && !waitingForKey) {
+      postMessage({
+        type: "log",
+        message: `video gap before chunk ${header.sequence}, waiting for keyframe`,
+      });
+      waitingForKey = true;
+      postMessage({ type: "request-keyframe" });
+    }
+  }
   if (waitingForKey && chunkType !== "key") {
     droppedSinceConfig += 1;
     if (droppedSinceConfig % 10 === 1) {
@@ -110,8 +157,14 @@ function decodeChunk(buffer) {
     return;
   }
   waitingForKey = false;
+  const timestamp = header
+    ? Math.round(header.timestampMs * 1000)
+    : Math.round(performance.now() * 1000); // microseconds
+  if (header && header.flags & FLAG_DISCARD) {
+    discardTimestamps.add(timestamp);
+  }
   const chunk = new EncodedVideoChunk({
-    timestamp: performance.now() * 1000, // microseconds
+    timestamp,
     type: chunkType,
     data,
   });
@@ -119,6 +172,10 @@ function decodeChunk(buffer) {
 }
 
 async function handleFrame(frame) {
+  if (discardTimestamps.delete(frame.timestamp)) {
+    frame.close();
+    return;
+  }
   try {
     const bitmap = await createImageBitmap(frame);
     postMessage(
@@ -0,0 +1,596 @@
+//! Hardware H.264 through VideoToolbox (`--features videotoolbox`, macOS only)
+
+use std::{
+    collections::VecDeque,
+    ffi::c_void,
+    ptr,
+    sync::{Arc, Mutex, PoisonError},
+    time::Instant,
+};
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD as B64;
+use base64::Engine;
+use core_foundation::{
+    base::{CFType, TCFType},
+    boolean::CFBoolean,
+    dictionary::CFDictionary,
+    number::CFNumber,
+    string::{CFString, CFStringRef},
+};
+use xcap::Frame;
+
+use crate::{
+    video_pipeline::{
+        build_avcc_from_nals, chunk_is_keyframe, ColorMatrix, EncodedChunk, PixelFormat, VideoCodec, VideoConfig,
+    },
+};
+
+/// kCMVideoCodecType_H264
+const CODEC_TYPE_H264: u32 = u32::from_be_bytes(*b"avc1");
+/// kCVPixelFormatType_32BGRA
+const PIXEL_FORMAT_BGRA: u32 = u32::from_be_bytes(*b"BGRA");
+/// Presentation timestamps are in microseconds since the session started
+const TIMESCALE: i32 = 1_000_000;
+/// Session created by `VtEncoder::new` to check hardware encoding works at all
+const PROBE_SIZE: (u32, u32) = (1280, 720);
+
+type OSStatus = i32;
+type VTCompressionSessionRef = *mut c_void;
+type CVPixelBufferPoolRef = *mut c_void;
+type CVPixelBufferRef = *mut c_void;
+type CMSampleBufferRef = *mut c_void;
+type CMBlockBufferRef = *mut c_void;
+type CMFormatDescriptionRef = *mut c_void;
+type CFArrayRef = *const c_void;
+type CFDictionaryRef = *const c_void;
+
+type OutputCallback = extern "C" fn(*mut c_void, *mut c_void, OSStatus, u32, CMSampleBufferRef);
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CMTime {
+    value: i64,
+    timescale: i32,
+    flags: u32,
+    epoch: i64,
+}
+
+/// kCMTimeInvalid: every flag clear
+const TIME_INVALID: CMTime = CMTime {
+    value: 0,
+    timescale: 0,
+    flags: 0,
+    epoch: 0,
+};
+/// kCMTimeFlags_Valid
+const TIME_FLAGS_VALID: u32 = 1;
+
+// FFI declarations for VideoToolbox/CoreVideo/CoreMedia - using Apple's naming convention
+#[allow(non_upper_case_globals)]
+#[link(name = "VideoToolbox", kind = "framework")]
+extern "C" {
+    static kVTCompressionPropertyKey_RealTime: CFStringRef;
+    static kVTCompressionPropertyKey_AverageBitRate: CFStringRef;
+    static kVTCompressionPropertyKey_Quality: CFStringRef;
+    static kVTCompressionPropertyKey_MaxKeyFrameInterval: CFStringRef;
+    static kVTCompressionPropertyKey_AllowFrameReordering: CFStringRef;
+    static kVTCompressionPropertyKey_ExpectedFrameRate: CFStringRef;
+    static kVTCompressionPropertyKey_ProfileLevel: CFStringRef;
+    static kVTCompressionPropertyKey_ColorPrimaries: CFStringRef;
+    static kVTCompressionPropertyKey_TransferFunction: CFStringRef;
+    static kVTCompressionPropertyKey_YCbCrMatrix: CFStringRef;
+    static kVTProfileLevel_H264_High_AutoLevel: CFStringRef;
+    static kVTEncodeFrameOptionKey_ForceKeyFrame: CFStringRef;
+    static kVTVideoEncoderSpecification_RequireHardwareAcceleratedVideoEncoder: CFStringRef;
+
+    fn VTCompressionSessionCreate(
+        allocator: *const c_void,
+        width: i32,
+        height: i32,
+        codec_type: u32,
+        encoder_specification: CFDictionaryRef,
+        source_image_buffer_attributes: CFDictionaryRef,
+        compressed_data_allocator: *const c_void,
+        output_callback: Option<OutputCallback>,
+        output_callback_refcon: *mut c_void,
+        session_out: *mut VTCompressionSessionRef,
+    ) -> OSStatus;
+    fn VTSessionSetProperty(session: VTCompressionSessionRef, key: CFStringRef, value: *const c_void) -> OSStatus;
+    fn VTCompressionSessionPrepareToEncodeFrames(session: VTCompressionSessionRef) -> OSStatus;
+    fn VTCompressionSessionGetPixelBufferPool(session: VTCompressionSessionRef) -> CVPixelBufferPoolRef;
+    fn VTCompressionSessionEncodeFrame(
+        session: VTCompressionSessionRef,
+        image_buffer: CVPixelBufferRef,
+        presentation_time: CMTime,
+        duration: CMTime,
+        frame_properties: CFDictionaryRef,
+        source_frame_refcon: *mut c_void,
+        info_flags_out: *mut u32,
+    ) -> OSStatus;
+    fn VTCompressionSessionCompleteFrames(session: VTCompressionSessionRef, complete_until: CMTime) -> OSStatus;
+    fn VTCompressionSessionInvalidate(session: VTCompressionSessionRef);
+}
+
+#[allow(non_upper_case_globals)]
+#[link(name = "CoreVideo", kind = "framework")]
+extern "C" {
+    static kCVPixelBufferPixelFormatTypeKey: CFStringRef;
+    static kCVPixelBufferWidthKey: CFStringRef;
+    static kCVPixelBufferHeightKey: CFStringRef;
+    static kCVImageBufferColorPrimaries_ITU_R_709_2: CFStringRef;
+    static kCVImageBufferTransferFunction_ITU_R_709_2: CFStringRef;
+    static kCVImageBufferYCbCrMatrix_ITU_R_709_2: CFStringRef;
+
+    fn CVPixelBufferPoolCreatePixelBuffer(
+        allocator: *const c_void,
+        pool: CVPixelBufferPoolRef,
+        pixel_buffer_out: *mut CVPixelBufferRef,
+    ) -> i32;
+    fn CVPixelBufferLockBaseAddress(pixel_buffer: CVPixelBufferRef, flags: u64) -> i32;
+    fn CVPixelBufferUnlockBaseAddress(pixel_buffer: CVPixelBufferRef, flags: u64) -> i32;
+    fn CVPixelBufferGetBaseAddress(pixel_buffer: CVPixelBufferRef) -> *mut u8;
+    fn CVPixelBufferGetBytesPerRow(pixel_buffer: CVPixelBufferRef) -> usize;
+    fn CVPixelBufferRelease(pixel_buffer: CVPixelBufferRef);
+}
+
+#[allow(non_upper_case_globals)]
+#[link(name = "CoreMedia", kind = "framework")]
+extern "C" {
+    static kCMSampleAttachmentKey_NotSync: CFStringRef;
+
+    fn CMSampleBufferGetDataBuffer(sample: CMSampleBufferRef) -> CMBlockBufferRef;
+    fn CMSampleBufferGetFormatDescription(sample: CMSampleBufferRef) -> CMFormatDescriptionRef;
+    fn CMSampleBufferGetSampleAttachmentsArray(sample: CMSampleBufferRef, create_if_necessary: bool) -> CFArrayRef;
+    fn CMBlockBufferGetDataLength(block: CMBlockBufferRef) -> usize;
+    fn CMBlockBufferCopyDataBytes(
+        block: CMBlockBufferRef,
+        offset: usize,
+        length: usize,
+        destination: *mut u8,
+    ) -> OSStatus;
+    fn CMVideoFormatDescriptionGetH264ParameterSetAtIndex(
+        description: CMFormatDescriptionRef,
+        index: usize,
+        parameter_set_out: *mut *const u8,
+        parameter_set_size_out: *mut usize,
+        parameter_set_count_out: *mut usize,
+        nal_unit_header_length_out: *mut i32,
+    ) -> OSStatus;
+}
+
+#[link(name = "CoreFoundation", kind = "framework")]
+extern "C" {
+    fn CFArrayGetCount(array: CFArrayRef) -> isize;
+    fn CFArrayGetValueAtIndex(array: CFArrayRef, index: isize) -> *const c_void;
+    fn CFDictionaryGetValue(dict: CFDictionaryRef, key: *const c_void) -> *const c_void;
+    fn CFRelease(cf: *const c_void);
+}
+
+/// One compressed frame handed to the output callback
+struct Output {
+    /// AVCC: 4-byte big-endian length before each NAL unit
+    data: Vec<u8>,
+    is_keyframe: bool,
+    /// avcC record built from the SPS/PPS, on keyframes
+    avcc: Option<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct Outputs {
+    frames: VecDeque<Output>,
+    /// Status of the last frame the encoder failed
+    failed: Option<OSStatus>,
+}
+
+/// A VTCompressionSession for one frame size
+struct Session {
+    raw: VTCompressionSessionRef,
+    /// Filled by `on_output`; boxed so the callback's pointer stays valid
+    outputs: Box<Mutex<Outputs>>,
+}
+
+// SAFETY: a compression session may be used from any thread, one at a time,
+// which `&mut VtEncoder` guarantees
+unsafe impl Send for Session {}
+
+impl Session {
+    /// `quality` (0.0-1.0) replaces the average bitrate target when set
+    fn create(width: u32, height: u32, bitrate_bps: u32, idr_interval_frames: u32, quality: Option<f32>) -> Result<Self> {
+        let outputs = Box::new(Mutex::new(Outputs::default()));
+        let specification = CFDictionary::from_CFType_pairs(&[(
+            key(unsafe { kVTVideoEncoderSpecification_RequireHardwareAcceleratedVideoEncoder }),
+            CFBoolean::true_value().as_CFType(),
+        )]);
+        // The pool then hands out IOSurface-backed buffers the encoder reads directly
+        let attributes = CFDictionary::from_CFType_pairs(&[
+            (
+                key(unsafe { kCVPixelBufferPixelFormatTypeKey }),
+                CFNumber::from(PIXEL_FORMAT_BGRA as i32).as_CFType(),
+            ),
+            (key(unsafe { kCVPixelBufferWidthKey }), CFNumber::from(width as i32).as_CFType()),
+            (key(unsafe { kCVPixelBufferHeightKey }), CFNumber::from(height as i32).as_CFType()),
+        ]);
+        let mut raw: VTCompressionSessionRef = ptr::null_mut();
+        let status = unsafe {
+            VTCompressionSessionCreate(
+                ptr::null(),
+                width as i32,
+                height as i32,
+                CODEC_TYPE_H264,
+                specification.as_concrete_TypeRef() as CFDictionaryRef,
+                attributes.as_concrete_TypeRef() as CFDictionaryRef,
+                ptr::null(),
+                Some(on_output),
+                &*outputs as *const Mutex<Outputs> as *mut c_void,
+                &mut raw,
+            )
+        };
+        if status != 0 || raw.is_null() {
+            return Err(anyhow!("VTCompressionSessionCreate failed with status {}", status));
+        }
+        let session = Self { raw, outputs };
+
+        let yes = CFBoolean::true_value().as_CFType();
+        let no = CFBoolean::false_value().as_CFType();
+        unsafe {
+            session.set(kVTCompressionPropertyKey_RealTime, &yes)?;
+            // No B-frames: every frame comes out before the next goes in
+            session.set(kVTCompressionPropertyKey_AllowFrameReordering, &no)?;
+            session.set(
+                kVTCompressionPropertyKey_ProfileLevel,
+                &CFString::wrap_under_get_rule(kVTProfileLevel_H264_High_AutoLevel).as_CFType(),
+            )?;
+            session.set(kVTCompressionPropertyKey_ExpectedFrameRate, &CFNumber::from(60).as_CFType())?;
+            // The hardware does the RGB to YUV conversion; pin it (and the VUI) to BT.709
+            for (property, value) in [
+                (kVTCompressionPropertyKey_ColorPrimaries, kCVImageBufferColorPrimaries_ITU_R_709_2),
+                (kVTCompressionPropertyKey_TransferFunction, kCVImageBufferTransferFunction_ITU_R_709_2),
+                (kVTCompressionPropertyKey_YCbCrMatrix, kCVImageBufferYCbCrMatrix_ITU_R_709_2),
+            ] {
+                session.set(property, &CFString::wrap_under_get_rule(value).as_CFType())?;
+            }
+            if idr_interval_frames > 0 {
+                session.set(
+                    kVTCompressionPropertyKey_MaxKeyFrameInterval,
+                    &CFNumber::from(idr_interval_frames as i32).as_CFType(),
+                )?;
+            }
+        }
+        match quality {
+            Some(quality) => unsafe {
+                session.set(kVTCompressionPropertyKey_Quality, &CFNumber::from(quality).as_CFType())?
+            },
+            None => session.set_bitrate(bitrate_bps)?,
+        }
+        let status = unsafe { VTCompressionSessionPrepareToEncodeFrames(session.raw) };
+        if status != 0 {
+            return Err(anyhow!("VTCompressionSessionPrepareToEncodeFrames failed with status {}", status));
+        }
+        Ok(session)
+    }
+
+    fn set(&self, key: CFStringRef, value: &CFType) -> Result<()> {
+        let status = unsafe { VTSessionSetProperty(self.raw, key, value.as_CFTypeRef()) };
+        if status != 0 {
+            let name = unsafe { CFString::wrap_under_get_rule(key) };
+            return Err(anyhow!("setting {} failed with status {}", name, status));
+        }
+        Ok(())
+    }
+
+    fn set_bitrate(&self, bitrate_bps: u32) -> Result<()> {
+        self.set(
+            unsafe { kVTCompressionPropertyKey_AverageBitRate },
+            &CFNumber::from(bitrate_bps as i32).as_CFType(),
+        )
+    }
+
+    /// A pooled BGRA buffer holding the top-left `width` x `height` of `frame`,
+    /// whose rows are `src_stride` bytes apart. RGBA frames have red and blue
+    /// swapped while copying; the colour conversion to YUV happens in the
+    /// encoder hardware.
+    fn pixel_buffer(
+        &self,
+        frame: &Frame,
+        src_stride: usize,
+        format: PixelFormat,
+        width: u32,
+        height: u32,
+    ) -> Result<CVPixelBufferRef> {
+        let pool = unsafe { VTCompressionSessionGetPixelBufferPool(self.raw) };
+        if pool.is_null() {
+            return Err(anyhow!("compression session has no pixel buffer pool"));
+        }
+        let mut buffer: CVPixelBufferRef = ptr::null_mut();
+        let rc = unsafe { CVPixelBufferPoolCreatePixelBuffer(ptr::null(), pool, &mut buffer) };
+        if rc != 0 || buffer.is_null() {
+            return Err(anyhow!("CVPixelBufferPoolCreatePixelBuffer failed with code {}", rc));
+        }
+        unsafe {
+            if CVPixelBufferLockBaseAddress(buffer, 0) != 0 {
+                CVPixelBufferRelease(buffer);
+                return Err(anyhow!("could not lock pixel buffer"));
+            }
+            let base = CVPixelBufferGetBaseAddress(buffer);
+            let stride = CVPixelBufferGetBytesPerRow(buffer);
+            let row_bytes = width as usize * 4;
+            for y in 0..height as usize {
+                let src = &frame.raw[y * src_stride..y * src_stride + row_bytes];
+                let dst = std::slice::from_raw_parts_mut(base.add(y * stride), row_bytes);
+                match format {
+                    PixelFormat::Bgra8888 => dst.copy_from_slice(src),
+                    PixelFormat::Rgba8888 => {
+                        for (dst, src) in dst.chunks_exact_mut(4).zip(src.chunks_exact(4)) {
+                            dst.copy_from_slice(&[src[2], src[1], src[0], src[3]]);
+                        }
+                    }
+                }
+            }
+            CVPixelBufferUnlockBaseAddress(buffer, 0);
+        }
+        Ok(buffer)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        unsafe {
+            // No callbacks run after this returns, so `outputs` can go
+            VTCompressionSessionInvalidate(self.raw);
+            CFRelease(self.raw);
+        }
+    }
+}
+
+fn key(key: CFStringRef) -> CFType {
+    unsafe { CFString::wrap_under_get_rule(key) }.as_CFType()
+}
+
+extern "C" fn on_output(
+    refcon: *mut c_void,
+    _source_frame_refcon: *mut c_void,
+    status: OSStatus,
+    _info_flags: u32,
+    sample: CMSampleBufferRef,
+) {
+    // SAFETY: refcon is the session's boxed Outputs, which outlives the session
+    let outputs = unsafe { &*(refcon as *const Mutex<Outputs>) };
+    // Never panic across the FFI boundary
+    let mut outputs = outputs.lock().unwrap_or_else(PoisonError::into_inner);
+    if status != 0 {
+        outputs.failed = Some(status);
+        return;
+    }
+    // A null sample means the encoder dropped the frame
+    if sample.is_null() {
+        return;
+    }
+    if let Some(output) = unsafe { read_sample(sample) } {
+        outputs.frames.push_back(output);
+    }
+}
+
+unsafe fn read_sample(sample: CMSampleBufferRef) -> Option<Output> {
+    let block = CMSampleBufferGetDataBuffer(sample);
+    if block.is_null() {
+        return None;
+    }
+    let length = CMBlockBufferGetDataLength(block);
+    let mut data = vec![0u8; length];
+    if CMBlockBufferCopyDataBytes(block, 0, length, data.as_mut_ptr()) != 0 {
+        return None;
+    }
+    let is_keyframe = is_sync_sample(sample).unwrap_or_else(|| chunk_is_keyframe(&data));
+    let avcc = if is_keyframe {
+        parameter_sets(CMSampleBufferGetFormatDescription(sample))
+    } else {
+        None
+    };
+    Some(Output {
+        data,
+        is_keyframe,
+        avcc,
+    })
+}
+
+/// Samples are sync (IDR) unless their attachments say NotSync; None when
+/// there are no attachments to go by
+unsafe fn is_sync_sample(sample: CMSampleBufferRef) -> Option<bool> {
+    let attachments = CMSampleBufferGetSampleAttachmentsArray(sample, false);
+    if attachments.is_null() || CFArrayGetCount(attachments) == 0 {
+        return None;
+    }
+    let attachment = CFArrayGetValueAtIndex(attachments, 0);
+    let not_sync = CFDictionaryGetValue(attachment, kCMSampleAttachmentKey_NotSync as *const c_void);
+    Some(not_sync.is_null() || not_sync == CFBoolean::false_value().as_CFTypeRef())
+}
+
+/// avcC built from the format description's SPS and PPS
+unsafe fn parameter_sets(description: CMFormatDescriptionRef) -> Option<Vec<u8>> {
+    if description.is_null() {
+        return None;
+    }
+    let mut nals = Vec::with_capacity(2);
+    for index in 0..2 {
+        let mut set: *const u8 = ptr::null();
+        let mut size = 0usize;
+        let status = CMVideoFormatDescriptionGetH264ParameterSetAtIndex(
+            description,
+            index,
+            &mut set,
+            &mut size,
+            ptr::null_mut(),
+            ptr::null_mut(),
+        );
+        if status != 0 || set.is_null() {
+            return None;
+        }
+        nals.push(std::slice::from_raw_parts(set, size).to_vec());
+    }
+    build_avcc_from_nals(&nals).ok().flatten()
+}
+
+/// VideoToolbox counterpart of the openh264 `EncoderImpl`: same lazy sizing,
+/// bitrate handling and IDR requests, but the encoding runs in hardware.
+pub struct VtEncoder {
+    session: Option<Session>,
+    width: u32,
+    height: u32,
+    codec: VideoCodec,
+    config_b64: String,
+    pending_idr: bool,
+    pub bitrate_bps: u32,
+    /// Set by set_bitrate; replaces the size-based default
+    pub bitrate_override: Option<u32>,
+    /// Quality property for quality/fixed-QP rate control; bitrate changes
+    /// are then only recorded
+    quality: Option<f32>,
+    /// MaxKeyFrameInterval, applied whenever the session is (re)created
+    pub idr_interval_frames: u32,
+    /// Creating a session for a new frame size failed; the pipeline falls
+    /// back to openh264 rather than retrying every frame
+    pub session_failed: bool,
+    started: Instant,
+}
+
+impl VtEncoder {
+    /// Fails when this Mac can't create a hardware H.264 session at all
+    pub fn new(codec: VideoCodec, quality: Option<f32>) -> Result<Self> {
+        if codec != VideoCodec::Avc {
+            return Err(anyhow!("{:?} not available in the VideoToolbox encoder; choose avc", codec));
+        }
+        drop(Session::create(PROBE_SIZE.0, PROBE_SIZE.1, 1_000_000, 0, quality)?);
+        Ok(Self {
+            session: None,
+            width: 0,
+            height: 0,
+            codec,
+            config_b64: String::new(),
+            pending_idr: true,
+            bitrate_bps: 0,
+            bitrate_override: None,
+            quality,
+            idr_interval_frames: 0,
+            session_failed: false,
+            started: Instant::now(),
+        })
+    }
+
+    pub fn set_bitrate(&mut self, bitrate_bps: u32) -> Result<()> {
+        self.bitrate_override = Some(bitrate_bps);
+        let Some(session) = self.session.as_ref().filter(|_| self.quality.is_none()) else {
+            // Applied when the session is created for the first frame, and
+            // unused while a quality target is set
+            return Ok(());
+        };
+        session.set_bitrate(bitrate_bps)?;
+        self.bitrate_bps = bitrate_bps;
+        Ok(())
+    }
+
+    pub fn config(&self) -> VideoConfig {
+        VideoConfig {
+            codec: self.codec,
+            width: self.width,
+            height: self.height,
+            description_b64: self.config_b64.clone(),
+            color_matrix: ColorMatrix::Bt709,
+        }
+    }
+
+    pub fn encode(
+        &mut self,
+        frame: Arc<Frame>,
+        stride: usize,
+        format: PixelFormat,
+        force_idr: bool,
+    ) -> Result<Option<EncodedChunk>> {
+        // Even dimensions, like the openh264 path, so the stream looks the same
+        let even_w = frame.width & !1;
+        let even_h = frame.height & !1;
+        if even_w == 0 || even_h == 0 {
+            return Ok(None);
+        }
+
+        if self.session.is_none() || self.width != even_w || self.height != even_h {
+            self.session = None;
+            // Same default as openh264: ~15Mbps for 1080p
+            let bitrate = self
+                .bitrate_override
+                .unwrap_or_else(|| (even_w * even_h * 8).clamp(500_000, 15_000_000));
+            let session = match Session::create(even_w, even_h, bitrate, self.idr_interval_frames, self.quality) {
+                Ok(session) => session,
+                Err(err) => {
+                    self.session_failed = true;
+                    return Err(err);
+                }
+            };
+            self.session = Some(session);
+            self.width = even_w;
+            self.height = even_h;
+            self.bitrate_bps = bitrate;
+            self.config_b64.clear();
+            self.pending_idr = true;
+        }
+        let Some(session) = &self.session else {
+            return Ok(None);
+        };
+
+        let buffer = session.pixel_buffer(&frame, stride, format, even_w, even_h)?;
+        let force = self.pending_idr || force_idr;
+        self.pending_idr = false;
+        let properties = force.then(|| {
+            CFDictionary::from_CFType_pairs(&[(
+                key(unsafe { kVTEncodeFrameOptionKey_ForceKeyFrame }),
+                CFBoolean::true_value().as_CFType(),
+            )])
+        });
+        let pts = CMTime {
+            value: self.started.elapsed().as_micros() as i64,
+            timescale: TIMESCALE,
+            flags: TIME_FLAGS_VALID,
+            epoch: 0,
+        };
+        let status = unsafe {
+            let status = VTCompressionSessionEncodeFrame(
+                session.raw,
+                buffer,
+                pts,
+                TIME_INVALID,
+                properties
+                    .as_ref()
+                    .map_or(ptr::null(), |properties| properties.as_concrete_TypeRef() as CFDictionaryRef),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            );
+            CVPixelBufferRelease(buffer);
+            if status == 0 {
+                // Keeps encode() synchronous like openh264: the frame is out when this returns
+                VTCompressionSessionCompleteFrames(session.raw, TIME_INVALID)
+            } else {
+                status
+            }
+        };
+        if status != 0 {
+            return Err(anyhow!("VideoToolbox encode failed with status {}", status));
+        }
+
+        let output = {
+            let mut outputs = session.outputs.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(status) = outputs.failed.take() {
+                // The next frame starts clean
+                self.pending_idr = true;
+                return Err(anyhow!("VideoToolbox dropped a frame with status {}", status));
+            }
+            outputs.frames.pop_front()
+        };
+        let Some(output) = output else {
+            return Ok(None);
+        };
+        if let Some(avcc) = output.avcc {
+            self.config_b64 = B64.encode(avcc);
+        }
+        Ok(Some(EncodedChunk::new(output.data, output.is_keyframe)))
+    }
+}
@@ -0,0 +1,382 @@
+//! RGBA/BGRA to I420 for the software encoders, straight from the captured
+//! frame into Y/U/V planes with rows converted in parallel
+
+use rayon::prelude::*;
+
+use crate::video_pipeline::{ColorMatrix, PipelineFrame, PixelFormat};
+
+/// 4:2:0 planes; `width` and `height` are even. Empty until the first
+/// `convert`, and reusable across frames.
+#[derive(Default)]
+pub struct I420 {
+    pub width: usize,
+    pub height: usize,
+    pub y: Vec<u8>,
+    pub u: Vec<u8>,
+    pub v: Vec<u8>,
+}
+
+/// Borrowed 4:2:0 planes with their bytes per row, as an encoder reads them
+pub struct Planes<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub y: &'a [u8],
+    pub u: &'a [u8],
+    pub v: &'a [u8],
+    pub strides: [usize; 3],
+}
+
+/// Rows of 8.8 fixed-point weights for Y, U and V from R, G and B, and the luma offset
+fn coefficients(matrix: ColorMatrix) -> ([[i32; 3]; 3], i32) {
+    let (kr, kb) = matrix.weights();
+    let kg = 1.0 - kr - kb;
+    let (luma_scale, chroma_scale, luma_offset) = if matrix.full_range() {
+        (1.0, 1.0, 0)
+    } else {
+        (219.0 / 255.0, 224.0 / 255.0, 16)
+    };
+    let fixed = |x: f32| (x * 256.0).round() as i32;
+    let u = chroma_scale / (2.0 * (1.0 - kb));
+    let v = chroma_scale / (2.0 * (1.0 - kr));
+    let rows = [
+        [fixed(kr * luma_scale), fixed(kg * luma_scale), fixed(kb * luma_scale)],
+        [fixed(-kr * u), fixed(-kg * u), fixed((1.0 - kb) * u)],
+        [fixed((1.0 - kr) * v), fixed(-kg * v), fixed(-kb * v)],
+    ];
+    (rows, luma_offset)
+}
+
+impl I420 {
+    /// The top-left `width` x `height` (even) of `frame` as 4:2:0: I420 input
+    /// is borrowed as it is, RGB is converted with `matrix` and NV12 split,
+    /// both into these planes
+    pub fn planes<'a>(
+        &'a mut self,
+        frame: &'a PipelineFrame,
+        width: usize,
+        height: usize,
+        matrix: ColorMatrix,
+    ) -> Planes<'a> {
+        match frame {
+            PipelineFrame::Rgba { frame, stride, format } => {
+                self.convert(&frame.raw, *format, *stride, width, height, matrix)
+            }
+            PipelineFrame::I420 { y, u, v, strides, .. } => {
+                return Planes {
+                    width,
+                    height,
+                    y,
+                    u,
+                    v,
+                    strides: *strides,
+                }
+            }
+            PipelineFrame::Nv12 { y, uv, strides, .. } => self.split_nv12(y, strides[0], uv, strides[1], width, height),
+        }
+        Planes {
+            width: self.width,
+            height: self.height,
+            y: &self.y,
+            u: &self.u,
+            v: &self.v,
+            strides: [self.width, self.width / 2, self.width / 2],
+        }
+    }
+
+    /// Copy the top-left `width` x `height` of NV12 planes with rows
+    /// `y_stride` and `uv_stride` bytes apart, separating U from V
+    pub fn split_nv12(&mut self, y: &[u8], y_stride: usize, uv: &[u8], uv_stride: usize, width: usize, height: usize) {
+        let (width, height) = (width & !1, height & !1);
+        let chroma_width = width / 2;
+        self.width = width;
+        self.height = height;
+        self.y.resize(width * height, 0);
+        self.u.resize(chroma_width * height / 2, 0);
+        self.v.resize(chroma_width * height / 2, 0);
+        if width == 0 || height == 0 {
+            return;
+        }
+        for (row, out) in self.y.chunks_exact_mut(width).enumerate() {
+            out.copy_from_slice(&y[row * y_stride..][..width]);
+        }
+        let chroma_rows = self.u.chunks_exact_mut(chroma_width).zip(self.v.chunks_exact_mut(chroma_width));
+        for (row, (u_row, v_row)) in chroma_rows.enumerate() {
+            let pairs = uv[row * uv_stride..][..width].chunks_exact(2);
+            for ((u, v), pair) in u_row.iter_mut().zip(v_row.iter_mut()).zip(pairs) {
+                *u = pair[0];
+                *v = pair[1];
+            }
+        }
+    }
+
+    /// Convert the top-left `width` x `height` of a `format` buffer with rows
+    /// `src_stride` bytes apart using `matrix`, replacing the planes' contents
+    /// (they are only reallocated when the size grows). Each pair of rows is
+    /// one task: it writes two luma rows and one row of each chroma plane,
+    /// with chroma averaged over the 2x2 block.
+    pub fn convert(
+        &mut self,
+        src: &[u8],
+        format: PixelFormat,
+        src_stride: usize,
+        width: usize,
+        height: usize,
+        matrix: ColorMatrix,
+    ) {
+        let (width, height) = (width & !1, height & !1);
+        let chroma_width = width / 2;
+        self.width = width;
+        self.height = height;
+        self.y.resize(width * height, 0);
+        self.u.resize(chroma_width * height / 2, 0);
+        self.v.resize(chroma_width * height / 2, 0);
+        if width == 0 || height == 0 {
+            return;
+        }
+        let (r_at, g_at, b_at) = format.rgb_offsets();
+        let ([ky, ku, kv], luma_offset) = coefficients(matrix);
+        self.y
+            .par_chunks_mut(width * 2)
+            .zip(self.u.par_chunks_mut(chroma_width))
+            .zip(self.v.par_chunks_mut(chroma_width))
+            .enumerate()
+            .for_each(|(pair, ((y_rows, u_row), v_row))| {
+                let (y_top, y_bottom) = y_rows.split_at_mut(width);
+                let top = &src[pair * 2 * src_stride..][..width * 4];
+                let bottom = &src[(pair * 2 + 1) * src_stride..][..width * 4];
+                for col in 0..chroma_width {
+                    let (mut r_sum, mut g_sum, mut b_sum) = (0, 0, 0);
+                    for (line, y_out) in [(top, &mut *y_top), (bottom, &mut *y_bottom)] {
+                        for x in [col * 2, col * 2 + 1] {
+                            let px = &line[x * 4..x * 4 + 4];
+                            let (r, g, b) = (px[r_at] as i32, px[g_at] as i32, px[b_at] as i32);
+                            let luma = (ky[0] * r + ky[1] * g + ky[2] * b + 128) >> 8;
+                            y_out[x] = (luma + luma_offset).clamp(0, 255) as u8;
+                            r_sum += r;
+                            g_sum += g;
+                            b_sum += b;
+                        }
+                    }
+                    let (r, g, b) = (r_sum / 4, g_sum / 4, b_sum / 4);
+                    u_row[col] = (((ku[0] * r + ku[1] * g + ku[2] * b + 128) >> 8) + 128).clamp(0, 255) as u8;
+                    v_row[col] = (((kv[0] * r + kv[1] * g + kv[2] * b + 128) >> 8) + 128).clamp(0, 255) as u8;
+                }
+            });
+    }
+}
+
+/// Straightforward per-pixel floating point conversion on one thread: the
+/// reference `I420::convert` is checked and benchmarked against
+pub fn reference_convert(
+    src: &[u8],
+    format: PixelFormat,
+    stride: usize,
+    width: usize,
+    height: usize,
+    matrix: ColorMatrix,
+) -> I420 {
+    let (kr, kb) = matrix.weights();
+    let kg = 1.0 - kr - kb;
+    let (luma_scale, chroma_scale, luma_offset) = if matrix.full_range() {
+        (1.0, 1.0, 0.0)
+    } else {
+        (219.0 / 255.0, 224.0 / 255.0, 16.0)
+    };
+    let (r_at, g_at, b_at) = format.rgb_offsets();
+    let rgb = |x: usize, y: usize| {
+        let px = &src[y * stride + x * 4..][..4];
+        (px[r_at] as f32, px[g_at] as f32, px[b_at] as f32)
+    };
+    let to_byte = |value: f32| value.round().clamp(0.0, 255.0) as u8;
+    let mut out = I420 {
+        width,
+        height,
+        ..I420::default()
+    };
+    for y in 0..height {
+        for x in 0..width {
+            let (r, g, b) = rgb(x, y);
+            out.y.push(to_byte(luma_offset + luma_scale * (kr * r + kg * g + kb * b)));
+        }
+    }
+    for y in (0..height).step_by(2) {
+        for x in (0..width).step_by(2) {
+            let block = [rgb(x, y), rgb(x + 1, y), rgb(x, y + 1), rgb(x + 1, y + 1)];
+            let r = block.iter().map(|px| px.0).sum::<f32>() / 4.0;
+            let g = block.iter().map(|px| px.1).sum::<f32>() / 4.0;
+            let b = block.iter().map(|px| px.2).sum::<f32>() / 4.0;
+            let luma = kr * r + kg * g + kb * b;
+            out.u.push(to_byte(128.0 + chroma_scale * (b - luma) / (2.0 * (1.0 - kb))));
+            out.v.push(to_byte(128.0 + chroma_scale * (r - luma) / (2.0 * (1.0 - kr))));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    const MATRICES: [ColorMatrix; 4] = [
+        ColorMatrix::Bt709,
+        ColorMatrix::Bt601,
+        ColorMatrix::Bt709Full,
+        ColorMatrix::Bt601Full,
+    ];
+
+    /// Pseudo-random bytes, the same for every run
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x1234_5678u32;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    fn assert_close(plane: &str, actual: &[u8], expected: &[u8]) {
+        assert_eq!(actual.len(), expected.len(), "{} plane size", plane);
+        for (i, (a, e)) in actual.iter().zip(expected).enumerate() {
+            assert!(a.abs_diff(*e) <= 1, "{}[{}]: {} vs {}", plane, i, a, e);
+        }
+    }
+
+    #[test]
+    fn matches_the_reference_conversion_within_one() {
+        let (width, height) = (64, 36);
+        let stride = width * 4;
+        let src = noise(stride * height);
+        let mut planes = I420::default();
+        for format in [PixelFormat::Rgba8888, PixelFormat::Bgra8888] {
+            for matrix in MATRICES {
+                planes.convert(&src, format, stride, width, height, matrix);
+                let expected = reference_convert(&src, format, stride, width, height, matrix);
+                assert_eq!((planes.width, planes.height), (width, height));
+                assert_close("y", &planes.y, &expected.y);
+                assert_close("u", &planes.u, &expected.u);
+                assert_close("v", &planes.v, &expected.v);
+            }
+        }
+    }
+
+    /// Mean of a plane
+    fn mean(plane: &[u8]) -> f64 {
+        plane.iter().map(|&v| v as f64).sum::<f64>() / plane.len() as f64
+    }
+
+    #[test]
+    fn solid_red_lands_on_the_red_side_in_either_byte_order() {
+        let (width, height) = (16, 8);
+        let mut planes = I420::default();
+        // Red as each format lays it out, and the same bytes read in the other order (blue)
+        for (format, red, blue) in [
+            (PixelFormat::Rgba8888, [0xff, 0, 0, 0xff], [0, 0, 0xff, 0xff]),
+            (PixelFormat::Bgra8888, [0, 0, 0xff, 0xff], [0xff, 0, 0, 0xff]),
+        ] {
+            // Y, U and V of pure red (limited range)
+            let red_yuv = [(ColorMatrix::Bt601, [81.0, 90.0, 240.0]), (ColorMatrix::Bt709, [63.0, 102.0, 240.0])];
+            for (matrix, expected) in red_yuv {
+                planes.convert(&red.repeat(width * height), format, width * 4, width, height, matrix);
+                let means = [mean(&planes.y), mean(&planes.u), mean(&planes.v)];
+                for (plane, (actual, expected)) in ["y", "u", "v"].iter().zip(means.iter().zip(expected)) {
+                    assert!((actual - expected).abs() <= 1.0, "{:?} {:?} {}: {}", format, matrix, plane, actual);
+                }
+            }
+            planes.convert(&blue.repeat(width * height), format, width * 4, width, height, ColorMatrix::Bt709);
+            assert!(mean(&planes.u) > 128.0 && mean(&planes.v) < 128.0, "{:?} blue", format);
+        }
+    }
+
+    #[test]
+    fn padded_rows_convert_like_packed_ones() {
+        let (width, height, pad) = (30, 20, 7);
+        let packed = noise(width * height * 4);
+        let stride = (width + pad) * 4;
+        let mut padded = vec![0xee; stride * height];
+        for (row, out) in packed.chunks_exact(width * 4).zip(padded.chunks_exact_mut(stride)) {
+            out[..width * 4].copy_from_slice(row);
+        }
+        let (mut expected, mut actual) = (I420::default(), I420::default());
+        expected.convert(&packed, PixelFormat::Bgra8888, width * 4, width, height, ColorMatrix::Bt709);
+        actual.convert(&padded, PixelFormat::Bgra8888, stride, width, height, ColorMatrix::Bt709);
+        assert_eq!(actual.y, expected.y);
+        assert_eq!(actual.u, expected.u);
+        assert_eq!(actual.v, expected.v);
+    }
+
+    #[test]
+    fn known_colors_convert_to_their_published_values() {
+        // (matrix, RGB, YUV)
+        let vectors = [
+            (ColorMatrix::Bt709, [255, 255, 255], [235, 128, 128]),
+            (ColorMatrix::Bt709, [0, 0, 0], [16, 128, 128]),
+            (ColorMatrix::Bt709, [0, 255, 0], [173, 42, 26]),
+            (ColorMatrix::Bt709, [0, 0, 255], [32, 240, 118]),
+            (ColorMatrix::Bt601, [255, 255, 255], [235, 128, 128]),
+            (ColorMatrix::Bt601, [0, 255, 0], [145, 54, 34]),
+            (ColorMatrix::Bt601, [0, 0, 255], [41, 240, 110]),
+            (ColorMatrix::Bt709Full, [255, 255, 255], [255, 128, 128]),
+            (ColorMatrix::Bt709Full, [0, 0, 0], [0, 128, 128]),
+            (ColorMatrix::Bt601Full, [128, 128, 128], [128, 128, 128]),
+        ];
+        let mut planes = I420::default();
+        for (matrix, [r, g, b], expected) in vectors {
+            planes.convert(&[r, g, b, 255].repeat(4), PixelFormat::Rgba8888, 8, 2, 2, matrix);
+            let actual = [planes.y[0], planes.u[0], planes.v[0]];
+            for (plane, (a, e)) in ["y", "u", "v"].iter().zip(actual.iter().zip(expected)) {
+                assert!(a.abs_diff(e) <= 1, "{:?} {:?} {}: {} vs {}", matrix, [r, g, b], plane, a, e);
+            }
+        }
+    }
+
+    /// `src` cut into rows of `row_bytes`, each followed by `pad` junk bytes
+    fn with_stride(src: &[u8], row_bytes: usize, pad: usize) -> Vec<u8> {
+        src.chunks_exact(row_bytes).flat_map(|row| row.iter().copied().chain(std::iter::repeat_n(0xee, pad))).collect()
+    }
+
+    #[test]
+    fn nv12_splits_into_the_i420_planes() {
+        // Odd sizes: the last column and row are dropped
+        let (width, height) = (11, 7);
+        let (y, u, v) = (noise(11 * 7), noise(6 * 4), noise(6 * 4).iter().map(|b| !b).collect::<Vec<_>>());
+        let uv: Vec<u8> = u.iter().zip(&v).flat_map(|(u, v)| [*u, *v]).collect();
+        let frame = PipelineFrame::Nv12 {
+            y: Bytes::from(with_stride(&y, 11, 5)),
+            uv: Bytes::from(with_stride(&uv, 12, 4)),
+            width,
+            height,
+            strides: [16, 16],
+        };
+        let mut yuv = I420::default();
+        let planes = yuv.planes(&frame, 10, 6, ColorMatrix::Bt709);
+        assert_eq!((planes.width, planes.height, planes.strides), (10, 6, [10, 5, 5]));
+        for row in 0..6 {
+            assert_eq!(planes.y[row * 10..][..10], y[row * 11..][..10], "y row {}", row);
+        }
+        for row in 0..3 {
+            assert_eq!(planes.u[row * 5..][..5], u[row * 6..][..5], "u row {}", row);
+            assert_eq!(planes.v[row * 5..][..5], v[row * 6..][..5], "v row {}", row);
+        }
+    }
+
+    #[test]
+    fn i420_input_is_passed_through_with_its_strides() {
+        let (y, u, v) = (Bytes::from(noise(24 * 8)), Bytes::from(noise(16 * 4)), Bytes::from(noise(16 * 4)));
+        let frame = PipelineFrame::I420 {
+            y: y.clone(),
+            u: u.clone(),
+            v: v.clone(),
+            width: 16,
+            height: 8,
+            strides: [24, 16, 16],
+        };
+        let mut yuv = I420::default();
+        let planes = yuv.planes(&frame, 16, 8, ColorMatrix::Bt709);
+        assert_eq!(planes.strides, [24, 16, 16]);
+        assert_eq!((planes.y.as_ptr(), planes.u.as_ptr(), planes.v.as_ptr()), (y.as_ptr(), u.as_ptr(), v.as_ptr()));
+        // Nothing was converted into the reusable planes
+        assert!(yuv.y.is_empty());
+    }
+}
@@ -0,0 +1,94 @@
+//! Allocation counts on the per-frame paths. Kept in its own test binary
+//! because counting needs a `#[global_allocator]`, which would otherwise
+//! replace the allocator for every unit test in the crate.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    sync::Arc,
+    time::Instant,
+};
+
+use foundry::{
+    test_frames,
+    video_pipeline::{ColorMatrix, PipelineFrame, PipelineOptions, PixelFormat, VideoCodec, VideoPipeline},
+    yuv::I420,
+};
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Counts allocations per thread, so tests running alongside don't add to each other's counts
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Allocations `f` makes on this thread
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+/// A synthetic capture as the Recorder hands it to the pipeline
+fn captured(frame: xcap::Frame) -> PipelineFrame {
+    PipelineFrame::Rgba {
+        stride: frame.width as usize * 4,
+        frame: Arc::new(frame),
+        format: PixelFormat::Bgra8888,
+    }
+}
+
+#[test]
+fn conversion_reuses_its_planes() {
+    let mut planes = I420::default();
+    let frame = captured(test_frames::text_like(640, 360, 0));
+    let convert = |planes: &mut I420| {
+        planes.planes(&frame, 640, 360, ColorMatrix::Bt709);
+    };
+    convert(&mut planes);
+    assert_eq!(allocations(|| (0..10).for_each(|_| convert(&mut planes))), 0);
+}
+
+#[cfg(not(feature = "videotoolbox"))]
+#[test]
+fn steady_state_encoding_barely_allocates() {
+    const FRAMES: usize = 20;
+    // Per frame: the chunk's bytes, the list it is handed out in, and the
+    // config (with its copy) that `encode` checks for changes
+    const PER_FRAME: usize = 4;
+    let mut pipeline = VideoPipeline::new(VideoCodec::Avc, PipelineOptions::default()).unwrap();
+    let frames: Vec<_> = (0..FRAMES as u32 + 3)
+        .map(|i| captured(test_frames::text_like(320, 240, i)))
+        .collect();
+    // The first frames create the encoder and find SPS/PPS
+    for frame in &frames[..3] {
+        pipeline.encode(frame.clone(), Instant::now(), false).unwrap();
+    }
+    let frames = frames[3..].to_vec();
+    let count = allocations(|| {
+        for frame in frames {
+            // Dropped straight away, as once every viewer has sent it
+            drop(pipeline.encode(frame, Instant::now(), false).unwrap());
+        }
+    });
+    assert!(count <= PER_FRAME * FRAMES, "{} allocations for {} frames", count, FRAMES);
+}
@@ -0,0 +1,353 @@
+//! Window enumeration and click-to-select, shared by `window-pick` and
+//! `foundry --pick`. Only macOS is supported for now.
+
+use serde::Serialize;
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WindowInfo {
+    pub id: u32,
+    pub title: Option<String>,
+    pub app: Option<String>,
+    pub bounds: WindowBounds,
+    pub layer: i32,
+    pub on_screen: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WindowBounds {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+/// Why click-to-select didn't produce a window
+#[derive(Debug)]
+pub enum PickError {
+    /// Not implemented on this platform
+    Unsupported,
+    /// Nothing was clicked before the timeout
+    TimedOut(Duration),
+    /// The click landed outside every on-screen window
+    NoWindowAt(f64, f64),
+}
+
+impl fmt::Display for PickError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PickError::Unsupported => write!(f, "window picking currently only supports macOS"),
+            PickError::TimedOut(timeout) => {
+                write!(f, "no window clicked within {}s", timeout.as_secs())
+            }
+            PickError::NoWindowAt(x, y) => write!(f, "No window found at ({}, {})", x, y),
+        }
+    }
+}
+
+impl std::error::Error for PickError {}
+
+/// Block until the user clicks a window and return it.
+///
+/// With `timeout`, gives up if no click arrives in time.
+pub fn click_to_select(timeout: Option<Duration>) -> Result<WindowInfo, PickError> {
+    if !cfg!(target_os = "macos") {
+        return Err(PickError::Unsupported);
+    }
+    let deadline = timeout.map(|t| (Instant::now() + t, t));
+    let wait = |until_down: bool| -> Result<(), PickError> {
+        while is_mouse_down() != until_down {
+            if let Some((deadline, timeout)) = deadline {
+                if Instant::now() >= deadline {
+                    return Err(PickError::TimedOut(timeout));
+                }
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    };
+
+    // Wait for mouse button to be released first (in case already pressed)
+    wait(false)?;
+
+    // Wait for mouse click
+    wait(true)?;
+
+    // Get mouse position at click
+    let (mouse_x, mouse_y) = get_mouse_position();
+
+    // Find window under cursor
+    let windows = get_all_windows();
+    find_window_at_point(&windows, mouse_x, mouse_y).ok_or(PickError::NoWindowAt(mouse_x, mouse_y))
+}
+
+/// Windows smaller than this (points, either side) are never taken as the
+/// frontmost one; menu extras and other slivers also sit at layer 0
+const MIN_FRONTMOST_SIZE: f64 = 64.0;
+
+/// The frontmost normal (layer 0) window on screen; None where unsupported.
+/// CGWindowList reports windows front to back, so it is the first match.
+pub fn frontmost_window() -> Option<WindowInfo> {
+    if !cfg!(target_os = "macos") {
+        return None;
+    }
+    get_all_windows().into_iter().find(|w| {
+        w.on_screen
+            && w.layer == 0
+            && w.bounds.width >= MIN_FRONTMOST_SIZE
+            && w.bounds.height >= MIN_FRONTMOST_SIZE
+    })
+}
+
+/// Current pointer location in global screen points (None where unsupported)
+pub fn cursor_position() -> Option<(f64, f64)> {
+    cfg!(target_os = "macos").then(get_mouse_position)
+}
+
+pub fn find_window_at_point(windows: &[WindowInfo], x: f64, y: f64) -> Option<WindowInfo> {
+    // Windows are returned in front-to-back order (lower layer = more in front)
+    // We want the topmost window that contains the point
+    let mut candidates: Vec<_> = windows
+        .iter()
+        .filter(|w| {
+            w.on_screen
+                && x >= w.bounds.x
+                && x < w.bounds.x + w.bounds.width
+                && y >= w.bounds.y
+                && y < w.bounds.y + w.bounds.height
+        })
+        .collect();
+
+    // Sort by layer (lower layer number = more in front on macOS)
+    candidates.sort_by_key(|w| w.layer);
+
+    candidates.first().cloned().cloned()
+}
+
+// ============================================================================
+// macOS-specific implementations
+// ============================================================================
+
+#[cfg(target_os = "macos")]
+mod macos {
+    use core_foundation::base::TCFType;
+    use core_foundation::boolean::CFBoolean;
+    use core_foundation::dictionary::CFDictionaryRef;
+    use core_foundation::number::CFNumber;
+    use core_foundation::string::CFString;
+    use core_graphics::event::CGEvent;
+    use core_graphics::event_source::{CGEventSource, CGEventSourceStateID};
+
+    use super::{WindowBounds, WindowInfo};
+
+    pub fn get_all_windows() -> Vec<WindowInfo> {
+        let mut windows = Vec::new();
+
+        unsafe {
+            let window_list = CGWindowListCopyWindowInfo(
+                kCGWindowListOptionOnScreenOnly | kCGWindowListExcludeDesktopElements,
+                kCGNullWindowID,
+            );
+
+            if window_list.is_null() {
+                return windows;
+            }
+
+            let count = CFArrayGetCount(window_list);
+
+            for i in 0..count {
+                let window_dict = CFArrayGetValueAtIndex(window_list, i) as CFDictionaryRef;
+                if window_dict.is_null() {
+                    continue;
+                }
+
+                if let Some(info) = parse_window_dict(window_dict) {
+                    windows.push(info);
+                }
+            }
+
+            CFRelease(window_list as *const _);
+        }
+
+        windows
+    }
+
+    unsafe fn parse_window_dict(dict: CFDictionaryRef) -> Option<WindowInfo> {
+        // Get window ID
+        let id_key = CFString::new("kCGWindowNumber");
+        let id_ptr = CFDictionaryGetValue(dict, id_key.as_CFTypeRef() as *const _);
+        if id_ptr.is_null() {
+            return None;
+        }
+        let id_num = CFNumber::wrap_under_get_rule(id_ptr as _);
+        let id: i32 = id_num.to_i32()?;
+
+        // Get window layer
+        let layer_key = CFString::new("kCGWindowLayer");
+        let layer_ptr = CFDictionaryGetValue(dict, layer_key.as_CFTypeRef() as *const _);
+        let layer = if !layer_ptr.is_null() {
+            let layer_num = CFNumber::wrap_under_get_rule(layer_ptr as _);
+            layer_num.to_i32().unwrap_or(0)
+        } else {
+            0
+        };
+
+        // Get window bounds
+        let bounds_key = CFString::new("kCGWindowBounds");
+        let bounds_ptr = CFDictionaryGetValue(dict, bounds_key.as_CFTypeRef() as *const _);
+        if bounds_ptr.is_null() {
+            return None;
+        }
+        let bounds_dict = bounds_ptr as CFDictionaryRef;
+
+        let x = get_dict_number(bounds_dict, "X").unwrap_or(0.0);
+        let y = get_dict_number(bounds_dict, "Y").unwrap_or(0.0);
+        let width = get_dict_number(bounds_dict, "Width").unwrap_or(0.0);
+        let height = get_dict_number(bounds_dict, "Height").unwrap_or(0.0);
+
+        // Get window title
+        let title_key = CFString::new("kCGWindowName");
+        let title_ptr = CFDictionaryGetValue(dict, title_key.as_CFTypeRef() as *const _);
+        let title = if !title_ptr.is_null() {
+            let cf_str = CFString::wrap_under_get_rule(title_ptr as _);
+            Some(cf_str.to_string())
+        } else {
+            None
+        };
+
+        // Get owner (app) name
+        let owner_key = CFString::new("kCGWindowOwnerName");
+        let owner_ptr = CFDictionaryGetValue(dict, owner_key.as_CFTypeRef() as *const _);
+        let app = if !owner_ptr.is_null() {
+            let cf_str = CFString::wrap_under_get_rule(owner_ptr as _);
+            Some(cf_str.to_string())
+        } else {
+            None
+        };
+
+        // Check if on screen
+        let onscreen_key = CFString::new("kCGWindowIsOnscreen");
+        let onscreen_ptr = CFDictionaryGetValue(dict, onscreen_key.as_CFTypeRef() as *const _);
+        let on_screen = if !onscreen_ptr.is_null() {
+            let cf_bool = CFBoolean::wrap_under_get_rule(onscreen_ptr as _);
+            cf_bool == CFBoolean::true_value()
+        } else {
+            true // Default to true for on-screen list
+        };
+
+        Some(WindowInfo {
+            id: id as u32,
+            title,
+            app,
+            bounds: WindowBounds {
+                x,
+                y,
+                width,
+                height,
+            },
+            layer,
+            on_screen,
+        })
+    }
+
+    unsafe fn get_dict_number(dict: CFDictionaryRef, key: &str) -> Option<f64> {
+        let cf_key = CFString::new(key);
+        let ptr = CFDictionaryGetValue(dict, cf_key.as_CFTypeRef() as *const _);
+        if ptr.is_null() {
+            return None;
+        }
+        let num = CFNumber::wrap_under_get_rule(ptr as _);
+        num.to_f64()
+    }
+
+    pub fn is_mouse_down() -> bool {
+        unsafe {
+            CGEventSourceButtonState(
+                CGEventSourceStateID::CombinedSessionState,
+                CGMouseButton::Left,
+            )
+        }
+    }
+
+    pub fn get_mouse_position() -> (f64, f64) {
+        if let Ok(source) = CGEventSource::new(CGEventSourceStateID::CombinedSessionState) {
+            if let Ok(event) = CGEvent::new(source) {
+                let location = event.location();
+                return (location.x, location.y);
+            }
+        }
+        (0.0, 0.0)
+    }
+
+    // FFI declarations for CoreFoundation/CoreGraphics
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFArrayGetCount(array: CFArrayRef) -> isize;
+        fn CFArrayGetValueAtIndex(array: CFArrayRef, index: isize) -> *const std::ffi::c_void;
+        fn CFDictionaryGetValue(
+            dict: CFDictionaryRef,
+            key: *const std::ffi::c_void,
+        ) -> *const std::ffi::c_void;
+        fn CFRelease(cf: *const std::ffi::c_void);
+    }
+
+    #[link(name = "CoreGraphics", kind = "framework")]
+    extern "C" {
+        fn CGWindowListCopyWindowInfo(option: u32, relativeToWindow: u32) -> CFArrayRef;
+        fn CGEventSourceButtonState(stateID: CGEventSourceStateID, button: CGMouseButton) -> bool;
+    }
+
+    type CFArrayRef = *const std::ffi::c_void;
+
+    // macOS constants - using Apple's naming convention
+    #[allow(non_upper_case_globals)]
+    const kCGWindowListOptionOnScreenOnly: u32 = 1 << 0;
+    #[allow(non_upper_case_globals)]
+    const kCGWindowListExcludeDesktopElements: u32 = 1 << 4;
+    #[allow(non_upper_case_globals)]
+    const kCGNullWindowID: u32 = 0;
+
+    #[repr(u32)]
+    #[derive(Clone, Copy)]
+    pub enum CGMouseButton {
+        Left = 0,
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn get_all_windows() -> Vec<WindowInfo> {
+    macos::get_all_windows()
+}
+
+#[cfg(target_os = "macos")]
+fn is_mouse_down() -> bool {
+    macos::is_mouse_down()
+}
+
+#[cfg(target_os = "macos")]
+fn get_mouse_position() -> (f64, f64) {
+    macos::get_mouse_position()
+}
+
+// ============================================================================
+// Stub implementations for non-macOS platforms
+// ============================================================================
+
+#[cfg(not(target_os = "macos"))]
+pub fn get_all_windows() -> Vec<WindowInfo> {
+    eprintln!("window-pick currently only supports macOS");
+    Vec::new()
+}
+
+#[cfg(not(target_os = "macos"))]
+fn is_mouse_down() -> bool {
+    false
+}
+
+#[cfg(not(target_os = "macos"))]
+fn get_mouse_position() -> (f64, f64) {
+    (0.0, 0.0)
+}